@@ -1,20 +1,24 @@
 pub mod banks;
 pub mod marginfi_accounts;
+pub mod mints;
+pub mod oracles;
 
 mod luts;
-mod mints;
-mod oracles;
+mod snapshot;
 
-use mints::MintsCache;
-use oracles::OraclesCache;
+use mints::{CachedMint, MintsCache};
+use oracles::{CachedOracle, OraclesCache};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    path::PathBuf,
     sync::{Arc, RwLock},
 };
 
-use anyhow::{anyhow, Result};
-use log::{error, info, trace};
-use marginfi::state::{marginfi_account::MarginfiAccount, marginfi_group::Bank};
+use anyhow::Result;
+use log::{error, info, trace, warn};
+use marginfi::state::{
+    marginfi_account::MarginfiAccount, marginfi_group::Bank, price::OracleSetup,
+};
 use solana_program::clock::Clock;
 use solana_sdk::{
     account::Account,
@@ -25,10 +29,19 @@ use solana_sdk::{
 use anchor_lang::AccountDeserialize;
 
 use crate::{
-    cache::{banks::BanksCache, luts::LutsCache, marginfi_accounts::MarginfiAccountsCache},
-    common::{get_marginfi_message_type, MessageType},
+    cache::{
+        banks::{BanksCache, CachedBank},
+        luts::LutsCache,
+        marginfi_accounts::{CachedMarginfiAccount, MarginfiAccountsCache},
+    },
+    common::{
+        discriminator_filter, get_marginfi_message_type, MessageType,
+        MARGINFI_ACCOUNT_DISCRIMINATOR, MARGINFI_BANK_DISCRIMINATOR,
+    },
     comms::CommsClient,
     config::Config,
+    error::MaryError,
+    metrics::{Counter, MetricsRegistry},
 };
 
 // TODO: not completely sure that this trait is really needed.
@@ -44,23 +57,22 @@ pub struct Cache {
 }
 
 impl Cache {
-    pub fn new(clock: Clock) -> Self {
+    pub fn new(clock: Clock, max_confidence_bps: u64) -> Self {
         Self {
             clock: RwLock::new(clock),
             marginfi_accounts: MarginfiAccountsCache::default(),
             banks: BanksCache::default(),
             mints: MintsCache::default(),
-            oracles: OraclesCache::default(),
+            oracles: OraclesCache::new(max_confidence_bps),
             luts: LutsCache::default(),
         }
     }
 
     pub fn update_clock(&self, clock: Clock) -> Result<()> {
         trace!("Updating Clock in cache: {:?}", clock);
-        *self
-            .clock
-            .write()
-            .map_err(|e| anyhow!("Failed to lock Clock for the update: {}", e))? = clock;
+        *self.clock.write().map_err(|e| {
+            MaryError::LockPoisoned(format!("Failed to lock Clock for the update: {}", e))
+        })? = clock;
         Ok(())
     }
 
@@ -68,64 +80,381 @@ impl Cache {
         Ok(self
             .clock
             .read()
-            .map_err(|e| anyhow!("Failed to lock Clock for reading: {}", e))?
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!("Failed to lock Clock for reading: {}", e))
+            })?
             .clone())
     }
+
+    /// Counts the cached entries, each cache is read under its own lock so the counts may be off
+    /// by the updates that landed in between.
+    pub fn stats(&self) -> Result<CacheStats> {
+        let stats = CacheStats {
+            marginfi_accounts: self.marginfi_accounts.count()?,
+            banks: self.banks.count()?,
+            oracles: self.oracles.count()?,
+            mints: self.mints.count()?,
+            luts: self.luts.count(),
+            estimated_bytes: 0,
+        };
+        // The heap allocations of the cached entries, e.g. the emode entries, are not accounted for
+        let estimated_bytes = stats.marginfi_accounts * size_of::<CachedMarginfiAccount>()
+            + stats.banks * size_of::<CachedBank>()
+            + stats.oracles * size_of::<CachedOracle>()
+            + stats.mints * size_of::<CachedMint>()
+            + stats.luts * size_of::<AddressLookupTableAccount>()
+            + self.luts.address_count() * size_of::<Pubkey>();
+        Ok(CacheStats {
+            estimated_bytes,
+            ..stats
+        })
+    }
+
+    pub fn update_marginfi_account(
+        &self,
+        slot: u64,
+        address: Pubkey,
+        account: &MarginfiAccount,
+    ) -> Result<()> {
+        let cached_account = CachedMarginfiAccount::from(slot, address, account);
+        let health = cached_account.health_with_fallback(self);
+        self.marginfi_accounts.upsert(cached_account, health)
+    }
+
+    /// Returns the cached Bank, fetching it with its missing Mint and Oracles if it is not cached yet,
+    /// e.g. the Bank created after the startup. Geyser picks them up on its next subscription.
+    pub fn ensure_bank(
+        &self,
+        address: &Pubkey,
+        comms_client: &dyn CommsClient,
+    ) -> Result<CachedBank> {
+        if let Ok(bank) = self.banks.get_bank(address) {
+            return Ok(bank);
+        }
+
+        info!("The Bank {} is not in cache, fetching it.", address);
+        let account = comms_client.get_account(address)?;
+        if get_marginfi_message_type(&account.data) != Some(MessageType::Bank) {
+            return Err(
+                MaryError::Deserialize(format!("The account {} is not a Bank", address)).into(),
+            );
+        }
+        let bank: Bank = Bank::try_deserialize(&mut account.data.as_slice())?;
+        let clock = self.get_clock()?;
+        self.banks.update(clock.slot, *address, &bank)?;
+        let cached_bank = self.banks.get_bank(address)?;
+
+        if self.mints.get(cached_bank.mint())?.is_none() {
+            let mint = comms_client.get_account(cached_bank.mint())?;
+            self.mints.update(*cached_bank.mint(), &mint)?;
+        }
+
+        let cached_oracles = self.oracles.get_oracle_addresses();
+        for (index, oracle_address) in cached_bank.oracle_addresses().iter().enumerate() {
+            if cached_oracles.contains(oracle_address) {
+                continue;
+            }
+            let oracle = comms_client.get_account(oracle_address)?;
+            self.insert_bank_oracle(
+                &clock,
+                cached_bank.oracle_type(),
+                index,
+                oracle_address,
+                oracle,
+            )?;
+        }
+
+        info!("Added the Bank {} to cache.", address);
+        Ok(cached_bank)
+    }
+
+    /// The Oracles of the cached Banks to subscribe to, including the ones not fetched yet, e.g. of
+    /// the Banks created or migrated to new Oracles after the startup.
+    pub fn get_watched_oracle_addresses(&self) -> Result<Vec<Pubkey>> {
+        let mut addresses: HashSet<Pubkey> =
+            self.oracles.get_oracle_addresses().into_iter().collect();
+        for oracle in self.banks.get_oracles_data()? {
+            addresses.extend(oracle.oracle_addresses);
+        }
+        Ok(addresses.into_iter().collect())
+    }
+
+    /// The Mints of the cached Banks to subscribe to, including the ones not fetched yet.
+    pub fn get_watched_mint_addresses(&self) -> Result<Vec<Pubkey>> {
+        let mut addresses: HashSet<Pubkey> = self.mints.get_mint_addresses()?.into_iter().collect();
+        addresses.extend(self.banks.get_mints()?);
+        Ok(addresses.into_iter().collect())
+    }
+
+    /// Adds the Oracle of a cached Bank from its first update. Returns false if no cached Bank uses it.
+    pub fn insert_new_oracle(&self, slot: u64, address: &Pubkey, account: Account) -> Result<bool> {
+        let Some(bank) = self
+            .banks
+            .get_banks_by_oracle(address)?
+            .first()
+            .map(|bank| self.banks.get_bank(bank))
+            .transpose()?
+        else {
+            return Ok(false);
+        };
+        let Some(index) = bank
+            .oracle_addresses()
+            .iter()
+            .position(|oracle| oracle == address)
+        else {
+            return Ok(false);
+        };
+
+        let clock = Clock {
+            slot,
+            ..self.get_clock()?
+        };
+        self.insert_bank_oracle(&clock, bank.oracle_type(), index, address, account)?;
+        info!(
+            "Added the Oracle {} of the Bank {} to cache.",
+            address, bank.address
+        );
+        Ok(true)
+    }
+
+    fn insert_bank_oracle(
+        &self,
+        clock: &Clock,
+        oracle_type: OracleSetup,
+        index: usize,
+        address: &Pubkey,
+        account: Account,
+    ) -> Result<()> {
+        // The staked collateral oracle keys are followed by the LST mint and the stake pool
+        if oracle_type == OracleSetup::StakedWithPythPush && index > 0 {
+            self.oracles
+                .insert_staked_account(clock.slot, address, account)
+        } else {
+            self.oracles.insert(
+                clock.slot,
+                clock.unix_timestamp,
+                address,
+                oracle_type,
+                account,
+            )
+        }
+    }
+}
+
+/// The number of the cached entries and a rough estimate of the memory they take.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub marginfi_accounts: usize,
+    pub banks: usize,
+    pub oracles: usize,
+    pub mints: usize,
+    pub luts: usize,
+    pub estimated_bytes: usize,
+}
+
+/// The program accounts added to the cache by a load, and the ones skipped as unparsable.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LoadSummary {
+    pub marginfi_accounts: usize,
+    pub banks: usize,
+    pub skipped: usize,
 }
 
 //TODO: consider moving out to it's own module if it grows larger
 pub struct CacheLoader<T: CommsClient> {
     program_id: Pubkey,
     lut_addresses: Vec<Pubkey>,
+    snapshot_path: Option<PathBuf>,
     cache: Arc<Cache>,
     comms_client: T,
+    metrics: Arc<MetricsRegistry>,
 }
 
 impl<T: CommsClient> CacheLoader<T> {
-    pub fn new(config: &Config, cache: Arc<Cache>) -> Result<Self> {
+    pub fn new(config: &Config, cache: Arc<Cache>, metrics: Arc<MetricsRegistry>) -> Result<Self> {
         let lut_addresses = config.lut_addresses.clone();
         let comms_client = T::new(config)?;
         Ok(Self {
             program_id: config.marginfi_program_id,
             lut_addresses,
+            snapshot_path: config.cache_snapshot_path.clone(),
             comms_client,
             cache,
+            metrics,
         })
     }
 
+    /// The Banks restored from a fresh snapshot are re-fetched by their addresses instead of the
+    /// program scan, the Mints are not fetched again and the LUTs only if the configured ones
+    /// changed. The Marginfi accounts, the Oracles and the Bank vaults are always fetched.
     pub fn load_cache(&self) -> Result<()> {
-        // Load Marginfi account and banks
-        self.load_accounts()?;
-        self.load_mints()?;
+        if self.restore_snapshot() {
+            self.reconcile_restored_banks()?;
+            self.load_new_banks()?;
+            self.load_program_accounts(&[MARGINFI_ACCOUNT_DISCRIMINATOR])?;
+            // The configured LUTs win over the snapshot ones
+            if !self.restored_luts_configured() {
+                self.cache.luts.populate(Vec::new());
+                self.load_luts()?;
+            }
+        } else {
+            // Load Marginfi account and banks
+            self.load_accounts()?;
+            self.load_mints()?;
+            self.load_luts()?;
+            self.save_snapshot();
+        }
         self.load_oracles()?;
-        self.load_luts()?;
+        self.load_vaults()?;
         Ok(())
     }
 
-    pub fn load_accounts(&self) -> Result<()> {
+    /// Saves the cache snapshot if configured, the failure only costs the next start a full load.
+    pub fn save_snapshot(&self) {
+        let Some(path) = &self.snapshot_path else {
+            return;
+        };
+        if let Err(err) = self.cache.snapshot_to(path, &self.program_id) {
+            error!("Failed to save the cache snapshot: {}", err);
+        }
+    }
+
+    fn restore_snapshot(&self) -> bool {
+        let Some(path) = &self.snapshot_path else {
+            return false;
+        };
+        self.cache
+            .restore_from(path, &self.program_id)
+            .unwrap_or_else(|err| {
+                warn!(
+                    "Failed to restore the cache snapshot, loading from scratch: {}",
+                    err
+                );
+                false
+            })
+    }
+
+    /// Brings the restored Banks up to date with the chain, they changed since the snapshot.
+    fn reconcile_restored_banks(&self) -> Result<()> {
+        let addresses: Vec<_> = self.cache.banks.get_banks_map()?.into_keys().collect();
+        let (slot, accounts) = self.comms_client.get_accounts_with_slot(&addresses)?;
+        let corrected = self.cache.banks.reconcile(slot, accounts)?;
+        info!(
+            "Updated {} of the {} restored Banks.",
+            corrected.len(),
+            addresses.len()
+        );
+        Ok(())
+    }
+
+    /// Adds the Banks created since the snapshot and their Mints, the restored set misses them.
+    fn load_new_banks(&self) -> Result<()> {
+        let slot = self.cache.get_clock()?.slot;
+        let restored: HashSet<Pubkey> = self.cache.banks.get_banks_map()?.into_keys().collect();
+
+        let mut summary = LoadSummary::default();
+        for (address, account) in self.comms_client.get_program_accounts_filtered(
+            &self.program_id,
+            vec![discriminator_filter(&MARGINFI_BANK_DISCRIMINATOR)],
+        )? {
+            if restored.contains(&address) {
+                continue;
+            }
+            let bank = match Bank::try_deserialize(&mut account.data.as_slice()) {
+                Ok(bank) => bank,
+                Err(err) => {
+                    self.skip_account("Bank", &address, err, &mut summary);
+                    continue;
+                }
+            };
+            self.cache.banks.update(slot, address, &bank)?;
+            info!(
+                "Added the Bank {:?} created since the snapshot to cache.",
+                address
+            );
+            summary.banks += 1;
+        }
+
+        let cached_mints: HashSet<Pubkey> =
+            self.cache.mints.get_mint_addresses()?.into_iter().collect();
+        let missing_mints: Vec<Pubkey> = self
+            .cache
+            .banks
+            .get_mints()?
+            .into_iter()
+            .filter(|mint| !cached_mints.contains(mint))
+            .collect();
+        for (address, mint) in self.comms_client.get_accounts(&missing_mints)? {
+            self.cache.mints.update(address, &mint)?;
+            info!("Added the Mint {:?} to cache.", address);
+        }
+
+        info!(
+            "Loaded {} Banks created since the snapshot, skipped {} unparsable ones.",
+            summary.banks, summary.skipped
+        );
+        Ok(())
+    }
+
+    fn restored_luts_configured(&self) -> bool {
+        let restored: HashSet<Pubkey> = self
+            .cache
+            .luts
+            .get_all()
+            .iter()
+            .map(|lut| lut.key)
+            .collect();
+        restored == self.lut_addresses.iter().copied().collect()
+    }
+
+    pub fn load_accounts(&self) -> Result<LoadSummary> {
+        // Banks go first so the accounts health can be computed against them
+        self.load_program_accounts(&[MARGINFI_BANK_DISCRIMINATOR, MARGINFI_ACCOUNT_DISCRIMINATOR])
+    }
+
+    /// The accounts that fail to deserialize, e.g. of a newer layout than the pinned marginfi
+    /// types, are counted and skipped rather than failing the whole load.
+    fn load_program_accounts(&self, discriminators: &[[u8; 8]]) -> Result<LoadSummary> {
         info!("Loading Accounts for the Program id {}...", self.program_id);
 
         let slot = self.cache.get_clock()?.slot;
 
-        let accounts = self.comms_client.get_program_accounts(&self.program_id)?;
-        let mut marginfi_accounts_count = 0;
-        let mut banks_count = 0;
+        let mut accounts = Vec::new();
+        for discriminator in discriminators {
+            accounts.extend(self.comms_client.get_program_accounts_filtered(
+                &self.program_id,
+                vec![discriminator_filter(discriminator)],
+            )?);
+        }
+
+        let mut summary = LoadSummary::default();
         for (address, account) in accounts {
             match get_marginfi_message_type(&account.data) {
                 Some(MessageType::MarginfiAccount) => {
-                    let marginfi_account: MarginfiAccount =
-                        MarginfiAccount::try_deserialize(&mut account.data.as_slice())?;
+                    let marginfi_account =
+                        match MarginfiAccount::try_deserialize(&mut account.data.as_slice()) {
+                            Ok(marginfi_account) => marginfi_account,
+                            Err(err) => {
+                                self.skip_account("Marginfi account", &address, err, &mut summary);
+                                continue;
+                            }
+                        };
                     self.cache
-                        .marginfi_accounts
-                        .update(slot, address, marginfi_account)?;
+                        .update_marginfi_account(slot, address, &marginfi_account)?;
                     trace!("Added the Marginfi Account {:?} to cache.", address);
-                    marginfi_accounts_count += 1;
+                    summary.marginfi_accounts += 1;
                 }
                 Some(MessageType::Bank) => {
-                    let bank: Bank = Bank::try_deserialize(&mut account.data.as_slice())?;
+                    let bank = match Bank::try_deserialize(&mut account.data.as_slice()) {
+                        Ok(bank) => bank,
+                        Err(err) => {
+                            self.skip_account("Bank", &address, err, &mut summary);
+                            continue;
+                        }
+                    };
                     self.cache.banks.update(slot, address, &bank)?;
                     info!("Added the Bank {:?} to cache.", address);
-                    banks_count += 1;
+                    summary.banks += 1;
                 }
                 _ => {
                     // Not yet
@@ -134,11 +463,26 @@ impl<T: CommsClient> CacheLoader<T> {
         }
 
         info!(
-            "Loaded {} Marginfi accounts and {} Banks.",
-            marginfi_accounts_count, banks_count
+            "Loaded {} Marginfi accounts and {} Banks, skipped {} unparsable accounts.",
+            summary.marginfi_accounts, summary.banks, summary.skipped
         );
 
-        Ok(())
+        Ok(summary)
+    }
+
+    fn skip_account(
+        &self,
+        kind: &str,
+        address: &Pubkey,
+        err: impl std::fmt::Display,
+        summary: &mut LoadSummary,
+    ) {
+        warn!(
+            "Skipping the {} {} that failed to deserialize: {}",
+            kind, address, err
+        );
+        self.metrics.inc(Counter::DeserializationFailures);
+        summary.skipped += 1;
     }
 
     pub fn load_mints(&self) -> Result<()> {
@@ -157,10 +501,29 @@ impl<T: CommsClient> CacheLoader<T> {
         Ok(())
     }
 
+    /// The vaults that fail to parse are left out, their liquidity is unknown until the next update.
+    pub fn load_vaults(&self) -> Result<()> {
+        info!("Loading the Bank vaults...");
+
+        let slot = self.cache.get_clock()?.slot;
+        let vault_addresses = self.cache.banks.get_vaults()?;
+
+        let mut vaults_counter = 0;
+        for (address, vault) in self.comms_client.get_accounts(&vault_addresses)? {
+            match self.cache.banks.update_vault(slot, address, &vault) {
+                Ok(()) => vaults_counter += 1,
+                Err(err) => warn!("Skipping the Bank vault {}: {}", address, err),
+            }
+        }
+
+        info!("Loaded {} Bank vaults.", vaults_counter);
+        Ok(())
+    }
+
     pub fn load_oracles(&self) -> Result<()> {
         info!("Loading Oracles...");
 
-        let slot = self.cache.get_clock()?.slot;
+        let clock = self.cache.get_clock()?;
 
         let oracles_data = self.cache.banks.get_oracles_data()?;
         let oracle_addresses: Vec<Pubkey> = oracles_data
@@ -176,15 +539,17 @@ impl<T: CommsClient> CacheLoader<T> {
 
         let mut oracle_counter = 0;
         for oracle_data in oracles_data {
-            for oracle_address in oracle_data.oracle_addresses {
+            for (index, oracle_address) in oracle_data.oracle_addresses.into_iter().enumerate() {
                 match oracle_accounts.get(&oracle_address) {
                     Some(account) => {
-                        if let Err(err) = self.cache.oracles.insert(
-                            slot,
-                            &oracle_address,
+                        let result = self.cache.insert_bank_oracle(
+                            &clock,
                             oracle_data.oracle_type,
+                            index,
+                            &oracle_address,
                             account.clone(),
-                        ) {
+                        );
+                        if let Err(err) = result {
                             error!(
                                 "Failed to add Oracle {:?} to cache: {}",
                                 oracle_address, err
@@ -217,8 +582,12 @@ impl<T: CommsClient> CacheLoader<T> {
 
         let mut luts: Vec<AddressLookupTableAccount> = Vec::new();
         for (lut_address, lut_account) in lut_accounts {
-            let lut = AddressLookupTable::deserialize(&lut_account.data)
-                .map_err(|e| anyhow!("Failed to deserialize the {} LUT : {:?}", lut_address, e))?;
+            let lut = AddressLookupTable::deserialize(&lut_account.data).map_err(|e| {
+                MaryError::Deserialize(format!(
+                    "Failed to deserialize the {} LUT : {:?}",
+                    lut_address, e
+                ))
+            })?;
             luts.push(AddressLookupTableAccount {
                 key: lut_address,
                 addresses: lut.addresses.to_vec(),
@@ -226,7 +595,7 @@ impl<T: CommsClient> CacheLoader<T> {
         }
 
         let luts_total = luts.len();
-        self.cache.luts.populate(luts)?;
+        self.cache.luts.populate(luts);
 
         info!("Loaded {} Luts.", luts_total);
         Ok(())
@@ -258,14 +627,18 @@ pub mod test_util {
     }
 
     pub fn create_dummy_cache() -> Cache {
-        Cache::new(generate_test_clock(1))
+        Cache::new(generate_test_clock(1), 0)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::test_util::generate_test_clock;
-    use crate::cache::{banks::test_util::create_bank_with_oracles, test_util::create_dummy_cache};
+    use crate::cache::{
+        banks::test_util::{create_bank_account, create_bank_with_oracles, create_token_account},
+        marginfi_accounts::test_util::{create_marginfi_account, create_marginfi_account_account},
+        test_util::create_dummy_cache,
+    };
     use crate::comms::test_util::MockedCommsClient;
     use crate::config::test_util::create_dummy_config;
     use solana_sdk::pubkey::Pubkey;
@@ -278,14 +651,50 @@ mod tests {
     #[test]
     fn test_cache_new() {
         let clock = generate_test_clock(1);
-        let cache = Cache::new(clock);
+        let cache = Cache::new(clock, 0);
         assert_eq!(cache.get_clock().unwrap().slot, 1);
     }
 
+    #[test]
+    fn test_cache_stats() {
+        let cache = create_dummy_cache();
+        assert_eq!(cache.stats().unwrap(), CacheStats::default());
+
+        cache
+            .banks
+            .update(1, Pubkey::new_unique(), &create_bank_with_oracles(vec![]))
+            .unwrap();
+        cache
+            .update_marginfi_account(
+                1,
+                Pubkey::new_unique(),
+                &create_marginfi_account(Pubkey::new_unique(), vec![]),
+            )
+            .unwrap();
+        cache.luts.populate(vec![AddressLookupTableAccount {
+            key: Pubkey::new_unique(),
+            addresses: vec![Pubkey::new_unique()],
+        }]);
+
+        let stats = cache.stats().unwrap();
+        assert_eq!(stats.marginfi_accounts, 1);
+        assert_eq!(stats.banks, 1);
+        assert_eq!(stats.oracles, 0);
+        assert_eq!(stats.mints, 0);
+        assert_eq!(stats.luts, 1);
+        assert_eq!(
+            stats.estimated_bytes,
+            size_of::<CachedMarginfiAccount>()
+                + size_of::<CachedBank>()
+                + size_of::<AddressLookupTableAccount>()
+                + size_of::<Pubkey>()
+        );
+    }
+
     #[test]
     fn test_cache_update_clock() {
         let initial_clock = generate_test_clock(1);
-        let cache = Cache::new(initial_clock);
+        let cache = Cache::new(initial_clock, 0);
 
         // Create a new clock with different values
         let mut updated_clock = generate_test_clock(2);
@@ -301,6 +710,106 @@ mod tests {
         assert_eq!(cached_clock.unix_timestamp, updated_clock.unix_timestamp);
     }
 
+    #[test]
+    fn test_ensure_bank_fetches_missing_bank() {
+        let cache = create_dummy_cache();
+        let bank_address = Pubkey::new_unique();
+        let oracle_address = Pubkey::new_unique();
+        let bank = create_bank_with_oracles(vec![oracle_address]);
+        let comms_client = MockedCommsClient::with_accounts(HashMap::from([
+            (bank_address, create_bank_account(&bank)),
+            (bank.mint, Account::new(1, 82, &spl_token_2022::id())),
+            (oracle_address, Account::new(1, 100, &Pubkey::new_unique())),
+        ]));
+        assert!(cache.banks.get_bank(&bank_address).is_err());
+
+        let cached_bank = cache.ensure_bank(&bank_address, &comms_client).unwrap();
+        assert_eq!(cached_bank.address, bank_address);
+        assert_eq!(*cached_bank.mint(), bank.mint);
+        assert_eq!(cached_bank.slot, cache.get_clock().unwrap().slot);
+        assert!(cache.banks.get_bank(&bank_address).is_ok());
+        assert!(cache.mints.get(&bank.mint).unwrap().is_some());
+        assert!(cache.oracles._get(&oracle_address).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_ensure_bank_returns_cached_bank() {
+        let cache = create_dummy_cache();
+        let bank_address = Pubkey::new_unique();
+        cache
+            .banks
+            .update(1, bank_address, &create_bank_with_oracles(vec![]))
+            .unwrap();
+
+        // Nothing is fetched for the cached bank
+        let comms_client = MockedCommsClient::with_accounts(HashMap::new());
+        assert!(cache.ensure_bank(&bank_address, &comms_client).is_ok());
+    }
+
+    #[test]
+    fn test_ensure_bank_rejects_missing_or_foreign_account() {
+        let cache = create_dummy_cache();
+        let foreign_address = Pubkey::new_unique();
+        let comms_client = MockedCommsClient::with_accounts(HashMap::from([(
+            foreign_address,
+            Account::new(1, 100, &Pubkey::new_unique()),
+        )]));
+
+        assert!(cache
+            .ensure_bank(&Pubkey::new_unique(), &comms_client)
+            .is_err());
+        assert!(cache.ensure_bank(&foreign_address, &comms_client).is_err());
+        assert_eq!(cache.banks.count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_insert_new_oracle_of_cached_bank() {
+        let cache = create_dummy_cache();
+        let cached_oracle = Pubkey::new_unique();
+        let new_oracle = Pubkey::new_unique();
+        cache
+            .oracles
+            .insert(
+                1,
+                0,
+                &cached_oracle,
+                OracleSetup::PythPushOracle,
+                Account::new(1, 100, &Pubkey::new_unique()),
+            )
+            .unwrap();
+        cache
+            .banks
+            .update(
+                1,
+                Pubkey::new_unique(),
+                &create_bank_with_oracles(vec![new_oracle]),
+            )
+            .unwrap();
+
+        let mut watched = cache.get_watched_oracle_addresses().unwrap();
+        watched.sort();
+        let mut expected = vec![cached_oracle, new_oracle];
+        expected.sort();
+        assert_eq!(watched, expected);
+
+        let bank_mint = cache.banks.get_mints().unwrap()[0];
+        assert_eq!(cache.get_watched_mint_addresses().unwrap(), vec![bank_mint]);
+
+        let account = Account::new(1, 100, &Pubkey::new_unique());
+        assert!(!cache.oracles.contains(&new_oracle).unwrap());
+        assert!(cache
+            .insert_new_oracle(5, &new_oracle, account.clone())
+            .unwrap());
+        assert!(cache.oracles.contains(&new_oracle).unwrap());
+
+        // No cached Bank uses the Oracle
+        let unknown_oracle = Pubkey::new_unique();
+        assert!(!cache
+            .insert_new_oracle(5, &unknown_oracle, account)
+            .unwrap());
+        assert!(!cache.oracles.contains(&unknown_oracle).unwrap());
+    }
+
     #[test]
     fn test_cache_loader_new() {
         // Prepare dummy config and cache
@@ -308,13 +817,103 @@ mod tests {
         let cache = Arc::new(create_dummy_cache());
 
         // Try to create a CacheLoader using the mocked comms client
-        let loader = CacheLoader::<MockedCommsClient>::new(&config, cache.clone());
+        let loader = CacheLoader::<MockedCommsClient>::new(
+            &config,
+            cache.clone(),
+            Arc::new(MetricsRegistry::default()),
+        );
         assert!(loader.is_ok());
         let loader = loader.unwrap();
         assert_eq!(loader.program_id, config.marginfi_program_id);
     }
 
-    //TODO: add the CacheLoader tests after figuring out how to serialize MarginfiAccount.
+    #[test]
+    fn test_cache_loader_load_accounts_skips_malformed_account() {
+        let config = create_dummy_config();
+        let cache = Arc::new(create_dummy_cache());
+        let metrics = Arc::new(MetricsRegistry::default());
+
+        let good = Pubkey::new_unique();
+        let malformed = Pubkey::new_unique();
+        let mut good_account =
+            create_marginfi_account_account(&create_marginfi_account(Pubkey::new_unique(), vec![]));
+        good_account.owner = config.marginfi_program_id;
+        // The discriminator matches, but the layout is too short for the pinned MarginfiAccount
+        let mut malformed_data = MARGINFI_ACCOUNT_DISCRIMINATOR.to_vec();
+        malformed_data.extend_from_slice(&[0u8; 16]);
+        let malformed_account = Account {
+            lamports: 1,
+            data: malformed_data,
+            owner: config.marginfi_program_id,
+            executable: false,
+            rent_epoch: 0,
+        };
+
+        let loader = CacheLoader {
+            program_id: config.marginfi_program_id,
+            lut_addresses: vec![],
+            snapshot_path: None,
+            comms_client: MockedCommsClient::with_accounts(HashMap::from([
+                (good, good_account),
+                (malformed, malformed_account),
+            ])),
+            cache: cache.clone(),
+            metrics: metrics.clone(),
+        };
+
+        assert_eq!(
+            loader.load_accounts().unwrap(),
+            LoadSummary {
+                marginfi_accounts: 1,
+                banks: 0,
+                skipped: 1,
+            }
+        );
+        assert!(cache.marginfi_accounts.get_account(&good).is_ok());
+        assert!(cache.marginfi_accounts.get_account(&malformed).is_err());
+        assert_eq!(metrics.get_counter(Counter::DeserializationFailures), 1);
+    }
+
+    // The loader deserializes the Banks with the same marginfi types the BanksCache stores
+    #[test]
+    fn test_cache_loader_load_accounts_banks() {
+        let config = create_dummy_config();
+        let cache = Arc::new(create_dummy_cache());
+
+        let bank_address = Pubkey::new_unique();
+        let oracle = Pubkey::new_unique();
+        let bank = create_bank_with_oracles(vec![oracle]);
+        let mut bank_account = create_bank_account(&bank);
+        bank_account.owner = config.marginfi_program_id;
+
+        let loader = CacheLoader {
+            program_id: config.marginfi_program_id,
+            lut_addresses: vec![],
+            snapshot_path: None,
+            comms_client: MockedCommsClient::with_accounts(HashMap::from([(
+                bank_address,
+                bank_account,
+            )])),
+            cache: cache.clone(),
+            metrics: Arc::new(MetricsRegistry::default()),
+        };
+
+        assert_eq!(
+            loader.load_accounts().unwrap(),
+            LoadSummary {
+                marginfi_accounts: 0,
+                banks: 1,
+                skipped: 0,
+            }
+        );
+        let cached_bank = cache.banks.get_bank(&bank_address).unwrap();
+        assert_eq!(cached_bank.address, bank_address);
+        assert_eq!(cached_bank.bank().mint, bank.mint);
+        assert_eq!(
+            cache.banks.get_banks_by_oracle(&oracle).unwrap(),
+            vec![bank_address]
+        );
+    }
 
     #[test]
     fn test_cache_loader_load_mints() {
@@ -347,8 +946,10 @@ mod tests {
         let loader = CacheLoader {
             program_id: config.marginfi_program_id,
             lut_addresses: vec![],
+            snapshot_path: None,
             comms_client: mocked_client,
             cache: cache.clone(),
+            metrics: Arc::new(MetricsRegistry::default()),
         };
 
         // Call load_mints and check that the mint was added to the cache
@@ -360,6 +961,33 @@ mod tests {
         assert!(mints.get(&mint_pubkey).is_ok());
     }
 
+    #[test]
+    fn test_cache_loader_load_vaults() {
+        let cache = Arc::new(create_dummy_cache());
+        let mut bank = create_bank_with_oracles(vec![]);
+        bank.liquidity_vault = Pubkey::new_unique();
+        bank.insurance_vault = Pubkey::new_unique();
+        let bank_address = Pubkey::new_unique();
+        cache.banks.update(1, bank_address, &bank).unwrap();
+
+        // The insurance vault is malformed, the fee vault is missing
+        let accounts = HashMap::from([
+            (bank.liquidity_vault, create_token_account(7_000)),
+            (bank.insurance_vault, Account::default()),
+        ]);
+        let loader = CacheLoader {
+            program_id: Pubkey::new_unique(),
+            lut_addresses: vec![],
+            snapshot_path: None,
+            comms_client: MockedCommsClient::with_accounts(accounts),
+            cache: cache.clone(),
+            metrics: Arc::new(MetricsRegistry::default()),
+        };
+
+        loader.load_vaults().unwrap();
+        assert_eq!(cache.banks.liquidity(&bank_address).unwrap(), 7_000);
+    }
+
     #[test]
     fn test_cache_loader_load_oracles() {
         // Prepare dummy config and cache
@@ -406,8 +1034,10 @@ mod tests {
         let loader = CacheLoader {
             program_id: config.marginfi_program_id,
             lut_addresses: vec![],
+            snapshot_path: None,
             comms_client: mocked_client,
             cache: cache.clone(),
+            metrics: Arc::new(MetricsRegistry::default()),
         };
 
         // Call load_oracles and check that the oracles were added to the cache
@@ -452,8 +1082,10 @@ mod tests {
         let loader = CacheLoader {
             program_id: config.marginfi_program_id,
             lut_addresses: config.lut_addresses.clone(),
+            snapshot_path: None,
             comms_client: mocked_client,
             cache: cache.clone(),
+            metrics: Arc::new(MetricsRegistry::default()),
         };
 
         // Call load_luts and check that the LUTs were added to the cache
@@ -462,8 +1094,103 @@ mod tests {
 
         // The LUT should now be present in the cache
         let luts_cache = &cache.luts;
-        let luts = luts_cache.get_all().unwrap();
+        let luts = luts_cache.get_all();
         assert!(!luts.is_empty());
         assert!(luts.iter().any(|lut| lut.key == lut_address));
     }
+
+    #[test]
+    fn test_cache_loader_refreshes_restored_snapshot() {
+        let cache = Arc::new(create_dummy_cache());
+        let address = Pubkey::new_unique();
+        let bank = create_bank_with_oracles(vec![]);
+        cache.banks.update(1, address, &bank).unwrap();
+        cache.luts.populate(vec![AddressLookupTableAccount {
+            key: Pubkey::new_unique(),
+            addresses: vec![],
+        }]);
+
+        let mut chain_bank = bank;
+        chain_bank.mint_decimals = bank.mint_decimals + 1;
+        let lut_address = Pubkey::new_unique();
+        let loader = CacheLoader {
+            program_id: Pubkey::new_unique(),
+            lut_addresses: vec![lut_address],
+            snapshot_path: None,
+            comms_client: MockedCommsClient::with_accounts(HashMap::from([(
+                address,
+                create_bank_account(&chain_bank),
+            )]))
+            .with_slot(5),
+            cache: cache.clone(),
+            metrics: Arc::new(MetricsRegistry::default()),
+        };
+
+        loader.reconcile_restored_banks().unwrap();
+        let restored = cache.banks.get_bank(&address).unwrap();
+        assert_eq!(restored.slot, 5);
+        assert_eq!(restored.mint_decimals(), chain_bank.mint_decimals);
+
+        // The snapshot LUTs are not the configured ones
+        assert!(!loader.restored_luts_configured());
+        cache.luts.populate(vec![AddressLookupTableAccount {
+            key: lut_address,
+            addresses: vec![],
+        }]);
+        assert!(loader.restored_luts_configured());
+    }
+
+    #[test]
+    fn test_cache_loader_loads_banks_created_after_snapshot() {
+        let cache = Arc::new(create_dummy_cache());
+        let program_id = Pubkey::new_unique();
+        let restored_address = Pubkey::new_unique();
+        let restored_bank = create_bank_with_oracles(vec![]);
+        cache
+            .banks
+            .update(1, restored_address, &restored_bank)
+            .unwrap();
+        cache
+            .mints
+            .update(restored_bank.mint, &Account::default())
+            .unwrap();
+
+        let new_address = Pubkey::new_unique();
+        let new_bank = create_bank_with_oracles(vec![]);
+        // The restored Banks are left to the reconciliation
+        let mut chain_bank = restored_bank;
+        chain_bank.mint_decimals = restored_bank.mint_decimals + 1;
+        let mut restored_account = create_bank_account(&chain_bank);
+        restored_account.owner = program_id;
+        let mut new_account = create_bank_account(&new_bank);
+        new_account.owner = program_id;
+        let loader = CacheLoader {
+            program_id,
+            lut_addresses: vec![],
+            snapshot_path: None,
+            comms_client: MockedCommsClient::with_accounts(HashMap::from([
+                (restored_address, restored_account),
+                (new_address, new_account),
+                (new_bank.mint, Account::default()),
+            ])),
+            cache: cache.clone(),
+            metrics: Arc::new(MetricsRegistry::default()),
+        };
+
+        loader.load_new_banks().unwrap();
+        assert_eq!(cache.banks.count().unwrap(), 2);
+        assert_eq!(
+            cache.banks.get_bank(&new_address).unwrap().mint(),
+            &new_bank.mint
+        );
+        assert_eq!(
+            cache
+                .banks
+                .get_bank(&restored_address)
+                .unwrap()
+                .mint_decimals(),
+            restored_bank.mint_decimals
+        );
+        assert!(cache.mints.get(&new_bank.mint).unwrap().is_some());
+    }
 }
@@ -1,15 +1,26 @@
 use std::{collections::HashMap, sync::RwLock};
 
+use anchor_lang::AccountDeserialize;
 use anyhow::{anyhow, Result};
-use log::trace;
+use fixed::types::I80F48;
+use log::{trace, warn};
 use marginfi::state::{
-    emode::EmodeConfig,
-    marginfi_group::{Bank, BankConfig},
+    emode::{EmodeConfig, EmodeEntry},
+    marginfi_group::{Bank, BankConfig, BankOperationalState},
     price::OracleSetup,
 };
-use solana_sdk::pubkey::Pubkey;
+use solana_sdk::{account::Account, pubkey::Pubkey};
 
-use crate::cache::CacheEntry;
+use crate::{
+    cache::{marginfi_accounts::calc_value, CacheEntry},
+    error::MaryError,
+};
+
+// The amount follows the mint and the owner in the SPL Token account layout, Token-2022 included.
+const TOKEN_ACCOUNT_AMOUNT_OFFSET: usize = 64;
+
+// The emode tag of the banks and the entries that do not participate in emode.
+const EMPTY_EMODE_TAG: u16 = 0;
 
 #[derive(Debug, Clone)]
 pub struct CachedBankOracle {
@@ -17,7 +28,7 @@ pub struct CachedBankOracle {
     pub oracle_addresses: Vec<Pubkey>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CachedBank {
     pub slot: u64,
     pub address: Pubkey,
@@ -40,28 +51,244 @@ impl CachedBank {
         }
     }
 
+    pub fn bank(&self) -> &Bank {
+        &self.bank
+    }
+
     pub fn mint(&self) -> &Pubkey {
         &self.bank.mint
     }
 
-    pub fn _emode_config(&self) -> &EmodeConfig {
+    pub fn group(&self) -> &Pubkey {
+        &self.bank.group
+    }
+
+    pub fn liquidity_vault(&self) -> &Pubkey {
+        &self.bank.liquidity_vault
+    }
+
+    pub fn insurance_vault(&self) -> &Pubkey {
+        &self.bank.insurance_vault
+    }
+
+    /// The liquidity, the insurance and the fee vault token accounts of the bank.
+    pub fn vaults(&self) -> [Pubkey; 3] {
+        [
+            self.bank.liquidity_vault,
+            self.bank.insurance_vault,
+            self.bank.fee_vault,
+        ]
+    }
+
+    pub fn mint_decimals(&self) -> u8 {
+        self.bank.mint_decimals
+    }
+
+    pub fn oracle_type(&self) -> OracleSetup {
+        self.oracle.oracle_type
+    }
+
+    pub fn oracle_addresses(&self) -> &Vec<Pubkey> {
+        &self.oracle.oracle_addresses
+    }
+
+    /// The price feeds of the bank, without the LST mint and the stake pool of the staked collateral.
+    pub fn price_oracle_addresses(&self) -> &[Pubkey] {
+        let addresses = self.oracle_addresses().as_slice();
+        if self.oracle_type() == OracleSetup::StakedWithPythPush {
+            &addresses[..addresses.len().min(1)]
+        } else {
+            addresses
+        }
+    }
+
+    /// The paused bank rejects all the operations, the liquidations against it included.
+    pub fn is_paused(&self) -> bool {
+        matches!(
+            self.bank.config.operational_state,
+            BankOperationalState::Paused
+        )
+    }
+
+    pub fn oracle_max_confidence(&self) -> u32 {
+        self.bank.config.oracle_max_confidence
+    }
+
+    pub fn asset_share_value(&self) -> I80F48 {
+        self.bank.asset_share_value.into()
+    }
+
+    pub fn liability_share_value(&self) -> I80F48 {
+        self.bank.liability_share_value.into()
+    }
+
+    /// Converts the position shares to the native token amount at the current share value.
+    pub fn shares_to_tokens(&self, shares: I80F48, liability: bool) -> Result<I80F48> {
+        let share_value = if liability {
+            self.liability_share_value()
+        } else {
+            self.asset_share_value()
+        };
+        shares.checked_mul(share_value).ok_or_else(|| {
+            anyhow!(
+                "Math overflow converting the shares of the bank {}",
+                self.address
+            )
+        })
+    }
+
+    pub fn asset_weight_maint(&self) -> I80F48 {
+        self.bank.config.asset_weight_maint.into()
+    }
+
+    pub fn liability_weight_maint(&self) -> I80F48 {
+        self.bank.config.liability_weight_maint.into()
+    }
+
+    pub fn asset_weight_init(&self) -> I80F48 {
+        self.bank.config.asset_weight_init.into()
+    }
+
+    pub fn liability_weight_init(&self) -> I80F48 {
+        self.bank.config.liability_weight_init.into()
+    }
+
+    pub fn asset_tag(&self) -> u8 {
+        self.bank.config.asset_tag
+    }
+
+    pub fn emode_tag(&self) -> u16 {
+        self.bank.emode.emode_tag
+    }
+
+    pub fn emode_config(&self) -> &EmodeConfig {
         &self.bank.emode.emode_config
     }
+
+    /// The maintenance or initial asset weight, raised to the emode one if the bank is the emode
+    /// collateral for all the liabilities of the account.
+    pub fn asset_weight(&self, maint: bool, emode_entries: &[EmodeEntry]) -> I80F48 {
+        let weight = if maint {
+            self.asset_weight_maint()
+        } else {
+            self.asset_weight_init()
+        };
+        if self.emode_tag() == EMPTY_EMODE_TAG {
+            return weight;
+        }
+
+        emode_entries
+            .iter()
+            .find(|entry| entry.collateral_bank_emode_tag == self.emode_tag())
+            .map_or(weight, |entry| {
+                let emode_weight = if maint {
+                    entry.asset_weight_maint
+                } else {
+                    entry.asset_weight_init
+                };
+                weight.max(emode_weight.into())
+            })
+    }
+
+    pub fn liability_weight(&self, maint: bool) -> I80F48 {
+        if maint {
+            self.liability_weight_maint()
+        } else {
+            self.liability_weight_init()
+        }
+    }
+
+    /// The USD value of the token amount counted towards the health.
+    pub fn weighted_asset_value(
+        &self,
+        amount: I80F48,
+        price: I80F48,
+        maint: bool,
+        emode_entries: &[EmodeEntry],
+    ) -> Result<I80F48> {
+        calc_value(
+            amount,
+            price,
+            self.mint_decimals(),
+            self.asset_weight(maint, emode_entries),
+        )
+    }
+
+    /// The USD value of the token amount counted against the health.
+    pub fn weighted_liability_value(
+        &self,
+        amount: I80F48,
+        price: I80F48,
+        maint: bool,
+    ) -> Result<I80F48> {
+        calc_value(
+            amount,
+            price,
+            self.mint_decimals(),
+            self.liability_weight(maint),
+        )
+    }
+}
+
+/// Returns the emode entries present in all the liability banks, each with the lowest weights
+/// among them. An account without the liabilities gets no emode benefit.
+pub fn reconcile_emode_entries<'a>(
+    liability_banks: impl IntoIterator<Item = &'a CachedBank>,
+) -> Vec<EmodeEntry> {
+    let mut liability_banks = liability_banks.into_iter();
+    let Some(first) = liability_banks.next() else {
+        return vec![];
+    };
+
+    let mut entries: Vec<EmodeEntry> = first
+        .emode_config()
+        .entries
+        .iter()
+        .filter(|entry| entry.collateral_bank_emode_tag != EMPTY_EMODE_TAG)
+        .copied()
+        .collect();
+    for bank in liability_banks {
+        entries = entries
+            .into_iter()
+            .filter_map(|entry| {
+                let other = bank.emode_config().entries.iter().find(|other| {
+                    other.collateral_bank_emode_tag == entry.collateral_bank_emode_tag
+                })?;
+                let mut reconciled = entry;
+                reconciled.asset_weight_init = I80F48::from(entry.asset_weight_init)
+                    .min(other.asset_weight_init.into())
+                    .into();
+                reconciled.asset_weight_maint = I80F48::from(entry.asset_weight_maint)
+                    .min(other.asset_weight_maint.into())
+                    .into();
+                Some(reconciled)
+            })
+            .collect();
+    }
+    entries
+}
+
+/// The token balance of a bank vault at the slot of its last update.
+#[derive(Debug, Clone, Copy)]
+struct CachedVaultBalance {
+    slot: u64,
+    amount: u64,
 }
 
 #[derive(Default)]
 pub struct BanksCache {
     banks: RwLock<HashMap<Pubkey, CachedBank>>,
+    // The vault token account balances by the vault address
+    vault_balances: RwLock<HashMap<Pubkey, CachedVaultBalance>>,
 }
 
 impl BanksCache {
     pub fn update(&self, slot: u64, address: Pubkey, bank: &Bank) -> Result<()> {
         let upd_cached_bank = CachedBank::from(slot, address, *bank);
 
-        let mut banks = self
-            .banks
-            .write()
-            .map_err(|e| anyhow!("Failed to lock the Banks cache for update! {}", e))?;
+        let mut banks = self.banks.write().map_err(|e| {
+            MaryError::LockPoisoned(format!("Failed to lock the Banks cache for update! {}", e))
+        })?;
 
         if banks
             .get(&address)
@@ -74,25 +301,219 @@ impl BanksCache {
         Ok(())
     }
 
+    /// Updates the cached Banks that diverged from the fetched ones, which are stamped with the
+    /// context slot of the fetch. The Banks updated at or past it are kept. Returns the addresses of
+    /// the corrected Banks.
+    pub fn reconcile(&self, slot: u64, accounts: Vec<(Pubkey, Account)>) -> Result<Vec<Pubkey>> {
+        let cached_banks = self.get_banks_map()?;
+        let mut corrected = Vec::new();
+        for (address, account) in accounts {
+            let bank = match Bank::try_deserialize(&mut account.data.as_slice()) {
+                Ok(bank) => bank,
+                Err(err) => {
+                    warn!(
+                        "Skipping the Bank {} that failed to deserialize: {}",
+                        address, err
+                    );
+                    continue;
+                }
+            };
+            let Some(cached_bank) = cached_banks.get(&address) else {
+                continue;
+            };
+            if cached_bank.slot >= slot
+                || bytemuck::bytes_of(cached_bank.bank()) == bytemuck::bytes_of(&bank)
+            {
+                continue;
+            }
+            trace!(
+                "The cached Bank {} from the slot {} diverged from the chain, updating it.",
+                address,
+                cached_bank.slot
+            );
+            self.update(slot, address, &bank)?;
+            corrected.push(address);
+        }
+        Ok(corrected)
+    }
+
+    pub fn get_bank(&self, address: &Pubkey) -> Result<CachedBank> {
+        self.banks
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Banks cache for getting a bank: {}",
+                    e
+                ))
+            })?
+            .get(address)
+            .cloned()
+            .ok_or_else(|| {
+                MaryError::NotFound(format!("Bank {} not found in cache", address)).into()
+            })
+    }
+
+    pub fn count(&self) -> Result<usize> {
+        Ok(self
+            .banks
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!("Failed to lock the Banks cache for count: {}", e))
+            })?
+            .len())
+    }
+
+    /// The bank of the mint in the group, without cloning the other banks.
+    pub fn get_group_bank_by_mint(&self, group: &Pubkey, mint: &Pubkey) -> Result<CachedBank> {
+        self.banks
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Banks cache for getting a bank by mint: {}",
+                    e
+                ))
+            })?
+            .values()
+            .find(|bank| bank.group() == group && bank.mint() == mint)
+            .cloned()
+            .ok_or_else(|| {
+                MaryError::NotFound(format!(
+                    "No bank of the mint {} in the group {} in cache",
+                    mint, group
+                ))
+                .into()
+            })
+    }
+
+    pub fn get_banks_map(&self) -> Result<HashMap<Pubkey, CachedBank>> {
+        Ok(self
+            .banks
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Banks cache for cloning: {}",
+                    e
+                ))
+            })?
+            .clone())
+    }
+
     pub fn get_mints(&self) -> Result<Vec<Pubkey>> {
         Ok(self
             .banks
             .read()
-            .map_err(|e| anyhow!("Failed to lock the Banks cache for reading mints: {}", e))?
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Banks cache for reading mints: {}",
+                    e
+                ))
+            })?
             .values()
             .map(|bank| *bank.mint())
             .collect())
     }
 
+    /// The vault token accounts of the cached banks.
+    pub fn get_vaults(&self) -> Result<Vec<Pubkey>> {
+        Ok(self
+            .banks
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Banks cache for reading vaults: {}",
+                    e
+                ))
+            })?
+            .values()
+            .flat_map(|bank| bank.vaults())
+            .collect())
+    }
+
+    /// Updates the balance of the vault from its token account, the older updates are ignored.
+    pub fn update_vault(&self, slot: u64, address: Pubkey, account: &Account) -> Result<()> {
+        if spl_token_2022::check_spl_token_program_account(&account.owner).is_err() {
+            return Err(MaryError::Deserialize(format!(
+                "The vault {} is owned by {}, not by a token program",
+                address, account.owner
+            ))
+            .into());
+        }
+        let amount = account
+            .data
+            .get(TOKEN_ACCOUNT_AMOUNT_OFFSET..TOKEN_ACCOUNT_AMOUNT_OFFSET + 8)
+            .and_then(|amount| amount.try_into().ok())
+            .map(u64::from_le_bytes)
+            .ok_or_else(|| {
+                MaryError::Deserialize(format!(
+                    "The vault {} is not a token account: {} bytes",
+                    address,
+                    account.data.len()
+                ))
+            })?;
+
+        let mut vault_balances = self.vault_balances.write().map_err(|e| {
+            MaryError::LockPoisoned(format!(
+                "Failed to lock the vault balances for update: {}",
+                e
+            ))
+        })?;
+        if vault_balances
+            .get(&address)
+            .map_or(true, |existing| existing.slot <= slot)
+        {
+            vault_balances.insert(address, CachedVaultBalance { slot, amount });
+        }
+        Ok(())
+    }
+
+    /// The token amount held by the liquidity vault of the bank.
+    pub fn liquidity(&self, bank: &Pubkey) -> Result<u64> {
+        let liquidity_vault = *self.get_bank(bank)?.liquidity_vault();
+        self.vault_balances
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the vault balances for read: {}",
+                    e
+                ))
+            })?
+            .get(&liquidity_vault)
+            .map(|balance| balance.amount)
+            .ok_or_else(|| {
+                MaryError::NotFound(format!(
+                    "The liquidity vault {} balance of the Bank {} not found in cache",
+                    liquidity_vault, bank
+                ))
+                .into()
+            })
+    }
+
+    /// The banks priced by the oracle, the staked collateral banks by any of their pricing accounts.
+    pub fn get_banks_by_oracle(&self, oracle: &Pubkey) -> Result<Vec<Pubkey>> {
+        Ok(self
+            .banks
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Banks cache for reading oracles: {}",
+                    e
+                ))
+            })?
+            .values()
+            .filter(|bank| bank.oracle_addresses().contains(oracle))
+            .map(|bank| bank.address)
+            .collect())
+    }
+
     pub fn get_oracles_data(&self) -> Result<Vec<CachedBankOracle>> {
         Ok(self
             .banks
             .read()
             .map_err(|e| {
-                anyhow!(
+                MaryError::LockPoisoned(format!(
                     "Failed to lock the banks cache for reading oracle accounts: {}",
                     e
-                )
+                ))
             })?
             .values()
             .map(|bank| bank.oracle.clone())
@@ -111,11 +532,15 @@ fn get_oracle_accounts(bank_config: &BankConfig) -> Vec<Pubkey> {
 
 #[cfg(test)]
 pub mod test_util {
-    use marginfi::state::marginfi_group::{Bank, BankConfig};
+    use fixed::types::I80F48;
+    use marginfi::state::marginfi_group::{Bank, BankConfig, BankOperationalState};
     use marginfi::state::price::OracleSetup;
-    use solana_sdk::pubkey::Pubkey;
+    use solana_sdk::{account::Account, pubkey::Pubkey};
 
-    use crate::cache::banks::CachedBank;
+    use crate::{
+        cache::{banks::CachedBank, oracles::test_util::create_pyth_account, Cache},
+        common::MARGINFI_BANK_DISCRIMINATOR,
+    };
 
     pub fn create_bank_with_oracles(oracles: Vec<Pubkey>) -> Bank {
         let mut keys = [Pubkey::default(); 5];
@@ -129,12 +554,88 @@ pub mod test_util {
             config: BankConfig {
                 oracle_setup: OracleSetup::PythPushOracle,
                 oracle_keys: keys,
+                operational_state: BankOperationalState::Operational,
                 ..Default::default()
             },
             ..Default::default()
         }
     }
 
+    /// The bank with the given emode tag accepting the listed collateral tags at the maintenance weights.
+    pub fn create_emode_bank(emode_tag: u16, entries: &[(u16, f64)]) -> Bank {
+        let mut bank = create_bank_with_oracles(vec![]);
+        bank.emode.emode_tag = emode_tag;
+        for (slot, (collateral_tag, weight)) in bank
+            .emode
+            .emode_config
+            .entries
+            .iter_mut()
+            .zip(entries.iter())
+        {
+            slot.collateral_bank_emode_tag = *collateral_tag;
+            slot.asset_weight_init = I80F48::from_num(*weight).into();
+            slot.asset_weight_maint = I80F48::from_num(*weight).into();
+        }
+        bank
+    }
+
+    /// Caches the Bank of a 6 decimals mint priced at 1 USD by its Pyth Oracle, at the 1:1 share
+    /// values and the given maintenance weights.
+    pub fn add_priced_bank(
+        cache: &Cache,
+        mut bank: Bank,
+        asset_weight: f64,
+        liability_weight: f64,
+    ) -> Pubkey {
+        let address = Pubkey::new_unique();
+        let oracle_address = Pubkey::new_unique();
+        bank.config.oracle_setup = OracleSetup::PythPushOracle;
+        bank.config.oracle_keys[0] = oracle_address;
+        bank.asset_share_value = I80F48::ONE.into();
+        bank.liability_share_value = I80F48::ONE.into();
+        bank.config.asset_weight_maint = I80F48::from_num(asset_weight).into();
+        bank.config.liability_weight_maint = I80F48::from_num(liability_weight).into();
+        cache.banks.update(1, address, &bank).unwrap();
+        cache.mints.update(bank.mint, &Account::default()).unwrap();
+        cache
+            .oracles
+            .insert(
+                1,
+                0,
+                &oracle_address,
+                OracleSetup::PythPushOracle,
+                create_pyth_account(1_000, 0, 1_000, 0, 1),
+            )
+            .unwrap();
+        address
+    }
+
+    /// The on-chain account of the zero-copy Bank, as fetched through the RPC.
+    pub fn create_bank_account(bank: &Bank) -> Account {
+        let mut data = MARGINFI_BANK_DISCRIMINATOR.to_vec();
+        data.extend_from_slice(bytemuck::bytes_of(bank));
+        Account {
+            lamports: 1,
+            data,
+            owner: Pubkey::new_unique(),
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+
+    /// The token account of a vault holding the amount.
+    pub fn create_token_account(amount: u64) -> Account {
+        let mut data = vec![0u8; 165];
+        data[64..72].copy_from_slice(&amount.to_le_bytes());
+        Account {
+            lamports: 1,
+            data,
+            owner: spl_token_2022::id(),
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+
     pub fn _create_dummy_cached_bank() -> CachedBank {
         CachedBank::from(0, Pubkey::new_unique(), create_bank_with_oracles(vec![]))
     }
@@ -142,9 +643,12 @@ pub mod test_util {
 
 #[cfg(test)]
 mod tests {
-    use super::test_util::create_bank_with_oracles;
+    use super::test_util::{create_bank_with_oracles, create_emode_bank, create_token_account};
     use super::*;
-    use marginfi::state::marginfi_group::BankConfig;
+    use marginfi::{
+        constants::ASSET_TAG_SOL,
+        state::marginfi_group::{BankConfig, BankOperationalState},
+    };
     use std::sync::Arc;
     use std::thread;
 
@@ -162,6 +666,196 @@ mod tests {
         assert_eq!(cached.mint(), &bank.mint);
         assert_eq!(cached.oracle.oracle_type, bank.config.oracle_setup);
         assert_eq!(cached.oracle.oracle_addresses, vec![oracle1, oracle2]);
+        assert!(!cached.is_paused());
+    }
+
+    #[test]
+    fn test_cached_bank_price_oracle_addresses() {
+        let feed = Pubkey::new_unique();
+        let second = Pubkey::new_unique();
+        let mut bank = create_bank_with_oracles(vec![feed, second, Pubkey::new_unique()]);
+        let cached = CachedBank::from(1, Pubkey::new_unique(), bank);
+        assert_eq!(
+            cached.price_oracle_addresses(),
+            cached.oracle_addresses().as_slice()
+        );
+
+        // The LST mint and the stake pool are not price feeds
+        bank.config.oracle_setup = OracleSetup::StakedWithPythPush;
+        let cached = CachedBank::from(1, Pubkey::new_unique(), bank);
+        assert_eq!(cached.price_oracle_addresses(), &[feed]);
+    }
+
+    #[test]
+    fn test_cached_bank_is_paused() {
+        let mut bank = create_bank_with_oracles(vec![]);
+        for (operational_state, paused) in [
+            (BankOperationalState::Paused, true),
+            (BankOperationalState::Operational, false),
+            (BankOperationalState::ReduceOnly, false),
+        ] {
+            bank.config.operational_state = operational_state;
+            assert_eq!(
+                CachedBank::from(1, Pubkey::new_unique(), bank).is_paused(),
+                paused
+            );
+        }
+    }
+
+    #[test]
+    fn test_cached_bank_preserves_asset_tag_and_emode() {
+        let mut bank = create_emode_bank(7, &[(3, 0.9)]);
+        bank.config.asset_tag = ASSET_TAG_SOL;
+        let cached = CachedBank::from(1, Pubkey::new_unique(), bank);
+
+        assert_eq!(cached.asset_tag(), ASSET_TAG_SOL);
+        assert_eq!(cached.emode_tag(), 7);
+        assert_eq!(
+            cached.emode_config().entries[0].collateral_bank_emode_tag,
+            3
+        );
+    }
+
+    #[test]
+    fn test_asset_weight_emode() {
+        let mut bank = create_emode_bank(3, &[]);
+        bank.config.asset_weight_maint = I80F48::from_num(0.8).into();
+        let collateral = CachedBank::from(1, Pubkey::new_unique(), bank);
+        let liability =
+            CachedBank::from(1, Pubkey::new_unique(), create_emode_bank(0, &[(3, 0.95)]));
+
+        let entries = reconcile_emode_entries([&liability]);
+        assert_eq!(
+            collateral.asset_weight(true, &entries),
+            I80F48::from_num(0.95)
+        );
+        assert_eq!(collateral.asset_weight(true, &[]), I80F48::from_num(0.8));
+
+        // The emode never lowers the base weight
+        let liability =
+            CachedBank::from(1, Pubkey::new_unique(), create_emode_bank(0, &[(3, 0.5)]));
+        let entries = reconcile_emode_entries([&liability]);
+        assert_eq!(
+            collateral.asset_weight(true, &entries),
+            I80F48::from_num(0.8)
+        );
+    }
+
+    #[test]
+    fn test_asset_weight_ignores_untagged_bank() {
+        let mut bank = create_emode_bank(EMPTY_EMODE_TAG, &[]);
+        bank.config.asset_weight_maint = I80F48::from_num(0.8).into();
+        let collateral = CachedBank::from(1, Pubkey::new_unique(), bank);
+        let liability =
+            CachedBank::from(1, Pubkey::new_unique(), create_emode_bank(0, &[(0, 0.95)]));
+
+        let entries = reconcile_emode_entries([&liability]);
+        assert!(entries.is_empty());
+        assert_eq!(
+            collateral.asset_weight(true, &entries),
+            I80F48::from_num(0.8)
+        );
+    }
+
+    fn create_weighted_bank() -> CachedBank {
+        let mut bank = create_bank_with_oracles(vec![]);
+        bank.config.asset_weight_maint = I80F48::from_num(0.9).into();
+        bank.config.asset_weight_init = I80F48::from_num(0.8).into();
+        bank.config.liability_weight_maint = I80F48::from_num(1.1).into();
+        bank.config.liability_weight_init = I80F48::from_num(1.25).into();
+        CachedBank::from(1, Pubkey::new_unique(), bank)
+    }
+
+    #[test]
+    fn test_bank_weights() {
+        let bank = create_weighted_bank();
+        assert_eq!(bank.asset_weight(true, &[]), I80F48::from_num(0.9));
+        assert_eq!(bank.asset_weight(false, &[]), I80F48::from_num(0.8));
+        assert_eq!(bank.liability_weight(true), I80F48::from_num(1.1));
+        assert_eq!(bank.liability_weight(false), I80F48::from_num(1.25));
+    }
+
+    #[test]
+    fn test_weighted_asset_value() {
+        let bank = create_weighted_bank();
+        // 50 tokens (6 decimals) at $4 = $200
+        let amount = I80F48::from_num(50_000_000);
+        let price = I80F48::from_num(4);
+
+        let maint = bank.weighted_asset_value(amount, price, true, &[]).unwrap();
+        let init = bank
+            .weighted_asset_value(amount, price, false, &[])
+            .unwrap();
+        assert!((maint - I80F48::from_num(180)).abs() < I80F48::from_num(0.0001));
+        assert!((init - I80F48::from_num(160)).abs() < I80F48::from_num(0.0001));
+    }
+
+    #[test]
+    fn test_weighted_liability_value() {
+        let bank = create_weighted_bank();
+        // 10 tokens (6 decimals) at $2 = $20
+        let amount = I80F48::from_num(10_000_000);
+        let price = I80F48::from_num(2);
+
+        let maint = bank.weighted_liability_value(amount, price, true).unwrap();
+        let init = bank.weighted_liability_value(amount, price, false).unwrap();
+        assert!((maint - I80F48::from_num(22)).abs() < I80F48::from_num(0.0001));
+        assert!((init - I80F48::from_num(25)).abs() < I80F48::from_num(0.0001));
+    }
+
+    #[test]
+    fn test_shares_to_tokens() {
+        let mut bank = create_bank_with_oracles(vec![]);
+        bank.asset_share_value = I80F48::from_num(1.5).into();
+        bank.liability_share_value = I80F48::from_num(2.25).into();
+        let bank = CachedBank::from(1, Pubkey::new_unique(), bank);
+
+        let shares = I80F48::from_num(1_000);
+        assert_eq!(
+            bank.shares_to_tokens(shares, false).unwrap(),
+            I80F48::from_num(1_500)
+        );
+        assert_eq!(
+            bank.shares_to_tokens(shares, true).unwrap(),
+            I80F48::from_num(2_250)
+        );
+        assert_eq!(
+            bank.shares_to_tokens(I80F48::ZERO, true).unwrap(),
+            I80F48::ZERO
+        );
+    }
+
+    #[test]
+    fn test_shares_to_tokens_overflow() {
+        let mut bank = create_bank_with_oracles(vec![]);
+        bank.asset_share_value = I80F48::MAX.into();
+        let bank = CachedBank::from(1, Pubkey::new_unique(), bank);
+
+        assert!(bank.shares_to_tokens(I80F48::from_num(2), false).is_err());
+    }
+
+    #[test]
+    fn test_reconcile_emode_entries() {
+        let liability1 = CachedBank::from(
+            1,
+            Pubkey::new_unique(),
+            create_emode_bank(0, &[(3, 0.9), (4, 0.85)]),
+        );
+        let liability2 = CachedBank::from(
+            1,
+            Pubkey::new_unique(),
+            create_emode_bank(0, &[(3, 0.95), (5, 0.9)]),
+        );
+
+        let entries = reconcile_emode_entries([&liability1, &liability2]);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].collateral_bank_emode_tag, 3);
+        assert_eq!(
+            I80F48::from(entries[0].asset_weight_maint),
+            I80F48::from_num(0.9)
+        );
+
+        assert!(reconcile_emode_entries([]).is_empty());
     }
 
     #[test]
@@ -189,6 +883,25 @@ mod tests {
         assert_eq!(cached.address, address);
     }
 
+    #[test]
+    fn test_banks_cache_get_bank() {
+        let cache = BanksCache::default();
+        let address = Pubkey::new_unique();
+        let bank = create_bank_with_oracles(vec![]);
+        cache.update(7, address, &bank).unwrap();
+
+        let cached = cache.get_bank(&address).unwrap();
+        assert_eq!(cached.slot, 7);
+        assert_eq!(cached.mint(), &bank.mint);
+
+        let missing = cache.get_bank(&Pubkey::new_unique()).unwrap_err();
+        assert!(format!("{}", missing).contains("not found in cache"));
+        assert!(matches!(
+            MaryError::of(&missing),
+            Some(MaryError::NotFound(_))
+        ));
+    }
+
     #[test]
     fn test_banks_cache_update_only_newer_slot() {
         let cache = BanksCache::default();
@@ -240,8 +953,51 @@ mod tests {
             .join();
         }
 
-        let result = cache.update(1, address, &bank);
-        assert!(result.is_err());
+        let err = cache.update(1, address, &bank).unwrap_err();
+        assert!(matches!(
+            MaryError::of(&err),
+            Some(MaryError::LockPoisoned(_))
+        ));
+    }
+
+    #[test]
+    fn test_banks_cache_get_banks_map() {
+        let cache = BanksCache::default();
+        let bank1 = create_bank_with_oracles(vec![]);
+        let bank2 = create_bank_with_oracles(vec![]);
+        let address1 = Pubkey::new_unique();
+        let address2 = Pubkey::new_unique();
+
+        cache.update(1, address1, &bank1).unwrap();
+        cache.update(2, address2, &bank2).unwrap();
+
+        let banks_map = cache.get_banks_map().unwrap();
+        assert_eq!(banks_map.len(), 2);
+        assert_eq!(banks_map.get(&address1).unwrap().address, address1);
+        assert_eq!(banks_map.get(&address1).unwrap().mint(), &bank1.mint);
+        assert_eq!(banks_map.get(&address2).unwrap().address, address2);
+        assert_eq!(banks_map.get(&address2).unwrap().mint(), &bank2.mint);
+    }
+
+    #[test]
+    fn test_banks_cache_get_group_bank_by_mint() {
+        let cache = BanksCache::default();
+        let bank = create_bank_with_oracles(vec![]);
+        let mut other_group_bank = create_bank_with_oracles(vec![]);
+        other_group_bank.mint = bank.mint;
+        let address = Pubkey::new_unique();
+        cache.update(1, address, &bank).unwrap();
+        cache
+            .update(1, Pubkey::new_unique(), &other_group_bank)
+            .unwrap();
+
+        let found = cache
+            .get_group_bank_by_mint(&bank.group, &bank.mint)
+            .unwrap();
+        assert_eq!(found.address, address);
+        assert!(cache
+            .get_group_bank_by_mint(&bank.group, &Pubkey::new_unique())
+            .is_err());
     }
 
     #[test]
@@ -272,6 +1028,69 @@ mod tests {
         assert_eq!(mints, expected);
     }
 
+    #[test]
+    fn test_update_vault_and_liquidity() {
+        let cache = BanksCache::default();
+        let mut bank = create_bank_with_oracles(vec![]);
+        bank.liquidity_vault = Pubkey::new_unique();
+        bank.insurance_vault = Pubkey::new_unique();
+        bank.fee_vault = Pubkey::new_unique();
+        let address = Pubkey::new_unique();
+        cache.update(1, address, &bank).unwrap();
+        assert_eq!(
+            cache.get_vaults().unwrap(),
+            vec![bank.liquidity_vault, bank.insurance_vault, bank.fee_vault]
+        );
+
+        let err = cache.liquidity(&address).unwrap_err();
+        assert!(matches!(MaryError::of(&err), Some(MaryError::NotFound(_))));
+
+        cache
+            .update_vault(2, bank.liquidity_vault, &create_token_account(1_000))
+            .unwrap();
+        assert_eq!(cache.liquidity(&address).unwrap(), 1_000);
+        cache
+            .update_vault(3, bank.liquidity_vault, &create_token_account(400))
+            .unwrap();
+        assert_eq!(cache.liquidity(&address).unwrap(), 400);
+        // Out of order
+        cache
+            .update_vault(2, bank.liquidity_vault, &create_token_account(1_000))
+            .unwrap();
+        assert_eq!(cache.liquidity(&address).unwrap(), 400);
+    }
+
+    #[test]
+    fn test_update_vault_rejects_short_account() {
+        let cache = BanksCache::default();
+        let err = cache
+            .update_vault(
+                1,
+                Pubkey::new_unique(),
+                &Account::new(1, 32, &spl_token_2022::id()),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            MaryError::of(&err),
+            Some(MaryError::Deserialize(_))
+        ));
+    }
+
+    #[test]
+    fn test_update_vault_rejects_non_token_account() {
+        let cache = BanksCache::default();
+        let vault = Pubkey::new_unique();
+        // The token account layout, but not owned by a token program
+        let mut account = create_token_account(1_000);
+        account.owner = Pubkey::new_unique();
+        let err = cache.update_vault(1, vault, &account).unwrap_err();
+        assert!(matches!(
+            MaryError::of(&err),
+            Some(MaryError::Deserialize(_))
+        ));
+        assert!(cache.vault_balances.read().unwrap().get(&vault).is_none());
+    }
+
     #[test]
     fn test_get_all_mints_lock_error() {
         let cache = Arc::new(BanksCache::default());
@@ -433,4 +1252,28 @@ mod tests {
         assert_eq!(oracles.len(), 1);
         assert_eq!(oracles[0].oracle_addresses, vec![oracle2]);
     }
+
+    #[test]
+    fn test_banks_cache_get_banks_by_oracle() {
+        let cache = BanksCache::default();
+        let oracle = Pubkey::new_unique();
+        let bank1 = Pubkey::new_unique();
+        let bank2 = Pubkey::new_unique();
+        cache
+            .update(1, bank1, &create_bank_with_oracles(vec![oracle]))
+            .unwrap();
+        cache
+            .update(
+                1,
+                bank2,
+                &create_bank_with_oracles(vec![Pubkey::new_unique()]),
+            )
+            .unwrap();
+
+        assert_eq!(cache.get_banks_by_oracle(&oracle).unwrap(), vec![bank1]);
+        assert!(cache
+            .get_banks_by_oracle(&Pubkey::new_unique())
+            .unwrap()
+            .is_empty());
+    }
 }
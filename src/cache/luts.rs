@@ -1,36 +1,38 @@
-use std::sync::RwLock;
+use std::sync::Arc;
 
-use anyhow::{anyhow, Result};
+use arc_swap::ArcSwap;
 use solana_sdk::address_lookup_table::AddressLookupTableAccount;
 
+/// The LUTs are only replaced as a whole on population, so the readers take a snapshot without
+/// locking or copying the addresses.
 #[derive(Default)]
-// TODO: the LUTs cache is effectively read-only after population. Come up with better way to share it lock free
 pub struct LutsCache {
-    luts: RwLock<Vec<AddressLookupTableAccount>>,
+    luts: ArcSwap<Vec<AddressLookupTableAccount>>,
 }
 
 impl LutsCache {
-    pub fn populate(&self, luts: Vec<AddressLookupTableAccount>) -> Result<()> {
-        let mut write_guard = self
-            .luts
-            .write()
-            .map_err(|e| anyhow!("Failed to lock the Mints cache for update: {}", e))?;
-        *write_guard = luts;
-        Ok(())
+    pub fn populate(&self, luts: Vec<AddressLookupTableAccount>) {
+        self.luts.store(Arc::new(luts));
     }
 
-    pub fn get_all(&self) -> Result<Vec<AddressLookupTableAccount>> {
-        let read_guard = self
-            .luts
-            .read()
-            .map_err(|e| anyhow!("Failed to lock the LUTs cache for reading: {}", e))?;
-        Ok(read_guard.clone())
+    pub fn get_all(&self) -> Arc<Vec<AddressLookupTableAccount>> {
+        self.luts.load_full()
+    }
+
+    pub fn count(&self) -> usize {
+        self.luts.load().len()
+    }
+
+    /// The addresses held by all the LUTs, they make up most of the LUTs memory.
+    pub fn address_count(&self) -> usize {
+        self.luts.load().iter().map(|lut| lut.addresses.len()).sum()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use solana_sdk::pubkey::Pubkey;
+    use std::thread;
 
     use super::*;
 
@@ -47,12 +49,11 @@ mod tests {
         let lut_1 = dummy_lut(Pubkey::new_unique());
         let lut_2 = dummy_lut(Pubkey::new_unique());
         let luts = vec![lut_1.clone(), lut_2.clone()];
-        let result = cache.populate(luts.clone());
-        assert!(result.is_ok());
-        let read_guard = cache.luts.read().unwrap();
-        assert_eq!(read_guard.len(), 2);
-        assert_eq!(read_guard[0].key, lut_1.key);
-        assert_eq!(read_guard[1].key, lut_2.key);
+        cache.populate(luts.clone());
+        let all = cache.get_all();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].key, lut_1.key);
+        assert_eq!(all[1].key, lut_2.key);
     }
 
     #[test]
@@ -63,19 +64,64 @@ mod tests {
             dummy_lut(Pubkey::new_unique()),
             dummy_lut(Pubkey::new_unique()),
         ];
-        cache.populate(luts1).unwrap();
-        cache.populate(luts2.clone()).unwrap();
-        let read_guard = cache.luts.read().unwrap();
-        assert_eq!(*read_guard, luts2);
+        cache.populate(luts1.clone());
+        let before = cache.get_all();
+        cache.populate(luts2.clone());
+        assert_eq!(*cache.get_all(), luts2);
+        // The snapshot taken before the population is left intact
+        assert_eq!(*before, luts1);
+    }
+
+    #[test]
+    fn test_count() {
+        let cache = LutsCache::default();
+        assert_eq!(cache.count(), 0);
+        cache.populate(vec![
+            dummy_lut(Pubkey::new_unique()),
+            dummy_lut(Pubkey::new_unique()),
+        ]);
+        assert_eq!(cache.count(), 2);
+        assert_eq!(cache.address_count(), 4);
     }
 
     #[test]
     fn test_populate_empty_vec() {
         let cache = LutsCache::default();
-        let luts = vec![];
-        let result = cache.populate(luts.clone());
-        assert!(result.is_ok());
-        let read_guard = cache.luts.read().unwrap();
-        assert_eq!(read_guard.len(), 0);
+        cache.populate(vec![]);
+        assert_eq!(cache.get_all().len(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_reads_during_populate() {
+        let cache = LutsCache::default();
+        let luts1 = vec![dummy_lut(Pubkey::new_unique())];
+        let luts2 = vec![
+            dummy_lut(Pubkey::new_unique()),
+            dummy_lut(Pubkey::new_unique()),
+        ];
+        cache.populate(luts1.clone());
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..10_000 {
+                        let luts = cache.get_all();
+                        // Never a partially populated vector
+                        assert!(*luts == luts1 || *luts == luts2);
+                    }
+                });
+            }
+            scope.spawn(|| {
+                for i in 0..1_000 {
+                    cache.populate(if i % 2 == 0 {
+                        luts2.clone()
+                    } else {
+                        luts1.clone()
+                    });
+                }
+            });
+        });
+
+        assert_eq!(*cache.get_all(), luts1);
     }
 }
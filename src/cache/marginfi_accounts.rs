@@ -1,22 +1,49 @@
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
 
 use anyhow::{anyhow, Result};
 use fixed::types::I80F48;
 use log::{trace, warn};
-use marginfi::state::marginfi_account::{Balance, MarginfiAccount};
+use marginfi::state::{
+    emode::EmodeEntry,
+    marginfi_account::{Balance, MarginfiAccount},
+    price::PriceBias,
+};
 use solana_sdk::pubkey::Pubkey;
 
-use crate::cache::CacheEntry;
+use crate::{
+    cache::{banks::reconcile_emode_entries, Cache, CacheEntry},
+    error::MaryError,
+};
 
 #[derive(Clone)]
 pub struct CachedMarginfiAccount {
     slot: u64,
     address: Pubkey,
+    // Copied out of the account for the group filters
+    group: Pubkey,
     _marginfi_account: MarginfiAccount,
     _positions: Vec<Balance>,
 }
 
-const INVALID_HEALTH: i64 = i64::MIN;
+pub const INVALID_HEALTH: i64 = i64::MIN;
+/// The health is kept in basis points of the asset value, e.g. 500 is 5%, so that the accounts
+/// with the fractional health ratios still rank apart.
+pub const HEALTH_BPS_SCALE: i64 = 10_000;
+
+/// The position of the account valued from the cached Bank and Oracle.
+#[derive(Debug, Clone)]
+pub struct PositionValue {
+    pub bank: Pubkey,
+    pub asset_shares: I80F48,
+    pub liability_shares: I80F48,
+    pub asset_price: Option<I80F48>,
+    pub liability_price: Option<I80F48>,
+    pub asset_value: I80F48,
+    pub liability_value: I80F48,
+}
 
 impl std::fmt::Debug for CachedMarginfiAccount {
     // TODO: add more relevant fields
@@ -31,7 +58,7 @@ impl std::fmt::Debug for CachedMarginfiAccount {
 impl CacheEntry for CachedMarginfiAccount {}
 
 impl CachedMarginfiAccount {
-    pub fn from(slot: u64, address: Pubkey, marginfi_account: MarginfiAccount) -> Self {
+    pub fn from(slot: u64, address: Pubkey, marginfi_account: &MarginfiAccount) -> Self {
         let positions = marginfi_account
             .lending_account
             .balances
@@ -43,7 +70,8 @@ impl CachedMarginfiAccount {
         Self {
             slot,
             address,
-            _marginfi_account: marginfi_account,
+            group: marginfi_account.group,
+            _marginfi_account: *marginfi_account,
             _positions: positions,
         }
     }
@@ -61,94 +89,564 @@ impl CachedMarginfiAccount {
             .into()
     }
 
+    /// The maintenance health ratio from the on-chain health cache, in basis points.
     #[inline]
     pub fn health(&self) -> Option<i64> {
         (self.asset_value_maint() - self.liability_value_maint())
             .checked_div(self.asset_value_maint())
-            .map(|v| v.to_num::<i64>())
+            .map(health_to_bps)
+    }
+
+    /// Uses the on-chain health cache as a fast path and falls back to the local
+    /// recomputation when the health cache has not been populated.
+    pub fn health_with_fallback(&self, cache: &Cache) -> Option<i64> {
+        if self.asset_value_maint() != I80F48::ZERO {
+            return self.health();
+        }
+
+        match self.compute_health(cache) {
+            Ok(health) => Some(health_to_bps(health)),
+            Err(err) => {
+                trace!(
+                    "Failed to recompute health for account {}: {}",
+                    self.address,
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Computes the maintenance health from the cached Banks and Oracles instead of the
+    /// on-chain health cache, which can be stale or zeroed.
+    pub fn compute_health(&self, cache: &Cache) -> Result<I80F48> {
+        let mut asset_value = I80F48::ZERO;
+        let mut liability_value = I80F48::ZERO;
+        for position in self.position_values(cache)? {
+            asset_value += position.asset_value;
+            liability_value += position.liability_value;
+        }
+
+        (asset_value - liability_value)
+            .checked_div(asset_value)
+            .ok_or_else(|| anyhow!("Account {} has no asset value", self.address))
+    }
+
+    /// The maintenance weighted values of the positions and the biased prices they are valued at.
+    pub fn position_values(&self, cache: &Cache) -> Result<Vec<PositionValue>> {
+        let clock = cache.get_clock()?;
+        let emode_entries = self.emode_entries(cache)?;
+        let mut values = Vec::with_capacity(self._positions.len());
+
+        for position in &self._positions {
+            let bank = cache.banks.get_bank(&position.bank_pk)?;
+            let mut value = PositionValue {
+                bank: position.bank_pk,
+                asset_shares: position.asset_shares.into(),
+                liability_shares: position.liability_shares.into(),
+                asset_price: None,
+                liability_price: None,
+                asset_value: I80F48::ZERO,
+                liability_value: I80F48::ZERO,
+            };
+
+            if value.asset_shares > I80F48::ZERO {
+                let price = cache
+                    .oracles
+                    .get_bank_price(&bank, &clock, Some(PriceBias::Low))?;
+                let amount = bank.shares_to_tokens(value.asset_shares, false)?;
+                value.asset_price = Some(price);
+                value.asset_value =
+                    bank.weighted_asset_value(amount, price, true, &emode_entries)?;
+            }
+
+            if value.liability_shares > I80F48::ZERO {
+                let price = cache
+                    .oracles
+                    .get_bank_price(&bank, &clock, Some(PriceBias::High))?;
+                let amount = bank.shares_to_tokens(value.liability_shares, true)?;
+                value.liability_price = Some(price);
+                value.liability_value = bank.weighted_liability_value(amount, price, true)?;
+            }
+
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    /// The emode entries that apply to the account, reconciled across its liability banks.
+    pub fn emode_entries(&self, cache: &Cache) -> Result<Vec<EmodeEntry>> {
+        let mut liability_banks = Vec::new();
+        for position in &self._positions {
+            if I80F48::from(position.liability_shares) > I80F48::ZERO {
+                liability_banks.push(cache.banks.get_bank(&position.bank_pk)?);
+            }
+        }
+        Ok(reconcile_emode_entries(&liability_banks))
+    }
+
+    pub fn slot(&self) -> u64 {
+        self.slot
+    }
+
+    pub fn address(&self) -> &Pubkey {
+        &self.address
+    }
+
+    pub fn group(&self) -> &Pubkey {
+        &self.group
+    }
+
+    pub fn authority(&self) -> &Pubkey {
+        &self._marginfi_account.authority
+    }
+
+    /// The raw flags of the account, e.g. whether it is disabled or being flashloaned.
+    pub fn account_flags(&self) -> u64 {
+        self._marginfi_account.account_flags
     }
 
     pub fn _positions(&self) -> &Vec<Balance> {
         &self._positions
     }
+
+    /// The banks the account has the liabilities in.
+    pub fn liability_banks(&self) -> impl Iterator<Item = &Pubkey> {
+        self._positions
+            .iter()
+            .filter(|position| I80F48::from(position.liability_shares) > I80F48::ZERO)
+            .map(|position| &position.bank_pk)
+    }
+}
+
+fn unindex_liabilities(
+    liability_index: &mut HashMap<Pubkey, HashSet<Pubkey>>,
+    account: &CachedMarginfiAccount,
+) {
+    for bank in account.liability_banks() {
+        if let Some(accounts) = liability_index.get_mut(bank) {
+            accounts.remove(&account.address);
+            if accounts.is_empty() {
+                liability_index.remove(bank);
+            }
+        }
+    }
 }
 
+pub fn health_to_bps(health: I80F48) -> i64 {
+    health
+        .saturating_mul(I80F48::from_num(HEALTH_BPS_SCALE))
+        .saturating_round()
+        .saturating_to_num::<i64>()
+}
+
+pub fn calc_value(
+    amount: I80F48,
+    price: I80F48,
+    mint_decimals: u8,
+    weight: I80F48,
+) -> Result<I80F48> {
+    let decimals_scale = 10u64
+        .checked_pow(mint_decimals as u32)
+        .map(I80F48::from_num)
+        .ok_or_else(|| anyhow!("Invalid mint decimals {}", mint_decimals))?;
+
+    amount
+        .checked_mul(price)
+        .and_then(|value| value.checked_div(decimals_scale))
+        .and_then(|value| value.checked_mul(weight))
+        .ok_or_else(|| anyhow!("Math overflow in the position value"))
+}
+
+// The accounts are spread over the shards by the first byte of their address, so that the updates
+// of the distinct accounts rarely contend for the same lock
+const ACCOUNT_SHARD_COUNT: usize = 32;
+
 #[derive(Default)]
+struct AccountsShard {
+    accounts: HashMap<Pubkey, CachedMarginfiAccount>,
+    // The health in basis points, INVALID_HEALTH if it could not be computed
+    account_to_health: HashMap<Pubkey, i64>,
+}
+
 pub struct MarginfiAccountsCache {
-    accounts: RwLock<HashMap<Pubkey, CachedMarginfiAccount>>,
-    account_to_health: RwLock<HashMap<Pubkey, i64>>,
+    shards: Vec<RwLock<AccountsShard>>,
+    // The accounts by the banks they have the liabilities in. Always locked after the shard.
+    liability_bank_to_accounts: RwLock<HashMap<Pubkey, HashSet<Pubkey>>>,
+}
+
+impl Default for MarginfiAccountsCache {
+    fn default() -> Self {
+        Self {
+            shards: (0..ACCOUNT_SHARD_COUNT)
+                .map(|_| RwLock::new(AccountsShard::default()))
+                .collect(),
+            liability_bank_to_accounts: RwLock::new(HashMap::new()),
+        }
+    }
 }
 
 impl MarginfiAccountsCache {
-    pub fn update(&self, slot: u64, address: Pubkey, account: MarginfiAccount) -> Result<()> {
+    fn shard(&self, address: &Pubkey) -> &RwLock<AccountsShard> {
+        &self.shards[address.as_ref()[0] as usize % ACCOUNT_SHARD_COUNT]
+    }
+
+    /// Stores the on-chain health, the cache writes the computed one through
+    /// `Cache::update_marginfi_account`.
+    #[cfg(test)]
+    pub(super) fn update(
+        &self,
+        slot: u64,
+        address: Pubkey,
+        account: &MarginfiAccount,
+    ) -> Result<()> {
         let upd_cached_account = CachedMarginfiAccount::from(slot, address, account);
         let upd_cached_account_health = upd_cached_account.health();
+        self.upsert(upd_cached_account, upd_cached_account_health)
+    }
+
+    pub(super) fn upsert(
+        &self,
+        upd_cached_account: CachedMarginfiAccount,
+        upd_cached_account_health: Option<i64>,
+    ) -> Result<()> {
+        let address = upd_cached_account.address;
 
-        let mut accounts = self.accounts.write().map_err(|e| {
-            anyhow!(
+        let mut shard = self.shard(&address).write().map_err(|e| {
+            MaryError::LockPoisoned(format!(
                 "Failed to lock the Marginfi accounts cache for update! {}",
                 e
-            )
-        })?;
-        let mut health = self.account_to_health.write().map_err(|e| {
-            anyhow!(
-                "Failed to lock the Marginfi account health cache for update! {}",
-                e
-            )
+            ))
         })?;
 
-        if accounts
-            .get(&address)
-            .map_or(true, |existing| existing.slot < upd_cached_account.slot)
-        {
-            trace!(
-                "Updating the Marginfi Account in cache: {:?}",
-                upd_cached_account
-            );
-            accounts.insert(address, upd_cached_account);
+        let existing = shard.accounts.get(&address);
+        if existing.is_some_and(|existing| existing.slot >= upd_cached_account.slot) {
+            return Ok(());
+        }
 
-            match upd_cached_account_health {
-                Some(upd_health) => {
-                    health.insert(address, upd_health);
-                }
-                None => {
-                    warn!(
-                        "Failed to compute health for account {}, invalidating it",
-                        address
-                    );
-                    health.insert(address, INVALID_HEALTH);
-                }
+        trace!(
+            "Updating the Marginfi Account in cache: {:?}",
+            upd_cached_account
+        );
+        // Most updates keep the liabilities as they are, the shared index is only locked when they change
+        let liabilities_changed = existing.map_or(true, |existing| {
+            existing.liability_banks().collect::<HashSet<_>>()
+                != upd_cached_account.liability_banks().collect::<HashSet<_>>()
+        });
+        if liabilities_changed {
+            let mut liability_index = self.liability_bank_to_accounts.write().map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Marginfi account liability index for update! {}",
+                    e
+                ))
+            })?;
+            if let Some(existing) = existing {
+                unindex_liabilities(&mut liability_index, existing);
+            }
+            for bank in upd_cached_account.liability_banks() {
+                liability_index.entry(*bank).or_default().insert(address);
             }
         }
+        shard.accounts.insert(address, upd_cached_account);
+
+        let upd_health = upd_cached_account_health.unwrap_or_else(|| {
+            warn!(
+                "Failed to compute health for account {}, invalidating it",
+                address
+            );
+            INVALID_HEALTH
+        });
+        shard.account_to_health.insert(address, upd_health);
 
         Ok(())
     }
 
+    pub fn remove(&self, address: &Pubkey) -> Result<()> {
+        let mut shard = self.shard(address).write().map_err(|e| {
+            MaryError::LockPoisoned(format!(
+                "Failed to lock the Marginfi accounts cache for removal! {}",
+                e
+            ))
+        })?;
+
+        trace!("Removing the Marginfi Account from cache: {:?}", address);
+        if let Some(removed) = shard.accounts.remove(address) {
+            let mut liability_index = self.liability_bank_to_accounts.write().map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Marginfi account liability index for removal! {}",
+                    e
+                ))
+            })?;
+            unindex_liabilities(&mut liability_index, &removed);
+        }
+        shard.account_to_health.remove(address);
+        Ok(())
+    }
+
+    /// Drops the accounts without liabilities that were not updated for more than `max_age_slots`.
+    /// The idle borrowers are kept, they can become liquidatable as the prices move.
+    /// Returns the number of the dropped accounts.
+    pub fn compact(&self, current_slot: u64, max_age_slots: u64) -> Result<usize> {
+        let mut dropped = 0;
+        // One shard at a time, so that the updates of the other shards are not blocked meanwhile
+        for shard in &self.shards {
+            let mut shard = shard.write().map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Marginfi accounts cache for compaction! {}",
+                    e
+                ))
+            })?;
+            let expired: Vec<Pubkey> = shard
+                .accounts
+                .iter()
+                .filter(|(_, account)| {
+                    account.slot.saturating_add(max_age_slots) < current_slot
+                        && account.liability_banks().next().is_none()
+                })
+                .map(|(address, _)| *address)
+                .collect();
+            // The accounts without liabilities are not in the liability index
+            for address in &expired {
+                shard.accounts.remove(address);
+                shard.account_to_health.remove(address);
+            }
+            dropped += expired.len();
+        }
+        Ok(dropped)
+    }
+
     pub fn get_account(&self, address: &Pubkey) -> Result<CachedMarginfiAccount> {
-        self.accounts
+        self.shard(address)
             .read()
             .map_err(|e| {
-                anyhow!(
+                MaryError::LockPoisoned(format!(
                     "Failed to lock the Marginfi accounts cache for getting an account: {}",
                     e
-                )
+                ))
             })?
+            .accounts
             .get(address)
             .cloned()
-            .ok_or_else(|| anyhow!("Account {} not found in cache", address))
+            .ok_or_else(|| {
+                MaryError::NotFound(format!("Account {} not found in cache", address)).into()
+            })
     }
 
-    pub fn get_accounts_with_health(&self) -> Result<HashMap<Pubkey, i64>> {
+    /// The group of the cached account without copying the whole account, None if not cached.
+    pub fn get_group(&self, address: &Pubkey) -> Result<Option<Pubkey>> {
         Ok(self
+            .shard(address)
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Marginfi accounts cache for getting a group: {}",
+                    e
+                ))
+            })?
+            .accounts
+            .get(address)
+            .map(|account| account.group))
+    }
+
+    /// The authority of the cached account without copying the whole account, None if not cached.
+    pub fn get_authority(&self, address: &Pubkey) -> Result<Option<Pubkey>> {
+        Ok(self
+            .shard(address)
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Marginfi accounts cache for getting an authority: {}",
+                    e
+                ))
+            })?
+            .accounts
+            .get(address)
+            .map(|account| *account.authority()))
+    }
+
+    pub fn contains(&self, address: &Pubkey) -> Result<bool> {
+        Ok(self
+            .shard(address)
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Marginfi accounts cache for checking an account: {}",
+                    e
+                ))
+            })?
+            .accounts
+            .contains_key(address))
+    }
+
+    pub fn get_health(&self, address: &Pubkey) -> Result<Option<i64>> {
+        Ok(self
+            .shard(address)
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Marginfi account health cache for getting a health: {}",
+                    e
+                ))
+            })?
             .account_to_health
+            .get(address)
+            .copied())
+    }
+
+    pub fn count(&self) -> Result<usize> {
+        let mut count = 0;
+        for shard in &self.shards {
+            count += shard
+                .read()
+                .map_err(|e| {
+                    MaryError::LockPoisoned(format!(
+                        "Failed to lock the Marginfi accounts cache for count: {}",
+                        e
+                    ))
+                })?
+                .accounts
+                .len();
+        }
+        Ok(count)
+    }
+
+    /// Calls the closure with every cached account without cloning them, e.g. to aggregate the
+    /// positions. A shard is read locked while its accounts are visited, so the closure must not
+    /// update the cache.
+    pub fn for_each<F: FnMut(&CachedMarginfiAccount)>(&self, mut f: F) -> Result<()> {
+        for shard in &self.shards {
+            shard
+                .read()
+                .map_err(|e| {
+                    MaryError::LockPoisoned(format!(
+                        "Failed to lock the Marginfi accounts cache for iteration: {}",
+                        e
+                    ))
+                })?
+                .accounts
+                .values()
+                .for_each(&mut f);
+        }
+        Ok(())
+    }
+
+    /// Recomputes the health of the accounts borrowing from the Banks repriced since the last
+    /// refresh and not updated at the current slot, as it drifts with the prices while the accounts
+    /// stay the same. The accounts whose health cannot be computed keep the last one. Returns the
+    /// number of the changed healths.
+    pub fn refresh_health(&self, cache: &Cache) -> Result<usize> {
+        let mut addresses = HashSet::new();
+        for oracle in cache.oracles.take_repriced()? {
+            for bank in cache.banks.get_banks_by_oracle(&oracle)? {
+                addresses.extend(self.accounts_with_liability_in(&bank)?);
+            }
+        }
+
+        let slot = cache.get_clock()?.slot;
+        let mut changed = 0;
+        for address in addresses {
+            // The account can be removed in the meantime
+            let Ok(account) = self.get_account(&address) else {
+                continue;
+            };
+            if account.slot >= slot {
+                continue;
+            }
+            let Ok(health) = account.compute_health(cache) else {
+                continue;
+            };
+            let health = health_to_bps(health);
+
+            let mut shard = self.shard(&address).write().map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Marginfi account health cache for the refresh: {}",
+                    e
+                ))
+            })?;
+            // The account updated in the meantime comes with the fresher health
+            if shard.accounts.get(&address).map(|cached| cached.slot) != Some(account.slot) {
+                continue;
+            }
+            if shard.account_to_health.insert(address, health) != Some(health) {
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    }
+
+    /// The accounts with a liability in the bank, e.g. to re-evaluate on its price move.
+    pub fn accounts_with_liability_in(&self, bank: &Pubkey) -> Result<Vec<Pubkey>> {
+        Ok(self
+            .liability_bank_to_accounts
             .read()
             .map_err(|e| {
-                anyhow!(
-                    "Failed to lock the Marginfi account health cache for cloning: {}",
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Marginfi account liability index for read: {}",
                     e
-                )
+                ))
             })?
-            .clone())
+            .get(bank)
+            .map(|accounts| accounts.iter().copied().collect())
+            .unwrap_or_default())
+    }
+
+    /// Returns up to `limit` accounts with the health at or below the threshold with their health,
+    /// the most unhealthy first. Only the candidates are copied under the read lock of each shard.
+    /// Accounts with invalid health are skipped because their health could not be computed.
+    pub fn liquidation_candidates(
+        &self,
+        threshold: i64,
+        limit: usize,
+    ) -> Result<Vec<(Pubkey, i64)>> {
+        let mut candidates: Vec<(Pubkey, i64)> = Vec::new();
+        for shard in &self.shards {
+            candidates.extend(
+                shard
+                    .read()
+                    .map_err(|e| {
+                        MaryError::LockPoisoned(format!(
+                            "Failed to lock the Marginfi account health cache for the candidates: {}",
+                            e
+                        ))
+                    })?
+                    .account_to_health
+                    .iter()
+                    .filter(|(_, &health)| health != INVALID_HEALTH && health <= threshold)
+                    .map(|(&address, &health)| (address, health)),
+            );
+        }
+
+        // The address breaks the ties, so that the order does not depend on the map
+        let key = |(address, health): &(Pubkey, i64)| (*health, *address);
+        if candidates.len() > limit {
+            if limit == 0 {
+                return Ok(Vec::new());
+            }
+            candidates.select_nth_unstable_by_key(limit - 1, key);
+            candidates.truncate(limit);
+        }
+        candidates.sort_unstable_by_key(key);
+        Ok(candidates)
+    }
+
+    pub fn get_accounts_with_health(&self) -> Result<HashMap<Pubkey, i64>> {
+        let mut accounts_with_health = HashMap::new();
+        for shard in &self.shards {
+            accounts_with_health.extend(
+                shard
+                    .read()
+                    .map_err(|e| {
+                        MaryError::LockPoisoned(format!(
+                            "Failed to lock the Marginfi account health cache for cloning: {}",
+                            e
+                        ))
+                    })?
+                    .account_to_health
+                    .iter()
+                    .map(|(&address, &health)| (address, health)),
+            );
+        }
+        Ok(accounts_with_health)
     }
 }
 
@@ -160,7 +658,9 @@ pub mod test_util {
         marginfi_account::{Balance, LendingAccount, MarginfiAccount},
         marginfi_group::WrappedI80F48,
     };
-    use solana_sdk::pubkey::Pubkey;
+    use solana_sdk::{account::Account, pubkey::Pubkey};
+
+    use crate::common::MARGINFI_ACCOUNT_DISCRIMINATOR;
 
     pub fn create_default_balance() -> Balance {
         Balance {
@@ -216,15 +716,37 @@ pub mod test_util {
             emissions_destination_account: Pubkey::default(),
         }
     }
+
+    /// The on-chain account of the zero-copy MarginfiAccount, as fetched through the RPC.
+    pub fn create_marginfi_account_account(marginfi_account: &MarginfiAccount) -> Account {
+        let mut data = MARGINFI_ACCOUNT_DISCRIMINATOR.to_vec();
+        data.extend_from_slice(bytemuck::bytes_of(marginfi_account));
+        Account {
+            lamports: 1,
+            data,
+            owner: Pubkey::new_unique(),
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::test_util::{create_balance, create_marginfi_account};
     use super::*;
+    use crate::cache::{
+        banks::test_util::{add_priced_bank, create_bank_with_oracles, create_emode_bank},
+        oracles::test_util::create_pyth_account,
+        test_util::{create_dummy_cache, generate_test_clock},
+    };
     use fixed::types::I80F48;
     use marginfi::state::marginfi_group::WrappedI80F48;
     use solana_sdk::pubkey::Pubkey;
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
 
     #[test]
     fn test_cached_marginfi_account_from() {
@@ -240,10 +762,11 @@ mod tests {
         ];
         let marginfi_account = create_marginfi_account(group, balances.clone());
 
-        let cached = CachedMarginfiAccount::from(slot, address, marginfi_account);
+        let cached = CachedMarginfiAccount::from(slot, address, &marginfi_account);
 
         assert_eq!(cached.slot, slot);
         assert_eq!(cached.address, address);
+        assert_eq!(cached.group(), &group);
         assert_eq!(cached._positions().len(), 2);
         assert_eq!(cached._positions()[0].bank_pk, bank1);
         assert_eq!(cached._positions()[1].bank_pk, bank2);
@@ -265,6 +788,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_cached_marginfi_account_accessors() {
+        let group = Pubkey::new_unique();
+        let authority = Pubkey::new_unique();
+        let mut marginfi_account = create_marginfi_account(group, vec![]);
+        marginfi_account.authority = authority;
+        marginfi_account.account_flags = 0b101;
+
+        let cached = CachedMarginfiAccount::from(1, Pubkey::new_unique(), &marginfi_account);
+        assert_eq!(cached.group(), &group);
+        assert_eq!(cached.authority(), &authority);
+        assert_eq!(cached.account_flags(), 0b101);
+    }
+
     #[test]
     fn test_marginfi_accounts_cache_update_and_retrieve() {
         let cache = MarginfiAccountsCache::default();
@@ -276,7 +813,7 @@ mod tests {
         let marginfi_account = create_marginfi_account(group, balances);
 
         cache
-            .update(slot, address, marginfi_account)
+            .update(slot, address, &marginfi_account)
             .expect("update should succeed");
 
         let cached = cache
@@ -289,6 +826,13 @@ mod tests {
 
         let health_map = cache.get_accounts_with_health().unwrap();
         assert_eq!(health_map.get(&address), Some(&INVALID_HEALTH));
+
+        assert_eq!(cache.get_group(&address).unwrap(), Some(group));
+        assert_eq!(cache.get_group(&Pubkey::new_unique()).unwrap(), None);
+        assert_eq!(
+            cache.get_authority(&address).unwrap(),
+            Some(marginfi_account.authority)
+        );
     }
 
     #[test]
@@ -304,10 +848,10 @@ mod tests {
         let marginfi_account2 = create_marginfi_account(group2, vec![create_balance(bank2, 3, 4)]);
 
         cache
-            .update(1, address, marginfi_account1)
+            .update(1, address, &marginfi_account1)
             .expect("first update");
         cache
-            .update(2, address, marginfi_account2)
+            .update(2, address, &marginfi_account2)
             .expect("second update");
 
         let cached = cache.get_account(&address).unwrap();
@@ -318,6 +862,58 @@ mod tests {
         assert_eq!(health_map.get(&address), Some(&INVALID_HEALTH));
     }
 
+    #[test]
+    fn test_for_each() {
+        let cache = MarginfiAccountsCache::default();
+        let bank = Pubkey::new_unique();
+        for asset_shares in [10, 20, 30] {
+            cache
+                .update(
+                    1,
+                    Pubkey::new_unique(),
+                    &create_marginfi_account(
+                        Pubkey::new_unique(),
+                        vec![create_balance(bank, asset_shares, 5)],
+                    ),
+                )
+                .unwrap();
+        }
+
+        let mut count = 0;
+        let mut total_asset_shares = I80F48::ZERO;
+        let mut total_liability_shares = I80F48::ZERO;
+        cache
+            .for_each(|account| {
+                count += 1;
+                for position in account._positions() {
+                    total_asset_shares += I80F48::from(position.asset_shares);
+                    total_liability_shares += I80F48::from(position.liability_shares);
+                }
+            })
+            .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(total_asset_shares, I80F48::from_num(60));
+        assert_eq!(total_liability_shares, I80F48::from_num(15));
+    }
+
+    #[test]
+    fn test_update_borrows_the_account() {
+        let cache = MarginfiAccountsCache::default();
+        let address = Pubkey::new_unique();
+        let bank = Pubkey::new_unique();
+        let marginfi_account =
+            create_marginfi_account(Pubkey::new_unique(), vec![create_balance(bank, 1, 2)]);
+
+        // The same account is still usable after the update
+        cache.update(1, address, &marginfi_account).unwrap();
+        cache.update(2, address, &marginfi_account).unwrap();
+
+        let cached = cache.get_account(&address).unwrap();
+        assert_eq!(cached.slot, 2);
+        assert_eq!(cached.group(), &marginfi_account.group);
+        assert_eq!(cached._positions()[0].bank_pk, bank);
+    }
+
     #[test]
     fn test_update_with_older_slot_does_not_overwrite() {
         let cache = MarginfiAccountsCache::default();
@@ -334,12 +930,12 @@ mod tests {
 
         // Insert with higher slot first
         cache
-            .update(10, address, marginfi_account_new)
+            .update(10, address, &marginfi_account_new)
             .expect("first update with new slot");
 
         // Try to update with lower slot
         cache
-            .update(5, address, marginfi_account_old)
+            .update(5, address, &marginfi_account_old)
             .expect("second update with old slot");
 
         let cached = cache.get_account(&address).unwrap();
@@ -365,6 +961,191 @@ mod tests {
         assert!(format!("{}", result.unwrap_err()).contains("not found in cache"));
     }
 
+    #[test]
+    fn test_remove_account() {
+        let cache = MarginfiAccountsCache::default();
+        let address = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        for account in [address, other] {
+            let marginfi_account = create_marginfi_account(
+                Pubkey::new_unique(),
+                vec![create_balance(Pubkey::new_unique(), 10, 5)],
+            );
+            cache.update(1, account, &marginfi_account).unwrap();
+        }
+
+        cache.remove(&address).unwrap();
+        assert!(cache.get_account(&address).is_err());
+        assert!(!cache
+            .get_accounts_with_health()
+            .unwrap()
+            .contains_key(&address));
+        assert!(cache.get_account(&other).is_ok());
+        assert_eq!(cache.count().unwrap(), 1);
+
+        // Removing the missing account is a no-op
+        cache.remove(&address).unwrap();
+    }
+
+    #[test]
+    fn test_updates_of_accounts_in_distinct_shards_do_not_block() {
+        let cache = MarginfiAccountsCache::default();
+        let locked = Pubkey::new_from_array([0; 32]);
+        let other_shard = Pubkey::new_from_array([1; 32]);
+        let same_shard = Pubkey::new_from_array([ACCOUNT_SHARD_COUNT as u8; 32]);
+        let liability_bank = Pubkey::new_unique();
+        let create_account = || {
+            create_marginfi_account(
+                Pubkey::new_unique(),
+                vec![create_balance(liability_bank, 10, 5)],
+            )
+        };
+
+        let (tx, rx) = crossbeam::channel::unbounded();
+        thread::scope(|scope| {
+            let guard = cache.shard(&locked).write().unwrap();
+
+            scope.spawn(|| {
+                cache.update(1, other_shard, &create_account()).unwrap();
+                tx.send(other_shard).unwrap();
+            });
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(other_shard));
+
+            scope.spawn(|| {
+                cache.update(1, same_shard, &create_account()).unwrap();
+                tx.send(same_shard).unwrap();
+            });
+            assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+            drop(guard);
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(same_shard));
+        });
+
+        assert_eq!(cache.count().unwrap(), 2);
+        let mut indexed = cache.accounts_with_liability_in(&liability_bank).unwrap();
+        indexed.sort();
+        assert_eq!(indexed, vec![other_shard, same_shard]);
+    }
+
+    #[test]
+    fn test_liability_index_follows_position_changes() {
+        let cache = MarginfiAccountsCache::default();
+        let address = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let bank1 = Pubkey::new_unique();
+        let bank2 = Pubkey::new_unique();
+        let collateral_bank = Pubkey::new_unique();
+        let group = Pubkey::new_unique();
+
+        cache
+            .update(
+                1,
+                address,
+                &create_marginfi_account(
+                    group,
+                    vec![
+                        create_balance(collateral_bank, 100, 0),
+                        create_balance(bank1, 0, 50),
+                    ],
+                ),
+            )
+            .unwrap();
+        cache
+            .update(
+                1,
+                other,
+                &create_marginfi_account(group, vec![create_balance(bank1, 0, 10)]),
+            )
+            .unwrap();
+        let mut accounts = cache.accounts_with_liability_in(&bank1).unwrap();
+        accounts.sort();
+        let mut expected = vec![address, other];
+        expected.sort();
+        assert_eq!(accounts, expected);
+        assert!(cache
+            .accounts_with_liability_in(&collateral_bank)
+            .unwrap()
+            .is_empty());
+
+        // The liability moves from bank1 to bank2
+        cache
+            .update(
+                2,
+                address,
+                &create_marginfi_account(
+                    group,
+                    vec![
+                        create_balance(collateral_bank, 100, 0),
+                        create_balance(bank2, 0, 50),
+                    ],
+                ),
+            )
+            .unwrap();
+        assert_eq!(
+            cache.accounts_with_liability_in(&bank1).unwrap(),
+            vec![other]
+        );
+        assert_eq!(
+            cache.accounts_with_liability_in(&bank2).unwrap(),
+            vec![address]
+        );
+
+        // The stale update does not touch the index
+        cache
+            .update(
+                1,
+                address,
+                &create_marginfi_account(group, vec![create_balance(bank1, 0, 50)]),
+            )
+            .unwrap();
+        assert_eq!(
+            cache.accounts_with_liability_in(&bank1).unwrap(),
+            vec![other]
+        );
+
+        cache.remove(&other).unwrap();
+        assert!(cache.accounts_with_liability_in(&bank1).unwrap().is_empty());
+        // The idle borrower is not compacted
+        assert_eq!(cache.compact(100, 10).unwrap(), 0);
+        assert_eq!(
+            cache.accounts_with_liability_in(&bank2).unwrap(),
+            vec![address]
+        );
+    }
+
+    #[test]
+    fn test_compact_drops_accounts_by_slot_age() {
+        let cache = MarginfiAccountsCache::default();
+        let old = Pubkey::new_unique();
+        let boundary = Pubkey::new_unique();
+        let recent = Pubkey::new_unique();
+        for (slot, account) in [(10, old), (50, boundary), (90, recent)] {
+            let marginfi_account = create_marginfi_account(Pubkey::new_unique(), vec![]);
+            cache.update(slot, account, &marginfi_account).unwrap();
+        }
+        let old_borrower = Pubkey::new_unique();
+        cache
+            .update(
+                10,
+                old_borrower,
+                &create_marginfi_account(
+                    Pubkey::new_unique(),
+                    vec![create_balance(Pubkey::new_unique(), 0, 50)],
+                ),
+            )
+            .unwrap();
+
+        assert_eq!(cache.compact(100, 50).unwrap(), 1);
+        assert!(cache.get_account(&old).is_err());
+        assert!(cache.get_account(&boundary).is_ok());
+        assert!(cache.get_account(&recent).is_ok());
+        assert!(cache.get_account(&old_borrower).is_ok());
+
+        let health_map = cache.get_accounts_with_health().unwrap();
+        assert_eq!(health_map.len(), 3);
+        assert!(!health_map.contains_key(&old));
+    }
+
     #[test]
     fn test_get_accounts_with_health_empty() {
         let cache = MarginfiAccountsCache::default();
@@ -389,8 +1170,8 @@ mod tests {
         let marginfi_account2 =
             create_marginfi_account(group2, vec![create_balance(bank2, 33, 44)]);
 
-        cache.update(slot1, address1, marginfi_account1).unwrap();
-        cache.update(slot2, address2, marginfi_account2).unwrap();
+        cache.update(slot1, address1, &marginfi_account1).unwrap();
+        cache.update(slot2, address2, &marginfi_account2).unwrap();
 
         let cached1 = cache.get_account(&address1).unwrap();
         let cached2 = cache.get_account(&address2).unwrap();
@@ -418,7 +1199,7 @@ mod tests {
         marginfi_account.health_cache.asset_value_maint = I80F48::from_num(500).into();
         marginfi_account.health_cache.liability_value_maint = I80F48::from_num(200).into();
 
-        let cached = CachedMarginfiAccount::from(slot, address, marginfi_account);
+        let cached = CachedMarginfiAccount::from(slot, address, &marginfi_account);
 
         assert_eq!(cached.asset_value_maint(), I80F48::from_num(500));
         assert_eq!(cached.liability_value_maint(), I80F48::from_num(200));
@@ -436,10 +1217,10 @@ mod tests {
         marginfi_account.health_cache.asset_value_maint = I80F48::from_num(1000).into();
         marginfi_account.health_cache.liability_value_maint = I80F48::from_num(500).into();
 
-        let cached = CachedMarginfiAccount::from(slot, address, marginfi_account);
+        let cached = CachedMarginfiAccount::from(slot, address, &marginfi_account);
 
-        // health = (1000 - 500) / 1000 = 0.5 -> to_num::<u64>() = 0
-        assert_eq!(cached.health(), Some(0));
+        // health = (1000 - 500) / 1000 = 0.5 -> 5000 bps
+        assert_eq!(cached.health(), Some(5_000));
     }
 
     #[test]
@@ -454,11 +1235,35 @@ mod tests {
         marginfi_account.health_cache.asset_value_maint = I80F48::from_num(0).into();
         marginfi_account.health_cache.liability_value_maint = I80F48::from_num(500).into();
 
-        let cached = CachedMarginfiAccount::from(slot, address, marginfi_account);
+        let cached = CachedMarginfiAccount::from(slot, address, &marginfi_account);
 
         assert_eq!(cached.health(), None);
     }
 
+    #[test]
+    fn test_health_preserves_fractional_ratios() {
+        let cache = MarginfiAccountsCache::default();
+        let mut expected = Vec::new();
+        for (liability, health_bps) in [(700, 3_000), (500, 5_000), (1300, -3_000), (1500, -5_000)]
+        {
+            let mut marginfi_account = create_marginfi_account(
+                Pubkey::new_unique(),
+                vec![create_balance(Pubkey::new_unique(), 100, 50)],
+            );
+            marginfi_account.health_cache.asset_value_maint = I80F48::from_num(1000).into();
+            marginfi_account.health_cache.liability_value_maint =
+                I80F48::from_num(liability).into();
+            let address = Pubkey::new_unique();
+            cache.update(1, address, &marginfi_account).unwrap();
+            expected.push((address, health_bps));
+        }
+
+        let health_map = cache.get_accounts_with_health().unwrap();
+        for (address, health_bps) in expected {
+            assert_eq!(health_map[&address], health_bps);
+        }
+    }
+
     #[test]
     fn test_health_negative_liability() {
         let slot = 1;
@@ -471,9 +1276,330 @@ mod tests {
         marginfi_account.health_cache.asset_value_maint = I80F48::from_num(1000).into();
         marginfi_account.health_cache.liability_value_maint = I80F48::from_num(1500).into();
 
-        let cached = CachedMarginfiAccount::from(slot, address, marginfi_account);
+        let cached = CachedMarginfiAccount::from(slot, address, &marginfi_account);
+
+        // health = (1000 - 1500) / 1000 = -0.5 -> -5000 bps
+        assert_eq!(cached.health(), Some(-5_000));
+    }
+
+    #[test]
+    fn test_compute_health_missing_bank() {
+        let cache = create_dummy_cache();
+        let marginfi_account = create_marginfi_account(
+            Pubkey::new_unique(),
+            vec![create_balance(Pubkey::new_unique(), 100, 50)],
+        );
+        let cached = CachedMarginfiAccount::from(1, Pubkey::new_unique(), &marginfi_account);
+
+        let err = cached.compute_health(&cache).unwrap_err();
+        assert!(format!("{}", err).contains("not found in cache"));
+        assert!(matches!(MaryError::of(&err), Some(MaryError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_compute_health_no_positions() {
+        let cache = create_dummy_cache();
+        let marginfi_account = create_marginfi_account(Pubkey::new_unique(), vec![]);
+        let cached = CachedMarginfiAccount::from(1, Pubkey::new_unique(), &marginfi_account);
+
+        let result = cached.compute_health(&cache);
+        assert!(result.is_err());
+        assert!(format!("{}", result.unwrap_err()).contains("has no asset value"));
+    }
+
+    #[test]
+    fn test_refresh_health_follows_price_moves() {
+        let cache = create_dummy_cache();
+        let collateral_bank = add_priced_bank(&cache, create_bank_with_oracles(vec![]), 0.8, 1.0);
+        let liability_bank = add_priced_bank(&cache, create_bank_with_oracles(vec![]), 0.8, 1.0);
+        let address = Pubkey::new_unique();
+        let fresh = Pubkey::new_unique();
+        let marginfi_account = create_marginfi_account(
+            Pubkey::new_unique(),
+            vec![
+                create_balance(collateral_bank, 1_000_000_000, 0),
+                create_balance(liability_bank, 0, 700_000_000),
+            ],
+        );
+        cache
+            .update_marginfi_account(1, address, &marginfi_account)
+            .unwrap();
+        let health = cache
+            .marginfi_accounts
+            .get_health(&address)
+            .unwrap()
+            .unwrap();
+        assert!(health > 0);
+
+        // The liability price rises by half while the account stays the same
+        cache.update_clock(generate_test_clock(2)).unwrap();
+        let oracle = cache
+            .banks
+            .get_bank(&liability_bank)
+            .unwrap()
+            .oracle_addresses()[0];
+        cache
+            .oracles
+            .update(
+                2,
+                0,
+                &oracle,
+                &mut create_pyth_account(1_500, 0, 1_500, 0, 2),
+            )
+            .unwrap();
+        cache
+            .update_marginfi_account(2, fresh, &marginfi_account)
+            .unwrap();
+        // The health of the account updated at the current slot is already fresh
+        assert!(cache.marginfi_accounts.get_health(&fresh).unwrap().unwrap() < 0);
+        // Only the accounts borrowing from the repriced banks are recomputed
+        assert_eq!(cache.marginfi_accounts.refresh_health(&cache).unwrap(), 0);
+
+        cache.oracles.mark_repriced(&oracle).unwrap();
+        assert_eq!(cache.marginfi_accounts.refresh_health(&cache).unwrap(), 1);
+        assert!(
+            cache
+                .marginfi_accounts
+                .get_health(&address)
+                .unwrap()
+                .unwrap()
+                < 0
+        );
+        assert_eq!(cache.marginfi_accounts.refresh_health(&cache).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_compute_health_emode() {
+        let cache = create_dummy_cache();
+        let group = Pubkey::new_unique();
+        let collateral_bank = add_priced_bank(&cache, create_emode_bank(3, &[]), 0.8, 1.0);
+        let emode_liability_bank =
+            add_priced_bank(&cache, create_emode_bank(0, &[(3, 0.95)]), 0.8, 1.0);
+        let liability_bank = add_priced_bank(&cache, create_emode_bank(0, &[]), 0.8, 1.0);
+
+        // The same 1000 of the collateral against the 900 of the liabilities
+        let emode_account = CachedMarginfiAccount::from(
+            1,
+            Pubkey::new_unique(),
+            &create_marginfi_account(
+                group,
+                vec![
+                    create_balance(collateral_bank, 1_000, 0),
+                    create_balance(emode_liability_bank, 0, 900),
+                ],
+            ),
+        );
+        let account = CachedMarginfiAccount::from(
+            1,
+            Pubkey::new_unique(),
+            &create_marginfi_account(
+                group,
+                vec![
+                    create_balance(collateral_bank, 1_000, 0),
+                    create_balance(liability_bank, 0, 900),
+                ],
+            ),
+        );
+
+        // Healthy at the emode weight of 0.95, liquidatable at the base weight of 0.8
+        let emode_health = emode_account.compute_health(&cache).unwrap();
+        let health = account.compute_health(&cache).unwrap();
+        assert!(emode_health > I80F48::ZERO);
+        assert!(health < I80F48::ZERO);
+        assert_eq!(
+            emode_account.emode_entries(&cache).unwrap()[0].collateral_bank_emode_tag,
+            3
+        );
+        assert!(account.emode_entries(&cache).unwrap().is_empty());
+
+        // An account without the liabilities gets the base weight
+        let lender = CachedMarginfiAccount::from(
+            1,
+            Pubkey::new_unique(),
+            &create_marginfi_account(group, vec![create_balance(collateral_bank, 1_000, 0)]),
+        );
+        let values = lender.position_values(&cache).unwrap();
+        let emode_values = emode_account.position_values(&cache).unwrap();
+        assert!(emode_values[0].asset_value > values[0].asset_value);
+    }
+
+    #[test]
+    fn test_health_with_fallback_uses_health_cache() {
+        let cache = create_dummy_cache();
+        let mut marginfi_account = create_marginfi_account(
+            Pubkey::new_unique(),
+            vec![create_balance(Pubkey::new_unique(), 100, 50)],
+        );
+        marginfi_account.health_cache.asset_value_maint = I80F48::from_num(1000).into();
+        marginfi_account.health_cache.liability_value_maint = I80F48::from_num(1500).into();
+        let cached = CachedMarginfiAccount::from(1, Pubkey::new_unique(), &marginfi_account);
+
+        // The bank is not cached, so only the health cache fast path can produce a value
+        assert_eq!(cached.health_with_fallback(&cache), Some(-5_000));
+    }
+
+    #[test]
+    fn test_health_with_fallback_invalid_when_not_computable() {
+        let cache = create_dummy_cache();
+        let marginfi_account = create_marginfi_account(
+            Pubkey::new_unique(),
+            vec![create_balance(Pubkey::new_unique(), 100, 50)],
+        );
+        let cached = CachedMarginfiAccount::from(1, Pubkey::new_unique(), &marginfi_account);
+
+        assert_eq!(cached.health_with_fallback(&cache), None);
+    }
+
+    #[test]
+    fn test_calc_value() {
+        // 1 token (6 decimals) at price 2 with 0.5 weight
+        let value = calc_value(
+            I80F48::from_num(1_000_000),
+            I80F48::from_num(2),
+            6,
+            I80F48::from_num(0.5),
+        )
+        .unwrap();
+        assert_eq!(value, I80F48::from_num(1));
+    }
+
+    fn create_cache_with_health(accounts: &[(Pubkey, i64)]) -> MarginfiAccountsCache {
+        let cache = MarginfiAccountsCache::default();
+        for (address, health) in accounts {
+            cache
+                .shard(address)
+                .write()
+                .unwrap()
+                .account_to_health
+                .insert(*address, *health);
+        }
+        cache
+    }
+
+    #[test]
+    fn test_liquidation_candidates_filters_invalid_health() {
+        let invalid = Pubkey::new_unique();
+        let unhealthy = Pubkey::new_unique();
+        let cache = create_cache_with_health(&[(invalid, INVALID_HEALTH), (unhealthy, -1)]);
+
+        let candidates = cache.liquidation_candidates(0, usize::MAX).unwrap();
+        assert_eq!(candidates, vec![(unhealthy, -1)]);
+    }
+
+    #[test]
+    fn test_liquidation_candidates_filters_above_threshold() {
+        let healthy = Pubkey::new_unique();
+        let at_threshold = Pubkey::new_unique();
+        let unhealthy = Pubkey::new_unique();
+        let cache = create_cache_with_health(&[(healthy, 1), (at_threshold, 0), (unhealthy, -1)]);
+
+        let candidates = cache.liquidation_candidates(0, usize::MAX).unwrap();
+        assert_eq!(candidates, vec![(unhealthy, -1), (at_threshold, 0)]);
+        let candidates = cache.liquidation_candidates(-1, usize::MAX).unwrap();
+        assert_eq!(candidates, vec![(unhealthy, -1)]);
+    }
+
+    #[test]
+    fn test_liquidation_candidates_most_unhealthy_first() {
+        let account1 = Pubkey::new_unique();
+        let account2 = Pubkey::new_unique();
+        let account3 = Pubkey::new_unique();
+        let cache = create_cache_with_health(&[(account1, -2), (account2, -10), (account3, -5)]);
+
+        let candidates = cache.liquidation_candidates(0, usize::MAX).unwrap();
+        assert_eq!(
+            candidates,
+            vec![(account2, -10), (account3, -5), (account1, -2)]
+        );
+    }
+
+    #[test]
+    fn test_liquidation_candidates_limit() {
+        let account1 = Pubkey::new_unique();
+        let account2 = Pubkey::new_unique();
+        let account3 = Pubkey::new_unique();
+        let cache = create_cache_with_health(&[(account1, -2), (account2, -10), (account3, -5)]);
+
+        assert_eq!(
+            cache.liquidation_candidates(0, 2).unwrap(),
+            vec![(account2, -10), (account3, -5)]
+        );
+        assert_eq!(
+            cache.liquidation_candidates(0, 1).unwrap(),
+            vec![(account2, -10)]
+        );
+        assert!(cache.liquidation_candidates(0, 0).unwrap().is_empty());
+        assert_eq!(cache.liquidation_candidates(0, 3).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_liquidation_candidates_fractional_ratios() {
+        let cache = MarginfiAccountsCache::default();
+        let mut addresses = Vec::new();
+        // The health ratios of 0.5, 0.3, -0.3 and -0.5
+        for liability in [500, 700, 1300, 1500] {
+            let mut marginfi_account = create_marginfi_account(
+                Pubkey::new_unique(),
+                vec![create_balance(Pubkey::new_unique(), 100, 50)],
+            );
+            marginfi_account.health_cache.asset_value_maint = I80F48::from_num(1000).into();
+            marginfi_account.health_cache.liability_value_maint =
+                I80F48::from_num(liability).into();
+            let address = Pubkey::new_unique();
+            cache.update(1, address, &marginfi_account).unwrap();
+            addresses.push(address);
+        }
 
-        // health = (1000 - 1500) / 1000 = -0.5 -> to_num::<i64>() = -1
-        assert_eq!(cached.health(), Some(-1));
+        let candidates: Vec<Pubkey> = cache
+            .liquidation_candidates(HEALTH_BPS_SCALE, usize::MAX)
+            .unwrap()
+            .into_iter()
+            .map(|(address, _)| address)
+            .collect();
+        assert_eq!(
+            candidates,
+            vec![addresses[3], addresses[2], addresses[1], addresses[0]]
+        );
+        let candidates: Vec<Pubkey> = cache
+            .liquidation_candidates(0, usize::MAX)
+            .unwrap()
+            .into_iter()
+            .map(|(address, _)| address)
+            .collect();
+        assert_eq!(candidates, vec![addresses[3], addresses[2]]);
+    }
+
+    #[test]
+    fn test_liquidation_candidates_empty() {
+        let cache = MarginfiAccountsCache::default();
+        assert!(cache.liquidation_candidates(0, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_liquidation_candidates_large_cache() {
+        // A mainnet sized cache with a few percent of the accounts below the threshold
+        let accounts: Vec<(Pubkey, i64)> = (0..200_000i64)
+            .map(|i| (Pubkey::new_unique(), (i * 7_919) % 20_000 - 500))
+            .collect();
+        let cache = create_cache_with_health(&accounts);
+
+        let started = Instant::now();
+        let candidates = cache.liquidation_candidates(0, 100).unwrap();
+        let elapsed = started.elapsed();
+
+        let mut expected: Vec<(Pubkey, i64)> = accounts
+            .iter()
+            .filter(|(_, health)| *health <= 0)
+            .copied()
+            .collect();
+        expected.sort_unstable_by_key(|(address, health)| (*health, *address));
+        expected.truncate(100);
+        assert_eq!(candidates, expected);
+        // Generous enough for the debug builds on the slow CI runners
+        assert!(
+            elapsed < Duration::from_secs(1),
+            "The candidates took {:?}",
+            elapsed
+        );
     }
 }
@@ -1,17 +1,52 @@
-use crate::cache::CacheEntry;
-use anyhow::{anyhow, Result};
+use crate::{cache::CacheEntry, error::MaryError};
+use anyhow::Result;
 use log::trace;
 use solana_sdk::{account::Account, pubkey::Pubkey};
+use spl_token_2022::{
+    extension::{
+        transfer_fee::TransferFeeConfig, transfer_hook::TransferHook, BaseStateWithExtensions,
+        StateWithExtensions,
+    },
+    state::Mint,
+};
 use std::{collections::HashMap, sync::RwLock};
 
 #[derive(Debug, Clone)]
 pub struct CachedMint {
     pub _address: Pubkey,
-    pub _owner: Pubkey,
+    pub owner: Pubkey,
+    // The raw mint state, the token-2022 extensions are parsed on demand.
+    pub data: Vec<u8>,
 }
 
 impl CacheEntry for CachedMint {}
 
+impl CachedMint {
+    /// The transfer fee of the epoch in basis points, zero for the mints without the fee.
+    pub fn transfer_fee_bps(&self, epoch: u64) -> Result<u16> {
+        Ok(self
+            .state()?
+            .get_extension::<TransferFeeConfig>()
+            .map_or(0, |fee_config| {
+                u16::from(fee_config.get_epoch_fee(epoch).transfer_fee_basis_points)
+            }))
+    }
+
+    pub fn has_transfer_hook(&self) -> Result<bool> {
+        Ok(self
+            .state()?
+            .get_extension::<TransferHook>()
+            .is_ok_and(|transfer_hook| Option::<Pubkey>::from(transfer_hook.program_id).is_some()))
+    }
+
+    // The legacy token mints parse as the token-2022 mints without extensions
+    fn state(&self) -> Result<StateWithExtensions<'_, Mint>> {
+        StateWithExtensions::<Mint>::unpack(&self.data).map_err(|e| {
+            MaryError::Deserialize(format!("Invalid mint {} data: {}", self._address, e)).into()
+        })
+    }
+}
+
 #[derive(Default)]
 pub struct MintsCache {
     mints: RwLock<HashMap<Pubkey, CachedMint>>,
@@ -21,14 +56,17 @@ impl MintsCache {
     pub fn update(&self, address: Pubkey, mint: &Account) -> Result<()> {
         let upd_cached_mint = CachedMint {
             _address: address,
-            _owner: mint.owner,
+            owner: mint.owner,
+            data: mint.data.clone(),
         };
 
         trace!("Updating the Mint in cache: {:?}", upd_cached_mint);
 
         self.mints
             .write()
-            .map_err(|e| anyhow!("Failed to lock the Mints cache for update: {}", e))?
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!("Failed to lock the Mints cache for update: {}", e))
+            })?
             .insert(address, upd_cached_mint);
 
         Ok(())
@@ -38,13 +76,114 @@ impl MintsCache {
         Ok(self
             .mints
             .read()
-            .map_err(|e| anyhow!("Failed to lock the Mints cache for read: {}", e))?
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!("Failed to lock the Mints cache for read: {}", e))
+            })?
             .get(address)
             .cloned())
     }
+
+    pub fn count(&self) -> Result<usize> {
+        Ok(self
+            .mints
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!("Failed to lock the Mints cache for count: {}", e))
+            })?
+            .len())
+    }
+
+    pub fn get_all(&self) -> Result<Vec<CachedMint>> {
+        Ok(self
+            .mints
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!("Failed to lock the Mints cache for read: {}", e))
+            })?
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    pub fn get_mint_addresses(&self) -> Result<Vec<Pubkey>> {
+        Ok(self
+            .mints
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!("Failed to lock the Mints cache for read: {}", e))
+            })?
+            .keys()
+            .cloned()
+            .collect())
+    }
 }
+#[cfg(test)]
+pub mod test_util {
+    use solana_sdk::{account::Account, pubkey::Pubkey};
+    use spl_pod::optional_keys::OptionalNonZeroPubkey;
+    use spl_token_2022::{
+        extension::{
+            transfer_fee::{TransferFee, TransferFeeConfig},
+            transfer_hook::TransferHook,
+            BaseStateWithExtensionsMut, ExtensionType, StateWithExtensionsMut,
+        },
+        state::Mint,
+    };
+
+    /// Creates the token-2022 mint account, with the transfer hook and the transfer fee extensions
+    /// if the hook program and the fee are given.
+    pub fn create_token_2022_mint(
+        decimals: u8,
+        hook_program_id: Option<Pubkey>,
+        transfer_fee_bps: Option<u16>,
+    ) -> Account {
+        let mut extensions = Vec::new();
+        if hook_program_id.is_some() {
+            extensions.push(ExtensionType::TransferHook);
+        }
+        if transfer_fee_bps.is_some() {
+            extensions.push(ExtensionType::TransferFeeConfig);
+        }
+        let mut data =
+            vec![0u8; ExtensionType::try_calculate_account_len::<Mint>(&extensions).unwrap()];
+        let mut state = StateWithExtensionsMut::<Mint>::unpack_uninitialized(&mut data).unwrap();
+        if let Some(hook_program_id) = hook_program_id {
+            state
+                .init_extension::<TransferHook>(true)
+                .unwrap()
+                .program_id = OptionalNonZeroPubkey::try_from(Some(hook_program_id)).unwrap();
+        }
+        if let Some(transfer_fee_bps) = transfer_fee_bps {
+            let fee_config = state.init_extension::<TransferFeeConfig>(true).unwrap();
+            let transfer_fee = TransferFee {
+                epoch: 0.into(),
+                maximum_fee: u64::MAX.into(),
+                transfer_fee_basis_points: transfer_fee_bps.into(),
+            };
+            fee_config.older_transfer_fee = transfer_fee;
+            fee_config.newer_transfer_fee = transfer_fee;
+        }
+        state.base = Mint {
+            decimals,
+            is_initialized: true,
+            ..Default::default()
+        };
+        state.pack_base();
+        state.init_account_type().unwrap();
+
+        Account {
+            lamports: 1,
+            data,
+            owner: spl_token_2022::id(),
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::test_util::create_token_2022_mint;
     use super::*;
 
     #[test]
@@ -65,7 +204,8 @@ mod tests {
         let mints = cache.mints.read().unwrap();
         let cached = mints.get(&address).unwrap();
         assert_eq!(cached._address, address);
-        assert_eq!(cached._owner, owner);
+        assert_eq!(cached.owner, owner);
+        assert!(cached.data.is_empty());
     }
 
     #[test]
@@ -91,11 +231,75 @@ mod tests {
         };
 
         cache.update(address, &account1).unwrap();
+        let account2 = Account {
+            data: vec![1, 2, 3],
+            ..account2
+        };
         cache.update(address, &account2).unwrap();
 
         let mints = cache.mints.read().unwrap();
         let cached = mints.get(&address).unwrap();
-        assert_eq!(cached._owner, owner2);
+        assert_eq!(cached.owner, owner2);
+        assert_eq!(cached.data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_get_mint_addresses() {
+        let cache = MintsCache::default();
+        assert!(cache.get_mint_addresses().unwrap().is_empty());
+
+        let address = Pubkey::new_unique();
+        let account = Account::new(1, 82, &Pubkey::new_unique());
+        cache.update(address, &account).unwrap();
+        cache.update(address, &account).unwrap();
+        assert_eq!(cache.get_mint_addresses().unwrap(), vec![address]);
+    }
+
+    #[test]
+    fn test_count() {
+        let cache = MintsCache::default();
+        assert_eq!(cache.count().unwrap(), 0);
+
+        let account = Account::new(1, 82, &Pubkey::new_unique());
+        cache.update(Pubkey::new_unique(), &account).unwrap();
+        cache.update(Pubkey::new_unique(), &account).unwrap();
+        assert_eq!(cache.count().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_cached_mint_token_2022_extensions() {
+        let cache = MintsCache::default();
+        let address = Pubkey::new_unique();
+        cache
+            .update(
+                address,
+                &create_token_2022_mint(9, Some(Pubkey::new_unique()), Some(150)),
+            )
+            .unwrap();
+
+        let mint = cache.get(&address).unwrap().unwrap();
+        assert_eq!(mint.transfer_fee_bps(10).unwrap(), 150);
+        assert!(mint.has_transfer_hook().unwrap());
+    }
+
+    #[test]
+    fn test_cached_mint_without_extensions() {
+        let cache = MintsCache::default();
+        let address = Pubkey::new_unique();
+        cache
+            .update(address, &create_token_2022_mint(6, None, None))
+            .unwrap();
+
+        let mint = cache.get(&address).unwrap().unwrap();
+        assert_eq!(mint.transfer_fee_bps(10).unwrap(), 0);
+        assert!(!mint.has_transfer_hook().unwrap());
+
+        let invalid = CachedMint {
+            _address: address,
+            owner: spl_token_2022::id(),
+            data: vec![0; 10],
+        };
+        assert!(invalid.transfer_fee_bps(10).is_err());
     }
 
     #[test]
@@ -124,6 +328,6 @@ mod tests {
         assert!(result.is_some());
         let cached = result.unwrap();
         assert_eq!(cached._address, address);
-        assert_eq!(cached._owner, owner);
+        assert_eq!(cached.owner, owner);
     }
 }
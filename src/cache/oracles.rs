@@ -1,62 +1,123 @@
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
 
+use fixed::types::I80F48;
 use marginfi::state::price::{
-    OraclePriceFeedAdapter, OracleSetup, PythPushOraclePriceFeed, SwitchboardPullPriceFeed,
+    OraclePriceFeedAdapter, OraclePriceType, OracleSetup, PriceAdapter, PriceBias,
+    PythPushOraclePriceFeed, SwitchboardPullPriceFeed,
+};
+use solana_sdk::{
+    account::Account,
+    clock::{Clock, UnixTimestamp},
+    native_token::LAMPORTS_PER_SOL,
+    pubkey::Pubkey,
+    stake::state::StakeStateV2,
 };
-use solana_sdk::{account::Account, pubkey::Pubkey};
 
-use crate::cache::CacheEntry;
+use crate::{
+    cache::{banks::CachedBank, CacheEntry},
+    error::MaryError,
+};
 use anyhow::{anyhow, Result};
 
 use log::{trace, warn};
 
-use anchor_lang::prelude::AccountInfo;
+use anchor_lang::prelude::{AccountInfo, AnchorDeserialize};
+use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
 
 use solana_sdk::account_info::IntoAccountInfo;
 use switchboard_on_demand::{Discriminator, PullFeedAccountData};
 
+// Prices that have not been updated for this many slots (~60 seconds) are rejected.
+pub const MAX_PRICE_AGE_SLOTS: u64 = 150;
+// Disables the confidence interval rejection, the full u32 range stands for 100%.
+const NO_MAX_CONFIDENCE: u32 = u32::MAX;
+// Every stake pool is initialized with 1 SOL that can never be withdrawn, marginfi excludes it from the pool value.
+const STAKE_POOL_INITIAL_LAMPORTS: u64 = LAMPORTS_PER_SOL;
+// The supply follows the COption<Pubkey> mint authority in the SPL Token mint layout.
+const MINT_SUPPLY_OFFSET: usize = 36;
+
 #[derive(Clone)]
 pub struct CachedPriceAdapter {
     pub slot: u64,
+    // The cached clock time when the price was received, lets the staleness be measured in seconds.
+    pub timestamp: UnixTimestamp,
+    // The slot the price was published at according to the payload, can lag the account write slot.
+    pub publish_slot: Option<u64>,
+    // The confidence interval in basis points of the price, None if the price is not positive.
+    pub confidence_bps: Option<u64>,
     _adapter: OraclePriceFeedAdapter,
 }
 
 impl CachedPriceAdapter {
     pub fn from(
         slot: u64,
+        timestamp: UnixTimestamp,
         oracle_type: &OracleSetup,
         address: &Pubkey,
         account: &mut Account,
     ) -> Result<Self> {
-        let adapter = match oracle_type {
-            OracleSetup::SwitchboardPull => Self::parse_swb_adapter(&account.data)?,
-            OracleSetup::PythPushOracle => Self::parse_pyth_adapter(address, account)?,
+        let (adapter, publish_slot) = match oracle_type {
+            OracleSetup::SwitchboardPull => (Self::parse_swb_adapter(&account.data)?, None),
+            // The staked collateral is priced by the SOL Pyth feed adjusted by its stake pool
+            OracleSetup::PythPushOracle | OracleSetup::StakedWithPythPush => {
+                let publish_slot = Self::parse_pyth_posted_slot(&account.data)?;
+                (
+                    Self::parse_pyth_adapter(address, account)?,
+                    Some(publish_slot),
+                )
+            }
+            OracleSetup::PythLegacy | OracleSetup::SwitchboardV2 => {
+                return Err(anyhow!(
+                    "The {:?} oracles are deprecated by marginfi",
+                    oracle_type
+                ))
+            }
             _ => return Err(anyhow!("Unsupported oracle type {:?}", oracle_type)),
         };
 
         Ok(Self {
             slot,
+            timestamp,
+            publish_slot,
+            confidence_bps: confidence_bps(&adapter),
             _adapter: adapter,
         })
     }
 
+    /// True if the adapter carries an older price than the other one, judged by the publish slots
+    /// in the payloads when both have them.
+    fn is_older_than(&self, other: &CachedPriceAdapter) -> bool {
+        matches!(
+            (self.publish_slot, other.publish_slot),
+            (Some(publish_slot), Some(other_publish_slot)) if publish_slot < other_publish_slot
+        )
+    }
+
     fn parse_swb_adapter(data: &[u8]) -> Result<OraclePriceFeedAdapter> {
         if data.len() < 8 {
-            return Err(anyhow!("Invalid Swb oracle account length"));
+            return Err(
+                MaryError::Deserialize("Invalid Swb oracle account length".to_string()).into(),
+            );
         }
 
         if data[..8] != PullFeedAccountData::DISCRIMINATOR {
-            return Err(anyhow!(
+            return Err(MaryError::Deserialize(format!(
                 "Invalid Swb oracle account discriminator {:?}! Expected {:?}",
                 &data[..8],
                 PullFeedAccountData::DISCRIMINATOR
-            ));
+            ))
+            .into());
         }
 
         let feed = bytemuck::try_pod_read_unaligned::<PullFeedAccountData>(
             &data[8..8 + std::mem::size_of::<PullFeedAccountData>()],
         )
-        .map_err(|err| anyhow!("Failed to parse the Swb oracle account: {:?}", err))?;
+        .map_err(|err| {
+            MaryError::Deserialize(format!("Failed to parse the Swb oracle account: {:?}", err))
+        })?;
 
         Ok(OraclePriceFeedAdapter::SwitchboardPull(
             SwitchboardPullPriceFeed {
@@ -65,12 +126,27 @@ impl CachedPriceAdapter {
         ))
     }
 
+    fn parse_pyth_posted_slot(data: &[u8]) -> Result<u64> {
+        if data.len() < 8 {
+            return Err(
+                MaryError::Deserialize("Invalid Pyth oracle account length".to_string()).into(),
+            );
+        }
+
+        let price_update = PriceUpdateV2::deserialize(&mut &data[8..]).map_err(|err| {
+            MaryError::Deserialize(format!("Failed to parse the Pyth price update: {:?}", err))
+        })?;
+        Ok(price_update.posted_slot)
+    }
+
     fn parse_pyth_adapter(
         &address: &Pubkey,
         account: &mut Account,
     ) -> Result<OraclePriceFeedAdapter> {
         if account.data.len() < 8 {
-            return Err(anyhow!("Invalid Pyth oracle account length"));
+            return Err(
+                MaryError::Deserialize("Invalid Pyth oracle account length".to_string()).into(),
+            );
         }
 
         let ai: AccountInfo = (&address, account).into_account_info();
@@ -84,6 +160,8 @@ pub struct CachedOracle {
     pub _address: Pubkey,
     pub _oracle_type: OracleSetup,
     adapter: Option<CachedPriceAdapter>,
+    // Whether a price update streamed in after the oracle was loaded
+    live: bool,
 }
 
 impl CacheEntry for CachedOracle {}
@@ -98,25 +176,98 @@ impl CachedOracle {
             _address: address,
             _oracle_type: oracle_type,
             adapter,
+            live: false,
         }
     }
+
+    /// The oracle without a valid price adapter is always considered stale.
+    pub fn is_stale(&self, clock: &Clock, max_age_slots: u64) -> bool {
+        self.adapter.is_none() || self.age_slots(clock) > max_age_slots
+    }
+
+    /// The slots since the last price update, the oracle without a price is as old as the chain.
+    pub fn age_slots(&self, clock: &Clock) -> u64 {
+        clock
+            .slot
+            .saturating_sub(self.adapter.as_ref().map_or(0, |adapter| adapter.slot))
+    }
+
+    /// The seconds since the last price update, None for the oracle without a price.
+    pub fn age_secs(&self, clock: &Clock) -> Option<u64> {
+        self.adapter.as_ref().map(|adapter| {
+            clock
+                .unix_timestamp
+                .saturating_sub(adapter.timestamp)
+                .max(0) as u64
+        })
+    }
+
+    /// Returns the spot (RealTime) or the EMA (TimeWeighted) price, optionally biased by the
+    /// confidence interval the same way marginfi values the positions. The confidence is not limited.
+    pub fn price(&self, price_type: OraclePriceType, bias: Option<PriceBias>) -> Result<I80F48> {
+        Ok(self
+            .adapter()?
+            .get_price_of_type(price_type, bias, NO_MAX_CONFIDENCE)?)
+    }
+
+    /// Returns the unbiased price and its confidence interval.
+    pub fn price_with_confidence(&self) -> Result<(I80F48, I80F48)> {
+        let price = self.price(OraclePriceType::RealTime, None)?;
+        let high_price = self.price(OraclePriceType::RealTime, Some(PriceBias::High))?;
+        Ok((price, high_price - price))
+    }
+
+    fn adapter(&self) -> Result<&OraclePriceFeedAdapter> {
+        self.adapter
+            .as_ref()
+            .map(|adapter| &adapter._adapter)
+            .ok_or_else(|| {
+                MaryError::OraclePrice(format!(
+                    "Oracle {} has no valid price adapter",
+                    self._address
+                ))
+                .into()
+            })
+    }
 }
 
+/// The LST mint or the stake pool account required to price the staked collateral.
+#[derive(Clone)]
+pub struct CachedStakedAccount {
+    pub slot: u64,
+    account: Account,
+}
+
+impl CacheEntry for CachedStakedAccount {}
+
 #[derive(Default)]
 pub struct OraclesCache {
     oracles: RwLock<HashMap<Pubkey, CachedOracle>>,
+    staked_accounts: RwLock<HashMap<Pubkey, CachedStakedAccount>>,
+    // The prices with a wider confidence interval are rejected, 0 disables it
+    max_confidence_bps: u64,
+    // The Oracles whose price changed since the last health refresh of the Marginfi accounts
+    repriced: RwLock<HashSet<Pubkey>>,
 }
 
 impl OraclesCache {
+    pub fn new(max_confidence_bps: u64) -> Self {
+        Self {
+            max_confidence_bps,
+            ..Default::default()
+        }
+    }
+
     pub fn insert(
         &self,
         slot: u64,
+        timestamp: UnixTimestamp,
         address: &Pubkey,
         oracle_type: OracleSetup,
         mut account: Account,
     ) -> Result<()> {
         let adapter: Option<CachedPriceAdapter> =
-            match CachedPriceAdapter::from(slot, &oracle_type, address, &mut account) {
+            match CachedPriceAdapter::from(slot, timestamp, &oracle_type, address, &mut account) {
                 Ok(adapter) => Some(adapter),
                 Err(err) => {
                     warn!(
@@ -129,24 +280,73 @@ impl OraclesCache {
 
         self.oracles
             .write()
-            .map_err(|e| anyhow::anyhow!("Failed to lock the Oracles cache for insert: {}", e))?
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Oracles cache for insert: {}",
+                    e
+                ))
+            })?
             .insert(*address, CachedOracle::from(*address, oracle_type, adapter));
 
         Ok(())
     }
 
-    pub fn update(&self, slot: u64, address: &Pubkey, account: &mut Account) -> Result<()> {
-        let mut oracles = self
-            .oracles
+    pub fn insert_staked_account(
+        &self,
+        slot: u64,
+        address: &Pubkey,
+        account: Account,
+    ) -> Result<()> {
+        self.staked_accounts
             .write()
-            .map_err(|e| anyhow::anyhow!("Failed to lock the Oracles cache for update: {}", e))?;
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the staked accounts cache for insert: {}",
+                    e
+                ))
+            })?
+            .insert(*address, CachedStakedAccount { slot, account });
+
+        Ok(())
+    }
+
+    pub fn update(
+        &self,
+        slot: u64,
+        timestamp: UnixTimestamp,
+        address: &Pubkey,
+        account: &mut Account,
+    ) -> Result<()> {
+        let mut oracles = self.oracles.write().map_err(|e| {
+            MaryError::LockPoisoned(format!(
+                "Failed to lock the Oracles cache for update: {}",
+                e
+            ))
+        })?;
 
         if let Some(cached_oracle) = oracles.get_mut(address) {
             if slot > cached_oracle.adapter.as_ref().map_or(0, |a| a.slot) {
-                match CachedPriceAdapter::from(slot, &cached_oracle._oracle_type, address, account)
-                {
+                match CachedPriceAdapter::from(
+                    slot,
+                    timestamp,
+                    &cached_oracle._oracle_type,
+                    address,
+                    account,
+                ) {
+                    Ok(adapter)
+                        if cached_oracle
+                            .adapter
+                            .as_ref()
+                            .is_some_and(|cached| adapter.is_older_than(cached)) =>
+                    {
+                        trace!(
+                            "Rejected the OraclePriceAdapter update for {:?} published at the older slot {:?}",
+                            address, adapter.publish_slot
+                        );
+                    }
                     Ok(adapter) => {
                         cached_oracle.adapter = Some(adapter);
+                        cached_oracle.live = true;
                         trace!("Updated OraclePriceAdapter for {:?}", address);
                     }
                     Err(err) => {
@@ -157,6 +357,24 @@ impl OraclesCache {
                     }
                 }
             }
+            return Ok(());
+        }
+        drop(oracles);
+
+        let mut staked_accounts = self.staked_accounts.write().map_err(|e| {
+            MaryError::LockPoisoned(format!(
+                "Failed to lock the staked accounts cache for update: {}",
+                e
+            ))
+        })?;
+        if let Some(cached_account) = staked_accounts.get_mut(address) {
+            if slot > cached_account.slot {
+                *cached_account = CachedStakedAccount {
+                    slot,
+                    account: account.clone(),
+                };
+                trace!("Updated the staked account {:?}", address);
+            }
         }
 
         Ok(())
@@ -166,31 +384,326 @@ impl OraclesCache {
         Ok(self
             .oracles
             .read()
-            .map_err(|e| anyhow::anyhow!("Failed to lock the Oracles cache for read: {}", e))?
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!("Failed to lock the Oracles cache for read: {}", e))
+            })?
             .get(address)
             .cloned())
     }
 
+    /// The confidence interval of the oracle price in basis points, None without a positive price.
+    pub fn confidence_bps(&self, address: &Pubkey) -> Result<Option<u64>> {
+        let oracles = self.oracles.read().map_err(|e| {
+            MaryError::LockPoisoned(format!("Failed to lock the Oracles cache for read: {}", e))
+        })?;
+        let oracle = oracles
+            .get(address)
+            .ok_or_else(|| MaryError::NotFound(format!("Oracle {} not found in cache", address)))?;
+        Ok(oracle
+            .adapter
+            .as_ref()
+            .and_then(|adapter| adapter.confidence_bps))
+    }
+
+    /// Returns the current price of the oracle, optionally biased by the confidence interval.
+    pub fn get_price(
+        &self,
+        address: &Pubkey,
+        clock: &Clock,
+        bias: Option<PriceBias>,
+        oracle_max_confidence: u32,
+    ) -> Result<I80F48> {
+        let oracles = self.oracles.read().map_err(|e| {
+            MaryError::LockPoisoned(format!("Failed to lock the Oracles cache for read: {}", e))
+        })?;
+
+        let oracle = oracles
+            .get(address)
+            .ok_or_else(|| MaryError::NotFound(format!("Oracle {} not found in cache", address)))?;
+
+        if oracle.is_stale(clock, MAX_PRICE_AGE_SLOTS) {
+            return Err(
+                MaryError::OraclePrice(format!("Oracle {} price is stale", address)).into(),
+            );
+        }
+
+        if let Some(confidence_bps) = oracle
+            .adapter
+            .as_ref()
+            .and_then(|adapter| adapter.confidence_bps)
+            .filter(|&confidence_bps| {
+                self.max_confidence_bps > 0 && confidence_bps > self.max_confidence_bps
+            })
+        {
+            return Err(MaryError::OraclePrice(format!(
+                "Oracle {} confidence of {} bps exceeds the {} bps limit",
+                address, confidence_bps, self.max_confidence_bps
+            ))
+            .into());
+        }
+
+        Ok(oracle.adapter()?.get_price_of_type(
+            OraclePriceType::RealTime,
+            bias,
+            oracle_max_confidence,
+        )?)
+    }
+
+    /// Returns the price of the bank asset, the staked collateral is priced through its stake pool.
+    pub fn get_bank_price(
+        &self,
+        bank: &CachedBank,
+        clock: &Clock,
+        bias: Option<PriceBias>,
+    ) -> Result<I80F48> {
+        let oracle_addresses = bank.oracle_addresses();
+        let feed_address = oracle_addresses
+            .first()
+            .ok_or_else(|| MaryError::NotFound(format!("Bank {} has no oracle", bank.address)))?;
+
+        if bank.oracle_type() != OracleSetup::StakedWithPythPush {
+            return self.get_price(feed_address, clock, bias, bank.oracle_max_confidence());
+        }
+
+        let [_, lst_mint, sol_pool, ..] = oracle_addresses.as_slice() else {
+            return Err(MaryError::OraclePrice(format!(
+                "Bank {} has no LST mint and stake pool oracle keys",
+                bank.address
+            ))
+            .into());
+        };
+        let (lst_supply, pool_stake) = {
+            let staked_accounts = self.staked_accounts.read().map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the staked accounts cache for read: {}",
+                    e
+                ))
+            })?;
+            let lst_mint_account = staked_accounts.get(lst_mint).ok_or_else(|| {
+                MaryError::NotFound(format!("LST mint {} is not in the cache yet", lst_mint))
+            })?;
+            let sol_pool_account = staked_accounts.get(sol_pool).ok_or_else(|| {
+                MaryError::NotFound(format!("Stake pool {} is not in the cache yet", sol_pool))
+            })?;
+            (
+                parse_mint_supply(&lst_mint_account.account.data)?,
+                parse_pool_stake(&sol_pool_account.account.data)?,
+            )
+        };
+
+        let sol_price = self.get_price(feed_address, clock, bias, bank.oracle_max_confidence())?;
+        staked_price(sol_price, pool_stake, lst_supply)
+    }
+
+    pub fn is_stale(&self, address: &Pubkey, clock: &Clock, max_age_slots: u64) -> Result<bool> {
+        Ok(self
+            .oracles
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!("Failed to lock the Oracles cache for read: {}", e))
+            })?
+            .get(address)
+            .ok_or_else(|| MaryError::NotFound(format!("Oracle {} not found in cache", address)))?
+            .is_stale(clock, max_age_slots))
+    }
+
+    /// Returns the age in slots of every cached oracle price, the oldest first.
+    pub fn staleness_report(&self, clock: &Clock) -> Result<Vec<(Pubkey, u64)>> {
+        let mut report: Vec<(Pubkey, u64)> = self
+            .oracles
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!("Failed to lock the Oracles cache for read: {}", e))
+            })?
+            .iter()
+            .map(|(address, oracle)| (*address, oracle.age_slots(clock)))
+            .collect();
+        report.sort_by(|a, b| b.1.cmp(&a.1));
+        Ok(report)
+    }
+
+    /// Returns the age in seconds of the oldest cached oracle price.
+    pub fn max_age_secs(&self, clock: &Clock) -> Result<u64> {
+        Ok(self
+            .oracles
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!("Failed to lock the Oracles cache for read: {}", e))
+            })?
+            .values()
+            .filter_map(|oracle| oracle.age_secs(clock))
+            .max()
+            .unwrap_or(0))
+    }
+
+    pub fn contains(&self, address: &Pubkey) -> Result<bool> {
+        if self
+            .oracles
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!("Failed to lock the Oracles cache for read: {}", e))
+            })?
+            .contains_key(address)
+        {
+            return Ok(true);
+        }
+        Ok(self
+            .staked_accounts
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the staked accounts cache for read: {}",
+                    e
+                ))
+            })?
+            .contains_key(address))
+    }
+
+    pub fn count(&self) -> Result<usize> {
+        Ok(self
+            .oracles
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the Oracles cache for count: {}",
+                    e
+                ))
+            })?
+            .len())
+    }
+
+    /// Returns the number of the oracles that received a live update since they were loaded, and
+    /// the number of all the oracles.
+    pub fn live_count(&self) -> Result<(usize, usize)> {
+        let oracles = self.oracles.read().map_err(|e| {
+            MaryError::LockPoisoned(format!("Failed to lock the Oracles cache for count: {}", e))
+        })?;
+        Ok((
+            oracles.values().filter(|oracle| oracle.live).count(),
+            oracles.len(),
+        ))
+    }
+
+    /// Records the Oracle price change, for the next health refresh of the Marginfi accounts.
+    pub fn mark_repriced(&self, address: &Pubkey) -> Result<()> {
+        self.repriced
+            .write()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the repriced Oracles for update: {}",
+                    e
+                ))
+            })?
+            .insert(*address);
+        Ok(())
+    }
+
+    /// Takes the Oracles repriced since the last call.
+    pub fn take_repriced(&self) -> Result<HashSet<Pubkey>> {
+        Ok(std::mem::take(&mut *self.repriced.write().map_err(
+            |e| {
+                MaryError::LockPoisoned(format!(
+                    "Failed to lock the repriced Oracles for take: {}",
+                    e
+                ))
+            },
+        )?))
+    }
+
     pub fn get_oracle_addresses(&self) -> Vec<Pubkey> {
-        self.oracles
+        let mut addresses: Vec<Pubkey> = self
+            .oracles
             .read()
-            .map_err(|e| anyhow::anyhow!("Failed to lock the Oracles cache for read: {}", e))
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!("Failed to lock the Oracles cache for read: {}", e))
+            })
             .unwrap()
             .keys()
             .cloned()
-            .collect()
+            .collect();
+        addresses.extend(
+            self.staked_accounts
+                .read()
+                .map_err(|e| {
+                    MaryError::LockPoisoned(format!(
+                        "Failed to lock the staked accounts cache for read: {}",
+                        e
+                    ))
+                })
+                .unwrap()
+                .keys(),
+        );
+        addresses
+    }
+}
+
+fn parse_mint_supply(data: &[u8]) -> Result<u64> {
+    data.get(MINT_SUPPLY_OFFSET..MINT_SUPPLY_OFFSET + 8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| MaryError::Deserialize("Invalid LST mint account length".to_string()).into())
+}
+
+fn parse_pool_stake(data: &[u8]) -> Result<u64> {
+    match bincode::deserialize::<StakeStateV2>(data).map_err(|e| {
+        MaryError::Deserialize(format!("Failed to parse the stake pool account: {}", e))
+    })? {
+        StakeStateV2::Stake(_, stake, _) => Ok(stake.delegation.stake),
+        _ => Err(
+            MaryError::OraclePrice("The stake pool account is not delegated".to_string()).into(),
+        ),
     }
 }
 
+/// Scales the SOL price by the amount of SOL backing a single LST token.
+fn staked_price(sol_price: I80F48, pool_stake: u64, lst_supply: u64) -> Result<I80F48> {
+    let pool_value = pool_stake
+        .checked_sub(STAKE_POOL_INITIAL_LAMPORTS)
+        .ok_or_else(|| {
+            MaryError::OraclePrice("The stake pool has less than the initial stake".to_string())
+        })?;
+    I80F48::from_num(pool_value)
+        .checked_div(I80F48::from_num(lst_supply))
+        .and_then(|sol_per_lst| sol_price.checked_mul(sol_per_lst))
+        .ok_or_else(|| {
+            MaryError::OraclePrice(format!(
+                "Invalid LST supply {} for the staked collateral price",
+                lst_supply
+            ))
+            .into()
+        })
+}
+
+fn confidence_bps(adapter: &OraclePriceFeedAdapter) -> Option<u64> {
+    let price = adapter
+        .get_price_of_type(OraclePriceType::RealTime, None, NO_MAX_CONFIDENCE)
+        .ok()?;
+    let high_price = adapter
+        .get_price_of_type(
+            OraclePriceType::RealTime,
+            Some(PriceBias::High),
+            NO_MAX_CONFIDENCE,
+        )
+        .ok()?;
+    if price <= I80F48::ZERO {
+        return None;
+    }
+    (high_price - price)
+        .checked_mul(I80F48::from_num(10_000))
+        .and_then(|confidence| confidence.checked_div(price))
+        .map(|confidence_bps| confidence_bps.saturating_to_num::<u64>())
+}
+
 #[cfg(test)]
-mod tests {
-    use super::*;
+pub mod test_util {
     use anchor_lang::prelude::AnchorSerialize;
-    use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
-    use pyth_solana_receiver_sdk::price_update::{PriceFeedMessage, VerificationLevel};
-    use switchboard_on_demand::PullFeedAccountData;
+    use marginfi::state::price::OracleSetup;
+    use pyth_solana_receiver_sdk::price_update::{
+        PriceFeedMessage, PriceUpdateV2, VerificationLevel,
+    };
+    use solana_sdk::{account::Account, pubkey::Pubkey};
+    use switchboard_on_demand::{Discriminator, PullFeedAccountData};
 
-    fn dummy_account(oracle_type: OracleSetup) -> Account {
+    pub fn create_dummy_oracle_account(oracle_type: OracleSetup) -> Account {
         let mut data = Vec::new();
         if oracle_type == OracleSetup::SwitchboardPull {
             data.extend_from_slice(&PullFeedAccountData::DISCRIMINATOR);
@@ -209,14 +722,59 @@ mod tests {
         }
     }
 
+    /// The Pyth push oracle account with the price and the EMA price in thousandths.
+    pub fn create_pyth_account(
+        price: i64,
+        conf: u64,
+        ema_price: i64,
+        ema_conf: u64,
+        posted_slot: u64,
+    ) -> Account {
+        let price_update = PriceUpdateV2 {
+            write_authority: Pubkey::new_unique(),
+            verification_level: VerificationLevel::Full,
+            price_message: PriceFeedMessage {
+                feed_id: [0; 32],
+                price,
+                conf,
+                exponent: -3,
+                publish_time: 900,
+                prev_publish_time: 899,
+                ema_price,
+                ema_conf,
+            },
+            posted_slot,
+        };
+        let mut account = create_dummy_oracle_account(OracleSetup::PythPushOracle);
+        account.owner = pyth_solana_receiver_sdk::id();
+        account.data = <PriceUpdateV2 as anchor_lang::Discriminator>::DISCRIMINATOR.to_vec();
+        price_update.serialize(&mut account.data).unwrap();
+        account
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_util::{create_dummy_oracle_account, create_pyth_account};
+    use super::*;
+    use crate::cache::banks::test_util::create_bank_with_oracles;
+    use anchor_lang::prelude::AnchorSerialize;
+    use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
+    use pyth_solana_receiver_sdk::price_update::{PriceFeedMessage, VerificationLevel};
+    use solana_sdk::stake::{
+        stake_flags::StakeFlags,
+        state::{Delegation, Meta, Stake},
+    };
+    use switchboard_on_demand::PullFeedAccountData;
+
     #[test]
     fn test_insert_and_get_oracle_addresses() {
         let cache = OraclesCache::default();
         let address = Pubkey::new_unique();
         let oracle_type = OracleSetup::PythPushOracle;
-        let account = dummy_account(oracle_type);
+        let account = create_dummy_oracle_account(oracle_type);
 
-        cache.insert(1, &address, oracle_type, account).unwrap();
+        cache.insert(1, 0, &address, oracle_type, account).unwrap();
         let addresses = cache.get_oracle_addresses();
         assert_eq!(addresses.len(), 1);
         assert_eq!(addresses[0], address);
@@ -227,32 +785,49 @@ mod tests {
         let cache = OraclesCache::default();
         let address = Pubkey::new_unique();
         let oracle_type = OracleSetup::PythPushOracle;
-        let mut account = dummy_account(oracle_type);
+        let mut account = create_dummy_oracle_account(oracle_type);
         account.owner = pyth_solana_receiver_sdk::id();
 
         cache
-            .insert(1, &address, oracle_type, account.clone())
+            .insert(1, 0, &address, oracle_type, account.clone())
             .unwrap();
         // Update with a higher slot
-        cache.update(2, &address, &mut account).unwrap();
+        cache.update(2, 0, &address, &mut account).unwrap();
 
         let oracles = cache.oracles.read().unwrap();
         let cached = oracles.get(&address).unwrap();
         assert_eq!(cached.adapter.as_ref().unwrap().slot, 2);
     }
 
+    #[test]
+    fn test_live_count() {
+        let cache = OraclesCache::default();
+        let live = Pubkey::new_unique();
+        let mut account = create_pyth_account(100_000, 0, 100_000, 0, 1);
+        for address in [live, Pubkey::new_unique()] {
+            cache
+                .insert(1, 0, &address, OracleSetup::PythPushOracle, account.clone())
+                .unwrap();
+        }
+        assert_eq!(cache.live_count().unwrap(), (0, 2));
+
+        account = create_pyth_account(100_000, 0, 100_000, 0, 2);
+        cache.update(2, 0, &live, &mut account).unwrap();
+        assert_eq!(cache.live_count().unwrap(), (1, 2));
+    }
+
     #[test]
     fn test_update_oracle_price_slot_lower_no_update() {
         let cache = OraclesCache::default();
         let address = Pubkey::new_unique();
         let oracle_type = OracleSetup::SwitchboardPull;
-        let mut account = dummy_account(oracle_type);
+        let mut account = create_dummy_oracle_account(oracle_type);
 
         cache
-            .insert(5, &address, oracle_type, account.clone())
+            .insert(5, 0, &address, oracle_type, account.clone())
             .unwrap();
         // Try to update with a lower slot, should not update
-        cache.update(3, &address, &mut account).unwrap();
+        cache.update(3, 0, &address, &mut account).unwrap();
 
         let oracles = cache.oracles.read().unwrap();
         let cached = oracles.get(&address).unwrap();
@@ -264,11 +839,11 @@ mod tests {
         let cache = OraclesCache::default();
         let addresses: Vec<_> = (0..5).map(|_| Pubkey::new_unique()).collect();
         let oracle_type = OracleSetup::SwitchboardPull;
-        let account = dummy_account(oracle_type);
+        let account = create_dummy_oracle_account(oracle_type);
 
         for (i, address) in addresses.iter().enumerate() {
             cache
-                .insert(i as u64, address, oracle_type.clone(), account.clone())
+                .insert(i as u64, 0, address, oracle_type.clone(), account.clone())
                 .unwrap();
         }
 
@@ -283,14 +858,170 @@ mod tests {
     fn test_update_nonexistent_oracle_does_nothing() {
         let cache = OraclesCache::default();
         let address = Pubkey::new_unique();
-        let mut account = dummy_account(OracleSetup::None);
+        let mut account = create_dummy_oracle_account(OracleSetup::None);
 
         // Should not panic or insert anything
-        cache.update(10, &address, &mut account).unwrap();
+        cache.update(10, 0, &address, &mut account).unwrap();
         let addresses = cache.get_oracle_addresses();
         assert!(addresses.is_empty());
     }
 
+    #[test]
+    fn test_get_price_missing_oracle() {
+        let cache = OraclesCache::default();
+        let address = Pubkey::new_unique();
+        let err = cache
+            .get_price(&address, &Clock::default(), None, 0)
+            .unwrap_err();
+        assert!(err.to_string().contains("not found in cache"));
+        assert!(matches!(MaryError::of(&err), Some(MaryError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_get_price_without_adapter() {
+        let cache = OraclesCache::default();
+        let address = Pubkey::new_unique();
+        let mut account = create_dummy_oracle_account(OracleSetup::SwitchboardPull);
+        account.data = vec![0u8; 4]; // Too short to parse an adapter
+
+        cache
+            .insert(1, 0, &address, OracleSetup::SwitchboardPull, account)
+            .unwrap();
+        let result = cache.get_price(&address, &Clock::default(), None, 0);
+        assert!(result.is_err());
+        assert!(result.err().unwrap().to_string().contains("is stale"));
+
+        let oracle = cache._get(&address).unwrap().unwrap();
+        let err = oracle.price_with_confidence().unwrap_err();
+        assert!(err.to_string().contains("has no valid price adapter"));
+        assert!(matches!(
+            MaryError::of(&err),
+            Some(MaryError::OraclePrice(_))
+        ));
+    }
+
+    #[test]
+    fn test_get_price_stale() {
+        let cache = OraclesCache::default();
+        let address = Pubkey::new_unique();
+        let oracle_type = OracleSetup::SwitchboardPull;
+        cache
+            .insert(
+                10,
+                0,
+                &address,
+                oracle_type,
+                create_dummy_oracle_account(oracle_type),
+            )
+            .unwrap();
+
+        let clock = Clock {
+            slot: 10 + MAX_PRICE_AGE_SLOTS + 1,
+            ..Default::default()
+        };
+        let err = cache.get_price(&address, &clock, None, 0).unwrap_err();
+        assert!(err.to_string().contains("is stale"));
+        assert!(matches!(
+            MaryError::of(&err),
+            Some(MaryError::OraclePrice(_))
+        ));
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let cache = OraclesCache::default();
+        let address = Pubkey::new_unique();
+        let oracle_type = OracleSetup::SwitchboardPull;
+        cache
+            .insert(
+                10,
+                0,
+                &address,
+                oracle_type,
+                create_dummy_oracle_account(oracle_type),
+            )
+            .unwrap();
+
+        let clock = Clock {
+            slot: 20,
+            ..Default::default()
+        };
+        assert!(!cache.is_stale(&address, &clock, 10).unwrap());
+        assert!(cache.is_stale(&address, &clock, 9).unwrap());
+        assert!(cache.is_stale(&Pubkey::new_unique(), &clock, 10).is_err());
+    }
+
+    #[test]
+    fn test_is_stale_without_adapter() {
+        let oracle = CachedOracle::from(Pubkey::new_unique(), OracleSetup::SwitchboardPull, None);
+        assert!(oracle.is_stale(&Clock::default(), u64::MAX));
+    }
+
+    #[test]
+    fn test_staleness_report() {
+        let cache = OraclesCache::default();
+        let oracle_type = OracleSetup::SwitchboardPull;
+        let fresh = Pubkey::new_unique();
+        let old = Pubkey::new_unique();
+        let unpriced = Pubkey::new_unique();
+        cache
+            .insert(
+                95,
+                1_000,
+                &fresh,
+                oracle_type,
+                create_dummy_oracle_account(oracle_type),
+            )
+            .unwrap();
+        cache
+            .insert(
+                40,
+                980,
+                &old,
+                oracle_type,
+                create_dummy_oracle_account(oracle_type),
+            )
+            .unwrap();
+        let mut account = create_dummy_oracle_account(oracle_type);
+        account.data = vec![0u8; 4]; // Too short to parse an adapter
+        cache
+            .insert(90, 990, &unpriced, oracle_type, account)
+            .unwrap();
+
+        let clock = Clock {
+            slot: 100,
+            unix_timestamp: 1_010,
+            ..Default::default()
+        };
+        assert_eq!(
+            cache.staleness_report(&clock).unwrap(),
+            vec![(unpriced, 100), (old, 60), (fresh, 5)]
+        );
+        assert_eq!(cache.max_age_secs(&clock).unwrap(), 30);
+    }
+
+    #[test]
+    fn test_update_oracle_timestamp() {
+        let cache = OraclesCache::default();
+        let address = Pubkey::new_unique();
+        let oracle_type = OracleSetup::SwitchboardPull;
+        let mut account = create_dummy_oracle_account(oracle_type);
+        cache
+            .insert(1, 100, &address, oracle_type, account.clone())
+            .unwrap();
+        cache.update(2, 160, &address, &mut account).unwrap();
+
+        let oracle = cache._get(&address).unwrap().unwrap();
+        assert_eq!(oracle.adapter.as_ref().unwrap().timestamp, 160);
+        let clock = Clock {
+            slot: 2,
+            unix_timestamp: 170,
+            ..Default::default()
+        };
+        assert_eq!(oracle.age_secs(&clock), Some(10));
+        assert_eq!(oracle.age_slots(&clock), 0);
+    }
+
     #[test]
     fn test_parse_swb_adapter() {
         // Construct valid data: discriminator + PullFeedAccountData bytes
@@ -327,16 +1058,20 @@ mod tests {
 
     #[test]
     fn test_parse_pyth_adapter_invalid_length() {
-        let mut account = dummy_account(OracleSetup::PythPushOracle);
+        let mut account = create_dummy_oracle_account(OracleSetup::PythPushOracle);
         account.owner = pyth_solana_receiver_sdk::id();
         account.data = vec![0u8; 4]; // Too short
-        let result = CachedPriceAdapter::parse_pyth_adapter(&Pubkey::new_unique(), &mut account);
-        assert!(result.is_err());
+        let err = CachedPriceAdapter::parse_pyth_adapter(&Pubkey::new_unique(), &mut account)
+            .unwrap_err();
+        assert!(matches!(
+            MaryError::of(&err),
+            Some(MaryError::Deserialize(_))
+        ));
     }
 
     #[test]
     fn test_parse_pyth_adapter_invalid_discriminator() {
-        let mut account = dummy_account(OracleSetup::PythPushOracle);
+        let mut account = create_dummy_oracle_account(OracleSetup::PythPushOracle);
         account.owner = pyth_solana_receiver_sdk::id();
         account.data = vec![1u8; 8]; // Use wrong discriminator
         account.data.extend_from_slice(&vec![0u8; 64]); // Add some bytes for the rest of the account data
@@ -347,7 +1082,7 @@ mod tests {
     #[test]
     fn test_parse_pyth_adapter_valid() {
         // Use correct discriminator but invalid payload (too short for deserialize)
-        let mut account = dummy_account(OracleSetup::PythPushOracle);
+        let mut account = create_dummy_oracle_account(OracleSetup::PythPushOracle);
         account.owner = pyth_solana_receiver_sdk::id();
         let discrim = <PriceUpdateV2 as anchor_lang::Discriminator>::DISCRIMINATOR;
         account.data.extend_from_slice(discrim);
@@ -374,4 +1109,254 @@ mod tests {
         let adapter = CachedPriceAdapter::parse_pyth_adapter(&Pubkey::new_unique(), &mut account);
         assert!(adapter.is_ok());
     }
+
+    fn create_pyth_oracle(price: i64, conf: u64, ema_price: i64, ema_conf: u64) -> CachedOracle {
+        let mut account = create_pyth_account(price, conf, ema_price, ema_conf, 0);
+        let address = Pubkey::new_unique();
+        let adapter =
+            CachedPriceAdapter::from(1, 0, &OracleSetup::PythPushOracle, &address, &mut account)
+                .unwrap();
+        CachedOracle::from(address, OracleSetup::PythPushOracle, Some(adapter))
+    }
+
+    #[test]
+    fn test_price_spot_and_ema() {
+        // 100.000 +- 0.500 spot, 90.000 +- 1.000 EMA
+        let oracle = create_pyth_oracle(100_000, 500, 90_000, 1_000);
+
+        let spot = oracle.price(OraclePriceType::RealTime, None).unwrap();
+        let ema = oracle.price(OraclePriceType::TimeWeighted, None).unwrap();
+        assert_eq!(spot, I80F48::from_num(100));
+        assert_eq!(ema, I80F48::from_num(90));
+
+        for (price_type, price) in [
+            (OraclePriceType::RealTime, spot),
+            (OraclePriceType::TimeWeighted, ema),
+        ] {
+            let low = oracle.price(price_type, Some(PriceBias::Low)).unwrap();
+            let high = oracle.price(price_type, Some(PriceBias::High)).unwrap();
+            assert!(low < price && price < high);
+            // The bias is symmetric around the price
+            assert_eq!(price - low, high - price);
+        }
+
+        // The EMA is biased by its own, wider confidence
+        let spot_spread = oracle
+            .price(OraclePriceType::RealTime, Some(PriceBias::High))
+            .unwrap()
+            - spot;
+        let ema_spread = oracle
+            .price(OraclePriceType::TimeWeighted, Some(PriceBias::High))
+            .unwrap()
+            - ema;
+        assert!(ema_spread > spot_spread);
+
+        let (price, confidence) = oracle.price_with_confidence().unwrap();
+        assert_eq!(price, spot);
+        assert_eq!(confidence, spot_spread);
+    }
+
+    #[test]
+    fn test_price_without_adapter() {
+        let oracle = CachedOracle::from(Pubkey::new_unique(), OracleSetup::PythPushOracle, None);
+        assert!(oracle.price(OraclePriceType::RealTime, None).is_err());
+    }
+
+    #[test]
+    fn test_update_rejects_older_publish_slot() {
+        let cache = OraclesCache::default();
+        let address = Pubkey::new_unique();
+        let spot_price = |cache: &OraclesCache| {
+            cache
+                ._get(&address)
+                .unwrap()
+                .unwrap()
+                .price(OraclePriceType::RealTime, None)
+                .unwrap()
+        };
+
+        cache
+            .insert(
+                10,
+                0,
+                &address,
+                OracleSetup::PythPushOracle,
+                create_pyth_account(100_000, 0, 100_000, 0, 10),
+            )
+            .unwrap();
+        assert_eq!(spot_price(&cache), I80F48::from_num(100));
+
+        // The account slot increases but the price was published before the cached one
+        let mut late_account = create_pyth_account(50_000, 0, 50_000, 0, 9);
+        cache.update(11, 0, &address, &mut late_account).unwrap();
+        assert_eq!(spot_price(&cache), I80F48::from_num(100));
+        assert_eq!(
+            cache
+                ._get(&address)
+                .unwrap()
+                .unwrap()
+                .adapter
+                .unwrap()
+                .publish_slot,
+            Some(10)
+        );
+
+        let mut account = create_pyth_account(70_000, 0, 70_000, 0, 12);
+        cache.update(12, 0, &address, &mut account).unwrap();
+        assert_eq!(spot_price(&cache), I80F48::from_num(70));
+    }
+
+    #[test]
+    fn test_get_price_rejects_wide_confidence() {
+        let cache = OraclesCache::new(100);
+        let address = Pubkey::new_unique();
+        let clock = Clock {
+            slot: 2,
+            ..Default::default()
+        };
+
+        // 100.000 +- 0.050
+        cache
+            .insert(
+                1,
+                0,
+                &address,
+                OracleSetup::PythPushOracle,
+                create_pyth_account(100_000, 50, 100_000, 50, 1),
+            )
+            .unwrap();
+        assert_eq!(
+            cache
+                .get_price(&address, &clock, None, NO_MAX_CONFIDENCE)
+                .unwrap(),
+            I80F48::from_num(100)
+        );
+        assert_eq!(cache.confidence_bps(&address).unwrap(), Some(5));
+
+        // 100.000 +- 10.000
+        let mut volatile_account = create_pyth_account(100_000, 10_000, 100_000, 10_000, 2);
+        cache.update(2, 0, &address, &mut volatile_account).unwrap();
+        assert_eq!(cache.confidence_bps(&address).unwrap(), Some(1_000));
+        let err = cache
+            .get_price(&address, &clock, None, NO_MAX_CONFIDENCE)
+            .unwrap_err();
+        assert!(err.to_string().contains("confidence"));
+        assert!(matches!(
+            MaryError::of(&err),
+            Some(MaryError::OraclePrice(_))
+        ));
+
+        // Usable again once a tighter update arrives
+        let mut tight_account = create_pyth_account(101_000, 50, 101_000, 50, 2);
+        cache.update(3, 0, &address, &mut tight_account).unwrap();
+        let clock = Clock {
+            slot: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            cache
+                .get_price(&address, &clock, None, NO_MAX_CONFIDENCE)
+                .unwrap(),
+            I80F48::from_num(101)
+        );
+
+        // Not rejected when disabled
+        let cache = OraclesCache::default();
+        cache
+            .insert(
+                1,
+                0,
+                &address,
+                OracleSetup::PythPushOracle,
+                create_pyth_account(100_000, 10_000, 100_000, 10_000, 1),
+            )
+            .unwrap();
+        assert!(cache
+            .get_price(&address, &clock, None, NO_MAX_CONFIDENCE)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_deprecated_oracle_types() {
+        let mut account = create_dummy_oracle_account(OracleSetup::PythPushOracle);
+        for oracle_type in [OracleSetup::PythLegacy, OracleSetup::SwitchboardV2] {
+            let result =
+                CachedPriceAdapter::from(1, 0, &oracle_type, &Pubkey::new_unique(), &mut account);
+            assert!(result.err().unwrap().to_string().contains("deprecated"));
+        }
+    }
+
+    #[test]
+    fn test_staked_price() {
+        let sol_price = I80F48::from_num(100);
+        let price =
+            staked_price(sol_price, 111 * LAMPORTS_PER_SOL, 100 * LAMPORTS_PER_SOL).unwrap();
+        assert_eq!(price, I80F48::from_num(110));
+
+        assert!(staked_price(sol_price, LAMPORTS_PER_SOL - 1, 1).is_err());
+        assert!(staked_price(sol_price, 2 * LAMPORTS_PER_SOL, 0).is_err());
+    }
+
+    #[test]
+    fn test_parse_mint_supply() {
+        let mut data = vec![0u8; 82];
+        data[MINT_SUPPLY_OFFSET..MINT_SUPPLY_OFFSET + 8].copy_from_slice(&42u64.to_le_bytes());
+        assert_eq!(parse_mint_supply(&data).unwrap(), 42);
+        assert!(parse_mint_supply(&data[..MINT_SUPPLY_OFFSET]).is_err());
+    }
+
+    #[test]
+    fn test_parse_pool_stake() {
+        let stake = Stake {
+            delegation: Delegation {
+                stake: 42,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let data = bincode::serialize(&StakeStateV2::Stake(
+            Meta::default(),
+            stake,
+            StakeFlags::empty(),
+        ))
+        .unwrap();
+        assert_eq!(parse_pool_stake(&data).unwrap(), 42);
+
+        let data = bincode::serialize(&StakeStateV2::Uninitialized).unwrap();
+        assert!(parse_pool_stake(&data).is_err());
+    }
+
+    #[test]
+    fn test_get_bank_price_missing_staked_accounts() {
+        let cache = OraclesCache::default();
+        let lst_mint = Pubkey::new_unique();
+        let sol_pool = Pubkey::new_unique();
+        let mut bank = create_bank_with_oracles(vec![Pubkey::new_unique(), lst_mint, sol_pool]);
+        bank.config.oracle_setup = OracleSetup::StakedWithPythPush;
+        let bank = CachedBank::from(1, Pubkey::new_unique(), bank);
+
+        let result = cache.get_bank_price(&bank, &Clock::default(), None);
+        assert!(result
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("is not in the cache yet"));
+    }
+
+    #[test]
+    fn test_update_staked_account() {
+        let cache = OraclesCache::default();
+        let address = Pubkey::new_unique();
+        let mut account = create_dummy_oracle_account(OracleSetup::None);
+        cache
+            .insert_staked_account(5, &address, account.clone())
+            .unwrap();
+        assert_eq!(cache.get_oracle_addresses(), vec![address]);
+
+        cache.update(3, 0, &address, &mut account).unwrap();
+        assert_eq!(cache.staked_accounts.read().unwrap()[&address].slot, 5);
+
+        cache.update(6, 0, &address, &mut account).unwrap();
+        assert_eq!(cache.staked_accounts.read().unwrap()[&address].slot, 6);
+    }
 }
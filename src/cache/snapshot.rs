@@ -0,0 +1,345 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use marginfi::state::marginfi_group::Bank;
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    account::Account, address_lookup_table::AddressLookupTableAccount, pubkey::Pubkey,
+};
+
+use crate::cache::Cache;
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"MARY";
+// Bump on any change of the CacheSnapshot layout, the snapshots of the other versions are ignored.
+const SNAPSHOT_VERSION: u32 = 2;
+const SNAPSHOT_HEADER_LEN: usize = SNAPSHOT_MAGIC.len() + size_of::<u32>();
+// The older snapshot, about a day, is ignored for the full load, which also picks up the Banks added
+// since.
+const SNAPSHOT_MAX_AGE_SLOTS: u64 = 216_000;
+
+/// The slowly changing part of the cache. The Oracle addresses come with the Banks, while the
+/// prices and the Marginfi accounts are always fetched fresh.
+#[derive(Serialize, Deserialize)]
+struct CacheSnapshot {
+    marginfi_program_id: Pubkey,
+    // The cache clock slot at the save
+    slot: u64,
+    banks: Vec<SnapshotBank>,
+    mints: Vec<SnapshotMint>,
+    luts: Vec<SnapshotLut>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotBank {
+    address: Pubkey,
+    slot: u64,
+    // The zero-copy Bank bytes, without the discriminator
+    data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotMint {
+    address: Pubkey,
+    owner: Pubkey,
+    data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotLut {
+    key: Pubkey,
+    addresses: Vec<Pubkey>,
+}
+
+impl Cache {
+    /// Writes the Banks, Mints and LUTs to the snapshot file, replacing the previous one atomically.
+    pub fn snapshot_to(&self, path: &Path, marginfi_program_id: &Pubkey) -> Result<()> {
+        let banks = self
+            .banks
+            .get_banks_map()?
+            .into_values()
+            .map(|bank| SnapshotBank {
+                address: bank.address,
+                slot: bank.slot,
+                data: bytemuck::bytes_of(bank.bank()).to_vec(),
+            })
+            .collect();
+        let mints = self
+            .mints
+            .get_all()?
+            .into_iter()
+            .map(|mint| SnapshotMint {
+                address: mint._address,
+                owner: mint.owner,
+                data: mint.data,
+            })
+            .collect();
+        let luts = self
+            .luts
+            .get_all()
+            .iter()
+            .map(|lut| SnapshotLut {
+                key: lut.key,
+                addresses: lut.addresses.clone(),
+            })
+            .collect();
+        let snapshot = CacheSnapshot {
+            marginfi_program_id: *marginfi_program_id,
+            slot: self.get_clock()?.slot,
+            banks,
+            mints,
+            luts,
+        };
+
+        let mut bytes = SNAPSHOT_MAGIC.to_vec();
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        bytes.extend(
+            bincode::serialize(&snapshot)
+                .map_err(|e| anyhow!("Failed to serialize the cache snapshot: {}", e))?,
+        );
+
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, bytes).map_err(|e| {
+            anyhow!(
+                "Failed to write the cache snapshot {}: {}",
+                tmp_path.display(),
+                e
+            )
+        })?;
+        fs::rename(&tmp_path, path).map_err(|e| {
+            anyhow!(
+                "Failed to replace the cache snapshot {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+
+        info!(
+            "Saved the cache snapshot with {} Banks, {} Mints and {} LUTs to {}.",
+            snapshot.banks.len(),
+            snapshot.mints.len(),
+            snapshot.luts.len(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Populates the Banks, Mints and LUTs from the snapshot file. Returns false if there is no
+    /// snapshot or it is too old or was made by another version or for another Marginfi program.
+    pub fn restore_from(&self, path: &Path, marginfi_program_id: &Pubkey) -> Result<bool> {
+        if !path.exists() {
+            info!("No cache snapshot found at {}.", path.display());
+            return Ok(false);
+        }
+
+        let bytes = fs::read(path).map_err(|e| {
+            anyhow!(
+                "Failed to read the cache snapshot {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+        if bytes.len() < SNAPSHOT_HEADER_LEN || !bytes.starts_with(SNAPSHOT_MAGIC) {
+            return Err(anyhow!("{} is not a cache snapshot", path.display()));
+        }
+        let version = u32::from_le_bytes(
+            bytes[SNAPSHOT_MAGIC.len()..SNAPSHOT_HEADER_LEN]
+                .try_into()
+                .unwrap(),
+        );
+        if version != SNAPSHOT_VERSION {
+            warn!(
+                "Ignoring the cache snapshot {} of the version {}, expected {}.",
+                path.display(),
+                version,
+                SNAPSHOT_VERSION
+            );
+            return Ok(false);
+        }
+
+        let snapshot: CacheSnapshot =
+            bincode::deserialize(&bytes[SNAPSHOT_HEADER_LEN..]).map_err(|e| {
+                anyhow!(
+                    "Failed to deserialize the cache snapshot {}: {}",
+                    path.display(),
+                    e
+                )
+            })?;
+        if snapshot.marginfi_program_id != *marginfi_program_id {
+            warn!(
+                "Ignoring the cache snapshot {} of the Marginfi program {}, expected {}.",
+                path.display(),
+                snapshot.marginfi_program_id,
+                marginfi_program_id
+            );
+            return Ok(false);
+        }
+        let age_slots = self.get_clock()?.slot.saturating_sub(snapshot.slot);
+        if age_slots > SNAPSHOT_MAX_AGE_SLOTS {
+            info!(
+                "Ignoring the cache snapshot {} made {} slots ago.",
+                path.display(),
+                age_slots
+            );
+            return Ok(false);
+        }
+
+        for bank in &snapshot.banks {
+            let data: Bank = bytemuck::try_pod_read_unaligned(&bank.data).map_err(|e| {
+                anyhow!("Invalid snapshot data of the Bank {}: {}", bank.address, e)
+            })?;
+            self.banks.update(bank.slot, bank.address, &data)?;
+        }
+        let mints_total = snapshot.mints.len();
+        for mint in snapshot.mints {
+            let account = Account {
+                lamports: 0,
+                data: mint.data,
+                owner: mint.owner,
+                executable: false,
+                rent_epoch: 0,
+            };
+            self.mints.update(mint.address, &account)?;
+        }
+        let luts_total = snapshot.luts.len();
+        self.luts.populate(
+            snapshot
+                .luts
+                .into_iter()
+                .map(|lut| AddressLookupTableAccount {
+                    key: lut.key,
+                    addresses: lut.addresses,
+                })
+                .collect(),
+        );
+
+        info!(
+            "Restored {} Banks, {} Mints and {} LUTs from the cache snapshot {}.",
+            snapshot.banks.len(),
+            mints_total,
+            luts_total,
+            path.display()
+        );
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+    use crate::cache::{
+        banks::test_util::create_bank_with_oracles,
+        mints::test_util::create_token_2022_mint,
+        test_util::{create_dummy_cache, generate_test_clock},
+    };
+
+    fn snapshot_path(name: &str) -> std::path::PathBuf {
+        env::temp_dir().join(format!("mary_{}_{}.snapshot", name, Pubkey::new_unique()))
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let cache = create_dummy_cache();
+        let program_id = Pubkey::new_unique();
+        let bank_address = Pubkey::new_unique();
+        let oracle_address = Pubkey::new_unique();
+        let bank = create_bank_with_oracles(vec![oracle_address]);
+        cache.banks.update(42, bank_address, &bank).unwrap();
+        cache
+            .mints
+            .update(bank.mint, &create_token_2022_mint(9, None, Some(25)))
+            .unwrap();
+        let lut = AddressLookupTableAccount {
+            key: Pubkey::new_unique(),
+            addresses: vec![Pubkey::new_unique(), Pubkey::new_unique()],
+        };
+        cache.luts.populate(vec![lut.clone()]);
+
+        let path = snapshot_path("round_trip");
+        cache.snapshot_to(&path, &program_id).unwrap();
+
+        let restored = create_dummy_cache();
+        assert!(restored.restore_from(&path, &program_id).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let restored_bank = restored.banks.get_bank(&bank_address).unwrap();
+        assert_eq!(restored_bank.slot, 42);
+        assert_eq!(*restored_bank.mint(), bank.mint);
+        assert_eq!(restored_bank.oracle_addresses(), &vec![oracle_address]);
+        assert_eq!(restored_bank.oracle_type(), bank.config.oracle_setup);
+
+        let restored_mint = restored.mints.get(&bank.mint).unwrap().unwrap();
+        assert_eq!(restored_mint.owner, spl_token_2022::id());
+        assert_eq!(restored_mint.transfer_fee_bps(0).unwrap(), 25);
+
+        let restored_luts = restored.luts.get_all();
+        assert_eq!(restored_luts.len(), 1);
+        assert_eq!(restored_luts[0].key, lut.key);
+        assert_eq!(restored_luts[0].addresses, lut.addresses);
+    }
+
+    #[test]
+    fn test_restore_ignores_other_program_snapshot() {
+        let cache = create_dummy_cache();
+        cache
+            .banks
+            .update(1, Pubkey::new_unique(), &create_bank_with_oracles(vec![]))
+            .unwrap();
+        let path = snapshot_path("other_program");
+        cache.snapshot_to(&path, &Pubkey::new_unique()).unwrap();
+
+        let restored = create_dummy_cache();
+        assert!(!restored.restore_from(&path, &Pubkey::new_unique()).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(restored.banks.count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_restore_ignores_old_snapshot() {
+        let cache = create_dummy_cache();
+        let program_id = Pubkey::new_unique();
+        let path = snapshot_path("old");
+        cache.snapshot_to(&path, &program_id).unwrap();
+
+        let restored = create_dummy_cache();
+        restored
+            .update_clock(generate_test_clock(1 + SNAPSHOT_MAX_AGE_SLOTS))
+            .unwrap();
+        assert!(restored.restore_from(&path, &program_id).unwrap());
+        restored
+            .update_clock(generate_test_clock(2 + SNAPSHOT_MAX_AGE_SLOTS))
+            .unwrap();
+        assert!(!restored.restore_from(&path, &program_id).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_restore_ignores_other_version_snapshot() {
+        let cache = create_dummy_cache();
+        let program_id = Pubkey::new_unique();
+        let path = snapshot_path("other_version");
+        cache.snapshot_to(&path, &program_id).unwrap();
+
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[SNAPSHOT_MAGIC.len()..SNAPSHOT_HEADER_LEN]
+            .copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+
+        assert!(!cache.restore_from(&path, &program_id).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_restore_missing_or_invalid_snapshot() {
+        let cache = create_dummy_cache();
+        let program_id = Pubkey::new_unique();
+        let path = snapshot_path("invalid");
+        assert!(!cache.restore_from(&path, &program_id).unwrap());
+
+        fs::write(&path, b"not a snapshot").unwrap();
+        assert!(cache.restore_from(&path, &program_id).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
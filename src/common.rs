@@ -1,15 +1,50 @@
+use solana_client::rpc_filter::{Memcmp, RpcFilterType};
+
 pub const MARGINFI_ACCOUNT_DISCRIMINATOR: [u8; 8] = [67, 178, 130, 109, 126, 114, 28, 42];
 pub const MARGINFI_ACCOUNT_DISCRIMINATOR_LEN: usize = MARGINFI_ACCOUNT_DISCRIMINATOR.len();
 pub const MARGINFI_BANK_DISCRIMINATOR: [u8; 8] = [142, 49, 166, 242, 50, 66, 97, 188];
 pub const MARGINFI_BANK_DISCRIMINATOR_LEN: usize = MARGINFI_BANK_DISCRIMINATOR.len();
 
 // TODO: Is there better home for Geysermessage and GeyserMessageType?
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum MessageType {
     Clock,
     MarginfiAccount,
     Bank,
     Oracle,
+    Mint,
+    // The token account of a Bank vault
+    Vault,
+    // The account with no lamports left, to be dropped from the cache
+    Closed,
+    // The extra account the operator asked to watch, only logged
+    Watched,
+}
+
+impl MessageType {
+    pub const ALL: [MessageType; 8] = [
+        MessageType::Clock,
+        MessageType::MarginfiAccount,
+        MessageType::Bank,
+        MessageType::Oracle,
+        MessageType::Mint,
+        MessageType::Vault,
+        MessageType::Closed,
+        MessageType::Watched,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageType::Clock => "clock",
+            MessageType::MarginfiAccount => "marginfi_account",
+            MessageType::Bank => "bank",
+            MessageType::Oracle => "oracle",
+            MessageType::Mint => "mint",
+            MessageType::Vault => "vault",
+            MessageType::Closed => "closed",
+            MessageType::Watched => "watched",
+        }
+    }
 }
 
 pub fn get_marginfi_message_type(account_data: &[u8]) -> Option<MessageType> {
@@ -25,6 +60,11 @@ pub fn get_marginfi_message_type(account_data: &[u8]) -> Option<MessageType> {
         None
     }
 }
+/// Builds the RPC filter matching the accounts that start with the discriminator.
+pub fn discriminator_filter(discriminator: &[u8]) -> RpcFilterType {
+    RpcFilterType::Memcmp(Memcmp::new_base58_encoded(0, discriminator))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -76,4 +116,20 @@ mod tests {
         data.extend_from_slice(&[9, 9, 9, 9, 9, 9, 9, 9]);
         assert_eq!(get_marginfi_message_type(&data), None);
     }
+
+    #[test]
+    fn test_discriminator_filter() {
+        let RpcFilterType::Memcmp(memcmp) = discriminator_filter(&MARGINFI_ACCOUNT_DISCRIMINATOR)
+        else {
+            panic!("Expected the Memcmp filter");
+        };
+
+        let mut data = MARGINFI_ACCOUNT_DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&[1, 2, 3, 4]);
+        assert!(memcmp.bytes_match(&data));
+
+        let mut data = MARGINFI_BANK_DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&[1, 2, 3, 4]);
+        assert!(!memcmp.bytes_match(&data));
+    }
 }
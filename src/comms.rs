@@ -1,11 +1,27 @@
+pub mod jito_comms_client;
 pub mod rpc_comms_client;
 
+pub use jito_comms_client::JitoCommsClient;
 pub use rpc_comms_client::RpcCommsClient;
 
+use std::{thread, time::Duration};
+
 use anyhow::Result;
-use solana_sdk::{account::Account, pubkey::Pubkey};
+use log::warn;
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    rpc_filter::RpcFilterType,
+    rpc_request::RpcError,
+};
+use solana_sdk::{
+    account::Account, address_lookup_table::AddressLookupTableAccount, instruction::Instruction,
+    pubkey::Pubkey, signature::Signature,
+};
+
+use crate::config::{Config, RetryConfig};
 
-use crate::config::Config;
+// The JSON RPC error code returned by the node that is behind or otherwise unhealthy.
+const RPC_NODE_UNHEALTHY_CODE: i64 = -32005;
 
 // TODO: consider renaming this trait to something more descriptive. Fetcher for example.
 pub trait CommsClient: Send + Sync {
@@ -13,38 +29,236 @@ pub trait CommsClient: Send + Sync {
     where
         Self: Sized;
 
+    /// The wallet signing and paying for the sent transactions.
+    fn signer_pubkey(&self) -> Pubkey;
+
+    /// The liquidator's Marginfi account used for the liquidations.
+    fn liquidator_account(&self) -> Pubkey;
+
     fn get_account(&self, address: &Pubkey) -> Result<Account>;
 
     fn get_program_accounts(&self, program_id: &Pubkey) -> Result<Vec<(Pubkey, Account)>>;
 
+    fn get_program_accounts_filtered(
+        &self,
+        program_id: &Pubkey,
+        filters: Vec<RpcFilterType>,
+    ) -> Result<Vec<(Pubkey, Account)>>;
+
+    /// Returns only the accounts that exist, the missing addresses are silently dropped.
+    /// Callers must diff the result against the requested addresses if they care about the missing ones.
     fn get_accounts(&self, addresses: &[Pubkey]) -> Result<Vec<(Pubkey, Account)>>;
+
+    /// The existing accounts with the lowest context slot of the RPC responses, none of the
+    /// accounts is older than it.
+    fn get_accounts_with_slot(&self, addresses: &[Pubkey])
+        -> Result<(u64, Vec<(Pubkey, Account)>)>;
+
+    /// Sends the instructions in a single transaction and waits for its confirmation.
+    fn send_ixs(&self, ixs: Vec<Instruction>) -> Result<Signature>;
+
+    /// Simulates the transaction of the instructions with the compute budget, without sending it.
+    fn simulate(&self, ixs: &[Instruction]) -> Result<SimulationResult>;
+
+    /// The expected fees of a sent transaction in lamports: the signature, the priority fee and the tip.
+    fn transaction_fee_lamports(&self) -> u64;
+
+    /// Sets the LUTs used to compress the account keys of the sent transactions.
+    fn set_luts(&self, _luts: Vec<AddressLookupTableAccount>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The outcome of a simulated transaction.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimulationResult {
+    /// The error the transaction would fail with, None if it would succeed.
+    pub err: Option<String>,
+    pub logs: Vec<String>,
+}
+
+impl SimulationResult {
+    pub fn is_success(&self) -> bool {
+        self.err.is_none()
+    }
+
+    /// The error with the last program log, which usually names the failed check.
+    pub fn revert_reason(&self) -> String {
+        let err = self.err.as_deref().unwrap_or("no error");
+        match self.logs.last() {
+            Some(log) => format!("{}, last log: {}", err, log),
+            None => err.to_string(),
+        }
+    }
+}
+
+/// Calls the operation until it succeeds, fails with a non-transient error or runs out of attempts.
+/// The delay between the attempts doubles starting from the base delay.
+pub fn with_retry<T, F>(retry: &RetryConfig, operation: &str, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Result<T>,
+{
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < retry.max_attempts && is_transient(&err) => {
+                let delay = Duration::from_millis(retry.base_delay_ms)
+                    .saturating_mul(1 << (attempt - 1).min(16));
+                warn!(
+                    "Attempt {}/{} to {} failed, retrying in {:?}: {}",
+                    attempt, retry.max_attempts, operation, delay, err
+                );
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// The network failures and the overloaded node are worth retrying, the logical errors are not.
+fn is_transient(err: &anyhow::Error) -> bool {
+    let Some(client_error) = err.downcast_ref::<ClientError>() else {
+        return false;
+    };
+    match client_error.kind() {
+        ClientErrorKind::Io(_) | ClientErrorKind::Reqwest(_) => true,
+        ClientErrorKind::RpcError(RpcError::RpcResponseError { code, .. }) => {
+            *code == RPC_NODE_UNHEALTHY_CODE
+        }
+        _ => false,
+    }
 }
 
 #[cfg(test)]
 pub mod test_util {
     use anyhow::{anyhow, Result};
-    use std::collections::HashMap;
+    use solana_sdk::{clock::Clock, sysvar};
+    use std::{
+        collections::{HashMap, VecDeque},
+        io,
+        sync::Mutex,
+    };
 
     use super::*;
+    use crate::error::MaryError;
 
     pub struct MockedCommsClient {
         accounts: HashMap<Pubkey, Account>,
+        signer: Pubkey,
+        liquidator_account: Pubkey,
+        slot: u64,
+        transaction_fee_lamports: u64,
+        sent_ixs: Mutex<Vec<Instruction>>,
+        transient_failures: Mutex<u32>,
+        // The scripted results of the next sends, the sends past the script land
+        send_results: Mutex<VecDeque<Result<(), String>>>,
+        // The scripted results of the next simulations, the simulations past the script succeed
+        simulate_results: Mutex<VecDeque<Result<(), String>>>,
     }
 
     impl MockedCommsClient {
         pub fn with_accounts(accounts: HashMap<Pubkey, Account>) -> Self {
-            Self { accounts }
+            Self {
+                accounts,
+                signer: Pubkey::new_unique(),
+                liquidator_account: Pubkey::new_unique(),
+                slot: 1,
+                transaction_fee_lamports: 0,
+                sent_ixs: Mutex::new(Vec::new()),
+                transient_failures: Mutex::new(0),
+                send_results: Mutex::new(VecDeque::new()),
+                simulate_results: Mutex::new(VecDeque::new()),
+            }
+        }
+
+        /// Makes the next account reads fail with the transient network error.
+        pub fn with_transient_failures(self, failures: u32) -> Self {
+            *self.transient_failures.lock().unwrap() = failures;
+            self
+        }
+
+        /// Makes the next sends fail with the given errors, e.g. to simulate the reverted transactions.
+        /// The failed transactions are still recorded as sent.
+        pub fn with_send_results(self, results: Vec<Result<(), &str>>) -> Self {
+            *self.send_results.lock().unwrap() = results
+                .into_iter()
+                .map(|result| result.map_err(str::to_string))
+                .collect();
+            self
+        }
+
+        /// Makes the next simulations fail with the given errors, e.g. to simulate the lost races.
+        pub fn with_simulate_results(self, results: Vec<Result<(), &str>>) -> Self {
+            *self.simulate_results.lock().unwrap() = results
+                .into_iter()
+                .map(|result| result.map_err(str::to_string))
+                .collect();
+            self
+        }
+
+        /// Sets the slot reported as the current one of the network.
+        pub fn with_slot(mut self, slot: u64) -> Self {
+            self.slot = slot;
+            self
+        }
+
+        /// Sets the fees reported for a sent transaction, free by default.
+        pub fn with_transaction_fee_lamports(mut self, transaction_fee_lamports: u64) -> Self {
+            self.transaction_fee_lamports = transaction_fee_lamports;
+            self
+        }
+
+        pub fn with_liquidator_account(mut self, liquidator_account: Pubkey) -> Self {
+            self.liquidator_account = liquidator_account;
+            self
+        }
+
+        pub fn sent_ixs(&self) -> Vec<Instruction> {
+            self.sent_ixs.lock().unwrap().clone()
         }
     }
 
     impl CommsClient for MockedCommsClient {
+        /// Serves just the Clock, so that the services can be started against the mock.
         fn new(_config: &Config) -> Result<Self> {
-            Ok(Self {
-                accounts: HashMap::new(),
-            })
+            let clock = Clock {
+                slot: 1,
+                ..Default::default()
+            };
+            let clock_account = Account {
+                lamports: 1,
+                data: bincode::serialize(&clock)?,
+                owner: sysvar::id(),
+                executable: false,
+                rent_epoch: 0,
+            };
+            Ok(Self::with_accounts(HashMap::from([(
+                sysvar::clock::id(),
+                clock_account,
+            )])))
+        }
+
+        fn signer_pubkey(&self) -> Pubkey {
+            self.signer
+        }
+
+        fn liquidator_account(&self) -> Pubkey {
+            self.liquidator_account
         }
 
         fn get_account(&self, pubkey: &Pubkey) -> Result<Account> {
+            let mut transient_failures = self
+                .transient_failures
+                .lock()
+                .map_err(|e| anyhow!("Failed to lock the transient failures: {}", e))?;
+            if *transient_failures > 0 {
+                *transient_failures -= 1;
+                return Err(ClientError::from(io::Error::other("Connection reset")).into());
+            }
+            drop(transient_failures);
+
             self.accounts
                 .get(pubkey)
                 .cloned()
@@ -60,6 +274,26 @@ pub mod test_util {
                 .collect())
         }
 
+        fn get_program_accounts_filtered(
+            &self,
+            program_id: &Pubkey,
+            filters: Vec<RpcFilterType>,
+        ) -> Result<Vec<(Pubkey, Account)>> {
+            Ok(self
+                .accounts
+                .iter()
+                .filter(|(_, account)| account.owner == *program_id)
+                .filter(|(_, account)| {
+                    filters.iter().all(|filter| match filter {
+                        RpcFilterType::Memcmp(memcmp) => memcmp.bytes_match(&account.data),
+                        RpcFilterType::DataSize(size) => account.data.len() as u64 == *size,
+                        _ => true,
+                    })
+                })
+                .map(|(pubkey, account)| (*pubkey, account.clone()))
+                .collect())
+        }
+
         fn get_accounts(&self, pubkeys: &[Pubkey]) -> Result<Vec<(Pubkey, Account)>> {
             let mut accounts = Vec::new();
             for pubkey in pubkeys {
@@ -69,5 +303,120 @@ pub mod test_util {
             }
             Ok(accounts)
         }
+
+        fn get_accounts_with_slot(
+            &self,
+            pubkeys: &[Pubkey],
+        ) -> Result<(u64, Vec<(Pubkey, Account)>)> {
+            Ok((self.slot, self.get_accounts(pubkeys)?))
+        }
+
+        fn send_ixs(&self, ixs: Vec<Instruction>) -> Result<Signature> {
+            self.sent_ixs
+                .lock()
+                .map_err(|e| anyhow!("Failed to lock the sent instructions: {}", e))?
+                .extend(ixs);
+            let result = self
+                .send_results
+                .lock()
+                .map_err(|e| anyhow!("Failed to lock the send results: {}", e))?
+                .pop_front()
+                .unwrap_or(Ok(()));
+            result.map_err(MaryError::Rpc)?;
+            Ok(Signature::new_unique())
+        }
+
+        fn transaction_fee_lamports(&self) -> u64 {
+            self.transaction_fee_lamports
+        }
+
+        fn simulate(&self, _ixs: &[Instruction]) -> Result<SimulationResult> {
+            let result = self
+                .simulate_results
+                .lock()
+                .map_err(|e| anyhow!("Failed to lock the simulate results: {}", e))?
+                .pop_front()
+                .unwrap_or(Ok(()));
+            Ok(SimulationResult {
+                err: result.err(),
+                ..Default::default()
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::test_util::MockedCommsClient;
+    use super::*;
+
+    fn send_through_trait<T: CommsClient>(comms_client: &T, ix: Instruction) -> Result<Signature> {
+        comms_client.send_ixs(vec![ix])
+    }
+
+    #[test]
+    fn test_mocked_comms_client_records_sent_ixs() {
+        let comms_client = MockedCommsClient::with_accounts(HashMap::new());
+        let ix1 = Instruction::new_with_bytes(Pubkey::new_unique(), &[1], vec![]);
+        let ix2 = Instruction::new_with_bytes(Pubkey::new_unique(), &[2], vec![]);
+
+        let signature1 = send_through_trait(&comms_client, ix1.clone()).unwrap();
+        let signature2 = send_through_trait(&comms_client, ix2.clone()).unwrap();
+
+        assert_ne!(signature1, signature2);
+        assert_eq!(comms_client.sent_ixs(), vec![ix1, ix2]);
+    }
+
+    fn test_retry_config() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 3,
+            base_delay_ms: 1,
+        }
+    }
+
+    #[test]
+    fn test_with_retry_recovers_from_transient_failures() {
+        let address = Pubkey::new_unique();
+        let account = Account::new(1, 2, &Pubkey::new_unique());
+        let comms_client =
+            MockedCommsClient::with_accounts(HashMap::from([(address, account.clone())]))
+                .with_transient_failures(2);
+
+        let fetched = with_retry(&test_retry_config(), "get account", || {
+            comms_client.get_account(&address)
+        })
+        .unwrap();
+        assert_eq!(fetched, account);
+    }
+
+    #[test]
+    fn test_with_retry_gives_up_after_max_attempts() {
+        let address = Pubkey::new_unique();
+        let comms_client =
+            MockedCommsClient::with_accounts(HashMap::from([(address, Account::default())]))
+                .with_transient_failures(3);
+
+        let mut attempts = 0;
+        let result = with_retry(&test_retry_config(), "get account", || {
+            attempts += 1;
+            comms_client.get_account(&address)
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn test_with_retry_does_not_retry_logical_errors() {
+        let comms_client = MockedCommsClient::with_accounts(HashMap::new());
+
+        let mut attempts = 0;
+        let result = with_retry(&test_retry_config(), "get account", || {
+            attempts += 1;
+            comms_client.get_account(&Pubkey::new_unique())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
     }
 }
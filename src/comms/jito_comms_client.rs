@@ -0,0 +1,242 @@
+use crate::comms::{CommsClient, RpcCommsClient, SimulationResult};
+use crate::config::Config;
+use crate::error::MaryError;
+use anyhow::{anyhow, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use log::debug;
+use reqwest::blocking::Client;
+use serde_json::{json, Value};
+use solana_client::rpc_filter::RpcFilterType;
+use solana_sdk::{
+    account::Account, address_lookup_table::AddressLookupTableAccount, instruction::Instruction,
+    pubkey, pubkey::Pubkey, signature::Signature, system_instruction,
+    transaction::VersionedTransaction,
+};
+use std::time::{Duration, SystemTime};
+
+const BUNDLES_PATH: &str = "/api/v1/bundles";
+const BLOCK_ENGINE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The tip accounts published by Jito, rotated to spread the write lock contention.
+const JITO_TIP_ACCOUNTS: [Pubkey; 8] = [
+    pubkey!("96gYZGLnJYVFmbjzopPSU6QiEV5fGqZNyN9nmNhvrZU5"),
+    pubkey!("HFqU5x63VTqvQss8hp11i4wVV8bD44PvwucfZ2bU7gRe"),
+    pubkey!("Cw8CFyM9FkoMi7K7Crf6HNQqf4uEMzpKw6QNghXLvLkY"),
+    pubkey!("ADaUMid9yfUytqMBgopwjb2DTLSokTSzL1zt6iGPaS49"),
+    pubkey!("DfXygSm4jCyNCybVYYK6DwvWqjKee8pbDmJGcLWNDXjh"),
+    pubkey!("ADuUkR4vqLUMWXxW9gh6D6L8pMSawimctcNZ5pGwDcEt"),
+    pubkey!("DttWaMuVvTiduZRnguLF7jNxTgiMBZ1hyAumKUiL2KRL"),
+    pubkey!("3AVi9Tg9Uo68tJfuvoKvqKNWKkC5wPdSSdeBnizKZ6jT"),
+];
+
+/// Submits the transactions as the Jito bundles with a tip, the reads go through the RPC.
+pub struct JitoCommsClient {
+    rpc_comms_client: RpcCommsClient,
+    http_client: Client,
+    bundles_url: String,
+    tip_lamports: u64,
+}
+
+impl JitoCommsClient {
+    fn send_bundle(&self, txs: &[VersionedTransaction]) -> Result<String> {
+        let response: Value = self
+            .http_client
+            .post(&self.bundles_url)
+            .json(&bundle_request(txs)?)
+            .send()
+            .and_then(|response| response.json())
+            .map_err(|e| {
+                MaryError::Rpc(format!(
+                    "Failed to send the bundle to the block engine: {}",
+                    e
+                ))
+            })?;
+
+        if let Some(error) = response.get("error") {
+            return Err(
+                MaryError::Rpc(format!("The block engine rejected the bundle: {}", error)).into(),
+            );
+        }
+        response
+            .get("result")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| {
+                MaryError::Rpc(format!("Unexpected block engine response: {}", response)).into()
+            })
+    }
+}
+
+impl CommsClient for JitoCommsClient {
+    fn new(config: &Config) -> Result<Self> {
+        let http_client = Client::builder().timeout(BLOCK_ENGINE_TIMEOUT).build()?;
+        Ok(JitoCommsClient {
+            rpc_comms_client: RpcCommsClient::new(config)?,
+            http_client,
+            bundles_url: format!(
+                "{}{}",
+                config.jito_block_engine_url.trim_end_matches('/'),
+                BUNDLES_PATH
+            ),
+            tip_lamports: config.jito_tip_lamports,
+        })
+    }
+
+    fn signer_pubkey(&self) -> Pubkey {
+        self.rpc_comms_client.signer_pubkey()
+    }
+
+    fn liquidator_account(&self) -> Pubkey {
+        self.rpc_comms_client.liquidator_account()
+    }
+
+    fn get_account(&self, address: &Pubkey) -> Result<Account> {
+        self.rpc_comms_client.get_account(address)
+    }
+
+    fn get_program_accounts(&self, program_id: &Pubkey) -> Result<Vec<(Pubkey, Account)>> {
+        self.rpc_comms_client.get_program_accounts(program_id)
+    }
+
+    fn get_program_accounts_filtered(
+        &self,
+        program_id: &Pubkey,
+        filters: Vec<RpcFilterType>,
+    ) -> Result<Vec<(Pubkey, Account)>> {
+        self.rpc_comms_client
+            .get_program_accounts_filtered(program_id, filters)
+    }
+
+    fn get_accounts(&self, addresses: &[Pubkey]) -> Result<Vec<(Pubkey, Account)>> {
+        self.rpc_comms_client.get_accounts(addresses)
+    }
+
+    fn get_accounts_with_slot(
+        &self,
+        addresses: &[Pubkey],
+    ) -> Result<(u64, Vec<(Pubkey, Account)>)> {
+        self.rpc_comms_client.get_accounts_with_slot(addresses)
+    }
+
+    fn send_ixs(&self, ixs: Vec<Instruction>) -> Result<Signature> {
+        let compute_unit_price_micro_lamports = self.rpc_comms_client.compute_unit_price(&ixs);
+        let mut ixs = self
+            .rpc_comms_client
+            .with_compute_budget(ixs, compute_unit_price_micro_lamports);
+        ixs.push(tip_ix(&self.signer_pubkey(), self.tip_lamports));
+
+        let tx = self.rpc_comms_client.build_transaction(&ixs)?;
+        let signature = *tx
+            .signatures
+            .first()
+            .ok_or_else(|| anyhow!("The signed transaction has no signatures"))?;
+
+        let bundle_id = self.send_bundle(&[tx])?;
+        debug!(
+            "Sent the bundle {} with the transaction {}",
+            bundle_id, signature
+        );
+
+        self.rpc_comms_client.confirm_signature(&signature)?;
+        Ok(signature)
+    }
+
+    fn transaction_fee_lamports(&self) -> u64 {
+        self.rpc_comms_client.transaction_fee_lamports() + self.tip_lamports
+    }
+
+    fn simulate(&self, ixs: &[Instruction]) -> Result<SimulationResult> {
+        self.rpc_comms_client.simulate(ixs)
+    }
+
+    fn set_luts(&self, luts: Vec<AddressLookupTableAccount>) -> Result<()> {
+        self.rpc_comms_client.set_luts(luts)
+    }
+}
+
+fn tip_ix(payer: &Pubkey, tip_lamports: u64) -> Instruction {
+    let index = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |now| now.subsec_nanos() as usize);
+    system_instruction::transfer(
+        payer,
+        &JITO_TIP_ACCOUNTS[index % JITO_TIP_ACCOUNTS.len()],
+        tip_lamports,
+    )
+}
+
+fn bundle_request(txs: &[VersionedTransaction]) -> Result<Value> {
+    let encoded = txs
+        .iter()
+        .map(|tx| Ok(BASE64_STANDARD.encode(bincode::serialize(tx)?)))
+        .collect::<Result<Vec<String>>>()?;
+    Ok(json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "sendBundle",
+        "params": [encoded, { "encoding": "base64" }],
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_util::create_dummy_config;
+    use solana_sdk::{
+        hash::Hash, signature::Keypair, signer::Signer, system_program, transaction::Transaction,
+    };
+
+    #[test]
+    fn test_jito_comms_client_new_uses_config() {
+        let mut config = create_dummy_config();
+        config.jito_block_engine_url = "https://block-engine.test/".into();
+        config.jito_tip_lamports = 25_000;
+        let client = JitoCommsClient::new(&config).unwrap();
+
+        assert_eq!(
+            client.bundles_url,
+            "https://block-engine.test/api/v1/bundles"
+        );
+        assert_eq!(client.tip_lamports, 25_000);
+        assert_eq!(
+            client.transaction_fee_lamports(),
+            client.rpc_comms_client.transaction_fee_lamports() + 25_000
+        );
+        assert_eq!(client.signer_pubkey(), config.wallet.pubkey());
+        assert_eq!(client.liquidator_account(), config.liquidator_account);
+    }
+
+    #[test]
+    fn test_tip_ix_pays_a_jito_tip_account() {
+        let payer = Pubkey::new_unique();
+        let ix = tip_ix(&payer, 10_000);
+
+        assert_eq!(ix.program_id, system_program::id());
+        assert_eq!(ix.accounts[0].pubkey, payer);
+        assert!(JITO_TIP_ACCOUNTS.contains(&ix.accounts[1].pubkey));
+        assert_eq!(
+            ix,
+            system_instruction::transfer(&payer, &ix.accounts[1].pubkey, 10_000)
+        );
+    }
+
+    #[test]
+    fn test_bundle_request_encodes_transactions() {
+        let signer = Keypair::new();
+        let tx = VersionedTransaction::from(Transaction::new_signed_with_payer(
+            &[tip_ix(&signer.pubkey(), 1)],
+            Some(&signer.pubkey()),
+            &[&signer],
+            Hash::default(),
+        ));
+
+        let request = bundle_request(&[tx.clone()]).unwrap();
+        assert_eq!(request["method"], "sendBundle");
+        assert_eq!(request["params"][1]["encoding"], "base64");
+
+        let encoded = request["params"][0][0].as_str().unwrap();
+        let decoded: VersionedTransaction =
+            bincode::deserialize(&BASE64_STANDARD.decode(encoded).unwrap()).unwrap();
+        assert_eq!(decoded, tx);
+    }
+}
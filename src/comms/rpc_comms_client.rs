@@ -1,46 +1,582 @@
-use crate::comms::CommsClient;
-use crate::config::Config;
+use crate::comms::{with_retry, CommsClient, SimulationResult};
+use crate::config::{Config, RetryConfig};
+use crate::error::MaryError;
 use anyhow::{anyhow, Result};
-use solana_client::rpc_client::RpcClient;
-use solana_sdk::{account::Account, commitment_config::CommitmentConfig, pubkey::Pubkey};
+use log::warn;
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::{
+    rpc_client::RpcClient,
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig, RpcSimulateTransactionConfig},
+    rpc_filter::RpcFilterType,
+};
+use solana_sdk::{
+    account::Account,
+    address_lookup_table::AddressLookupTableAccount,
+    commitment_config::CommitmentConfig,
+    compute_budget::ComputeBudgetInstruction,
+    hash::Hash,
+    instruction::Instruction,
+    message::{v0, VersionedMessage},
+    pubkey::Pubkey,
+    signature::{Keypair, Signature},
+    signer::Signer,
+    transaction::{Transaction, VersionedTransaction},
+};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, RwLock,
+    },
+    thread,
+    time::Duration,
+};
 
 const ADDRESSES_CHUNK_SIZE: usize = 100;
+const PRIORITY_FEE_PERCENTILE: usize = 75;
+const LAMPORTS_PER_SIGNATURE: u64 = 5_000;
+const MICRO_LAMPORTS_PER_LAMPORT: u64 = 1_000_000;
 
 pub struct RpcCommsClient {
     solana_rpc_client: RpcClient,
+    signer: Keypair,
+    liquidator_account: Pubkey,
+    compute_unit_limit: u32,
+    compute_unit_price_micro_lamports: u64,
+    dynamic_priority_fee: bool,
+    retry: RetryConfig,
+    // The number of the address chunks fetched in parallel by get_accounts
+    fetch_concurrency: usize,
+    luts: RwLock<Vec<AddressLookupTableAccount>>,
+}
+
+impl RpcCommsClient {
+    /// Estimates the compute unit price as a percentile of the recent prioritization fees
+    /// paid for the writable accounts.
+    pub fn estimate_priority_fee(&self, writable: &[Pubkey]) -> Result<u64> {
+        let fees: Vec<u64> = self
+            .solana_rpc_client
+            .get_recent_prioritization_fees(writable)
+            .map_err(|e| {
+                MaryError::Rpc(format!(
+                    "Failed to get the recent prioritization fees: {}",
+                    e
+                ))
+            })?
+            .into_iter()
+            .map(|fee| fee.prioritization_fee)
+            .collect();
+
+        percentile_fee(fees, PRIORITY_FEE_PERCENTILE)
+            .ok_or_else(|| anyhow!("No recent prioritization fees"))
+    }
+
+    /// Sends the instructions with the compute unit price overriding the configured default.
+    pub fn send_ixs_with_priority(
+        &self,
+        ixs: Vec<Instruction>,
+        compute_unit_price_micro_lamports: u64,
+    ) -> Result<Signature> {
+        let ixs = self.with_compute_budget(ixs, compute_unit_price_micro_lamports);
+        let tx = self.build_transaction(&ixs)?;
+        self.solana_rpc_client
+            .send_and_confirm_transaction(&tx)
+            .map_err(|e| MaryError::Rpc(format!("Failed to send the transaction: {}", e)).into())
+    }
+
+    /// Signs the transaction with the latest blockhash, compiled against the LUTs when they are set.
+    pub(super) fn build_transaction(&self, ixs: &[Instruction]) -> Result<VersionedTransaction> {
+        let luts = self
+            .luts
+            .read()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!("Failed to lock the LUTs for reading: {}", e))
+            })?
+            .clone();
+        let recent_blockhash = self.solana_rpc_client.get_latest_blockhash()?;
+
+        if luts.is_empty() {
+            let tx = Transaction::new_signed_with_payer(
+                ixs,
+                Some(&self.signer.pubkey()),
+                &[&self.signer],
+                recent_blockhash,
+            );
+            Ok(VersionedTransaction::from(tx))
+        } else {
+            let message = compile_v0_message(&self.signer.pubkey(), ixs, &luts, recent_blockhash)?;
+            Ok(VersionedTransaction::try_new(message, &[&self.signer])?)
+        }
+    }
+
+    /// Waits until the transaction sent through another channel is confirmed.
+    pub(super) fn confirm_signature(&self, signature: &Signature) -> Result<()> {
+        self.solana_rpc_client
+            .poll_for_signature(signature)
+            .map_err(|e| {
+                MaryError::Rpc(format!(
+                    "Failed to confirm the transaction {}: {}",
+                    signature, e
+                ))
+                .into()
+            })
+    }
+
+    /// The configured compute unit price, or the estimated one if the dynamic priority fee is enabled.
+    pub(super) fn compute_unit_price(&self, ixs: &[Instruction]) -> u64 {
+        if !self.dynamic_priority_fee {
+            return self.compute_unit_price_micro_lamports;
+        }
+
+        let mut writable: Vec<Pubkey> = ixs
+            .iter()
+            .flat_map(|ix| ix.accounts.iter())
+            .filter(|meta| meta.is_writable)
+            .map(|meta| meta.pubkey)
+            .collect();
+        writable.sort();
+        writable.dedup();
+        self.estimate_priority_fee(&writable).unwrap_or_else(|err| {
+            warn!(
+                "Failed to estimate the priority fee, falling back to the configured one: {}",
+                err
+            );
+            self.compute_unit_price_micro_lamports
+        })
+    }
+
+    pub(super) fn with_compute_budget(
+        &self,
+        ixs: Vec<Instruction>,
+        compute_unit_price_micro_lamports: u64,
+    ) -> Vec<Instruction> {
+        let mut budgeted_ixs = vec![
+            ComputeBudgetInstruction::set_compute_unit_limit(self.compute_unit_limit),
+            ComputeBudgetInstruction::set_compute_unit_price(compute_unit_price_micro_lamports),
+        ];
+        budgeted_ixs.extend(ixs);
+        budgeted_ixs
+    }
 }
 
 impl CommsClient for RpcCommsClient {
     fn new(config: &Config) -> Result<Self> {
-        let solana_rpc_client =
-            RpcClient::new_with_commitment(&config.rpc_url, CommitmentConfig::confirmed());
-        Ok(RpcCommsClient { solana_rpc_client })
+        // The reads and the sends share the client, so both are bounded by the same timeout
+        let solana_rpc_client = RpcClient::new_with_timeout_and_commitment(
+            &config.rpc_url,
+            Duration::from_millis(config.rpc_timeout_ms),
+            CommitmentConfig {
+                commitment: config.commitment,
+            },
+        );
+        Ok(RpcCommsClient {
+            solana_rpc_client,
+            signer: config.wallet.insecure_clone(),
+            liquidator_account: config.liquidator_account,
+            compute_unit_limit: config.compute_unit_limit,
+            compute_unit_price_micro_lamports: config.compute_unit_price_micro_lamports,
+            dynamic_priority_fee: config.dynamic_priority_fee,
+            retry: config.rpc_retry,
+            fetch_concurrency: config.rpc_fetch_concurrency,
+            luts: RwLock::new(Vec::new()),
+        })
+    }
+
+    fn signer_pubkey(&self) -> Pubkey {
+        self.signer.pubkey()
+    }
+
+    fn liquidator_account(&self) -> Pubkey {
+        self.liquidator_account
+    }
+
+    fn send_ixs(&self, ixs: Vec<Instruction>) -> Result<Signature> {
+        let compute_unit_price_micro_lamports = self.compute_unit_price(&ixs);
+        self.send_ixs_with_priority(ixs, compute_unit_price_micro_lamports)
+    }
+
+    fn transaction_fee_lamports(&self) -> u64 {
+        // The dynamic price is only estimated at the send, the configured one stands in for it
+        LAMPORTS_PER_SIGNATURE
+            + self.compute_unit_limit as u64 * self.compute_unit_price_micro_lamports
+                / MICRO_LAMPORTS_PER_LAMPORT
+    }
+
+    fn simulate(&self, ixs: &[Instruction]) -> Result<SimulationResult> {
+        // The price does not change the outcome, the configured one spares the fee estimation
+        let ixs = self.with_compute_budget(ixs.to_vec(), self.compute_unit_price_micro_lamports);
+        let tx = self.build_transaction(&ixs)?;
+        let config = RpcSimulateTransactionConfig {
+            sig_verify: false,
+            commitment: Some(self.solana_rpc_client.commitment()),
+            ..Default::default()
+        };
+        let simulation = with_retry(&self.retry, "simulate transaction", || {
+            Ok(self
+                .solana_rpc_client
+                .simulate_transaction_with_config(&tx, config.clone())?)
+        })
+        .map_err(|e| MaryError::Rpc(format!("Failed to simulate the transaction: {}", e)))?
+        .value;
+        Ok(SimulationResult {
+            err: simulation.err.map(|err| err.to_string()),
+            logs: simulation.logs.unwrap_or_default(),
+        })
+    }
+
+    fn set_luts(&self, luts: Vec<AddressLookupTableAccount>) -> Result<()> {
+        *self.luts.write().map_err(|e| {
+            MaryError::LockPoisoned(format!("Failed to lock the LUTs for update: {}", e))
+        })? = luts;
+        Ok(())
     }
 
     fn get_account(&self, pubkey: &Pubkey) -> Result<Account> {
-        self.solana_rpc_client
-            .get_account(pubkey)
-            .map_err(|e| anyhow!("Failed to get account {}: {}", pubkey, e))
+        with_retry(&self.retry, "get account", || {
+            Ok(self.solana_rpc_client.get_account(pubkey)?)
+        })
+        .map_err(|e| MaryError::Rpc(format!("Failed to get account {}: {}", pubkey, e)).into())
     }
 
     fn get_program_accounts(&self, program_id: &Pubkey) -> Result<Vec<(Pubkey, Account)>> {
-        self.solana_rpc_client
-            .get_program_accounts(program_id)
-            .map_err(|e| anyhow!("Failed to get accounts for program{}: {}", program_id, e))
+        with_retry(&self.retry, "get program accounts", || {
+            Ok(self.solana_rpc_client.get_program_accounts(program_id)?)
+        })
+        .map_err(|e| {
+            MaryError::Rpc(format!(
+                "Failed to get accounts for program{}: {}",
+                program_id, e
+            ))
+            .into()
+        })
+    }
+
+    fn get_program_accounts_filtered(
+        &self,
+        program_id: &Pubkey,
+        filters: Vec<RpcFilterType>,
+    ) -> Result<Vec<(Pubkey, Account)>> {
+        let config = RpcProgramAccountsConfig {
+            filters: Some(filters),
+            account_config: RpcAccountInfoConfig {
+                encoding: Some(UiAccountEncoding::Base64),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        with_retry(&self.retry, "get filtered program accounts", || {
+            Ok(self
+                .solana_rpc_client
+                .get_program_accounts_with_config(program_id, config.clone())?)
+        })
+        .map_err(|e| {
+            MaryError::Rpc(format!(
+                "Failed to get filtered accounts for program {}: {}",
+                program_id, e
+            ))
+            .into()
+        })
     }
 
     fn get_accounts(&self, addresses: &[Pubkey]) -> Result<Vec<(Pubkey, Account)>> {
-        let mut tuples: Vec<(Pubkey, Account)> = Vec::new();
+        self.get_accounts_with_slot(addresses)
+            .map(|(_, accounts)| accounts)
+    }
 
-        for chunk in addresses.chunks(ADDRESSES_CHUNK_SIZE) {
-            let accounts = self.solana_rpc_client.get_multiple_accounts(chunk)?;
-            for (address, account_opt) in chunk.iter().zip(accounts.iter()) {
-                if let Some(account) = account_opt {
-                    tuples.push((*address, account.clone()));
+    fn get_accounts_with_slot(
+        &self,
+        addresses: &[Pubkey],
+    ) -> Result<(u64, Vec<(Pubkey, Account)>)> {
+        fetch_chunks_concurrently(
+            addresses,
+            ADDRESSES_CHUNK_SIZE,
+            self.fetch_concurrency,
+            |chunk| {
+                with_retry(&self.retry, "get multiple accounts", || {
+                    let response = self
+                        .solana_rpc_client
+                        .get_multiple_accounts_with_commitment(
+                            chunk,
+                            self.solana_rpc_client.commitment(),
+                        )?;
+                    Ok((response.context.slot, response.value))
+                })
+            },
+        )
+    }
+}
+
+/// Fetches the address chunks on up to the given number of threads, keeping the accounts in the
+/// order of the addresses. The missing accounts are dropped and the first failed chunk fails all.
+/// Returns the lowest context slot of the chunks with the accounts.
+fn fetch_chunks_concurrently<F>(
+    addresses: &[Pubkey],
+    chunk_size: usize,
+    concurrency: usize,
+    fetch_chunk: F,
+) -> Result<(u64, Vec<(Pubkey, Account)>)>
+where
+    F: Fn(&[Pubkey]) -> Result<(u64, Vec<Option<Account>>)> + Sync,
+{
+    let chunks: Vec<&[Pubkey]> = addresses.chunks(chunk_size).collect();
+    let results: Vec<Mutex<Option<Result<(u64, Vec<Option<Account>>)>>>> =
+        chunks.iter().map(|_| Mutex::new(None)).collect();
+    let next_chunk = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        for _ in 0..concurrency.clamp(1, chunks.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next_chunk.fetch_add(1, Ordering::Relaxed);
+                let Some(chunk) = chunks.get(index) else {
+                    break;
+                };
+                let result = fetch_chunk(chunk);
+                let failed = result.is_err();
+                if let Ok(mut slot) = results[index].lock() {
+                    *slot = Some(result);
+                }
+                if failed {
+                    // Skip the remaining chunks, the whole fetch fails anyway
+                    next_chunk.fetch_add(chunks.len(), Ordering::Relaxed);
                 }
+            });
+        }
+    });
+
+    let mut min_slot: Option<u64> = None;
+    let mut tuples: Vec<(Pubkey, Account)> = Vec::new();
+    for (chunk, result) in chunks.iter().zip(results) {
+        let (slot, accounts) = result
+            .into_inner()
+            .map_err(|e| {
+                MaryError::LockPoisoned(format!("Failed to lock the fetched accounts chunk: {}", e))
+            })?
+            .ok_or_else(|| anyhow!("The accounts chunk was not fetched"))??;
+        min_slot = Some(min_slot.map_or(slot, |min_slot| min_slot.min(slot)));
+        for (address, account_opt) in chunk.iter().zip(accounts) {
+            if let Some(account) = account_opt {
+                tuples.push((*address, account));
             }
         }
+    }
+
+    Ok((min_slot.unwrap_or_default(), tuples))
+}
+
+/// Compiles the v0 message resolving the instruction accounts through the LUTs where possible.
+fn compile_v0_message(
+    payer: &Pubkey,
+    ixs: &[Instruction],
+    luts: &[AddressLookupTableAccount],
+    recent_blockhash: Hash,
+) -> Result<VersionedMessage> {
+    let message = v0::Message::try_compile(payer, ixs, luts, recent_blockhash)
+        .map_err(|e| anyhow!("Failed to compile the v0 message: {}", e))?;
+    Ok(VersionedMessage::V0(message))
+}
+
+fn percentile_fee(mut fees: Vec<u64>, percentile: usize) -> Option<u64> {
+    if fees.is_empty() {
+        return None;
+    }
+
+    fees.sort_unstable();
+    let index = (fees.len() - 1) * percentile.min(100) / 100;
+    Some(fees[index])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_util::create_dummy_config;
+    use solana_sdk::instruction::AccountMeta;
+
+    #[test]
+    fn test_rpc_comms_client_new_uses_config_wallet() {
+        let config = create_dummy_config();
+        let client = RpcCommsClient::new(&config).unwrap();
+
+        assert_eq!(client.signer_pubkey(), config.wallet.pubkey());
+        assert_eq!(client.liquidator_account(), config.liquidator_account);
+    }
+
+    #[test]
+    fn test_get_account_unreachable_rpc() {
+        let mut config = create_dummy_config();
+        // Nothing listens on the port, the connection is refused right away
+        config.rpc_url = "http://127.0.0.1:1".to_string();
+        config.rpc_retry.max_attempts = 1;
+        let client = RpcCommsClient::new(&config).unwrap();
+
+        let err = client.get_account(&Pubkey::new_unique()).unwrap_err();
+        assert!(matches!(MaryError::of(&err), Some(MaryError::Rpc(_))));
+    }
+
+    #[test]
+    fn test_get_account_times_out() {
+        // The connections are queued but never answered
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = create_dummy_config();
+        config.rpc_url = format!("http://{}", listener.local_addr().unwrap());
+        config.rpc_retry.max_attempts = 1;
+        config.rpc_timeout_ms = 200;
+        let client = RpcCommsClient::new(&config).unwrap();
+
+        let start = std::time::Instant::now();
+        let err = client.get_account(&Pubkey::new_unique()).unwrap_err();
+        assert!(matches!(MaryError::of(&err), Some(MaryError::Rpc(_))));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_with_compute_budget_prepends_limit_and_price() {
+        let mut config = create_dummy_config();
+        config.compute_unit_limit = 300_000;
+        config.compute_unit_price_micro_lamports = 5_000;
+        let client = RpcCommsClient::new(&config).unwrap();
+
+        let ix = Instruction::new_with_bytes(Pubkey::new_unique(), &[1, 2, 3], vec![]);
+        let ixs = client.with_compute_budget(vec![ix.clone()], 7_500);
+
+        assert_eq!(ixs.len(), 3);
+        assert_eq!(
+            ixs[0],
+            ComputeBudgetInstruction::set_compute_unit_limit(300_000)
+        );
+        assert_eq!(
+            ixs[1],
+            ComputeBudgetInstruction::set_compute_unit_price(7_500)
+        );
+        assert_eq!(ixs[2], ix);
+    }
+
+    #[test]
+    fn test_transaction_fee_lamports() {
+        let mut config = create_dummy_config();
+        config.compute_unit_limit = 300_000;
+        config.compute_unit_price_micro_lamports = 5_000;
+        let client = RpcCommsClient::new(&config).unwrap();
+
+        assert_eq!(client.transaction_fee_lamports(), 5_000 + 1_500);
+    }
+
+    #[test]
+    fn test_percentile_fee() {
+        let fees = vec![50, 10, 40, 20, 30];
+        assert_eq!(percentile_fee(fees.clone(), 0), Some(10));
+        assert_eq!(percentile_fee(fees.clone(), 50), Some(30));
+        assert_eq!(percentile_fee(fees.clone(), 75), Some(40));
+        assert_eq!(percentile_fee(fees, 100), Some(50));
+    }
+
+    #[test]
+    fn test_percentile_fee_empty() {
+        assert_eq!(percentile_fee(vec![], 75), None);
+    }
+
+    #[test]
+    fn test_compile_v0_message_resolves_account_through_lut() {
+        let payer = Pubkey::new_unique();
+        let program_id = Pubkey::new_unique();
+        let lut_account = Pubkey::new_unique();
+        let lut = AddressLookupTableAccount {
+            key: Pubkey::new_unique(),
+            addresses: vec![Pubkey::new_unique(), lut_account],
+        };
+        let ix = Instruction::new_with_bytes(
+            program_id,
+            &[1, 2, 3],
+            vec![AccountMeta::new(lut_account, false)],
+        );
+
+        let message = compile_v0_message(&payer, &[ix], &[lut.clone()], Hash::default()).unwrap();
+
+        let VersionedMessage::V0(message) = message else {
+            panic!("Expected the v0 message");
+        };
+        assert!(!message.account_keys.contains(&lut_account));
+        assert_eq!(message.address_table_lookups.len(), 1);
+        assert_eq!(message.address_table_lookups[0].account_key, lut.key);
+        assert_eq!(message.address_table_lookups[0].writable_indexes, vec![1]);
+        assert!(message.address_table_lookups[0].readonly_indexes.is_empty());
+    }
+
+    #[test]
+    fn test_fetch_chunks_concurrently_keeps_order() {
+        let addresses: Vec<Pubkey> = (0..1_050).map(|_| Pubkey::new_unique()).collect();
+        // Every third account is missing
+        let missing =
+            |address: &Pubkey| addresses.iter().position(|a| a == address).unwrap() % 3 == 0;
+        let fetched_chunks = AtomicUsize::new(0);
+
+        let (slot, accounts) =
+            fetch_chunks_concurrently(&addresses, ADDRESSES_CHUNK_SIZE, 4, |chunk| {
+                fetched_chunks.fetch_add(1, Ordering::Relaxed);
+                // Let the first chunk complete last, at the earlier slot than the others
+                let mut slot = 7;
+                if chunk[0] == addresses[0] {
+                    thread::sleep(std::time::Duration::from_millis(20));
+                    slot = 5;
+                }
+                Ok((
+                    slot,
+                    chunk
+                        .iter()
+                        .map(|address| (!missing(address)).then(|| Account::new(1, 0, address)))
+                        .collect(),
+                ))
+            })
+            .unwrap();
+
+        assert_eq!(fetched_chunks.load(Ordering::Relaxed), 11);
+        assert_eq!(slot, 5);
+        let expected: Vec<Pubkey> = addresses
+            .iter()
+            .filter(|address| !missing(address))
+            .cloned()
+            .collect();
+        assert_eq!(
+            accounts
+                .iter()
+                .map(|(address, _)| *address)
+                .collect::<Vec<_>>(),
+            expected
+        );
+        assert!(accounts
+            .iter()
+            .all(|(address, account)| account.owner == *address));
+    }
+
+    #[test]
+    fn test_fetch_chunks_concurrently_fails_on_failed_chunk() {
+        let addresses: Vec<Pubkey> = (0..500).map(|_| Pubkey::new_unique()).collect();
+        let failing = addresses[250];
+
+        let result = fetch_chunks_concurrently(&addresses, ADDRESSES_CHUNK_SIZE, 3, |chunk| {
+            if chunk.contains(&failing) {
+                return Err(anyhow!("RPC failure"));
+            }
+            Ok((1, vec![None; chunk.len()]))
+        });
+        assert!(result.is_err());
+
+        assert!(
+            fetch_chunks_concurrently(&[], ADDRESSES_CHUNK_SIZE, 3, |_| { Ok((1, vec![])) })
+                .unwrap()
+                .1
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_set_luts() {
+        let client = RpcCommsClient::new(&create_dummy_config()).unwrap();
+        let lut = AddressLookupTableAccount {
+            key: Pubkey::new_unique(),
+            addresses: vec![Pubkey::new_unique()],
+        };
 
-        Ok(tuples)
+        client.set_luts(vec![lut.clone()]).unwrap();
+        assert_eq!(*client.luts.read().unwrap(), vec![lut]);
     }
 }
@@ -1,62 +1,830 @@
+use clap::{error::ErrorKind, Arg, ArgAction, Command};
+use log::warn;
 use solana_program::pubkey::Pubkey;
-use solana_sdk::{signature::Keypair, signer::Signer};
-use std::str::FromStr;
+use solana_sdk::{
+    commitment_config::CommitmentLevel,
+    signature::{read_keypair_file, Keypair},
+    signer::Signer,
+};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+// The settings that can be passed as the command line flags, e.g. RPC_URL as --rpc-url.
+// The WALLET bytes are left out to keep the key out of the process list, WALLET_PATH is there.
+const CLI_KEYS: &[&str] = &[
+    "WALLET_PATH",
+    "LIQUIDATOR_ACCOUNT",
+    "MARGINFI_PROGRAM_ID",
+    "LUT_ADDRESSES",
+    "STATS_INTERVAL_SEC",
+    "RPC_URL",
+    "SUBSCRIBER_MODE",
+    "WS_URL",
+    "EXTRA_SUBSCRIBE_ACCOUNTS",
+    "GEYSER_ENDPOINT",
+    "GEYSER_X_TOKEN",
+    "GEYSER_RECONNECT_BASE_MS",
+    "GEYSER_RECONNECT_MAX_MS",
+    "GEYSER_WORKER_THREADS",
+    "COMPUTE_UNIT_LIMIT",
+    "COMPUTE_UNIT_PRICE_MICRO_LAMPORTS",
+    "DYNAMIC_PRIORITY_FEE",
+    "DRY_RUN",
+    "MIN_PROFIT_USD",
+    "MAX_REPAY_FRACTION",
+    "LIQUIDATION_PREFILTER",
+    "SIMULATE_BEFORE_SEND",
+    "LIQUIDATION_INTERVAL_MS",
+    "CONTINUOUS_LIQUIDATION",
+    "LIQUIDATION_HEALTH_THRESHOLD",
+    "LIQUIDATION_MAX_CANDIDATES",
+    "MAX_LIQUIDATIONS_PER_CYCLE",
+    "LIQUIDATION_SCAN_BATCH_SIZE",
+    "EXCLUDED_ACCOUNTS",
+    "ALLOWED_GROUPS",
+    "EXCLUDED_AUTHORITIES",
+    "PRICE_MOVE_TRIGGER_BPS",
+    "MAX_CONFIDENCE_BPS",
+    "METRICS_PORT",
+    "MAX_SLOT_STALENESS_SEC",
+    "SERVICE_MAX_RESTARTS",
+    "SERVICE_RESTART_WINDOW_SEC",
+    "LIQUIDATION_COOLDOWN_SEC",
+    "LIQUIDATION_MAX_RETRIES",
+    "LIQUIDATION_RETRY_DEADLINE_MS",
+    "READINESS_LIVE_ORACLES_RATIO",
+    "READINESS_MAX_WAIT_SEC",
+    "RPC_RETRY_MAX_ATTEMPTS",
+    "RPC_RETRY_BASE_DELAY_MS",
+    "RPC_FETCH_CONCURRENCY",
+    "RPC_TIMEOUT_MS",
+    "SUBMISSION_MODE",
+    "JITO_BLOCK_ENGINE_URL",
+    "JITO_TIP_LAMPORTS",
+    "MARGINFI_ACCOUNT_MAX_AGE_SLOTS",
+    "RECONCILE_INTERVAL_SEC",
+    "COMMITMENT",
+    "LAZY_BANK_LOADING",
+    "CACHE_SNAPSHOT_PATH",
+    "NOTIFY_WEBHOOK_URL",
+];
+// The flags that mean true when passed without a value, e.g. --dry-run.
+const CLI_SWITCH_KEYS: &[&str] = &[
+    "DRY_RUN",
+    "CONTINUOUS_LIQUIDATION",
+    "DYNAMIC_PRIORITY_FEE",
+    "LAZY_BANK_LOADING",
+    "LIQUIDATION_PREFILTER",
+    "SIMULATE_BEFORE_SEND",
+];
+const CLI_CONFIG_FILE_ARG: &str = "CONFIG_FILE";
+// The secret and the public key bytes of the WALLET keypair
+const WALLET_KEYPAIR_LEN: usize = 64;
 
 pub struct Config {
     pub wallet: Keypair,
+    pub liquidator_account: Pubkey,
     pub marginfi_program_id: Pubkey,
     pub lut_addresses: Vec<Pubkey>,
     pub stats_interval_sec: u64,
     pub rpc_url: String,
+    /// Where the account updates come from, the Yellowstone Geyser or the RPC WebSocket subscriptions.
+    pub subscriber_mode: SubscriberMode,
+    /// The RPC WebSocket endpoint of the ws subscriber mode, derived from RPC_URL by default.
+    pub ws_url: String,
+    /// The accounts subscribed to on top of the Marginfi program, the Oracles and the Mints, whose updates are logged.
+    pub extra_subscribe_accounts: Vec<Pubkey>,
     pub geyser_endpoint: String,
     pub geyser_x_token: String,
+    pub geyser_reconnect_base_ms: u64,
+    pub geyser_reconnect_max_ms: u64,
+    /// The tokio worker threads receiving the Geyser stream, raise it for the busy mainnet endpoints.
+    pub geyser_worker_threads: usize,
+    pub compute_unit_limit: u32,
+    pub compute_unit_price_micro_lamports: u64,
+    pub dynamic_priority_fee: bool,
+    pub dry_run: bool,
+    pub min_profit_usd: f64,
+    /// The share of each liability repaid by a liquidation at most, 1.0 repays as much as the protocol allows.
+    pub max_repay_fraction: f64,
+    /// Skips the accounts whose cached shortfall is too small to plausibly clear the min profit before preparing them.
+    pub liquidation_prefilter: bool,
+    /// Simulates each liquidation transaction and only sends the ones that succeed. Costs an RPC
+    /// round trip per transaction, which pays off once the priority fees of the reverts add up.
+    pub simulate_before_send: bool,
+    pub liquidation_interval_ms: u64,
+    pub continuous_liquidation: bool,
+    /// The maintenance health ratio at or below which the accounts are considered for liquidation.
+    pub liquidation_health_threshold: f64,
+    /// The most unhealthy accounts processed per liquidation cycle, 0 processes all of them.
+    pub liquidation_max_candidates: usize,
+    /// The liquidations attempted per cycle at most, the rest of the candidates wait for the next one, 0 attempts all of them.
+    pub max_liquidations_per_cycle: usize,
+    /// The candidates processed between the stop checks and the yields of a liquidation cycle.
+    pub liquidation_scan_batch_size: usize,
+    /// The Marginfi accounts never liquidated on top of the liquidator account itself.
+    pub excluded_accounts: Vec<Pubkey>,
+    /// The Marginfi groups whose accounts are liquidated, empty liquidates all of them.
+    pub allowed_groups: Vec<Pubkey>,
+    /// The authorities whose Marginfi accounts are never liquidated, e.g. the partners or the own sub-accounts.
+    pub excluded_authorities: Vec<Pubkey>,
+    /// The Oracle price move in basis points that re-evaluates the accounts borrowing against it
+    /// right away instead of at the next cycle, 0 disables it.
+    pub price_move_trigger_bps: u64,
+    /// The Oracle prices with the confidence interval wider than this many basis points of the
+    /// price are not used until a tighter update arrives, 0 disables it.
+    pub max_confidence_bps: u64,
+    pub metrics_port: u16,
+    pub max_slot_staleness_sec: u64,
+    pub service_max_restarts: u32,
+    pub service_restart_window_sec: u64,
+    pub liquidation_cooldown_sec: u64,
+    /// The reverted liquidation is re-prepared from the fetched account state and resent at most
+    /// this many times, 0 disables it.
+    pub liquidation_max_retries: u32,
+    /// No retries of the reverted liquidation start after this many milliseconds since the first attempt.
+    pub liquidation_retry_deadline_ms: u64,
+    /// The liquidations start once the clock advanced past the cache load and at least this share
+    /// of the Oracles received a live update, 0 only waits for the clock.
+    pub readiness_live_oracles_ratio: f64,
+    /// The liquidations start anyway this many seconds after the startup, 0 waits indefinitely.
+    pub readiness_max_wait_sec: u64,
+    pub rpc_retry: RetryConfig,
+    /// The number of the getMultipleAccounts requests in flight while fetching many accounts.
+    pub rpc_fetch_concurrency: usize,
+    /// The timeout of each RPC request, 30 seconds by default like the Solana RPC client.
+    pub rpc_timeout_ms: u64,
+    pub submission_mode: SubmissionMode,
+    pub jito_block_engine_url: String,
+    pub jito_tip_lamports: u64,
+    pub marginfi_account_max_age_slots: u64,
+    /// Seconds between the re-fetches of the cached Banks that correct the missed Geyser updates, 0 disables them.
+    pub reconcile_interval_sec: u64,
+    /// The commitment of the RPC reads, the transaction confirmations and the Geyser updates.
+    /// Processed reacts to the unhealthy accounts first at the risk of the forks, finalized is the
+    /// safest but lags ~13 seconds behind the competing liquidators.
+    pub commitment: CommitmentLevel,
+    pub lazy_bank_loading: bool,
+    /// The file the Banks, Mints and LUTs are saved to and restored from to skip their bulk fetch on restart.
+    pub cache_snapshot_path: Option<PathBuf>,
+    /// The URL the landed and repeatedly failing liquidations are POSTed to, e.g. a Slack incoming webhook.
+    pub notify_webhook_url: Option<String>,
+}
+
+/// How the liquidation transactions are submitted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubmissionMode {
+    Rpc,
+    Jito,
+}
+
+impl FromStr for SubmissionMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        match value.to_lowercase().as_str() {
+            "rpc" => Ok(SubmissionMode::Rpc),
+            "jito" => Ok(SubmissionMode::Jito),
+            _ => Err(anyhow::anyhow!("Unknown submission mode {}", value)),
+        }
+    }
+}
+
+/// The source of the account updates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubscriberMode {
+    Geyser,
+    Ws,
+}
+
+impl FromStr for SubscriberMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        match value.to_lowercase().as_str() {
+            "geyser" => Ok(SubscriberMode::Geyser),
+            "ws" => Ok(SubscriberMode::Ws),
+            _ => Err(anyhow::anyhow!("Unknown subscriber mode {}", value)),
+        }
+    }
+}
+
+/// The retries of the RPC reads failing with the transient errors.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryConfig {
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
 }
 
+const DEFAULT_GEYSER_RECONNECT_BASE_MS: u64 = 500;
+const DEFAULT_GEYSER_RECONNECT_MAX_MS: u64 = 30_000;
+const DEFAULT_GEYSER_WORKER_THREADS: usize = 2;
+const DEFAULT_COMPUTE_UNIT_LIMIT: u32 = 200_000;
+const DEFAULT_COMPUTE_UNIT_PRICE_MICRO_LAMPORTS: u64 = 0;
+const DEFAULT_MIN_PROFIT_USD: f64 = 1.0;
+const DEFAULT_MAX_REPAY_FRACTION: f64 = 1.0;
+const DEFAULT_LIQUIDATION_INTERVAL_MS: u64 = 5_000;
+const DEFAULT_LIQUIDATION_HEALTH_THRESHOLD: f64 = 0.0;
+const DEFAULT_LIQUIDATION_MAX_CANDIDATES: usize = 0;
+const DEFAULT_MAX_LIQUIDATIONS_PER_CYCLE: usize = 0;
+const DEFAULT_LIQUIDATION_SCAN_BATCH_SIZE: usize = 16;
+const DEFAULT_PRICE_MOVE_TRIGGER_BPS: u64 = 0;
+const DEFAULT_MAX_CONFIDENCE_BPS: u64 = 0;
+const DEFAULT_METRICS_PORT: u16 = 9090;
+const DEFAULT_MAX_SLOT_STALENESS_SEC: u64 = 30;
+const DEFAULT_SERVICE_MAX_RESTARTS: u32 = 5;
+const DEFAULT_SERVICE_RESTART_WINDOW_SEC: u64 = 600;
+const DEFAULT_LIQUIDATION_COOLDOWN_SEC: u64 = 30;
+const DEFAULT_LIQUIDATION_MAX_RETRIES: u32 = 2;
+const DEFAULT_LIQUIDATION_RETRY_DEADLINE_MS: u64 = 10_000;
+const DEFAULT_READINESS_LIVE_ORACLES_RATIO: f64 = 0.8;
+const DEFAULT_READINESS_MAX_WAIT_SEC: u64 = 300;
+const DEFAULT_RPC_RETRY_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_RPC_RETRY_BASE_DELAY_MS: u64 = 200;
+const DEFAULT_RPC_FETCH_CONCURRENCY: usize = 4;
+const DEFAULT_RPC_TIMEOUT_MS: u64 = 30_000;
+const DEFAULT_JITO_BLOCK_ENGINE_URL: &str = "https://mainnet.block-engine.jito.wtf";
+const DEFAULT_JITO_TIP_LAMPORTS: u64 = 10_000;
+const DEFAULT_MARGINFI_ACCOUNT_MAX_AGE_SLOTS: u64 = 0;
+const DEFAULT_RECONCILE_INTERVAL_SEC: u64 = 300;
+const DEFAULT_COMMITMENT: CommitmentLevel = CommitmentLevel::Confirmed;
+
 impl Config {
+    /// Loads the config file named by CONFIG_FILE if set, otherwise the environment variables alone.
     pub fn new() -> anyhow::Result<Self> {
-        let wallet_str = std::env::var("WALLET").expect("WALLET environment variable is not set");
-        let wallet_bytes: Vec<u8> = serde_json::from_str(&wallet_str)
-            .map_err(|e| anyhow::anyhow!("Invalid WALLET format (JSON): {}", e))?;
-        let wallet = Keypair::from_bytes(&wallet_bytes)
-            .map_err(|e| anyhow::anyhow!("Invalid WALLET format (Keypair bytes): {}", e))?;
-
-        let marginfi_program_id = Pubkey::from_str(
-            &std::env::var("MARGINFI_PROGRAM_ID")
-                .expect("MARGINFI_PROGRAM_ID environment variable is not set"),
-        )
-        .expect("Invalid MARGINFI_PROGRAM_ID Pubkey");
+        match std::env::var("CONFIG_FILE") {
+            Ok(path) => Self::from_file(Path::new(&path)),
+            Err(_) => Self::from_source(&ConfigSource::default()),
+        }
+    }
 
-        let lut_addresses: Vec<Pubkey> = std::env::var("LUT_ADDRESSES")
-            .expect("LUT_ADDRESSES environment variable is not set")
-            .split(',')
-            .map(|s| {
-                Pubkey::from_str(s.trim())
-                    .map_err(|_| anyhow::anyhow!("Invalid LUT_ADDRESSES Pubkey: {}", s.trim()))
+    /// Loads the TOML config file, the environment variables override its values.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        Self::from_source(&ConfigSource::from_file(path)?)
+    }
+
+    /// Loads the command line flags on top of the environment variables and the config file given
+    /// by --config or CONFIG_FILE. Without flags it is the same as Config::new.
+    pub fn from_args<I, T>(args: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let mut cli_values = parse_cli_values(args)?;
+        let config_file = cli_values
+            .remove(CLI_CONFIG_FILE_ARG)
+            .or_else(|| std::env::var(CLI_CONFIG_FILE_ARG).ok());
+        let mut source = match config_file {
+            Some(path) => ConfigSource::from_file(Path::new(&path))?,
+            None => ConfigSource::default(),
+        };
+        source.cli_values = cli_values;
+        Self::from_source(&source)
+    }
+
+    /// Parses every setting before failing, so that all the missing and invalid ones are reported at once.
+    fn from_source(source: &ConfigSource) -> anyhow::Result<Self> {
+        let mut parser = ConfigParser::new(source);
+
+        let wallet = parse_wallet(source, &mut parser);
+
+        let liquidator_account = parser.pubkey("LIQUIDATOR_ACCOUNT");
+        let marginfi_program_id = parser.pubkey("MARGINFI_PROGRAM_ID");
+
+        let lut_addresses: Vec<Pubkey> = parser
+            .required("LUT_ADDRESSES")
+            .map(|lut_addresses| {
+                lut_addresses
+                    .split(',')
+                    .filter_map(|s| {
+                        Pubkey::from_str(s.trim())
+                            .map_err(|_| {
+                                parser.error(format!("Invalid LUT_ADDRESSES Pubkey: {}", s.trim()))
+                            })
+                            .ok()
+                    })
+                    .collect()
             })
-            .collect::<Result<_, _>>()?;
+            .unwrap_or_default();
+
+        let stats_interval_sec: u64 = parser
+            .required("STATS_INTERVAL_SEC")
+            .and_then(|value| parser.parse("STATS_INTERVAL_SEC", &value, "must be a number"))
+            .unwrap_or_default();
+
+        let rpc_url = parser.required("RPC_URL").unwrap_or_default();
+
+        let subscriber_mode = parser.optional_with(
+            "SUBSCRIBER_MODE",
+            SubscriberMode::Geyser,
+            "must be geyser or ws",
+        );
+        let ws_url = source
+            .var("WS_URL")
+            .unwrap_or_else(|| ws_url_from_rpc_url(&rpc_url));
+
+        let extra_subscribe_accounts = parser.optional_pubkeys("EXTRA_SUBSCRIBE_ACCOUNTS");
+
+        // The Geyser endpoint is only needed when subscribing through it
+        let (geyser_endpoint, geyser_x_token) = match subscriber_mode {
+            SubscriberMode::Geyser => (
+                parser.required("GEYSER_ENDPOINT").unwrap_or_default(),
+                parser.required("GEYSER_X_TOKEN").unwrap_or_default(),
+            ),
+            SubscriberMode::Ws => (
+                source.var("GEYSER_ENDPOINT").unwrap_or_default(),
+                source.var("GEYSER_X_TOKEN").unwrap_or_default(),
+            ),
+        };
+
+        let geyser_reconnect_base_ms =
+            parser.optional("GEYSER_RECONNECT_BASE_MS", DEFAULT_GEYSER_RECONNECT_BASE_MS);
+        let geyser_reconnect_max_ms =
+            parser.optional("GEYSER_RECONNECT_MAX_MS", DEFAULT_GEYSER_RECONNECT_MAX_MS);
+
+        let geyser_worker_threads =
+            parser.optional("GEYSER_WORKER_THREADS", DEFAULT_GEYSER_WORKER_THREADS);
+        if geyser_worker_threads == 0 {
+            parser.error("Invalid GEYSER_WORKER_THREADS value, must be at least 1".into());
+        }
+
+        let compute_unit_limit = parser.optional("COMPUTE_UNIT_LIMIT", DEFAULT_COMPUTE_UNIT_LIMIT);
+        let compute_unit_price_micro_lamports = parser.optional(
+            "COMPUTE_UNIT_PRICE_MICRO_LAMPORTS",
+            DEFAULT_COMPUTE_UNIT_PRICE_MICRO_LAMPORTS,
+        );
+        let dynamic_priority_fee = parser.optional_bool("DYNAMIC_PRIORITY_FEE", false);
+
+        let dry_run = parser.optional_bool("DRY_RUN", false);
+
+        let min_profit_usd = parser.optional("MIN_PROFIT_USD", DEFAULT_MIN_PROFIT_USD);
+        if !min_profit_usd.is_finite() || min_profit_usd < 0.0 {
+            parser.error("Invalid MIN_PROFIT_USD value, must be a non-negative number".into());
+        }
+
+        let max_repay_fraction = parser.optional("MAX_REPAY_FRACTION", DEFAULT_MAX_REPAY_FRACTION);
+        if !(max_repay_fraction > 0.0 && max_repay_fraction <= 1.0) {
+            parser.error("Invalid MAX_REPAY_FRACTION value, must be above 0 and up to 1".into());
+        }
+        let liquidation_prefilter = parser.optional_bool("LIQUIDATION_PREFILTER", false);
+        let simulate_before_send = parser.optional_bool("SIMULATE_BEFORE_SEND", false);
+
+        let liquidation_interval_ms =
+            parser.optional("LIQUIDATION_INTERVAL_MS", DEFAULT_LIQUIDATION_INTERVAL_MS);
+        let continuous_liquidation = parser.optional_bool("CONTINUOUS_LIQUIDATION", false);
+
+        let liquidation_health_threshold = parser.optional(
+            "LIQUIDATION_HEALTH_THRESHOLD",
+            DEFAULT_LIQUIDATION_HEALTH_THRESHOLD,
+        );
+        if !liquidation_health_threshold.is_finite() || liquidation_health_threshold > 1.0 {
+            parser.error(
+                "Invalid LIQUIDATION_HEALTH_THRESHOLD value, must be a health ratio up to 1".into(),
+            );
+        }
+
+        let liquidation_max_candidates = parser.optional(
+            "LIQUIDATION_MAX_CANDIDATES",
+            DEFAULT_LIQUIDATION_MAX_CANDIDATES,
+        );
+        let max_liquidations_per_cycle = parser.optional(
+            "MAX_LIQUIDATIONS_PER_CYCLE",
+            DEFAULT_MAX_LIQUIDATIONS_PER_CYCLE,
+        );
+
+        let liquidation_scan_batch_size = parser.optional(
+            "LIQUIDATION_SCAN_BATCH_SIZE",
+            DEFAULT_LIQUIDATION_SCAN_BATCH_SIZE,
+        );
+        if liquidation_scan_batch_size == 0 {
+            parser.error("Invalid LIQUIDATION_SCAN_BATCH_SIZE value, must be at least 1".into());
+        }
+
+        let excluded_accounts = parser.optional_pubkeys("EXCLUDED_ACCOUNTS");
+
+        let allowed_groups = parser.optional_pubkeys("ALLOWED_GROUPS");
+
+        let excluded_authorities = parser.optional_pubkeys("EXCLUDED_AUTHORITIES");
+
+        let price_move_trigger_bps =
+            parser.optional("PRICE_MOVE_TRIGGER_BPS", DEFAULT_PRICE_MOVE_TRIGGER_BPS);
+
+        let max_confidence_bps = parser.optional("MAX_CONFIDENCE_BPS", DEFAULT_MAX_CONFIDENCE_BPS);
+
+        let metrics_port = parser.optional("METRICS_PORT", DEFAULT_METRICS_PORT);
+
+        let max_slot_staleness_sec =
+            parser.optional("MAX_SLOT_STALENESS_SEC", DEFAULT_MAX_SLOT_STALENESS_SEC);
 
-        let stats_interval_sec = std::env::var("STATS_INTERVAL_SEC")
-            .expect("STATS_INTERVAL_SEC environment variable is not set")
-            .parse::<u64>()
-            .expect("Invalid STATS_INTERVAL_SEC value, must be a number");
+        let service_max_restarts =
+            parser.optional("SERVICE_MAX_RESTARTS", DEFAULT_SERVICE_MAX_RESTARTS);
 
-        let rpc_url = std::env::var("RPC_URL").expect("RPC_URL environment variable is not set");
+        let service_restart_window_sec = parser.optional(
+            "SERVICE_RESTART_WINDOW_SEC",
+            DEFAULT_SERVICE_RESTART_WINDOW_SEC,
+        );
+
+        let liquidation_cooldown_sec =
+            parser.optional("LIQUIDATION_COOLDOWN_SEC", DEFAULT_LIQUIDATION_COOLDOWN_SEC);
+
+        let liquidation_max_retries =
+            parser.optional("LIQUIDATION_MAX_RETRIES", DEFAULT_LIQUIDATION_MAX_RETRIES);
+
+        let liquidation_retry_deadline_ms = parser.optional(
+            "LIQUIDATION_RETRY_DEADLINE_MS",
+            DEFAULT_LIQUIDATION_RETRY_DEADLINE_MS,
+        );
+
+        let readiness_live_oracles_ratio = parser.optional(
+            "READINESS_LIVE_ORACLES_RATIO",
+            DEFAULT_READINESS_LIVE_ORACLES_RATIO,
+        );
+        if !(0.0..=1.0).contains(&readiness_live_oracles_ratio) {
+            parser.error(
+                "Invalid READINESS_LIVE_ORACLES_RATIO value, must be a ratio from 0 to 1".into(),
+            );
+        }
+
+        let readiness_max_wait_sec =
+            parser.optional("READINESS_MAX_WAIT_SEC", DEFAULT_READINESS_MAX_WAIT_SEC);
+
+        let rpc_retry = RetryConfig {
+            max_attempts: parser.optional("RPC_RETRY_MAX_ATTEMPTS", DEFAULT_RPC_RETRY_MAX_ATTEMPTS),
+            base_delay_ms: parser
+                .optional("RPC_RETRY_BASE_DELAY_MS", DEFAULT_RPC_RETRY_BASE_DELAY_MS),
+        };
+        if rpc_retry.max_attempts == 0 {
+            parser.error("Invalid RPC_RETRY_MAX_ATTEMPTS value, must be at least 1".into());
+        }
+
+        let rpc_fetch_concurrency =
+            parser.optional("RPC_FETCH_CONCURRENCY", DEFAULT_RPC_FETCH_CONCURRENCY);
+        if rpc_fetch_concurrency == 0 {
+            parser.error("Invalid RPC_FETCH_CONCURRENCY value, must be at least 1".into());
+        }
+
+        let rpc_timeout_ms = parser.optional("RPC_TIMEOUT_MS", DEFAULT_RPC_TIMEOUT_MS);
+        if rpc_timeout_ms == 0 {
+            parser.error("Invalid RPC_TIMEOUT_MS value, must be at least 1".into());
+        }
+
+        let submission_mode = parser.optional_with(
+            "SUBMISSION_MODE",
+            SubmissionMode::Rpc,
+            "must be rpc or jito",
+        );
+        let jito_block_engine_url = source
+            .var("JITO_BLOCK_ENGINE_URL")
+            .unwrap_or_else(|| DEFAULT_JITO_BLOCK_ENGINE_URL.to_string());
+        let jito_tip_lamports = parser.optional("JITO_TIP_LAMPORTS", DEFAULT_JITO_TIP_LAMPORTS);
+
+        let marginfi_account_max_age_slots = parser.optional(
+            "MARGINFI_ACCOUNT_MAX_AGE_SLOTS",
+            DEFAULT_MARGINFI_ACCOUNT_MAX_AGE_SLOTS,
+        );
+
+        let reconcile_interval_sec =
+            parser.optional("RECONCILE_INTERVAL_SEC", DEFAULT_RECONCILE_INTERVAL_SEC);
+
+        let commitment = parser.optional_with(
+            "COMMITMENT",
+            DEFAULT_COMMITMENT,
+            "must be processed, confirmed or finalized",
+        );
+
+        let lazy_bank_loading = parser.optional_bool("LAZY_BANK_LOADING", false);
+
+        let cache_snapshot_path = source.var("CACHE_SNAPSHOT_PATH").map(PathBuf::from);
 
-        let geyser_endpoint = std::env::var("GEYSER_ENDPOINT")
-            .expect("GEYSER_ENDPOINT environment variable is not set");
-        let geyser_x_token = std::env::var("GEYSER_X_TOKEN")
-            .expect("GEYSER_X_TOKEN environment variable is not set");
+        let notify_webhook_url = source.var("NOTIFY_WEBHOOK_URL");
+
+        parser.finish()?;
+        // Only reachable with the wallet parsed, its errors are reported by the parser
+        let wallet = wallet.ok_or_else(|| anyhow::anyhow!("The wallet is not configured"))?;
 
         Ok(Config {
             wallet,
+            liquidator_account,
             marginfi_program_id,
             lut_addresses,
             stats_interval_sec,
             rpc_url,
+            subscriber_mode,
+            ws_url,
+            extra_subscribe_accounts,
             geyser_endpoint,
             geyser_x_token,
+            geyser_reconnect_base_ms,
+            geyser_reconnect_max_ms,
+            geyser_worker_threads,
+            compute_unit_limit,
+            compute_unit_price_micro_lamports,
+            dynamic_priority_fee,
+            dry_run,
+            min_profit_usd,
+            max_repay_fraction,
+            liquidation_prefilter,
+            simulate_before_send,
+            liquidation_interval_ms,
+            continuous_liquidation,
+            liquidation_health_threshold,
+            liquidation_max_candidates,
+            max_liquidations_per_cycle,
+            liquidation_scan_batch_size,
+            excluded_accounts,
+            allowed_groups,
+            excluded_authorities,
+            price_move_trigger_bps,
+            max_confidence_bps,
+            metrics_port,
+            max_slot_staleness_sec,
+            service_max_restarts,
+            service_restart_window_sec,
+            liquidation_cooldown_sec,
+            liquidation_max_retries,
+            liquidation_retry_deadline_ms,
+            readiness_live_oracles_ratio,
+            readiness_max_wait_sec,
+            rpc_retry,
+            rpc_fetch_concurrency,
+            rpc_timeout_ms,
+            submission_mode,
+            jito_block_engine_url,
+            jito_tip_lamports,
+            marginfi_account_max_age_slots,
+            reconcile_interval_sec,
+            commitment,
+            lazy_bank_loading,
+            cache_snapshot_path,
+            notify_webhook_url,
+        })
+    }
+}
+
+/// The WebSocket endpoint served next to the HTTP one, as the Solana RPC nodes do.
+fn ws_url_from_rpc_url(rpc_url: &str) -> String {
+    if let Some(rest) = rpc_url.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = rpc_url.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        rpc_url.to_string()
+    }
+}
+
+/// Loads the keypair file of WALLET_PATH, or the inlined WALLET bytes that end up in the process environment.
+/// The errors never include the key bytes.
+fn parse_wallet(source: &ConfigSource, parser: &mut ConfigParser) -> Option<Keypair> {
+    let wallet_str = source.var("WALLET");
+    if let Some(wallet_path) = source.var("WALLET_PATH") {
+        if wallet_str.is_some() {
+            warn!("Both WALLET and WALLET_PATH are set, using the WALLET_PATH keypair file.");
+        }
+        return read_keypair_file(&wallet_path)
+            .map_err(|e| {
+                parser.error(format!(
+                    "Failed to read the WALLET_PATH keypair file {}: {}",
+                    wallet_path, e
+                ))
+            })
+            .ok();
+    }
+
+    let Some(wallet_str) = wallet_str else {
+        parser.error("WALLET or WALLET_PATH environment variable is not set".into());
+        return None;
+    };
+    // E.g. the WALLET= line left blank in the env file
+    if wallet_str.trim().is_empty() {
+        parser.error(
+            "WALLET environment variable is empty, expected the JSON array of the keypair bytes"
+                .into(),
+        );
+        return None;
+    }
+    serde_json::from_str::<Vec<u8>>(&wallet_str)
+        .map_err(|e| format!("Invalid WALLET format (JSON): {}", e))
+        .and_then(|wallet_bytes| {
+            if wallet_bytes.len() != WALLET_KEYPAIR_LEN {
+                return Err(format!(
+                    "Invalid WALLET format (Keypair bytes): expected {} bytes, got {}",
+                    WALLET_KEYPAIR_LEN,
+                    wallet_bytes.len()
+                ));
+            }
+            Keypair::from_bytes(&wallet_bytes)
+                .map_err(|e| format!("Invalid WALLET format (Keypair bytes): {}", e))
+        })
+        .map_err(|e| parser.error(e))
+        .ok()
+}
+
+/// Parses the command line flags into the values keyed by the environment variable names.
+fn parse_cli_values<I, T>(args: I) -> anyhow::Result<HashMap<String, String>>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let mut command = Command::new("mary")
+        .about("Nextgen Marginfi Liquidator")
+        .arg(
+            Arg::new(CLI_CONFIG_FILE_ARG)
+                .long("config")
+                .value_name("FILE")
+                .help("The TOML config file, CONFIG_FILE"),
+        );
+    for key in CLI_KEYS {
+        let mut arg = Arg::new(*key)
+            .long(key.to_lowercase().replace('_', "-"))
+            .value_name("VALUE")
+            .help(format!("Overrides {}", key));
+        if CLI_SWITCH_KEYS.contains(key) {
+            arg = arg
+                .num_args(0..=1)
+                .default_missing_value("true")
+                .value_name("BOOL");
+        }
+        command = command.arg(arg.action(ArgAction::Set));
+    }
+
+    let matches = match command.try_get_matches_from(args) {
+        Ok(matches) => matches,
+        Err(err)
+            if matches!(
+                err.kind(),
+                ErrorKind::DisplayHelp | ErrorKind::DisplayVersion
+            ) =>
+        {
+            err.exit()
+        }
+        Err(err) => return Err(anyhow::anyhow!("Invalid command line arguments: {}", err)),
+    };
+
+    Ok(std::iter::once(CLI_CONFIG_FILE_ARG)
+        .chain(CLI_KEYS.iter().copied())
+        .filter_map(|key| {
+            matches
+                .get_one::<String>(key)
+                .map(|value| (key.to_string(), value.clone()))
+        })
+        .collect())
+}
+
+/// The values of the command line flags keyed by the variable names and of the optional config
+/// file keyed by the lowercase variable names.
+#[derive(Default)]
+struct ConfigSource {
+    cli_values: HashMap<String, String>,
+    file_values: HashMap<String, String>,
+}
+
+impl ConfigSource {
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            anyhow::anyhow!("Failed to read the config file {}: {}", path.display(), e)
+        })?;
+        let table: toml::Table = toml::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("Invalid config file {}: {}", path.display(), e))?;
+
+        let file_values = table
+            .into_iter()
+            .map(|(key, value)| {
+                let value = toml_value_to_string(&key, value)?;
+                Ok((key.to_lowercase(), value))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            cli_values: HashMap::new(),
+            file_values,
         })
     }
+
+    /// The command line flag takes precedence over the environment variable, which takes
+    /// precedence over the config file value.
+    fn var(&self, key: &str) -> Option<String> {
+        self.cli_values
+            .get(key)
+            .cloned()
+            .or_else(|| std::env::var(key).ok())
+            .or_else(|| self.file_values.get(&key.to_lowercase()).cloned())
+    }
+}
+
+/// Collects the missing and invalid settings instead of failing on the first one.
+struct ConfigParser<'a> {
+    source: &'a ConfigSource,
+    errors: Vec<String>,
+}
+
+impl<'a> ConfigParser<'a> {
+    fn new(source: &'a ConfigSource) -> Self {
+        Self {
+            source,
+            errors: Vec::new(),
+        }
+    }
+
+    fn error(&mut self, error: String) {
+        self.errors.push(error);
+    }
+
+    fn required(&mut self, key: &str) -> Option<String> {
+        let value = self.source.var(key);
+        if value.is_none() {
+            self.error(format!("{} environment variable is not set", key));
+        }
+        value
+    }
+
+    fn pubkey(&mut self, key: &str) -> Pubkey {
+        self.required(key)
+            .and_then(|value| {
+                Pubkey::from_str(&value)
+                    .map_err(|_| self.error(format!("Invalid {} Pubkey", key)))
+                    .ok()
+            })
+            .unwrap_or_default()
+    }
+
+    fn parse<T: FromStr>(&mut self, key: &str, value: &str, expected: &str) -> Option<T> {
+        value
+            .parse::<T>()
+            .map_err(|_| self.error(format!("Invalid {} value, {}", key, expected)))
+            .ok()
+    }
+
+    fn optional_with<T: FromStr>(&mut self, key: &str, default: T, expected: &str) -> T {
+        self.source
+            .var(key)
+            .and_then(|value| self.parse(key, &value, expected))
+            .unwrap_or(default)
+    }
+
+    fn optional<T: FromStr>(&mut self, key: &str, default: T) -> T {
+        self.optional_with(key, default, "must be a number")
+    }
+
+    fn optional_bool(&mut self, key: &str, default: bool) -> bool {
+        self.optional_with(key, default, "must be true or false")
+    }
+
+    fn optional_pubkeys(&mut self, key: &str) -> Vec<Pubkey> {
+        let Some(value) = self.source.var(key) else {
+            return Vec::new();
+        };
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| {
+                Pubkey::from_str(s)
+                    .map_err(|_| self.error(format!("Invalid {} Pubkey: {}", key, s)))
+                    .ok()
+            })
+            .collect()
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow::anyhow!(
+            "Invalid configuration, {} error(s):\n- {}",
+            self.errors.len(),
+            self.errors.join("\n- ")
+        ))
+    }
+}
+
+fn format_pubkeys(pubkeys: &[Pubkey]) -> String {
+    pubkeys
+        .iter()
+        .map(|pubkey| pubkey.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Converts the config file value to the environment variable format, the arrays become comma separated.
+fn toml_value_to_string(key: &str, value: toml::Value) -> anyhow::Result<String> {
+    match value {
+        toml::Value::String(value) => Ok(value),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        toml::Value::Array(values) => Ok(values
+            .into_iter()
+            .map(|value| toml_value_to_string(key, value))
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .join(",")),
+        _ => Err(anyhow::anyhow!(
+            "Unsupported {} value type in the config file",
+            key
+        )),
+    }
 }
 
 impl std::fmt::Display for Config {
@@ -65,19 +833,112 @@ impl std::fmt::Display for Config {
             f,
             "Config: \n\
             - wallet: {} \n\
+            - liquidator_account: {} \n\
             - marginfi_program_id: {} \n\
             - lut_addresses: [{}] \n\
             - stats_interval_sec: {} \n\
-            - geyser_endpoint: {}",
+            - subscriber_mode: {:?} \n\
+            - extra_subscribe_accounts: [{}] \n\
+            - geyser_endpoint: {} \n\
+            - geyser_reconnect_base_ms: {} \n\
+            - geyser_reconnect_max_ms: {} \n\
+            - geyser_worker_threads: {} \n\
+            - compute_unit_limit: {} \n\
+            - compute_unit_price_micro_lamports: {} \n\
+            - dynamic_priority_fee: {} \n\
+            - dry_run: {} \n\
+            - min_profit_usd: {} \n\
+            - max_repay_fraction: {} \n\
+            - liquidation_prefilter: {} \n\
+            - simulate_before_send: {} \n\
+            - liquidation_interval_ms: {} \n\
+            - continuous_liquidation: {} \n\
+            - liquidation_health_threshold: {} \n\
+            - liquidation_max_candidates: {} \n\
+            - max_liquidations_per_cycle: {} \n\
+            - liquidation_scan_batch_size: {} \n\
+            - excluded_accounts: [{}] \n\
+            - allowed_groups: [{}] \n\
+            - excluded_authorities: [{}] \n\
+            - price_move_trigger_bps: {} \n\
+            - max_confidence_bps: {} \n\
+            - metrics_port: {} \n\
+            - max_slot_staleness_sec: {} \n\
+            - service_max_restarts: {} \n\
+            - service_restart_window_sec: {} \n\
+            - liquidation_cooldown_sec: {} \n\
+            - liquidation_max_retries: {} \n\
+            - liquidation_retry_deadline_ms: {} \n\
+            - readiness_live_oracles_ratio: {} \n\
+            - readiness_max_wait_sec: {} \n\
+            - rpc_retry: {:?} \n\
+            - rpc_fetch_concurrency: {} \n\
+            - rpc_timeout_ms: {} \n\
+            - submission_mode: {:?} \n\
+            - jito_block_engine_url: {} \n\
+            - jito_tip_lamports: {} \n\
+            - marginfi_account_max_age_slots: {} \n\
+            - reconcile_interval_sec: {} \n\
+            - commitment: {:?} \n\
+            - lazy_bank_loading: {} \n\
+            - cache_snapshot_path: {:?} \n\
+            - notify_webhook_url: {}",
             self.wallet.pubkey(),
+            self.liquidator_account,
             self.marginfi_program_id,
-            self.lut_addresses
-                .iter()
-                .map(|addr| addr.to_string())
-                .collect::<Vec<_>>()
-                .join(", "),
+            format_pubkeys(&self.lut_addresses),
             self.stats_interval_sec,
-            self.geyser_endpoint
+            self.subscriber_mode,
+            format_pubkeys(&self.extra_subscribe_accounts),
+            self.geyser_endpoint,
+            self.geyser_reconnect_base_ms,
+            self.geyser_reconnect_max_ms,
+            self.geyser_worker_threads,
+            self.compute_unit_limit,
+            self.compute_unit_price_micro_lamports,
+            self.dynamic_priority_fee,
+            self.dry_run,
+            self.min_profit_usd,
+            self.max_repay_fraction,
+            self.liquidation_prefilter,
+            self.simulate_before_send,
+            self.liquidation_interval_ms,
+            self.continuous_liquidation,
+            self.liquidation_health_threshold,
+            self.liquidation_max_candidates,
+            self.max_liquidations_per_cycle,
+            self.liquidation_scan_batch_size,
+            format_pubkeys(&self.excluded_accounts),
+            format_pubkeys(&self.allowed_groups),
+            format_pubkeys(&self.excluded_authorities),
+            self.price_move_trigger_bps,
+            self.max_confidence_bps,
+            self.metrics_port,
+            self.max_slot_staleness_sec,
+            self.service_max_restarts,
+            self.service_restart_window_sec,
+            self.liquidation_cooldown_sec,
+            self.liquidation_max_retries,
+            self.liquidation_retry_deadline_ms,
+            self.readiness_live_oracles_ratio,
+            self.readiness_max_wait_sec,
+            self.rpc_retry,
+            self.rpc_fetch_concurrency,
+            self.rpc_timeout_ms,
+            self.submission_mode,
+            self.jito_block_engine_url,
+            self.jito_tip_lamports,
+            self.marginfi_account_max_age_slots,
+            self.reconcile_interval_sec,
+            self.commitment,
+            self.lazy_bank_loading,
+            self.cache_snapshot_path,
+            // The webhook URLs embed their credentials
+            if self.notify_webhook_url.is_some() {
+                "<set>"
+            } else {
+                "<unset>"
+            }
         )
     }
 }
@@ -86,21 +947,59 @@ impl std::fmt::Display for Config {
 pub mod test_util {
     use std::env;
 
-    use solana_sdk::{pubkey::Pubkey, signature::Keypair};
+    use solana_sdk::{commitment_config::CommitmentLevel, pubkey::Pubkey, signature::Keypair};
 
-    use crate::config::Config;
+    use crate::config::{Config, RetryConfig, SubmissionMode, SubscriberMode};
 
+    pub const TEST_LIQUIDATOR_ACCOUNT: &str = "SysvarC1ock11111111111111111111111111111111";
     pub const TEST_MARGINFI_PROGRAM_ID: &str = "11111111111111111111111111111111";
     pub const TEST_STATS_INTERVAL_SEC: &str = "60";
     pub const TEST_RPC_URL: &str = "http://dummy_rpc_url";
     pub const TEST_GEYSER_ENDPOINT: &str = "http://dummy_geyser_endpoint";
     pub const TEST_GEYSER_X_TOKEN: &str = "dummy_x_token";
+    pub const TEST_GEYSER_RECONNECT_BASE_MS: &str = "250";
+    pub const TEST_GEYSER_RECONNECT_MAX_MS: &str = "10000";
+    pub const TEST_GEYSER_WORKER_THREADS: &str = "4";
+    pub const TEST_COMPUTE_UNIT_LIMIT: &str = "400000";
+    pub const TEST_COMPUTE_UNIT_PRICE_MICRO_LAMPORTS: &str = "1000";
+    pub const TEST_MIN_PROFIT_USD: &str = "2.5";
+    pub const TEST_MAX_REPAY_FRACTION: &str = "0.5";
+    pub const TEST_LIQUIDATION_HEALTH_THRESHOLD: &str = "0.05";
+    pub const TEST_LIQUIDATION_MAX_CANDIDATES: &str = "50";
+    pub const TEST_MAX_LIQUIDATIONS_PER_CYCLE: &str = "5";
+    pub const TEST_LIQUIDATION_SCAN_BATCH_SIZE: &str = "8";
+    pub const TEST_PRICE_MOVE_TRIGGER_BPS: &str = "100";
+    pub const TEST_MAX_CONFIDENCE_BPS: &str = "250";
+    pub const TEST_LIQUIDATION_INTERVAL_MS: &str = "1500";
+    pub const TEST_METRICS_PORT: &str = "9191";
+    pub const TEST_MAX_SLOT_STALENESS_SEC: &str = "15";
+    pub const TEST_SERVICE_MAX_RESTARTS: &str = "3";
+    pub const TEST_SERVICE_RESTART_WINDOW_SEC: &str = "120";
+    pub const TEST_LIQUIDATION_COOLDOWN_SEC: &str = "45";
+    pub const TEST_LIQUIDATION_MAX_RETRIES: &str = "1";
+    pub const TEST_LIQUIDATION_RETRY_DEADLINE_MS: &str = "3000";
+    pub const TEST_READINESS_LIVE_ORACLES_RATIO: &str = "0.5";
+    pub const TEST_READINESS_MAX_WAIT_SEC: &str = "60";
+    pub const TEST_RPC_RETRY_MAX_ATTEMPTS: &str = "4";
+    pub const TEST_RPC_RETRY_BASE_DELAY_MS: &str = "50";
+    pub const TEST_RPC_FETCH_CONCURRENCY: &str = "8";
+    pub const TEST_RPC_TIMEOUT_MS: &str = "15000";
+    pub const TEST_JITO_BLOCK_ENGINE_URL: &str = "http://dummy_block_engine_url";
+    pub const TEST_JITO_TIP_LAMPORTS: &str = "20000";
+    pub const TEST_MARGINFI_ACCOUNT_MAX_AGE_SLOTS: &str = "432000";
+    pub const TEST_RECONCILE_INTERVAL_SEC: &str = "600";
+    pub const TEST_COMMITMENT: &str = "processed";
 
     pub fn set_test_env() {
+        env::remove_var("CONFIG_FILE");
+        env::remove_var("WALLET_PATH");
+        env::remove_var("CACHE_SNAPSHOT_PATH");
+        env::remove_var("NOTIFY_WEBHOOK_URL");
         env::set_var(
             "WALLET",
             serde_json::to_string(&Keypair::new().to_bytes().to_vec()).unwrap(),
         );
+        env::set_var("LIQUIDATOR_ACCOUNT", TEST_LIQUIDATOR_ACCOUNT);
         env::set_var("MARGINFI_PROGRAM_ID", TEST_MARGINFI_PROGRAM_ID);
         env::set_var(
             "LUT_ADDRESSES",
@@ -112,8 +1011,80 @@ pub mod test_util {
         );
         env::set_var("STATS_INTERVAL_SEC", TEST_STATS_INTERVAL_SEC);
         env::set_var("RPC_URL", TEST_RPC_URL);
+        env::remove_var("SUBSCRIBER_MODE");
+        env::remove_var("WS_URL");
+        env::remove_var("EXTRA_SUBSCRIBE_ACCOUNTS");
+        env::remove_var("EXCLUDED_ACCOUNTS");
+        env::remove_var("ALLOWED_GROUPS");
+        env::remove_var("EXCLUDED_AUTHORITIES");
         env::set_var("GEYSER_ENDPOINT", TEST_GEYSER_ENDPOINT);
         env::set_var("GEYSER_X_TOKEN", TEST_GEYSER_X_TOKEN);
+        env::set_var("GEYSER_RECONNECT_BASE_MS", TEST_GEYSER_RECONNECT_BASE_MS);
+        env::set_var("GEYSER_RECONNECT_MAX_MS", TEST_GEYSER_RECONNECT_MAX_MS);
+        env::set_var("GEYSER_WORKER_THREADS", TEST_GEYSER_WORKER_THREADS);
+        env::set_var("COMPUTE_UNIT_LIMIT", TEST_COMPUTE_UNIT_LIMIT);
+        env::set_var(
+            "COMPUTE_UNIT_PRICE_MICRO_LAMPORTS",
+            TEST_COMPUTE_UNIT_PRICE_MICRO_LAMPORTS,
+        );
+        env::set_var("DYNAMIC_PRIORITY_FEE", "false");
+        env::set_var("DRY_RUN", "false");
+        env::set_var("MIN_PROFIT_USD", TEST_MIN_PROFIT_USD);
+        env::set_var("MAX_REPAY_FRACTION", TEST_MAX_REPAY_FRACTION);
+        env::set_var("LIQUIDATION_INTERVAL_MS", TEST_LIQUIDATION_INTERVAL_MS);
+        env::set_var("CONTINUOUS_LIQUIDATION", "false");
+        env::set_var(
+            "LIQUIDATION_HEALTH_THRESHOLD",
+            TEST_LIQUIDATION_HEALTH_THRESHOLD,
+        );
+        env::set_var(
+            "LIQUIDATION_MAX_CANDIDATES",
+            TEST_LIQUIDATION_MAX_CANDIDATES,
+        );
+        env::set_var(
+            "MAX_LIQUIDATIONS_PER_CYCLE",
+            TEST_MAX_LIQUIDATIONS_PER_CYCLE,
+        );
+        env::set_var(
+            "LIQUIDATION_SCAN_BATCH_SIZE",
+            TEST_LIQUIDATION_SCAN_BATCH_SIZE,
+        );
+        env::set_var("PRICE_MOVE_TRIGGER_BPS", TEST_PRICE_MOVE_TRIGGER_BPS);
+        env::set_var("MAX_CONFIDENCE_BPS", TEST_MAX_CONFIDENCE_BPS);
+        env::set_var("METRICS_PORT", TEST_METRICS_PORT);
+        env::set_var("MAX_SLOT_STALENESS_SEC", TEST_MAX_SLOT_STALENESS_SEC);
+        env::set_var("SERVICE_MAX_RESTARTS", TEST_SERVICE_MAX_RESTARTS);
+        env::set_var(
+            "SERVICE_RESTART_WINDOW_SEC",
+            TEST_SERVICE_RESTART_WINDOW_SEC,
+        );
+        env::set_var("LIQUIDATION_COOLDOWN_SEC", TEST_LIQUIDATION_COOLDOWN_SEC);
+        env::set_var("LIQUIDATION_MAX_RETRIES", TEST_LIQUIDATION_MAX_RETRIES);
+        env::set_var(
+            "LIQUIDATION_RETRY_DEADLINE_MS",
+            TEST_LIQUIDATION_RETRY_DEADLINE_MS,
+        );
+        env::set_var(
+            "READINESS_LIVE_ORACLES_RATIO",
+            TEST_READINESS_LIVE_ORACLES_RATIO,
+        );
+        env::set_var("READINESS_MAX_WAIT_SEC", TEST_READINESS_MAX_WAIT_SEC);
+        env::set_var("RPC_RETRY_MAX_ATTEMPTS", TEST_RPC_RETRY_MAX_ATTEMPTS);
+        env::set_var("RPC_RETRY_BASE_DELAY_MS", TEST_RPC_RETRY_BASE_DELAY_MS);
+        env::set_var("RPC_FETCH_CONCURRENCY", TEST_RPC_FETCH_CONCURRENCY);
+        env::set_var("RPC_TIMEOUT_MS", TEST_RPC_TIMEOUT_MS);
+        env::set_var("SUBMISSION_MODE", "rpc");
+        env::set_var("JITO_BLOCK_ENGINE_URL", TEST_JITO_BLOCK_ENGINE_URL);
+        env::set_var("JITO_TIP_LAMPORTS", TEST_JITO_TIP_LAMPORTS);
+        env::set_var(
+            "MARGINFI_ACCOUNT_MAX_AGE_SLOTS",
+            TEST_MARGINFI_ACCOUNT_MAX_AGE_SLOTS,
+        );
+        env::set_var("RECONCILE_INTERVAL_SEC", TEST_RECONCILE_INTERVAL_SEC);
+        env::set_var("COMMITMENT", TEST_COMMITMENT);
+        env::set_var("LAZY_BANK_LOADING", "false");
+        env::set_var("LIQUIDATION_PREFILTER", "false");
+        env::set_var("SIMULATE_BEFORE_SEND", "false");
     }
 
     pub fn remove_env(key: &str) {
@@ -122,6 +1093,7 @@ pub mod test_util {
 
     pub fn create_dummy_config() -> Config {
         let wallet = Keypair::new();
+        let liquidator_account = Pubkey::new_unique();
         let marginfi_program_id = Pubkey::new_unique();
         let lut_addresses = vec![Pubkey::new_unique(), Pubkey::new_unique()];
         let stats_interval_sec = 60;
@@ -131,12 +1103,62 @@ pub mod test_util {
 
         Config {
             wallet,
+            liquidator_account,
             marginfi_program_id,
             lut_addresses,
             stats_interval_sec,
             rpc_url,
+            subscriber_mode: SubscriberMode::Geyser,
+            ws_url: "ws://dummy_rpc_url".into(),
+            extra_subscribe_accounts: vec![],
             geyser_endpoint,
             geyser_x_token,
+            geyser_reconnect_base_ms: 500,
+            geyser_reconnect_max_ms: 30_000,
+            geyser_worker_threads: 2,
+            compute_unit_limit: 200_000,
+            compute_unit_price_micro_lamports: 0,
+            dynamic_priority_fee: false,
+            dry_run: false,
+            min_profit_usd: 1.0,
+            max_repay_fraction: 1.0,
+            liquidation_prefilter: false,
+            simulate_before_send: false,
+            liquidation_interval_ms: 5_000,
+            continuous_liquidation: false,
+            liquidation_health_threshold: 0.0,
+            liquidation_max_candidates: 0,
+            max_liquidations_per_cycle: 0,
+            liquidation_scan_batch_size: 16,
+            excluded_accounts: vec![],
+            allowed_groups: vec![],
+            excluded_authorities: vec![],
+            price_move_trigger_bps: 0,
+            max_confidence_bps: 0,
+            metrics_port: 9090,
+            max_slot_staleness_sec: 30,
+            service_max_restarts: 5,
+            service_restart_window_sec: 600,
+            liquidation_cooldown_sec: 30,
+            liquidation_max_retries: 2,
+            liquidation_retry_deadline_ms: 10_000,
+            readiness_live_oracles_ratio: 0.8,
+            readiness_max_wait_sec: 300,
+            rpc_retry: RetryConfig {
+                max_attempts: 3,
+                base_delay_ms: 200,
+            },
+            rpc_fetch_concurrency: 4,
+            rpc_timeout_ms: 30_000,
+            submission_mode: SubmissionMode::Rpc,
+            jito_block_engine_url: "http://dummy_block_engine_url".into(),
+            jito_tip_lamports: 10_000,
+            marginfi_account_max_age_slots: 0,
+            reconcile_interval_sec: 300,
+            commitment: CommitmentLevel::Confirmed,
+            lazy_bank_loading: false,
+            cache_snapshot_path: None,
+            notify_webhook_url: None,
         }
     }
 }
@@ -144,12 +1166,14 @@ pub mod test_util {
 #[cfg(test)]
 mod tests {
     use crate::config::test_util::{
-        remove_env, set_test_env, TEST_GEYSER_ENDPOINT, TEST_GEYSER_X_TOKEN,
+        remove_env, set_test_env, TEST_GEYSER_ENDPOINT, TEST_GEYSER_RECONNECT_BASE_MS,
+        TEST_GEYSER_RECONNECT_MAX_MS, TEST_GEYSER_X_TOKEN, TEST_LIQUIDATOR_ACCOUNT,
         TEST_MARGINFI_PROGRAM_ID, TEST_RPC_URL, TEST_STATS_INTERVAL_SEC,
     };
 
     use serial_test::serial;
-    use std::env;
+    use solana_sdk::signature::write_keypair_file;
+    use std::{env, fs, path::PathBuf};
 
     use super::*;
 
@@ -159,6 +1183,10 @@ mod tests {
         set_test_env();
 
         let config = Config::new().unwrap();
+        assert_eq!(
+            config.liquidator_account.to_string(),
+            TEST_LIQUIDATOR_ACCOUNT
+        );
         assert_eq!(
             config.marginfi_program_id.to_string(),
             TEST_MARGINFI_PROGRAM_ID
@@ -170,88 +1198,1099 @@ mod tests {
         assert_eq!(config.rpc_url, TEST_RPC_URL);
         assert_eq!(config.geyser_endpoint, TEST_GEYSER_ENDPOINT);
         assert_eq!(config.geyser_x_token, TEST_GEYSER_X_TOKEN);
+        assert_eq!(
+            config.geyser_reconnect_base_ms,
+            TEST_GEYSER_RECONNECT_BASE_MS.parse::<u64>().unwrap()
+        );
+        assert_eq!(
+            config.geyser_reconnect_max_ms,
+            TEST_GEYSER_RECONNECT_MAX_MS.parse::<u64>().unwrap()
+        );
     }
 
     #[test]
     #[serial]
-    #[should_panic(expected = "MARGINFI_PROGRAM_ID environment variable is not set")]
-    fn test_config_missing_marginfi_program_id() {
+    fn test_config_geyser_reconnect_defaults() {
         set_test_env();
-        remove_env("MARGINFI_PROGRAM_ID");
-        let _ = Config::new();
+        remove_env("GEYSER_RECONNECT_BASE_MS");
+        remove_env("GEYSER_RECONNECT_MAX_MS");
+
+        let config = Config::new().unwrap();
+        assert_eq!(
+            config.geyser_reconnect_base_ms,
+            DEFAULT_GEYSER_RECONNECT_BASE_MS
+        );
+        assert_eq!(
+            config.geyser_reconnect_max_ms,
+            DEFAULT_GEYSER_RECONNECT_MAX_MS
+        );
     }
 
     #[test]
     #[serial]
-    #[should_panic(expected = "Invalid MARGINFI_PROGRAM_ID Pubkey")]
-    fn test_config_invalid_marginfi_program_id() {
+    fn test_config_compute_budget() {
         set_test_env();
-        env::set_var("MARGINFI_PROGRAM_ID", "invalid_pubkey");
-        let _ = Config::new();
+        let config = Config::new().unwrap();
+        assert_eq!(
+            config.compute_unit_limit,
+            super::test_util::TEST_COMPUTE_UNIT_LIMIT
+                .parse::<u32>()
+                .unwrap()
+        );
+        assert_eq!(
+            config.compute_unit_price_micro_lamports,
+            super::test_util::TEST_COMPUTE_UNIT_PRICE_MICRO_LAMPORTS
+                .parse::<u64>()
+                .unwrap()
+        );
+
+        remove_env("COMPUTE_UNIT_LIMIT");
+        remove_env("COMPUTE_UNIT_PRICE_MICRO_LAMPORTS");
+        let config = Config::new().unwrap();
+        assert_eq!(config.compute_unit_limit, DEFAULT_COMPUTE_UNIT_LIMIT);
+        assert_eq!(
+            config.compute_unit_price_micro_lamports,
+            DEFAULT_COMPUTE_UNIT_PRICE_MICRO_LAMPORTS
+        );
     }
 
     #[test]
     #[serial]
-    #[should_panic(expected = "STATS_INTERVAL_SEC environment variable is not set")]
-    fn test_config_missing_stats_interval_sec() {
+    fn test_config_dynamic_priority_fee() {
         set_test_env();
-        remove_env("STATS_INTERVAL_SEC");
-        let _ = Config::new();
+        remove_env("DYNAMIC_PRIORITY_FEE");
+        assert!(!Config::new().unwrap().dynamic_priority_fee);
+
+        env::set_var("DYNAMIC_PRIORITY_FEE", "true");
+        assert!(Config::new().unwrap().dynamic_priority_fee);
     }
 
     #[test]
     #[serial]
-    #[should_panic(expected = "Invalid STATS_INTERVAL_SEC value, must be a number")]
-    fn test_config_invalid_stats_interval_sec() {
+    fn test_config_service_max_restarts() {
         set_test_env();
-        env::set_var("STATS_INTERVAL_SEC", "not_a_number");
-        let _ = Config::new();
+        assert_eq!(
+            Config::new().unwrap().service_max_restarts,
+            super::test_util::TEST_SERVICE_MAX_RESTARTS
+                .parse::<u32>()
+                .unwrap()
+        );
+
+        remove_env("SERVICE_MAX_RESTARTS");
+        assert_eq!(
+            Config::new().unwrap().service_max_restarts,
+            DEFAULT_SERVICE_MAX_RESTARTS
+        );
     }
 
     #[test]
     #[serial]
-    #[should_panic(expected = "GEYSER_ENDPOINT environment variable is not set")]
-    fn test_config_missing_geyser_endpoint() {
+    fn test_config_service_restart_window_sec() {
         set_test_env();
-        remove_env("GEYSER_ENDPOINT");
-        let _ = Config::new();
+        assert_eq!(
+            Config::new().unwrap().service_restart_window_sec,
+            super::test_util::TEST_SERVICE_RESTART_WINDOW_SEC
+                .parse::<u64>()
+                .unwrap()
+        );
+
+        remove_env("SERVICE_RESTART_WINDOW_SEC");
+        assert_eq!(
+            Config::new().unwrap().service_restart_window_sec,
+            DEFAULT_SERVICE_RESTART_WINDOW_SEC
+        );
     }
 
     #[test]
     #[serial]
-    #[should_panic(expected = "GEYSER_X_TOKEN environment variable is not set")]
-    fn test_config_missing_geyser_x_token() {
+    fn test_config_liquidation_cooldown_sec() {
         set_test_env();
-        remove_env("GEYSER_X_TOKEN");
-        let _ = Config::new();
+        assert_eq!(
+            Config::new().unwrap().liquidation_cooldown_sec,
+            super::test_util::TEST_LIQUIDATION_COOLDOWN_SEC
+                .parse::<u64>()
+                .unwrap()
+        );
+
+        remove_env("LIQUIDATION_COOLDOWN_SEC");
+        assert_eq!(
+            Config::new().unwrap().liquidation_cooldown_sec,
+            DEFAULT_LIQUIDATION_COOLDOWN_SEC
+        );
     }
 
     #[test]
     #[serial]
-    fn test_config_display() {
-        super::test_util::set_test_env();
+    fn test_config_liquidation_retries() {
+        set_test_env();
         let config = Config::new().unwrap();
-        let display = format!("{}", config);
-        assert!(display.contains(&format!(
-            "marginfi_program_id: {}",
-            super::test_util::TEST_MARGINFI_PROGRAM_ID
-        )));
-        assert!(display.contains(&format!(
-            "stats_interval_sec: {}",
-            super::test_util::TEST_STATS_INTERVAL_SEC
-        )));
+        assert_eq!(
+            config.liquidation_max_retries,
+            super::test_util::TEST_LIQUIDATION_MAX_RETRIES
+                .parse::<u32>()
+                .unwrap()
+        );
+        assert_eq!(
+            config.liquidation_retry_deadline_ms,
+            super::test_util::TEST_LIQUIDATION_RETRY_DEADLINE_MS
+                .parse::<u64>()
+                .unwrap()
+        );
+
+        remove_env("LIQUIDATION_MAX_RETRIES");
+        remove_env("LIQUIDATION_RETRY_DEADLINE_MS");
+        let config = Config::new().unwrap();
+        assert_eq!(
+            config.liquidation_max_retries,
+            DEFAULT_LIQUIDATION_MAX_RETRIES
+        );
+        assert_eq!(
+            config.liquidation_retry_deadline_ms,
+            DEFAULT_LIQUIDATION_RETRY_DEADLINE_MS
+        );
     }
 
     #[test]
     #[serial]
-    fn test_config_lut_addresses_parsing() {
-        super::test_util::set_test_env();
+    fn test_config_readiness_live_oracles_ratio() {
+        set_test_env();
+        assert_eq!(Config::new().unwrap().readiness_live_oracles_ratio, 0.5);
 
-        let pk1 = Pubkey::new_unique();
-        let pk2 = Pubkey::new_unique();
-        // Set LUT_ADDRESSES to two valid pubkeys
-        let lut_addresses = format!("{},{}", pk1, pk2);
-        std::env::set_var("LUT_ADDRESSES", lut_addresses);
+        remove_env("READINESS_LIVE_ORACLES_RATIO");
+        assert_eq!(
+            Config::new().unwrap().readiness_live_oracles_ratio,
+            DEFAULT_READINESS_LIVE_ORACLES_RATIO
+        );
+
+        env::set_var("READINESS_LIVE_ORACLES_RATIO", "1.5");
+        assert_config_error(
+            "Invalid READINESS_LIVE_ORACLES_RATIO value, must be a ratio from 0 to 1",
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_readiness_max_wait_sec() {
+        set_test_env();
+        assert_eq!(Config::new().unwrap().readiness_max_wait_sec, 60);
+
+        remove_env("READINESS_MAX_WAIT_SEC");
+        assert_eq!(
+            Config::new().unwrap().readiness_max_wait_sec,
+            DEFAULT_READINESS_MAX_WAIT_SEC
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_rpc_retry() {
+        set_test_env();
+        let rpc_retry = Config::new().unwrap().rpc_retry;
+        assert_eq!(
+            rpc_retry.max_attempts,
+            super::test_util::TEST_RPC_RETRY_MAX_ATTEMPTS
+                .parse::<u32>()
+                .unwrap()
+        );
+        assert_eq!(
+            rpc_retry.base_delay_ms,
+            super::test_util::TEST_RPC_RETRY_BASE_DELAY_MS
+                .parse::<u64>()
+                .unwrap()
+        );
+
+        remove_env("RPC_RETRY_MAX_ATTEMPTS");
+        remove_env("RPC_RETRY_BASE_DELAY_MS");
+        let rpc_retry = Config::new().unwrap().rpc_retry;
+        assert_eq!(rpc_retry.max_attempts, DEFAULT_RPC_RETRY_MAX_ATTEMPTS);
+        assert_eq!(rpc_retry.base_delay_ms, DEFAULT_RPC_RETRY_BASE_DELAY_MS);
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_submission_mode() {
+        set_test_env();
+        assert_eq!(Config::new().unwrap().submission_mode, SubmissionMode::Rpc);
+
+        env::set_var("SUBMISSION_MODE", "Jito");
+        assert_eq!(Config::new().unwrap().submission_mode, SubmissionMode::Jito);
+
+        remove_env("SUBMISSION_MODE");
+        assert_eq!(Config::new().unwrap().submission_mode, SubmissionMode::Rpc);
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_subscriber_mode() {
+        set_test_env();
+        let config = Config::new().unwrap();
+        assert_eq!(config.subscriber_mode, SubscriberMode::Geyser);
+        assert_eq!(config.ws_url, "ws://dummy_rpc_url");
+
+        // The Geyser endpoint is not required by the WebSocket subscriptions
+        env::set_var("SUBSCRIBER_MODE", "ws");
+        remove_env("GEYSER_ENDPOINT");
+        remove_env("GEYSER_X_TOKEN");
+        env::set_var("WS_URL", "wss://dummy_ws_url");
+        let config = Config::new().unwrap();
+        assert_eq!(config.subscriber_mode, SubscriberMode::Ws);
+        assert_eq!(config.ws_url, "wss://dummy_ws_url");
+        assert!(config.geyser_endpoint.is_empty());
+
+        env::set_var("SUBSCRIBER_MODE", "geyser");
+        assert_config_error("GEYSER_ENDPOINT environment variable is not set");
+
+        env::set_var("SUBSCRIBER_MODE", "grpc");
+        assert_config_error("Invalid SUBSCRIBER_MODE value, must be geyser or ws");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_extra_subscribe_accounts() {
+        set_test_env();
+        assert!(Config::new().unwrap().extra_subscribe_accounts.is_empty());
+
+        let group = Pubkey::new_unique();
+        let insurance_vault = Pubkey::new_unique();
+        env::set_var(
+            "EXTRA_SUBSCRIBE_ACCOUNTS",
+            format!("{}, {}", group, insurance_vault),
+        );
+        assert_eq!(
+            Config::new().unwrap().extra_subscribe_accounts,
+            vec![group, insurance_vault]
+        );
+
+        env::set_var("EXTRA_SUBSCRIBE_ACCOUNTS", "not_a_pubkey");
+        assert_config_error("Invalid EXTRA_SUBSCRIBE_ACCOUNTS Pubkey: not_a_pubkey");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_excluded_accounts() {
+        set_test_env();
+        assert!(Config::new().unwrap().excluded_accounts.is_empty());
+
+        let treasury = Pubkey::new_unique();
+        let market_maker = Pubkey::new_unique();
+        env::set_var(
+            "EXCLUDED_ACCOUNTS",
+            format!("{},{}", treasury, market_maker),
+        );
+        assert_eq!(
+            Config::new().unwrap().excluded_accounts,
+            vec![treasury, market_maker]
+        );
+
+        env::set_var("EXCLUDED_ACCOUNTS", "not_a_pubkey");
+        assert_config_error("Invalid EXCLUDED_ACCOUNTS Pubkey: not_a_pubkey");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_allowed_groups() {
+        set_test_env();
+        assert!(Config::new().unwrap().allowed_groups.is_empty());
+
+        let group = Pubkey::new_unique();
+        env::set_var("ALLOWED_GROUPS", format!(" {}, ", group));
+        assert_eq!(Config::new().unwrap().allowed_groups, vec![group]);
+
+        env::set_var("ALLOWED_GROUPS", "not_a_pubkey");
+        assert_config_error("Invalid ALLOWED_GROUPS Pubkey: not_a_pubkey");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_excluded_authorities() {
+        set_test_env();
+        assert!(Config::new().unwrap().excluded_authorities.is_empty());
+
+        let partner = Pubkey::new_unique();
+        let operator = Pubkey::new_unique();
+        env::set_var("EXCLUDED_AUTHORITIES", format!("{}, {}", partner, operator));
+        assert_eq!(
+            Config::new().unwrap().excluded_authorities,
+            vec![partner, operator]
+        );
+
+        env::set_var("EXCLUDED_AUTHORITIES", "not_a_pubkey");
+        assert_config_error("Invalid EXCLUDED_AUTHORITIES Pubkey: not_a_pubkey");
+    }
+
+    #[test]
+    fn test_ws_url_from_rpc_url() {
+        assert_eq!(
+            ws_url_from_rpc_url("https://rpc.example.com/key"),
+            "wss://rpc.example.com/key"
+        );
+        assert_eq!(
+            ws_url_from_rpc_url("http://localhost:8899"),
+            "ws://localhost:8899"
+        );
+        assert_eq!(
+            ws_url_from_rpc_url("ws://localhost:8900"),
+            "ws://localhost:8900"
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_marginfi_account_max_age_slots() {
+        set_test_env();
+        assert_eq!(
+            Config::new().unwrap().marginfi_account_max_age_slots,
+            super::test_util::TEST_MARGINFI_ACCOUNT_MAX_AGE_SLOTS
+                .parse::<u64>()
+                .unwrap()
+        );
+
+        remove_env("MARGINFI_ACCOUNT_MAX_AGE_SLOTS");
+        assert_eq!(
+            Config::new().unwrap().marginfi_account_max_age_slots,
+            DEFAULT_MARGINFI_ACCOUNT_MAX_AGE_SLOTS
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_reconcile_interval_sec() {
+        set_test_env();
+        assert_eq!(Config::new().unwrap().reconcile_interval_sec, 600);
+
+        remove_env("RECONCILE_INTERVAL_SEC");
+        assert_eq!(
+            Config::new().unwrap().reconcile_interval_sec,
+            DEFAULT_RECONCILE_INTERVAL_SEC
+        );
+    }
+
+    fn assert_config_error(expected: &str) {
+        let error = Config::new().err().unwrap().to_string();
+        assert!(
+            error.contains(expected),
+            "Expected {:?} in the error: {}",
+            expected,
+            error
+        );
+    }
+
+    fn write_temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("mary_{}_{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_cli_args_override_env() {
+        set_test_env();
+        let config = Config::from_args([
+            "mary",
+            "--rpc-url",
+            "http://cli_rpc_url",
+            "--metrics-port",
+            "9292",
+            "--dry-run",
+        ])
+        .unwrap();
+        assert_eq!(config.rpc_url, "http://cli_rpc_url");
+        assert_eq!(config.metrics_port, 9292);
+        assert!(config.dry_run);
+        // The settings without the flags come from the environment
+        assert_eq!(config.geyser_endpoint, TEST_GEYSER_ENDPOINT);
+
+        let config = Config::from_args(["mary", "--dry-run", "false"]).unwrap();
+        assert_eq!(config.rpc_url, TEST_RPC_URL);
+        assert!(!config.dry_run);
+
+        assert!(Config::from_args(["mary", "--unknown-flag", "1"]).is_err());
+        let error = Config::from_args(["mary", "--metrics-port", "port"])
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("Invalid METRICS_PORT value, must be a number"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_from_args_with_config_file() {
+        set_test_env();
+        remove_env("RPC_URL");
+        let config_path = write_temp_file("cli_config.toml", "rpc_url = \"http://file_rpc_url\"\n");
+
+        let config =
+            Config::from_args(["mary", "--config", config_path.to_str().unwrap()]).unwrap();
+        fs::remove_file(&config_path).unwrap();
+        assert_eq!(config.rpc_url, "http://file_rpc_url");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_from_file() {
+        set_test_env();
+        remove_env("WALLET");
+        remove_env("RPC_URL");
+        remove_env("LUT_ADDRESSES");
+        remove_env("METRICS_PORT");
+        remove_env("DRY_RUN");
+
+        let wallet = Keypair::new();
+        let wallet_path = env::temp_dir().join(format!("mary_{}_wallet.json", std::process::id()));
+        write_keypair_file(&wallet, &wallet_path).unwrap();
+        let lut_addresses = [Pubkey::new_unique(), Pubkey::new_unique()];
+        let config_path = write_temp_file(
+            "config.toml",
+            &format!(
+                "wallet_path = \"{}\"\n\
+                rpc_url = \"http://file_rpc_url\"\n\
+                lut_addresses = [\"{}\", \"{}\"]\n\
+                metrics_port = 9292\n\
+                dry_run = true\n\
+                liquidator_account = \"{}\"\n",
+                wallet_path.display(),
+                lut_addresses[0],
+                lut_addresses[1],
+                Pubkey::new_unique()
+            ),
+        );
+
+        let config = Config::from_file(&config_path).unwrap();
+        assert_eq!(config.wallet.pubkey(), wallet.pubkey());
+        assert_eq!(config.rpc_url, "http://file_rpc_url");
+        assert_eq!(config.lut_addresses, lut_addresses);
+        assert_eq!(config.metrics_port, 9292);
+        assert!(config.dry_run);
+        // The environment variables override the file values
+        assert_eq!(
+            config.liquidator_account.to_string(),
+            TEST_LIQUIDATOR_ACCOUNT
+        );
+
+        env::set_var("CONFIG_FILE", &config_path);
+        let config = Config::new().unwrap();
+        assert_eq!(config.rpc_url, "http://file_rpc_url");
+
+        remove_env("CONFIG_FILE");
+        fs::remove_file(config_path).unwrap();
+        fs::remove_file(wallet_path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_wallet_path() {
+        set_test_env();
+        let wallet = Keypair::new();
+        let wallet_path =
+            env::temp_dir().join(format!("mary_{}_wallet_path.json", std::process::id()));
+        write_keypair_file(&wallet, &wallet_path).unwrap();
+
+        // The keypair file is preferred over the inlined WALLET set by the test env
+        env::set_var("WALLET_PATH", &wallet_path);
+        let config = Config::new().unwrap();
+        assert_eq!(config.wallet.pubkey(), wallet.pubkey());
+
+        remove_env("WALLET");
+        let config = Config::new().unwrap();
+        assert_eq!(config.wallet.pubkey(), wallet.pubkey());
+
+        // Neither the Display nor the errors may contain the secret bytes
+        let secret = serde_json::to_string(&wallet.to_bytes().to_vec()).unwrap();
+        assert!(!format!("{}", config).contains(&secret[1..secret.len() - 1]));
+
+        fs::remove_file(&wallet_path).unwrap();
+        assert_config_error("Failed to read the WALLET_PATH keypair file");
+        remove_env("WALLET_PATH");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_invalid_wallet() {
+        set_test_env();
+        let wallet_path = write_temp_file("invalid_wallet.json", "[1, 2, 3]");
+        env::set_var("WALLET_PATH", &wallet_path);
+        assert_config_error("Failed to read the WALLET_PATH keypair file");
+        remove_env("WALLET_PATH");
+        fs::remove_file(wallet_path).unwrap();
+
+        env::set_var("WALLET", "[1, 2, 3]");
+        let error = Config::new().err().unwrap().to_string();
+        assert!(error.contains("Invalid WALLET format (Keypair bytes): expected 64 bytes, got 3"));
+        assert!(!error.contains("1, 2, 3"));
+
+        for blank in ["", "  \n"] {
+            env::set_var("WALLET", blank);
+            assert_config_error("WALLET environment variable is empty");
+        }
+
+        env::set_var("WALLET", "not json");
+        assert_config_error("Invalid WALLET format (JSON)");
+
+        remove_env("WALLET");
+        assert_config_error("WALLET or WALLET_PATH environment variable is not set");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_from_invalid_file() {
+        set_test_env();
+        let missing_path = env::temp_dir().join("mary_missing_config.toml");
+        let result = Config::from_file(&missing_path);
+        assert!(result
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("Failed to read the config file"));
+
+        let config_path = write_temp_file("invalid_config.toml", "rpc_url = ");
+        let result = Config::from_file(&config_path);
+        assert!(result
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("Invalid config file"));
+
+        fs::write(&config_path, "[geyser]\nendpoint = \"http://nested\"\n").unwrap();
+        let result = Config::from_file(&config_path);
+        assert!(result
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("Unsupported geyser value type"));
+        fs::remove_file(config_path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_commitment() {
+        set_test_env();
+        let config = Config::new().unwrap();
+        assert_eq!(config.commitment, CommitmentLevel::Processed);
+
+        env::set_var("COMMITMENT", "finalized");
+        let config = Config::new().unwrap();
+        assert_eq!(config.commitment, CommitmentLevel::Finalized);
+
+        remove_env("COMMITMENT");
+        let config = Config::new().unwrap();
+        assert_eq!(config.commitment, DEFAULT_COMMITMENT);
+
+        env::set_var("COMMITMENT", "latest");
+        assert_config_error("Invalid COMMITMENT value, must be processed, confirmed or finalized");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_lazy_bank_loading() {
+        set_test_env();
+        assert!(!Config::new().unwrap().lazy_bank_loading);
+
+        env::set_var("LAZY_BANK_LOADING", "true");
+        assert!(Config::new().unwrap().lazy_bank_loading);
+
+        remove_env("LAZY_BANK_LOADING");
+        assert!(!Config::new().unwrap().lazy_bank_loading);
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_liquidation_prefilter() {
+        set_test_env();
+        assert!(!Config::new().unwrap().liquidation_prefilter);
+
+        env::set_var("LIQUIDATION_PREFILTER", "true");
+        assert!(Config::new().unwrap().liquidation_prefilter);
+
+        remove_env("LIQUIDATION_PREFILTER");
+        assert!(!Config::new().unwrap().liquidation_prefilter);
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_simulate_before_send() {
+        set_test_env();
+        assert!(!Config::new().unwrap().simulate_before_send);
+
+        env::set_var("SIMULATE_BEFORE_SEND", "true");
+        assert!(Config::new().unwrap().simulate_before_send);
+
+        remove_env("SIMULATE_BEFORE_SEND");
+        assert!(!Config::new().unwrap().simulate_before_send);
+
+        env::set_var("SIMULATE_BEFORE_SEND", "maybe");
+        assert_config_error("Invalid SIMULATE_BEFORE_SEND value, must be true or false");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_cache_snapshot_path() {
+        set_test_env();
+        assert_eq!(Config::new().unwrap().cache_snapshot_path, None);
+
+        env::set_var("CACHE_SNAPSHOT_PATH", "/var/lib/mary/cache.snapshot");
+        assert_eq!(
+            Config::new().unwrap().cache_snapshot_path,
+            Some(PathBuf::from("/var/lib/mary/cache.snapshot"))
+        );
+        remove_env("CACHE_SNAPSHOT_PATH");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_notify_webhook_url() {
+        set_test_env();
+        let config = Config::new().unwrap();
+        assert_eq!(config.notify_webhook_url, None);
+        assert!(config.to_string().contains("notify_webhook_url: <unset>"));
+
+        env::set_var("NOTIFY_WEBHOOK_URL", "https://hooks.example.com/secret");
+        let config = Config::new().unwrap();
+        assert_eq!(
+            config.notify_webhook_url.as_deref(),
+            Some("https://hooks.example.com/secret")
+        );
+        assert!(!config.to_string().contains("secret"));
+        remove_env("NOTIFY_WEBHOOK_URL");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_invalid_submission_mode() {
+        set_test_env();
+        env::set_var("SUBMISSION_MODE", "bundle");
+        assert_config_error("Invalid SUBMISSION_MODE value, must be rpc or jito");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_jito() {
+        set_test_env();
+        let config = Config::new().unwrap();
+        assert_eq!(
+            config.jito_block_engine_url,
+            super::test_util::TEST_JITO_BLOCK_ENGINE_URL
+        );
+        assert_eq!(
+            config.jito_tip_lamports,
+            super::test_util::TEST_JITO_TIP_LAMPORTS
+                .parse::<u64>()
+                .unwrap()
+        );
+
+        remove_env("JITO_BLOCK_ENGINE_URL");
+        remove_env("JITO_TIP_LAMPORTS");
+        let config = Config::new().unwrap();
+        assert_eq!(config.jito_block_engine_url, DEFAULT_JITO_BLOCK_ENGINE_URL);
+        assert_eq!(config.jito_tip_lamports, DEFAULT_JITO_TIP_LAMPORTS);
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_rpc_fetch_concurrency() {
+        set_test_env();
+        assert_eq!(Config::new().unwrap().rpc_fetch_concurrency, 8);
+
+        remove_env("RPC_FETCH_CONCURRENCY");
+        assert_eq!(
+            Config::new().unwrap().rpc_fetch_concurrency,
+            DEFAULT_RPC_FETCH_CONCURRENCY
+        );
+
+        env::set_var("RPC_FETCH_CONCURRENCY", "0");
+        assert_config_error("Invalid RPC_FETCH_CONCURRENCY value, must be at least 1");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_rpc_timeout_ms() {
+        set_test_env();
+        assert_eq!(Config::new().unwrap().rpc_timeout_ms, 15_000);
+
+        remove_env("RPC_TIMEOUT_MS");
+        assert_eq!(
+            Config::new().unwrap().rpc_timeout_ms,
+            DEFAULT_RPC_TIMEOUT_MS
+        );
+
+        env::set_var("RPC_TIMEOUT_MS", "0");
+        assert_config_error("Invalid RPC_TIMEOUT_MS value, must be at least 1");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_zero_rpc_retry_max_attempts() {
+        set_test_env();
+        env::set_var("RPC_RETRY_MAX_ATTEMPTS", "0");
+        assert_config_error("Invalid RPC_RETRY_MAX_ATTEMPTS value, must be at least 1");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_invalid_dynamic_priority_fee() {
+        set_test_env();
+        env::set_var("DYNAMIC_PRIORITY_FEE", "maybe");
+        assert_config_error("Invalid DYNAMIC_PRIORITY_FEE value, must be true or false");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_dry_run() {
+        set_test_env();
+        remove_env("DRY_RUN");
+        assert!(!Config::new().unwrap().dry_run);
+
+        env::set_var("DRY_RUN", "true");
+        assert!(Config::new().unwrap().dry_run);
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_invalid_dry_run() {
+        set_test_env();
+        env::set_var("DRY_RUN", "yes");
+        assert_config_error("Invalid DRY_RUN value, must be true or false");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_min_profit_usd() {
+        set_test_env();
+        assert_eq!(
+            Config::new().unwrap().min_profit_usd,
+            super::test_util::TEST_MIN_PROFIT_USD
+                .parse::<f64>()
+                .unwrap()
+        );
+
+        remove_env("MIN_PROFIT_USD");
+        assert_eq!(
+            Config::new().unwrap().min_profit_usd,
+            DEFAULT_MIN_PROFIT_USD
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_liquidation_health_threshold() {
+        set_test_env();
+        assert_eq!(Config::new().unwrap().liquidation_health_threshold, 0.05);
+
+        remove_env("LIQUIDATION_HEALTH_THRESHOLD");
+        assert_eq!(
+            Config::new().unwrap().liquidation_health_threshold,
+            DEFAULT_LIQUIDATION_HEALTH_THRESHOLD
+        );
+
+        env::set_var("LIQUIDATION_HEALTH_THRESHOLD", "-0.1");
+        assert_eq!(Config::new().unwrap().liquidation_health_threshold, -0.1);
+
+        env::set_var("LIQUIDATION_HEALTH_THRESHOLD", "1.5");
+        assert_config_error(
+            "Invalid LIQUIDATION_HEALTH_THRESHOLD value, must be a health ratio up to 1",
+        );
+
+        env::set_var("LIQUIDATION_HEALTH_THRESHOLD", "low");
+        assert_config_error("Invalid LIQUIDATION_HEALTH_THRESHOLD value, must be a number");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_liquidation_max_candidates() {
+        set_test_env();
+        assert_eq!(Config::new().unwrap().liquidation_max_candidates, 50);
+
+        remove_env("LIQUIDATION_MAX_CANDIDATES");
+        assert_eq!(
+            Config::new().unwrap().liquidation_max_candidates,
+            DEFAULT_LIQUIDATION_MAX_CANDIDATES
+        );
+
+        env::set_var("LIQUIDATION_MAX_CANDIDATES", "-1");
+        assert_config_error("Invalid LIQUIDATION_MAX_CANDIDATES value, must be a number");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_max_liquidations_per_cycle() {
+        set_test_env();
+        assert_eq!(Config::new().unwrap().max_liquidations_per_cycle, 5);
+
+        remove_env("MAX_LIQUIDATIONS_PER_CYCLE");
+        assert_eq!(
+            Config::new().unwrap().max_liquidations_per_cycle,
+            DEFAULT_MAX_LIQUIDATIONS_PER_CYCLE
+        );
+
+        env::set_var("MAX_LIQUIDATIONS_PER_CYCLE", "-1");
+        assert_config_error("Invalid MAX_LIQUIDATIONS_PER_CYCLE value, must be a number");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_liquidation_scan_batch_size() {
+        set_test_env();
+        assert_eq!(Config::new().unwrap().liquidation_scan_batch_size, 8);
+
+        remove_env("LIQUIDATION_SCAN_BATCH_SIZE");
+        assert_eq!(
+            Config::new().unwrap().liquidation_scan_batch_size,
+            DEFAULT_LIQUIDATION_SCAN_BATCH_SIZE
+        );
+
+        env::set_var("LIQUIDATION_SCAN_BATCH_SIZE", "0");
+        assert_config_error("Invalid LIQUIDATION_SCAN_BATCH_SIZE value, must be at least 1");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_price_move_trigger_bps() {
+        set_test_env();
+        assert_eq!(Config::new().unwrap().price_move_trigger_bps, 100);
+
+        remove_env("PRICE_MOVE_TRIGGER_BPS");
+        assert_eq!(
+            Config::new().unwrap().price_move_trigger_bps,
+            DEFAULT_PRICE_MOVE_TRIGGER_BPS
+        );
+
+        env::set_var("PRICE_MOVE_TRIGGER_BPS", "1%");
+        assert_config_error("Invalid PRICE_MOVE_TRIGGER_BPS value, must be a number");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_max_confidence_bps() {
+        set_test_env();
+        assert_eq!(Config::new().unwrap().max_confidence_bps, 250);
+
+        remove_env("MAX_CONFIDENCE_BPS");
+        assert_eq!(
+            Config::new().unwrap().max_confidence_bps,
+            DEFAULT_MAX_CONFIDENCE_BPS
+        );
+
+        env::set_var("MAX_CONFIDENCE_BPS", "2.5%");
+        assert_config_error("Invalid MAX_CONFIDENCE_BPS value, must be a number");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_max_repay_fraction() {
+        set_test_env();
+        assert_eq!(Config::new().unwrap().max_repay_fraction, 0.5);
+
+        remove_env("MAX_REPAY_FRACTION");
+        assert_eq!(
+            Config::new().unwrap().max_repay_fraction,
+            DEFAULT_MAX_REPAY_FRACTION
+        );
+
+        for invalid in ["0", "1.5", "-0.5", "NaN"] {
+            env::set_var("MAX_REPAY_FRACTION", invalid);
+            assert_config_error("Invalid MAX_REPAY_FRACTION value, must be above 0 and up to 1");
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_invalid_min_profit_usd() {
+        set_test_env();
+        env::set_var("MIN_PROFIT_USD", "ten");
+        assert_config_error("Invalid MIN_PROFIT_USD value, must be a number");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_negative_min_profit_usd() {
+        set_test_env();
+        env::set_var("MIN_PROFIT_USD", "-1");
+        assert_config_error("Invalid MIN_PROFIT_USD value, must be a non-negative number");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_liquidation_cadence() {
+        set_test_env();
+        let config = Config::new().unwrap();
+        assert_eq!(
+            config.liquidation_interval_ms,
+            super::test_util::TEST_LIQUIDATION_INTERVAL_MS
+                .parse::<u64>()
+                .unwrap()
+        );
+        assert!(!config.continuous_liquidation);
+
+        remove_env("LIQUIDATION_INTERVAL_MS");
+        env::set_var("CONTINUOUS_LIQUIDATION", "true");
+        let config = Config::new().unwrap();
+        assert_eq!(
+            config.liquidation_interval_ms,
+            DEFAULT_LIQUIDATION_INTERVAL_MS
+        );
+        assert!(config.continuous_liquidation);
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_invalid_liquidation_interval_ms() {
+        set_test_env();
+        env::set_var("LIQUIDATION_INTERVAL_MS", "often");
+        assert_config_error("Invalid LIQUIDATION_INTERVAL_MS value, must be a number");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_metrics_port() {
+        set_test_env();
+        assert_eq!(
+            Config::new().unwrap().metrics_port,
+            super::test_util::TEST_METRICS_PORT.parse::<u16>().unwrap()
+        );
+
+        remove_env("METRICS_PORT");
+        assert_eq!(Config::new().unwrap().metrics_port, DEFAULT_METRICS_PORT);
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_invalid_metrics_port() {
+        set_test_env();
+        env::set_var("METRICS_PORT", "70000");
+        assert_config_error("Invalid METRICS_PORT value, must be a number");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_max_slot_staleness_sec() {
+        set_test_env();
+        assert_eq!(
+            Config::new().unwrap().max_slot_staleness_sec,
+            super::test_util::TEST_MAX_SLOT_STALENESS_SEC
+                .parse::<u64>()
+                .unwrap()
+        );
+
+        remove_env("MAX_SLOT_STALENESS_SEC");
+        assert_eq!(
+            Config::new().unwrap().max_slot_staleness_sec,
+            DEFAULT_MAX_SLOT_STALENESS_SEC
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_geyser_worker_threads() {
+        set_test_env();
+        assert_eq!(Config::new().unwrap().geyser_worker_threads, 4);
+
+        remove_env("GEYSER_WORKER_THREADS");
+        assert_eq!(
+            Config::new().unwrap().geyser_worker_threads,
+            DEFAULT_GEYSER_WORKER_THREADS
+        );
+
+        env::set_var("GEYSER_WORKER_THREADS", "0");
+        assert_config_error("Invalid GEYSER_WORKER_THREADS value, must be at least 1");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_invalid_geyser_reconnect_base_ms() {
+        set_test_env();
+        env::set_var("GEYSER_RECONNECT_BASE_MS", "not_a_number");
+        assert_config_error("Invalid GEYSER_RECONNECT_BASE_MS value, must be a number");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_missing_liquidator_account() {
+        set_test_env();
+        remove_env("LIQUIDATOR_ACCOUNT");
+        assert_config_error("LIQUIDATOR_ACCOUNT environment variable is not set");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_invalid_liquidator_account() {
+        set_test_env();
+        env::set_var("LIQUIDATOR_ACCOUNT", "invalid_pubkey");
+        assert_config_error("Invalid LIQUIDATOR_ACCOUNT Pubkey");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_missing_marginfi_program_id() {
+        set_test_env();
+        remove_env("MARGINFI_PROGRAM_ID");
+        assert_config_error("MARGINFI_PROGRAM_ID environment variable is not set");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_invalid_marginfi_program_id() {
+        set_test_env();
+        env::set_var("MARGINFI_PROGRAM_ID", "invalid_pubkey");
+        assert_config_error("Invalid MARGINFI_PROGRAM_ID Pubkey");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_missing_stats_interval_sec() {
+        set_test_env();
+        remove_env("STATS_INTERVAL_SEC");
+        assert_config_error("STATS_INTERVAL_SEC environment variable is not set");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_invalid_stats_interval_sec() {
+        set_test_env();
+        env::set_var("STATS_INTERVAL_SEC", "not_a_number");
+        assert_config_error("Invalid STATS_INTERVAL_SEC value, must be a number");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_missing_geyser_endpoint() {
+        set_test_env();
+        remove_env("GEYSER_ENDPOINT");
+        assert_config_error("GEYSER_ENDPOINT environment variable is not set");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_missing_geyser_x_token() {
+        set_test_env();
+        remove_env("GEYSER_X_TOKEN");
+        assert_config_error("GEYSER_X_TOKEN environment variable is not set");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_display() {
+        super::test_util::set_test_env();
+        let config = Config::new().unwrap();
+        let display = format!("{}", config);
+        assert!(display.contains(&format!(
+            "marginfi_program_id: {}",
+            super::test_util::TEST_MARGINFI_PROGRAM_ID
+        )));
+        assert!(display.contains(&format!(
+            "stats_interval_sec: {}",
+            super::test_util::TEST_STATS_INTERVAL_SEC
+        )));
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_lut_addresses_parsing() {
+        super::test_util::set_test_env();
+
+        let pk1 = Pubkey::new_unique();
+        let pk2 = Pubkey::new_unique();
+        // Set LUT_ADDRESSES to two valid pubkeys
+        let lut_addresses = format!("{},{}", pk1, pk2);
+        std::env::set_var("LUT_ADDRESSES", lut_addresses);
 
         let config = Config::new().unwrap();
         assert_eq!(config.lut_addresses.len(), 2);
@@ -261,25 +2300,22 @@ mod tests {
 
     #[test]
     #[serial]
-    #[should_panic(expected = "Invalid LUT_ADDRESSES Pubkey:")]
     fn test_config_lut_addresses_empty() {
         super::test_util::set_test_env();
         std::env::set_var("LUT_ADDRESSES", "");
-        let _ = Config::new().unwrap();
+        assert_config_error("Invalid LUT_ADDRESSES Pubkey:");
     }
 
     #[test]
     #[serial]
-    #[should_panic(expected = "LUT_ADDRESSES environment variable is not set")]
     fn test_config_missing_lut_addresses() {
         super::test_util::set_test_env();
         super::test_util::remove_env("LUT_ADDRESSES");
-        let _ = Config::new();
+        assert_config_error("LUT_ADDRESSES environment variable is not set");
     }
 
     #[test]
     #[serial]
-    #[should_panic(expected = "Invalid LUT_ADDRESSES Pubkey:")]
     fn test_config_lut_addresses_with_invalid_pubkey() {
         super::test_util::set_test_env();
         // One valid, one invalid pubkey
@@ -287,12 +2323,23 @@ mod tests {
             "LUT_ADDRESSES",
             "11111111111111111111111111111111,invalid_pubkey",
         );
-        let config = Config::new().unwrap();
-        // Only the valid pubkey should be parsed
-        assert_eq!(config.lut_addresses.len(), 1);
-        assert_eq!(
-            config.lut_addresses[0].to_string(),
-            "11111111111111111111111111111111"
-        );
+        assert_config_error("Invalid LUT_ADDRESSES Pubkey: invalid_pubkey");
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_reports_all_errors() {
+        set_test_env();
+        remove_env("RPC_URL");
+        remove_env("GEYSER_X_TOKEN");
+        env::set_var("METRICS_PORT", "70000");
+        env::set_var("DRY_RUN", "yes");
+
+        let error = Config::new().err().unwrap().to_string();
+        assert!(error.starts_with("Invalid configuration, 4 error(s):"));
+        assert!(error.contains("- RPC_URL environment variable is not set"));
+        assert!(error.contains("- GEYSER_X_TOKEN environment variable is not set"));
+        assert!(error.contains("- Invalid METRICS_PORT value, must be a number"));
+        assert!(error.contains("- Invalid DRY_RUN value, must be true or false"));
     }
 }
@@ -0,0 +1,41 @@
+use thiserror::Error;
+
+/// The failure kinds of the cache and the comms that the callers act on, e.g. retrying the RPC
+/// failures or refetching what is not cached yet. They travel through `anyhow` like the rest of
+/// the errors, `MaryError::of` recovers the kind.
+#[derive(Debug, Error)]
+pub enum MaryError {
+    #[error("{0}")]
+    LockPoisoned(String),
+    #[error("{0}")]
+    Rpc(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Deserialize(String),
+    #[error("{0}")]
+    OraclePrice(String),
+}
+
+impl MaryError {
+    /// The kind of the error, None if it does not originate from the cache or the comms.
+    pub fn of(err: &anyhow::Error) -> Option<&MaryError> {
+        err.downcast_ref::<MaryError>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_of() {
+        let err: anyhow::Error =
+            MaryError::NotFound("Bank 1 not found in cache".to_string()).into();
+        assert!(matches!(MaryError::of(&err), Some(MaryError::NotFound(_))));
+        assert_eq!(err.to_string(), "Bank 1 not found in cache");
+
+        assert!(MaryError::of(&anyhow!("Math overflow")).is_none());
+    }
+}
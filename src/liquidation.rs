@@ -1,6 +1,10 @@
 mod basic_liquidation_strategy;
+mod utils;
 use basic_liquidation_strategy::BasicLiquidationStrategy;
-use std::sync::Arc;
+use fixed::types::I80F48;
+use serde::{Serialize, Serializer};
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+use std::{fmt::Display, sync::Arc};
 
 use crate::{
     cache::{marginfi_accounts::CachedMarginfiAccount, Cache},
@@ -10,21 +14,178 @@ use crate::{
 pub trait LiquidationStrategy {
     fn prepare(&self, account: &CachedMarginfiAccount)
         -> anyhow::Result<Option<LiquidationParams>>;
+    /// Returns the legs of the liquidation that landed, if any was sent.
     fn liquidate<T: CommsClient>(
         &self,
         liquidation_params: LiquidationParams,
         comms_client: &T,
-    ) -> anyhow::Result<()>;
+    ) -> anyhow::Result<Option<LandedLegs>>;
+}
+
+/// The leading legs of a liquidation that landed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LandedLegs {
+    /// The signature of the last landed transaction.
+    pub signature: Signature,
+    /// Fewer than the planned legs if the liquidation stopped part way.
+    pub count: usize,
+}
+
+/// A single liquidate instruction, seizing the collateral of one bank for the liability of another.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LiquidationLeg {
+    #[serde(serialize_with = "serialize_display")]
+    pub asset_bank: Pubkey,
+    #[serde(serialize_with = "serialize_display")]
+    pub liab_bank: Pubkey,
+    /// The collateral to seize in the native units of the asset bank mint.
+    pub asset_amount: u64,
 }
 
 #[derive(Debug)]
-pub struct LiquidationParams {}
+pub struct LiquidationParams {
+    pub liquidatee_account: Pubkey,
+    /// The legs in the order they are sent, an account with many positions may need several.
+    pub legs: Vec<LiquidationLeg>,
+    /// The estimated liquidation profit, net of the transaction fees.
+    pub profit_usd: I80F48,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LiquidationStatus {
+    /// The liquidation transaction was confirmed.
+    Landed,
+    /// Only the leading legs of the liquidation landed, the rest were not sent.
+    Partial,
+    /// The liquidation transaction failed or was not confirmed.
+    Reverted,
+    /// The liquidation was prepared but not sent, e.g. in the dry run, or the account recovered
+    /// after a failed attempt.
+    Skipped,
+}
+
+/// The audit record of a liquidation attempt, logged as JSON regardless of the log format.
+#[derive(Debug, Clone, Serialize)]
+pub struct LiquidationOutcome {
+    #[serde(serialize_with = "serialize_display")]
+    pub liquidatee_account: Pubkey,
+    /// The collateral seized in exchange for the repaid liability per leg.
+    pub legs: Vec<LiquidationLeg>,
+    pub profit_usd: f64,
+    #[serde(serialize_with = "serialize_optional_display")]
+    pub signature: Option<Signature>,
+    pub status: LiquidationStatus,
+}
+
+impl LiquidationOutcome {
+    pub fn new(
+        params: &LiquidationParams,
+        signature: Option<Signature>,
+        status: LiquidationStatus,
+    ) -> Self {
+        Self {
+            liquidatee_account: params.liquidatee_account,
+            legs: params.legs.clone(),
+            profit_usd: params.profit_usd.to_num(),
+            signature,
+            status,
+        }
+    }
+}
+
+fn serialize_display<S: Serializer>(
+    value: &impl Display,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+fn serialize_optional_display<S: Serializer>(
+    value: &Option<impl Display>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.collect_str(value),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// In the dry run the liquidations are prepared and logged but never sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionMode {
+    DryRun,
+    Live,
+}
+
+impl ExecutionMode {
+    pub fn from_dry_run(dry_run: bool) -> Self {
+        if dry_run {
+            ExecutionMode::DryRun
+        } else {
+            ExecutionMode::Live
+        }
+    }
+}
 
 // TODO: create static reusable strategy objects instead of initializing them each time
 pub fn choose_liquidation_strategy(
     _account: &CachedMarginfiAccount,
-    _cache: &Arc<Cache>,
+    cache: &Arc<Cache>,
+    marginfi_program_id: &Pubkey,
+    min_profit_usd: f64,
+    max_repay_fraction: f64,
+    simulate_before_send: bool,
+    comms_client: &impl CommsClient,
 ) -> anyhow::Result<impl LiquidationStrategy> {
     // For now, we'll just use the basic strategy
-    Ok(BasicLiquidationStrategy {})
+    Ok(
+        BasicLiquidationStrategy::new(cache.clone(), *marginfi_program_id, min_profit_usd)
+            .with_max_repay_fraction(max_repay_fraction)
+            .with_simulate_before_send(simulate_before_send)
+            .with_liquidator_account(comms_client.liquidator_account())
+            .with_transaction_fee_lamports(comms_client.transaction_fee_lamports()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_liquidation_outcome_serializes_to_json() {
+        let params = LiquidationParams {
+            liquidatee_account: Pubkey::new_unique(),
+            legs: vec![LiquidationLeg {
+                asset_bank: Pubkey::new_unique(),
+                liab_bank: Pubkey::new_unique(),
+                asset_amount: 1_000,
+            }],
+            profit_usd: I80F48::from_num(2.5),
+        };
+
+        let outcome = LiquidationOutcome::new(&params, None, LiquidationStatus::Skipped);
+        let json: serde_json::Value = serde_json::to_value(&outcome).unwrap();
+        assert_eq!(
+            json["liquidatee_account"],
+            params.liquidatee_account.to_string()
+        );
+        let leg = &json["legs"][0];
+        assert_eq!(leg["asset_bank"], params.legs[0].asset_bank.to_string());
+        assert_eq!(leg["liab_bank"], params.legs[0].liab_bank.to_string());
+        assert_eq!(leg["asset_amount"], 1_000);
+        assert_eq!(json["profit_usd"], 2.5);
+        assert!(json["signature"].is_null());
+        assert_eq!(json["status"], "skipped");
+
+        let signature = Signature::new_unique();
+        let outcome = LiquidationOutcome::new(&params, Some(signature), LiquidationStatus::Landed);
+        let json: serde_json::Value = serde_json::to_value(&outcome).unwrap();
+        assert_eq!(json["signature"], signature.to_string());
+        assert_eq!(json["status"], "landed");
+
+        let outcome = LiquidationOutcome::new(&params, Some(signature), LiquidationStatus::Partial);
+        let json: serde_json::Value = serde_json::to_value(&outcome).unwrap();
+        assert_eq!(json["status"], "partial");
+    }
 }
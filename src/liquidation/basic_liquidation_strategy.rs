@@ -1,41 +1,1725 @@
-use log::debug;
+use std::{cmp::Reverse, collections::HashSet, sync::Arc};
+
+use anchor_lang::{InstructionData, ToAccountMetas};
+use anyhow::{anyhow, Result};
+use fixed::types::I80F48;
+use log::{debug, error, warn};
+use marginfi::{
+    constants::{
+        ASSET_TAG_DEFAULT, ASSET_TAG_STAKED, LIQUIDATION_INSURANCE_FEE, LIQUIDATION_LIQUIDATOR_FEE,
+        LIQUIDITY_VAULT_AUTHORITY_SEED,
+    },
+    state::{
+        marginfi_account::{ACCOUNT_DISABLED, ACCOUNT_IN_FLASHLOAN},
+        price::PriceBias,
+    },
+};
+use solana_sdk::{
+    instruction::{AccountMeta, Instruction},
+    pubkey,
+    pubkey::Pubkey,
+    signature::Signature,
+};
 
 use crate::{
-    cache::marginfi_accounts::CachedMarginfiAccount,
-    liquidation::{CommsClient, LiquidationParams},
+    cache::{
+        banks::CachedBank,
+        marginfi_accounts::{calc_value, CachedMarginfiAccount},
+        oracles::MAX_PRICE_AGE_SLOTS,
+        Cache,
+    },
+    error::MaryError,
+    liquidation::{
+        utils::{maybe_add_bank_mint, transfer_hook_account_metas, HookedTransfer},
+        CommsClient, LandedLegs, LiquidationLeg, LiquidationParams, LiquidationStrategy,
+    },
 };
 
-// Make sure to import or define the LiquidationStrategy trait
-use crate::liquidation::LiquidationStrategy;
+// The liquidate instructions prepared per account, every one is sent in its own transaction
+const MAX_LIQUIDATION_LEGS: usize = 4;
+// Every bank is referenced with its oracles by the liquidator and the liquidatee health checks, the
+// transaction account lock limit leaves room for about this many next to the fixed accounts
+const MAX_BANKS_PER_TRANSACTION: usize = 12;
+// The transaction fees are priced through the bank of this mint
+const WRAPPED_SOL_MINT: Pubkey = pubkey!("So11111111111111111111111111111111111111112");
 
-pub struct BasicLiquidationStrategy {}
+/// The USD value of a single position, priced with the bias that is unfavorable for the account.
+#[derive(Clone)]
+struct PositionValue {
+    bank: CachedBank,
+    price: I80F48,
+    value: I80F48,
+    weight: I80F48,
+    // The USD value of the cached liquidity vault balance, the liquidator can withdraw at most this
+    // much of the seized collateral
+    liquidity: I80F48,
+    // The confidence interval of the bank oracle price
+    confidence_bps: u64,
+    // The share of the transferred amount the token-2022 mint withholds
+    transfer_fee: I80F48,
+    // The hook program of the mint may reject the liquidator's transfers
+    transfer_hook: bool,
+}
 
-impl LiquidationStrategy for BasicLiquidationStrategy {
-    fn prepare(
+impl PositionValue {
+    fn weighted_value(&self) -> I80F48 {
+        self.value * self.weight
+    }
+
+    /// Ranks the collateral to seize. The seized collateral is credited to the liquidator's Marginfi
+    /// balance, the profit is realized by withdrawing it later, so only the part of the position the
+    /// vault liquidity covers counts. It is discounted by the oracle confidence interval, as the wide
+    /// one makes the seizure value uncertain.
+    fn collateral_score(&self) -> I80F48 {
+        let confidence =
+            I80F48::from_num(self.confidence_bps.min(10_000)) / I80F48::from_num(10_000);
+        self.value.min(self.liquidity) * (I80F48::ONE - confidence)
+    }
+}
+
+pub struct BasicLiquidationStrategy {
+    cache: Arc<Cache>,
+    marginfi_program_id: Pubkey,
+    min_profit_usd: I80F48,
+    // The share of each liability repaid at most, on top of the protocol limits
+    max_repay_fraction: I80F48,
+    // The legs are only sent if their simulation succeeds
+    simulate_before_send: bool,
+    // The Marginfi account receiving the seized collateral and taking over the liabilities
+    liquidator_account: Option<Pubkey>,
+    // The expected fees of every leg's transaction, deducted from the profit
+    transaction_fee_lamports: u64,
+}
+
+impl BasicLiquidationStrategy {
+    pub fn new(cache: Arc<Cache>, marginfi_program_id: Pubkey, min_profit_usd: f64) -> Self {
+        Self {
+            cache,
+            marginfi_program_id,
+            min_profit_usd: I80F48::from_num(min_profit_usd),
+            max_repay_fraction: I80F48::ONE,
+            simulate_before_send: false,
+            liquidator_account: None,
+            transaction_fee_lamports: 0,
+        }
+    }
+
+    pub fn with_max_repay_fraction(mut self, max_repay_fraction: f64) -> Self {
+        self.max_repay_fraction = I80F48::from_num(max_repay_fraction);
+        self
+    }
+
+    pub fn with_simulate_before_send(mut self, simulate_before_send: bool) -> Self {
+        self.simulate_before_send = simulate_before_send;
+        self
+    }
+
+    pub fn with_liquidator_account(mut self, liquidator_account: Pubkey) -> Self {
+        self.liquidator_account = Some(liquidator_account);
+        self
+    }
+
+    pub fn with_transaction_fee_lamports(mut self, transaction_fee_lamports: u64) -> Self {
+        self.transaction_fee_lamports = transaction_fee_lamports;
+        self
+    }
+
+    /// The USD value of the lamports, priced through the wrapped SOL bank of the group.
+    fn lamports_value(&self, group: &Pubkey, lamports: u64) -> Result<I80F48> {
+        let clock = self.cache.get_clock()?;
+        let sol_bank = self
+            .cache
+            .banks
+            .get_group_bank_by_mint(group, &WRAPPED_SOL_MINT)?;
+        let price = self
+            .cache
+            .oracles
+            .get_bank_price(&sol_bank, &clock, Some(PriceBias::High))?;
+        calc_value(
+            I80F48::from_num(lamports),
+            price,
+            sol_bank.mint_decimals(),
+            I80F48::ONE,
+        )
+    }
+
+    /// The asset tags of the banks the liquidator's Marginfi account has the balances in, none until
+    /// the account is cached.
+    fn liquidator_asset_tags(&self) -> Result<HashSet<u8>> {
+        let Some(liquidator_account) = self.liquidator_account else {
+            return Ok(HashSet::new());
+        };
+        let liquidator = match self
+            .cache
+            .marginfi_accounts
+            .get_account(&liquidator_account)
+        {
+            Ok(liquidator) => liquidator,
+            Err(err) if matches!(MaryError::of(&err), Some(MaryError::NotFound(_))) => {
+                return Ok(HashSet::new())
+            }
+            Err(err) => return Err(err),
+        };
+        liquidator
+            ._positions()
+            .iter()
+            .map(|position| Ok(self.cache.banks.get_bank(&position.bank_pk)?.asset_tag()))
+            .collect()
+    }
+
+    /// Liquidating on a stale price risks a reverted or losing transaction.
+    fn has_stale_oracle(&self, account: &CachedMarginfiAccount) -> Result<bool> {
+        let clock = self.cache.get_clock()?;
+        for position in account._positions() {
+            let bank = self.cache.banks.get_bank(&position.bank_pk)?;
+            let oracle_addresses = bank.price_oracle_addresses();
+            if oracle_addresses.is_empty() {
+                return Err(anyhow!("Bank {} has no oracle", position.bank_pk));
+            }
+            for oracle_address in oracle_addresses {
+                if self
+                    .cache
+                    .oracles
+                    .is_stale(oracle_address, &clock, MAX_PRICE_AGE_SLOTS)?
+                {
+                    debug!(
+                        "The oracle {} of the bank {} is stale.",
+                        oracle_address, position.bank_pk
+                    );
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns the asset and the liability positions of the account.
+    fn evaluate_positions(
         &self,
-        _account: &CachedMarginfiAccount,
-    ) -> anyhow::Result<Option<LiquidationParams>> {
-        debug!("Evaluating account {:?} for liquidation.", _account);
-        /*
-        1. Calc total account's  assets amount in USD.
-        2. Calc total account's liab amount in USD.
-        // get_account_health_components(bank_accounts_with_price, emode_config, requirement_type, optional health_cache)
-
-        3. Confirm that the account is liquidable.
-        4. Select collat and liab banks.
-        5. Calc the collat withdraw and liab repay amounts.
-        6. Confirm that the liquidation profit in USD > the configured min liquidation profit.
-        7. Create the LiquidationParams object.
-        */
-        Ok(Some(LiquidationParams {}))
+        account: &CachedMarginfiAccount,
+    ) -> Result<(Vec<PositionValue>, Vec<PositionValue>)> {
+        let clock = self.cache.get_clock()?;
+        let emode_entries = account.emode_entries(&self.cache)?;
+        let mut assets = Vec::new();
+        let mut liabilities = Vec::new();
+
+        for position in account._positions() {
+            let bank = self.cache.banks.get_bank(&position.bank_pk)?;
+            let (transfer_fee, transfer_hook) = self.transfer_extensions(&bank, clock.epoch)?;
+
+            let asset_shares: I80F48 = position.asset_shares.into();
+            if asset_shares > I80F48::ZERO {
+                let price =
+                    self.cache
+                        .oracles
+                        .get_bank_price(&bank, &clock, Some(PriceBias::Low))?;
+                let amount = bank.shares_to_tokens(asset_shares, false)?;
+                assets.push(PositionValue {
+                    value: calc_value(amount, price, bank.mint_decimals(), I80F48::ONE)?,
+                    weight: bank.asset_weight(true, &emode_entries),
+                    price,
+                    liquidity: self.liquidity_value(&bank, price)?,
+                    confidence_bps: self.oracle_confidence_bps(&bank)?,
+                    transfer_fee,
+                    transfer_hook,
+                    bank: bank.clone(),
+                });
+            }
+
+            let liability_shares: I80F48 = position.liability_shares.into();
+            if liability_shares > I80F48::ZERO {
+                let price =
+                    self.cache
+                        .oracles
+                        .get_bank_price(&bank, &clock, Some(PriceBias::High))?;
+                let amount = bank.shares_to_tokens(liability_shares, true)?;
+                liabilities.push(PositionValue {
+                    value: calc_value(amount, price, bank.mint_decimals(), I80F48::ONE)?,
+                    weight: bank.liability_weight(true),
+                    price,
+                    // Not ranked, the liabilities are repaid by the liquidator
+                    liquidity: I80F48::MAX,
+                    confidence_bps: 0,
+                    transfer_fee,
+                    transfer_hook,
+                    bank,
+                });
+            }
+        }
+
+        Ok((assets, liabilities))
     }
 
+    /// The USD value of the cached liquidity vault balance, unbounded until the balance is cached.
+    fn liquidity_value(&self, bank: &CachedBank, price: I80F48) -> Result<I80F48> {
+        match self.cache.banks.liquidity(&bank.address) {
+            Ok(amount) => calc_value(
+                I80F48::from_num(amount),
+                price,
+                bank.mint_decimals(),
+                I80F48::ONE,
+            ),
+            Err(err) if matches!(MaryError::of(&err), Some(MaryError::NotFound(_))) => {
+                Ok(I80F48::MAX)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The transfer fee of the bank mint in the epoch and whether the mint has a transfer hook. Only
+    /// the token-2022 mints have either, the mints that are not cached yet are assumed to have neither.
+    fn transfer_extensions(&self, bank: &CachedBank, epoch: u64) -> Result<(I80F48, bool)> {
+        match self.cache.mints.get(bank.mint())? {
+            Some(mint) if mint.owner == spl_token_2022::id() => Ok((
+                I80F48::from_num(mint.transfer_fee_bps(epoch)?) / I80F48::from_num(10_000),
+                mint.has_transfer_hook()?,
+            )),
+            _ => Ok((I80F48::ZERO, false)),
+        }
+    }
+
+    fn oracle_confidence_bps(&self, bank: &CachedBank) -> Result<u64> {
+        let oracle_address = bank
+            .oracle_addresses()
+            .first()
+            .ok_or_else(|| anyhow!("Bank {} has no oracle", bank.address))?;
+        Ok(self
+            .cache
+            .oracles
+            .confidence_bps(oracle_address)?
+            .unwrap_or_default())
+    }
+
+    /// The bank and its oracles for every position, followed by the banks the liquidation adds.
+    fn observation_accounts(
+        &self,
+        account: &CachedMarginfiAccount,
+        new_banks: &[&CachedBank],
+    ) -> Result<Vec<Pubkey>> {
+        let mut banks: Vec<CachedBank> = account
+            ._positions()
+            .iter()
+            .map(|position| self.cache.banks.get_bank(&position.bank_pk))
+            .collect::<Result<_>>()?;
+        for new_bank in new_banks {
+            if !banks.iter().any(|bank| bank.address == new_bank.address) {
+                banks.push((*new_bank).clone());
+            }
+        }
+
+        Ok(banks
+            .iter()
+            .flat_map(|bank| {
+                std::iter::once(bank.address).chain(bank.oracle_addresses().iter().copied())
+            })
+            .collect())
+    }
+
+    /// Sends the leg in its own transaction. Returns None without sending it if the simulation
+    /// before the send is enabled and fails.
+    fn liquidate_leg<T: CommsClient>(
+        &self,
+        leg: &LiquidationLeg,
+        liquidatee: &CachedMarginfiAccount,
+        liquidator: &CachedMarginfiAccount,
+        liquidator_new_banks: &[CachedBank],
+        comms_client: &T,
+    ) -> Result<Option<Signature>> {
+        let asset_bank = self.cache.banks.get_bank(&leg.asset_bank)?;
+        let liab_bank = self.cache.banks.get_bank(&leg.liab_bank)?;
+        let liab_mint = self
+            .cache
+            .mints
+            .get(liab_bank.mint())?
+            .ok_or_else(|| anyhow!("Mint {} not found in cache", liab_bank.mint()))?;
+        let token_program = liab_mint.owner;
+
+        let mut remaining_accounts: Vec<Pubkey> = asset_bank
+            .oracle_addresses()
+            .iter()
+            .chain(liab_bank.oracle_addresses())
+            .copied()
+            .collect();
+        maybe_add_bank_mint(&mut remaining_accounts, liab_bank.mint(), &token_program);
+        let new_banks: Vec<&CachedBank> = liquidator_new_banks.iter().collect();
+        remaining_accounts.extend(self.observation_accounts(liquidator, &new_banks)?);
+        remaining_accounts.extend(self.observation_accounts(liquidatee, &[])?);
+
+        let mut remaining_account_metas: Vec<AccountMeta> = remaining_accounts
+            .iter()
+            .map(|address| AccountMeta::new_readonly(*address, false))
+            .collect();
+        if token_program == spl_token_2022::id() {
+            // The insurance fee is moved from the liquidity vault to the insurance vault, its amount
+            // is only known on-chain so the hooks seeded by the amount are not supported
+            let transfer = HookedTransfer {
+                source: *liab_bank.liquidity_vault(),
+                destination: *liab_bank.insurance_vault(),
+                authority: liquidity_vault_authority(&self.marginfi_program_id, &liab_bank.address),
+                amount: 0,
+            };
+            remaining_account_metas.extend(transfer_hook_account_metas(
+                comms_client,
+                liab_bank.mint(),
+                &liab_mint.data,
+                &transfer,
+            )?);
+        }
+
+        let ixs = vec![make_liquidate_ix(
+            &self.marginfi_program_id,
+            leg,
+            liquidatee,
+            &liab_bank,
+            liquidator.address(),
+            &comms_client.signer_pubkey(),
+            &token_program,
+            remaining_account_metas,
+        )];
+        if self.simulate_before_send {
+            let simulation = comms_client.simulate(&ixs)?;
+            if !simulation.is_success() {
+                warn!(
+                    "Skipping the leg {:?} of the Marginfi account {}, its simulation failed: {}",
+                    leg,
+                    liquidatee.address(),
+                    simulation.revert_reason()
+                );
+                return Ok(None);
+            }
+        }
+        comms_client.send_ixs(ixs).map(Some)
+    }
+}
+
+impl LiquidationStrategy for BasicLiquidationStrategy {
+    fn prepare(&self, account: &CachedMarginfiAccount) -> Result<Option<LiquidationParams>> {
+        debug!("Evaluating account {:?} for liquidation.", account);
+        // The program rejects the liquidation of the disabled accounts and of the ones mid-flashloan
+        if account.account_flags() & (ACCOUNT_DISABLED | ACCOUNT_IN_FLASHLOAN) != 0 {
+            debug!(
+                "Skipping account {:?} with the flags {:#b}.",
+                account,
+                account.account_flags()
+            );
+            return Ok(None);
+        }
+        if self.has_stale_oracle(account)? {
+            debug!("Skipping account {:?} with a stale oracle.", account);
+            return Ok(None);
+        }
+
+        let (assets, liabilities) = self.evaluate_positions(account)?;
+        let health: I80F48 = assets
+            .iter()
+            .map(PositionValue::weighted_value)
+            .sum::<I80F48>()
+            - liabilities
+                .iter()
+                .map(PositionValue::weighted_value)
+                .sum::<I80F48>();
+        if health >= I80F48::ZERO {
+            debug!("Account {:?} is not liquidatable.", account);
+            return Ok(None);
+        }
+
+        let mut legs = Vec::new();
+        let mut profit_usd = I80F48::ZERO;
+        for (asset_index, liability_index, liquidation_value) in plan_legs(
+            account.address(),
+            health,
+            &assets,
+            &liabilities,
+            &self.liquidator_asset_tags()?,
+            self.max_repay_fraction,
+            MAX_LIQUIDATION_LEGS,
+        ) {
+            let asset = &assets[asset_index];
+            let asset_amount =
+                native_amount(liquidation_value, asset.price, asset.bank.mint_decimals())?;
+            if asset_amount == 0 {
+                continue;
+            }
+            let liability = &liabilities[liability_index];
+            legs.push(LiquidationLeg {
+                asset_bank: asset.bank.address,
+                liab_bank: liability.bank.address,
+                asset_amount,
+            });
+            profit_usd += estimate_profit(
+                liquidation_value,
+                asset.transfer_fee,
+                liability.transfer_fee,
+            );
+        }
+        if legs.is_empty() {
+            debug!(
+                "Liquidating the account {:?} does not improve its health.",
+                account
+            );
+            return Ok(None);
+        }
+        // Every leg is sent in its own transaction
+        if self.transaction_fee_lamports > 0 {
+            let fee_lamports = self.transaction_fee_lamports * legs.len() as u64;
+            match self.lamports_value(account.group(), fee_lamports) {
+                Ok(fee_usd) => profit_usd -= fee_usd,
+                Err(err) => {
+                    warn!(
+                        "Skipping the liquidation of the account {:?}, failed to price the transaction fees: {}",
+                        account, err
+                    );
+                    return Ok(None);
+                }
+            }
+        }
+
+        debug!(
+            "Estimated profit of liquidating the account {:?} in {} leg(s): {} USD.",
+            account,
+            legs.len(),
+            profit_usd
+        );
+        if profit_usd < self.min_profit_usd {
+            return Ok(None);
+        }
+
+        Ok(Some(LiquidationParams {
+            liquidatee_account: *account.address(),
+            legs,
+            profit_usd,
+        }))
+    }
+
+    /// Sends the legs one transaction at a time and returns the ones that landed. The legs after a
+    /// failed one are not sent, as they were planned on top of it. Neither are the legs from the one
+    /// exceeding the bank cap on, they are left to the next pass.
     fn liquidate<T: CommsClient>(
         &self,
         liquidation_params: LiquidationParams,
-        _comms_client: &T,
-    ) -> anyhow::Result<()> {
+        comms_client: &T,
+    ) -> Result<Option<LandedLegs>> {
         debug!("Liquidating {:?}", liquidation_params);
-        Ok(())
+
+        let liquidatee = self
+            .cache
+            .marginfi_accounts
+            .get_account(&liquidation_params.liquidatee_account)?;
+        let liquidator = self
+            .cache
+            .marginfi_accounts
+            .get_account(&comms_client.liquidator_account())?;
+
+        // The liquidator takes over the positions of the previous legs before the cache catches up
+        let mut liquidator_new_banks: Vec<CachedBank> = Vec::new();
+        let mut landed = None;
+        for (index, leg) in liquidation_params.legs.iter().enumerate() {
+            let asset_bank = self.cache.banks.get_bank(&leg.asset_bank)?;
+            let liab_bank = self.cache.banks.get_bank(&leg.liab_bank)?;
+            for bank in [asset_bank, liab_bank] {
+                if !liquidator_new_banks
+                    .iter()
+                    .any(|new_bank| new_bank.address == bank.address)
+                {
+                    liquidator_new_banks.push(bank);
+                }
+            }
+
+            let banks = referenced_banks(&[&liquidator, &liquidatee], &liquidator_new_banks);
+            if banks > MAX_BANKS_PER_TRANSACTION {
+                warn!(
+                    "The leg {} of {} of the Marginfi account {} references {} banks, more than {} fit in a transaction, leaving the rest for the next pass.",
+                    index + 1,
+                    liquidation_params.legs.len(),
+                    liquidation_params.liquidatee_account,
+                    banks,
+                    MAX_BANKS_PER_TRANSACTION
+                );
+                break;
+            }
+
+            let result = self.liquidate_leg(
+                leg,
+                &liquidatee,
+                &liquidator,
+                &liquidator_new_banks,
+                comms_client,
+            );
+            match (result, landed) {
+                (Ok(Some(signature)), _) => {
+                    landed = Some(LandedLegs {
+                        signature,
+                        count: index + 1,
+                    })
+                }
+                // Not sent, the following legs rely on the liquidator's position it takes over
+                (Ok(None), _) => break,
+                (Err(err), None) => return Err(err),
+                (Err(err), Some(_)) => {
+                    error!(
+                        "Failed to liquidate the leg {} of {} of the Marginfi account {}, the previous legs landed: {}",
+                        index + 1,
+                        liquidation_params.legs.len(),
+                        liquidation_params.liquidatee_account,
+                        err
+                    );
+                    break;
+                }
+            }
+        }
+
+        Ok(landed)
+    }
+}
+
+/// The distinct banks the health checks of the accounts reference, with the banks the liquidation adds.
+fn referenced_banks(accounts: &[&CachedMarginfiAccount], new_banks: &[CachedBank]) -> usize {
+    accounts
+        .iter()
+        .flat_map(|account| account._positions().iter().map(|position| position.bank_pk))
+        .chain(new_banks.iter().map(|bank| bank.address))
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// Pairs the largest remaining liability with the best scoring remaining collateral that can be
+/// seized against it until the health is restored, the positions are exhausted or the legs run out.
+/// At most the max repay fraction of every liability is repaid. Every leg adds its banks to the
+/// asset tags of the liquidator's balances the later legs have to be compatible with.
+/// Returns the asset and the liability position indices with the USD value to seize per leg.
+fn plan_legs(
+    account: &Pubkey,
+    mut health: I80F48,
+    assets: &[PositionValue],
+    liabilities: &[PositionValue],
+    liquidator_tags: &HashSet<u8>,
+    max_repay_fraction: I80F48,
+    max_legs: usize,
+) -> Vec<(usize, usize, I80F48)> {
+    let repaid_ratio = I80F48::ONE - LIQUIDATION_LIQUIDATOR_FEE - LIQUIDATION_INSURANCE_FEE;
+    let mut assets = assets.to_vec();
+    // From here on the liability value is the part left to repay
+    let mut liabilities = liabilities.to_vec();
+    for liability in &mut liabilities {
+        liability.value *= max_repay_fraction;
+    }
+    let mut liquidator_tags = liquidator_tags.clone();
+    let mut legs = Vec::new();
+
+    while health < I80F48::ZERO && legs.len() < max_legs {
+        let Some((asset_index, liability_index)) =
+            best_pair(account, &assets, &liabilities, &liquidator_tags)
+        else {
+            break;
+        };
+
+        let asset = &assets[asset_index];
+        let liability = &liabilities[liability_index];
+        let Some(value) = max_liquidation_value(health, asset, liability) else {
+            debug!(
+                "Liquidating the bank {} against the bank {} does not improve the health.",
+                asset.bank.address, liability.bank.address
+            );
+            break;
+        };
+        if value <= I80F48::ZERO {
+            break;
+        }
+
+        // Not capped by the positions, so the leg restores the health up to the rounding
+        let health_gain = liability.weight * repaid_ratio - asset.weight;
+        let restores_health = (-health).checked_div(health_gain) == Some(value);
+        liquidator_tags.extend([asset.bank.asset_tag(), liability.bank.asset_tag()]);
+        health += value * health_gain;
+        assets[asset_index].value -= value;
+        liabilities[liability_index].value -= value * repaid_ratio;
+        legs.push((asset_index, liability_index, value));
+        if restores_health {
+            break;
+        }
+    }
+
+    legs
+}
+
+/// Returns the asset and the liability position indices of the largest remaining liability and the
+/// best scoring remaining collateral whose asset tags are compatible with the liquidator's balances.
+/// The paused banks are passed over, as is the collateral of the mints with a transfer hook, which
+/// the liquidator may not be able to withdraw.
+fn best_pair(
+    account: &Pubkey,
+    assets: &[PositionValue],
+    liabilities: &[PositionValue],
+    liquidator_tags: &HashSet<u8>,
+) -> Option<(usize, usize)> {
+    let mut liability_indices: Vec<usize> = (0..liabilities.len())
+        .filter(|&index| {
+            liabilities[index].value > I80F48::ZERO
+                && !passed_over_paused(&liabilities[index].bank, account)
+        })
+        .collect();
+    liability_indices.sort_by_key(|&index| Reverse(liabilities[index].value));
+
+    liability_indices.into_iter().find_map(|liability_index| {
+        let liability_tag = liabilities[liability_index].bank.asset_tag();
+        assets
+            .iter()
+            .enumerate()
+            .filter(|(_, asset)| {
+                asset.value > I80F48::ZERO
+                    && !passed_over_paused(&asset.bank, account)
+                    && !asset.transfer_hook
+                    && compatible_asset_tags(
+                        liquidator_tags
+                            .iter()
+                            .copied()
+                            .chain([asset.bank.asset_tag(), liability_tag]),
+                    )
+            })
+            // The size breaks the ties, e.g. between the fully borrowed out vaults
+            .max_by_key(|(_, asset)| (asset.collateral_score(), asset.value))
+            .map(|(asset_index, _)| (asset_index, liability_index))
+    })
+}
+
+/// Whether the bank is paused, the positions of the account in it still count towards its health
+/// but are not liquidated.
+fn passed_over_paused(bank: &CachedBank, account: &Pubkey) -> bool {
+    let paused = bank.is_paused();
+    if paused {
+        debug!(
+            "The bank {} of the Marginfi account {} is paused, not liquidating against it.",
+            bank.address, account
+        );
+    }
+    paused
+}
+
+/// The liquidator receives the seized collateral next to the taken over liability, the program
+/// rejects the accounts whose balances mix the staked collateral with the default asset tag banks.
+/// The SOL asset tag banks go with either.
+fn compatible_asset_tags(tags: impl IntoIterator<Item = u8>) -> bool {
+    let (mut default, mut staked) = (false, false);
+    for tag in tags {
+        match tag {
+            ASSET_TAG_DEFAULT => default = true,
+            ASSET_TAG_STAKED => staked = true,
+            _ => {}
+        }
+    }
+    !(default && staked)
+}
+
+/// Returns the USD value of the collateral to seize so that the account health is restored
+/// to zero, capped by the collateral and the liability positions.
+/// None if seizing the collateral does not improve the account health.
+fn max_liquidation_value(
+    health: I80F48,
+    asset: &PositionValue,
+    liability: &PositionValue,
+) -> Option<I80F48> {
+    // The liquidatee's liability is repaid with the seized value minus the liquidator and insurance fees
+    let repaid_ratio = I80F48::ONE - LIQUIDATION_LIQUIDATOR_FEE - LIQUIDATION_INSURANCE_FEE;
+    let health_gain = liability.weight * repaid_ratio - asset.weight;
+    if health_gain <= I80F48::ZERO {
+        return None;
+    }
+
+    let value = (-health)
+        .checked_div(health_gain)?
+        .min(asset.value)
+        .min(liability.value.checked_div(repaid_ratio)?);
+    Some(value)
+}
+
+/// The liquidator takes over the liability worth the seized collateral minus the liquidator fee,
+/// the insurance fee is deducted from the repaid liquidatee's liability and does not affect the profit.
+/// The mints withhold their transfer fees as the liquidator withdraws the seized collateral and
+/// repays the taken over liability.
+fn estimate_profit(
+    seized_value: I80F48,
+    asset_transfer_fee: I80F48,
+    liability_transfer_fee: I80F48,
+) -> I80F48 {
+    let repaid_value = seized_value * (I80F48::ONE - LIQUIDATION_LIQUIDATOR_FEE);
+    seized_value * (I80F48::ONE - asset_transfer_fee)
+        - repaid_value * (I80F48::ONE + liability_transfer_fee)
+}
+
+fn native_amount(value: I80F48, price: I80F48, mint_decimals: u8) -> Result<u64> {
+    let decimals_scale = 10u64
+        .checked_pow(mint_decimals as u32)
+        .map(I80F48::from_num)
+        .ok_or_else(|| anyhow!("Invalid mint decimals {}", mint_decimals))?;
+
+    value
+        .checked_div(price)
+        .and_then(|amount| amount.checked_mul(decimals_scale))
+        .and_then(|amount| amount.checked_to_num::<u64>())
+        .ok_or_else(|| anyhow!("Math overflow in the native amount"))
+}
+
+fn liquidity_vault_authority(marginfi_program_id: &Pubkey, bank: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[LIQUIDITY_VAULT_AUTHORITY_SEED.as_bytes(), bank.as_ref()],
+        marginfi_program_id,
+    )
+    .0
+}
+
+#[allow(clippy::too_many_arguments)]
+fn make_liquidate_ix(
+    marginfi_program_id: &Pubkey,
+    leg: &LiquidationLeg,
+    liquidatee: &CachedMarginfiAccount,
+    liab_bank: &CachedBank,
+    liquidator_account: &Pubkey,
+    signer: &Pubkey,
+    token_program: &Pubkey,
+    remaining_accounts: Vec<AccountMeta>,
+) -> Instruction {
+    let mut accounts = marginfi::accounts::LendingAccountLiquidate {
+        group: *liquidatee.group(),
+        asset_bank: leg.asset_bank,
+        liab_bank: leg.liab_bank,
+        liquidator_marginfi_account: *liquidator_account,
+        authority: *signer,
+        liquidatee_marginfi_account: *liquidatee.address(),
+        bank_liquidity_vault_authority: liquidity_vault_authority(
+            marginfi_program_id,
+            &liab_bank.address,
+        ),
+        bank_liquidity_vault: *liab_bank.liquidity_vault(),
+        bank_insurance_vault: *liab_bank.insurance_vault(),
+        token_program: *token_program,
+    }
+    .to_account_metas(Some(true));
+    accounts.extend(remaining_accounts);
+
+    Instruction {
+        program_id: *marginfi_program_id,
+        accounts,
+        data: marginfi::instruction::LendingAccountLiquidate {
+            asset_amount: leg.asset_amount,
+        }
+        .data(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use marginfi::{
+        constants::ASSET_TAG_SOL,
+        state::{marginfi_group::BankOperationalState, price::OracleSetup},
+    };
+    use solana_sdk::account::Account;
+
+    use super::*;
+    use crate::cache::{
+        banks::test_util::{add_priced_bank, create_bank_with_oracles, create_token_account},
+        marginfi_accounts::test_util::{create_balance, create_marginfi_account},
+        mints::test_util::create_token_2022_mint,
+        oracles::test_util::{create_dummy_oracle_account, create_pyth_account},
+        test_util::{create_dummy_cache, generate_test_clock},
+    };
+    use crate::comms::test_util::MockedCommsClient;
+
+    fn setup(oracle_slot: u64) -> (Arc<Cache>, CachedMarginfiAccount) {
+        setup_with_oracle_slots(&[oracle_slot])
+    }
+
+    /// The account borrowing from the bank priced by an oracle updated at each of the slots.
+    fn setup_with_oracle_slots(oracle_slots: &[u64]) -> (Arc<Cache>, CachedMarginfiAccount) {
+        let cache = Arc::new(create_dummy_cache());
+        let bank_address = Pubkey::new_unique();
+        let oracle_addresses: Vec<Pubkey> =
+            oracle_slots.iter().map(|_| Pubkey::new_unique()).collect();
+        cache
+            .banks
+            .update(
+                1,
+                bank_address,
+                &create_bank_with_oracles(oracle_addresses.clone()),
+            )
+            .unwrap();
+        for (oracle_address, oracle_slot) in oracle_addresses.iter().zip(oracle_slots) {
+            cache
+                .oracles
+                .insert(
+                    *oracle_slot,
+                    0,
+                    oracle_address,
+                    OracleSetup::SwitchboardPull,
+                    create_dummy_oracle_account(OracleSetup::SwitchboardPull),
+                )
+                .unwrap();
+        }
+
+        let address = Pubkey::new_unique();
+        let marginfi_account = create_marginfi_account(
+            Pubkey::new_unique(),
+            vec![create_balance(bank_address, 100, 50)],
+        );
+        cache
+            .update_marginfi_account(1, address, &marginfi_account)
+            .unwrap();
+        let account = cache.marginfi_accounts.get_account(&address).unwrap();
+        (cache, account)
+    }
+
+    /// Adds the wrapped SOL bank of the group priced at the pyth price.
+    fn add_sol_bank(cache: &Cache, group: Pubkey, price: i64) {
+        let oracle_address = Pubkey::new_unique();
+        let mut bank = create_bank_with_oracles(vec![oracle_address]);
+        bank.group = group;
+        bank.mint = WRAPPED_SOL_MINT;
+        bank.mint_decimals = 9;
+        cache.banks.update(1, Pubkey::new_unique(), &bank).unwrap();
+        cache
+            .oracles
+            .insert(
+                1,
+                0,
+                &oracle_address,
+                OracleSetup::PythPushOracle,
+                create_pyth_account(price, 0, price, 0, 1),
+            )
+            .unwrap();
+    }
+
+    fn position(value: i64, weight: f64) -> PositionValue {
+        tagged_position(value, weight, ASSET_TAG_DEFAULT)
+    }
+
+    fn tagged_position(value: i64, weight: f64, asset_tag: u8) -> PositionValue {
+        let mut bank = create_bank_with_oracles(vec![]);
+        bank.config.asset_tag = asset_tag;
+        PositionValue {
+            bank: CachedBank::from(1, Pubkey::new_unique(), bank),
+            price: I80F48::ONE,
+            value: I80F48::from_num(value),
+            weight: I80F48::from_num(weight),
+            liquidity: I80F48::MAX,
+            confidence_bps: 0,
+            transfer_fee: I80F48::ZERO,
+            transfer_hook: false,
+        }
+    }
+
+    #[test]
+    fn test_basic_liquidation_strategy_prepare_healthy_account() {
+        let (cache, account) = setup(1);
+        let strategy = BasicLiquidationStrategy::new(cache, Pubkey::new_unique(), 0.0);
+
+        assert!(strategy.prepare(&account).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_basic_liquidation_strategy_skips_flagged_accounts() {
+        let cache = Arc::new(create_dummy_cache());
+        let collateral_bank = add_priced_bank(&cache, create_bank_with_oracles(vec![]), 0.8, 1.0);
+        let liability_bank = add_priced_bank(&cache, create_bank_with_oracles(vec![]), 0.8, 1.0);
+        let strategy = BasicLiquidationStrategy::new(cache.clone(), Pubkey::new_unique(), 0.0);
+
+        for (flags, liquidatable) in [
+            (0, true),
+            (ACCOUNT_DISABLED, false),
+            (ACCOUNT_IN_FLASHLOAN, false),
+        ] {
+            let address = Pubkey::new_unique();
+            let mut marginfi_account = create_marginfi_account(
+                Pubkey::new_unique(),
+                vec![
+                    create_balance(collateral_bank, 1_000_000_000, 0),
+                    create_balance(liability_bank, 0, 900_000_000),
+                ],
+            );
+            marginfi_account.account_flags = flags;
+            cache
+                .update_marginfi_account(1, address, &marginfi_account)
+                .unwrap();
+            let account = cache.marginfi_accounts.get_account(&address).unwrap();
+
+            assert_eq!(strategy.prepare(&account).unwrap().is_some(), liquidatable);
+        }
+    }
+
+    #[test]
+    fn test_basic_liquidation_strategy_deducts_transaction_fees() {
+        let cache = Arc::new(create_dummy_cache());
+        let group = Pubkey::new_unique();
+        // The wrapped SOL bank of another group does not price the fees
+        add_sol_bank(&cache, Pubkey::new_unique(), 1_000_000);
+        add_sol_bank(&cache, group, 100_000);
+        let collateral_bank = add_priced_bank(&cache, create_bank_with_oracles(vec![]), 0.8, 1.0);
+        let liability_bank = add_priced_bank(&cache, create_bank_with_oracles(vec![]), 0.8, 1.0);
+        let address = Pubkey::new_unique();
+        cache
+            .update_marginfi_account(
+                1,
+                address,
+                &create_marginfi_account(
+                    group,
+                    vec![
+                        create_balance(collateral_bank, 1_000_000_000, 0),
+                        create_balance(liability_bank, 0, 900_000_000),
+                    ],
+                ),
+            )
+            .unwrap();
+        let account = cache.marginfi_accounts.get_account(&address).unwrap();
+        let strategy = |transaction_fee_lamports| {
+            BasicLiquidationStrategy::new(cache.clone(), Pubkey::new_unique(), 0.0)
+                .with_transaction_fee_lamports(transaction_fee_lamports)
+        };
+
+        let free = strategy(0).prepare(&account).unwrap().unwrap();
+        let paid = strategy(5_000).prepare(&account).unwrap().unwrap();
+        let fee_usd = strategy(0)
+            .lamports_value(&group, 5_000 * paid.legs.len() as u64)
+            .unwrap();
+        assert!(fee_usd > I80F48::ZERO);
+        let sol_usd = strategy(0).lamports_value(&group, 1_000_000_000).unwrap();
+        assert!(sol_usd > I80F48::from_num(99) && sol_usd < I80F48::from_num(101));
+        assert_eq!(paid.profit_usd, free.profit_usd - fee_usd);
+
+        // The fees eating the whole profit leave the account to the others
+        let fee_lamports = (free.profit_usd * I80F48::from_num(10_000_000)).to_num::<u64>() + 1;
+        assert!(strategy(fee_lamports).prepare(&account).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_basic_liquidation_strategy_skips_stale_oracle() {
+        let (cache, account) = setup(1);
+        cache
+            .update_clock(generate_test_clock(MAX_PRICE_AGE_SLOTS + 2))
+            .unwrap();
+        let strategy = BasicLiquidationStrategy::new(cache, Pubkey::new_unique(), 0.0);
+
+        assert!(strategy.prepare(&account).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_basic_liquidation_strategy_skips_stale_secondary_oracle() {
+        let fresh_slot = MAX_PRICE_AGE_SLOTS + 2;
+        let (cache, account) = setup_with_oracle_slots(&[fresh_slot, 1]);
+        cache.update_clock(generate_test_clock(fresh_slot)).unwrap();
+        let strategy = BasicLiquidationStrategy::new(cache, Pubkey::new_unique(), 0.0);
+
+        assert!(strategy.has_stale_oracle(&account).unwrap());
+        assert!(strategy.prepare(&account).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_basic_liquidation_strategy_liquidate_sends_ix() {
+        let (cache, liquidatee) = setup(1);
+        let liquidator_address = Pubkey::new_unique();
+        cache
+            .update_marginfi_account(
+                1,
+                liquidator_address,
+                &create_marginfi_account(*liquidatee.group(), vec![]),
+            )
+            .unwrap();
+        let bank = cache
+            .banks
+            .get_bank(&liquidatee._positions()[0].bank_pk)
+            .unwrap();
+        let token_program = Pubkey::new_unique();
+        cache
+            .mints
+            .update(
+                *bank.mint(),
+                &Account {
+                    owner: token_program,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let marginfi_program_id = Pubkey::new_unique();
+        let strategy = BasicLiquidationStrategy::new(cache, marginfi_program_id, 0.0);
+        let comms_client = MockedCommsClient::with_accounts(HashMap::new())
+            .with_liquidator_account(liquidator_address);
+        let liquidation_params = LiquidationParams {
+            liquidatee_account: *liquidatee.address(),
+            legs: vec![LiquidationLeg {
+                asset_bank: bank.address,
+                liab_bank: bank.address,
+                asset_amount: 1_000,
+            }],
+            profit_usd: I80F48::ONE,
+        };
+
+        assert!(strategy
+            .liquidate(liquidation_params, &comms_client)
+            .unwrap()
+            .is_some());
+        let sent_ixs = comms_client.sent_ixs();
+        assert_eq!(sent_ixs.len(), 1);
+        assert_eq!(sent_ixs[0].program_id, marginfi_program_id);
+        assert!(sent_ixs[0]
+            .accounts
+            .iter()
+            .any(|meta| meta.pubkey == token_program));
+    }
+
+    #[test]
+    fn test_basic_liquidation_strategy_liquidate_token_2022_mint() {
+        let (cache, liquidatee) = setup(1);
+        let liquidator_address = Pubkey::new_unique();
+        cache
+            .update_marginfi_account(
+                1,
+                liquidator_address,
+                &create_marginfi_account(*liquidatee.group(), vec![]),
+            )
+            .unwrap();
+        let bank = cache
+            .banks
+            .get_bank(&liquidatee._positions()[0].bank_pk)
+            .unwrap();
+        cache
+            .mints
+            .update(*bank.mint(), &create_token_2022_mint(6, None, None))
+            .unwrap();
+
+        let strategy = BasicLiquidationStrategy::new(cache, Pubkey::new_unique(), 0.0);
+        let comms_client = MockedCommsClient::with_accounts(HashMap::new())
+            .with_liquidator_account(liquidator_address);
+        let liquidation_params = LiquidationParams {
+            liquidatee_account: *liquidatee.address(),
+            legs: vec![LiquidationLeg {
+                asset_bank: bank.address,
+                liab_bank: bank.address,
+                asset_amount: 1_000,
+            }],
+            profit_usd: I80F48::ONE,
+        };
+        strategy
+            .liquidate(liquidation_params, &comms_client)
+            .unwrap();
+
+        let sent_ixs = comms_client.sent_ixs();
+        let accounts = &sent_ixs[0].accounts;
+        // The token program is the last of the fixed accounts, the mint leads the remaining ones
+        let token_program_index = accounts
+            .iter()
+            .position(|meta| meta.pubkey == spl_token_2022::id())
+            .unwrap();
+        assert_eq!(accounts[token_program_index + 1].pubkey, *bank.mint());
+    }
+
+    #[test]
+    fn test_basic_liquidation_strategy_liquidate_sends_every_leg() {
+        let (cache, liquidatee) = setup(1);
+        let liquidator_address = Pubkey::new_unique();
+        cache
+            .update_marginfi_account(
+                1,
+                liquidator_address,
+                &create_marginfi_account(*liquidatee.group(), vec![]),
+            )
+            .unwrap();
+        let bank = cache
+            .banks
+            .get_bank(&liquidatee._positions()[0].bank_pk)
+            .unwrap();
+        cache
+            .mints
+            .update(*bank.mint(), &Account::default())
+            .unwrap();
+
+        let strategy = BasicLiquidationStrategy::new(cache, Pubkey::new_unique(), 0.0);
+        let comms_client = MockedCommsClient::with_accounts(HashMap::new())
+            .with_liquidator_account(liquidator_address);
+        let leg = |asset_amount| LiquidationLeg {
+            asset_bank: bank.address,
+            liab_bank: bank.address,
+            asset_amount,
+        };
+        let liquidation_params = LiquidationParams {
+            liquidatee_account: *liquidatee.address(),
+            legs: vec![leg(1_000), leg(500)],
+            profit_usd: I80F48::ONE,
+        };
+
+        assert_eq!(
+            strategy
+                .liquidate(liquidation_params, &comms_client)
+                .unwrap()
+                .unwrap()
+                .count,
+            2
+        );
+        let sent_ixs = comms_client.sent_ixs();
+        assert_eq!(sent_ixs.len(), 2);
+        for (ix, asset_amount) in sent_ixs.iter().zip([1_000u64, 500]) {
+            assert_eq!(
+                ix.data,
+                marginfi::instruction::LendingAccountLiquidate { asset_amount }.data()
+            );
+        }
+    }
+
+    #[test]
+    fn test_basic_liquidation_strategy_liquidate_caps_the_banks() {
+        let cache = Arc::new(create_dummy_cache());
+        let banks: Vec<Pubkey> = (0..MAX_BANKS_PER_TRANSACTION + 1)
+            .map(|_| Pubkey::new_unique())
+            .collect();
+        let mint = Pubkey::new_unique();
+        for bank in &banks {
+            let mut bank_state = create_bank_with_oracles(vec![]);
+            bank_state.mint = mint;
+            cache.banks.update(1, *bank, &bank_state).unwrap();
+        }
+        cache.mints.update(mint, &Account::default()).unwrap();
+        // The liquidatee fills the cap on its own, the leg brings one more bank
+        let liquidatee_address = Pubkey::new_unique();
+        let liquidatee = create_marginfi_account(
+            Pubkey::new_unique(),
+            banks[..MAX_BANKS_PER_TRANSACTION]
+                .iter()
+                .map(|bank| create_balance(*bank, 100, 50))
+                .collect(),
+        );
+        let liquidator_address = Pubkey::new_unique();
+        cache
+            .update_marginfi_account(1, liquidatee_address, &liquidatee)
+            .unwrap();
+        cache
+            .update_marginfi_account(
+                1,
+                liquidator_address,
+                &create_marginfi_account(Pubkey::new_unique(), vec![]),
+            )
+            .unwrap();
+
+        let strategy = BasicLiquidationStrategy::new(cache, Pubkey::new_unique(), 0.0);
+        let leg = |asset_bank| LiquidationLeg {
+            asset_bank,
+            liab_bank: banks[0],
+            asset_amount: 1_000,
+        };
+        let comms_client = MockedCommsClient::with_accounts(HashMap::new())
+            .with_liquidator_account(liquidator_address);
+        let liquidation_params = LiquidationParams {
+            liquidatee_account: liquidatee_address,
+            legs: vec![leg(banks[MAX_BANKS_PER_TRANSACTION])],
+            profit_usd: I80F48::ONE,
+        };
+
+        assert!(strategy
+            .liquidate(liquidation_params, &comms_client)
+            .unwrap()
+            .is_none());
+        assert!(comms_client.sent_ixs().is_empty());
+
+        // The legs within the cap land, the rest is left for later
+        let comms_client = MockedCommsClient::with_accounts(HashMap::new())
+            .with_liquidator_account(liquidator_address);
+        let liquidation_params = LiquidationParams {
+            liquidatee_account: liquidatee_address,
+            legs: vec![leg(banks[1]), leg(banks[MAX_BANKS_PER_TRANSACTION])],
+            profit_usd: I80F48::ONE,
+        };
+
+        assert_eq!(
+            strategy
+                .liquidate(liquidation_params, &comms_client)
+                .unwrap()
+                .unwrap()
+                .count,
+            1
+        );
+        assert_eq!(comms_client.sent_ixs().len(), 1);
+    }
+
+    #[test]
+    fn test_basic_liquidation_strategy_liquidate_skips_failed_simulation() {
+        let (cache, liquidatee) = setup(1);
+        let liquidator_address = Pubkey::new_unique();
+        cache
+            .update_marginfi_account(
+                1,
+                liquidator_address,
+                &create_marginfi_account(*liquidatee.group(), vec![]),
+            )
+            .unwrap();
+        let bank = cache
+            .banks
+            .get_bank(&liquidatee._positions()[0].bank_pk)
+            .unwrap();
+        cache
+            .mints
+            .update(*bank.mint(), &Account::default())
+            .unwrap();
+
+        let strategy = BasicLiquidationStrategy::new(cache, Pubkey::new_unique(), 0.0)
+            .with_simulate_before_send(true);
+        let liquidation_params = || LiquidationParams {
+            liquidatee_account: *liquidatee.address(),
+            legs: vec![
+                LiquidationLeg {
+                    asset_bank: bank.address,
+                    liab_bank: bank.address,
+                    asset_amount: 1_000,
+                };
+                2
+            ],
+            profit_usd: I80F48::ONE,
+        };
+
+        // Nothing is sent if the first leg would revert
+        let comms_client = MockedCommsClient::with_accounts(HashMap::new())
+            .with_liquidator_account(liquidator_address)
+            .with_simulate_results(vec![Err("custom program error: 0x1771")]);
+        assert!(strategy
+            .liquidate(liquidation_params(), &comms_client)
+            .unwrap()
+            .is_none());
+        assert!(comms_client.sent_ixs().is_empty());
+
+        // The legs past the one that would revert are not sent
+        let comms_client = MockedCommsClient::with_accounts(HashMap::new())
+            .with_liquidator_account(liquidator_address)
+            .with_simulate_results(vec![Ok(()), Err("custom program error: 0x1771")]);
+        assert_eq!(
+            strategy
+                .liquidate(liquidation_params(), &comms_client)
+                .unwrap()
+                .unwrap()
+                .count,
+            1
+        );
+        assert_eq!(comms_client.sent_ixs().len(), 1);
+    }
+
+    #[test]
+    fn test_evaluate_positions_prices_cached_vault_liquidity() {
+        let (cache, account) = setup(1);
+        let strategy = BasicLiquidationStrategy::new(cache.clone(), Pubkey::new_unique(), 0.0);
+        // Not ranked down while the vault balance is unknown
+        let (assets, _) = strategy.evaluate_positions(&account).unwrap();
+        assert_eq!(assets[0].liquidity, I80F48::MAX);
+
+        let bank = cache
+            .banks
+            .get_bank(&account._positions()[0].bank_pk)
+            .unwrap();
+        cache
+            .banks
+            .update_vault(1, *bank.liquidity_vault(), &create_token_account(1_000))
+            .unwrap();
+        let (assets, _) = strategy.evaluate_positions(&account).unwrap();
+        assert_eq!(
+            assets[0].liquidity,
+            calc_value(
+                I80F48::from_num(1_000),
+                assets[0].price,
+                bank.mint_decimals(),
+                I80F48::ONE
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_plan_legs_splits_across_liabilities() {
+        // The health is 200 * 0.8 - 2 * 100 = -40, repaying one liability does not restore it
+        let assets = vec![position(200, 0.8)];
+        let liabilities = vec![position(100, 1.0), position(100, 1.0)];
+
+        let legs = plan_legs(
+            &Pubkey::default(),
+            I80F48::from_num(-40),
+            &assets,
+            &liabilities,
+            &HashSet::new(),
+            I80F48::ONE,
+            4,
+        );
+        assert_eq!(legs.len(), 2);
+        assert_eq!(legs[0].0, 0);
+        assert_eq!(legs[1].0, 0);
+        assert_ne!(legs[0].1, legs[1].1);
+        // The first leg repays the whole liability, the second one seizes the rest of the collateral
+        let epsilon = I80F48::from_num(0.001);
+        assert!((legs[0].2 - I80F48::from_num(100) / I80F48::from_num(0.95)).abs() < epsilon);
+        assert!((legs[0].2 + legs[1].2 - I80F48::from_num(200)).abs() < epsilon);
+    }
+
+    #[test]
+    fn test_plan_legs_clamps_repay_to_max_fraction() {
+        let assets = vec![position(200, 0.8)];
+        let liabilities = vec![position(100, 1.0), position(100, 1.0)];
+
+        // At most half of each liability is repaid
+        let legs = plan_legs(
+            &Pubkey::default(),
+            I80F48::from_num(-40),
+            &assets,
+            &liabilities,
+            &HashSet::new(),
+            I80F48::from_num(0.5),
+            4,
+        );
+        assert_eq!(legs.len(), 2);
+        let epsilon = I80F48::from_num(0.001);
+        for leg in &legs {
+            assert!((leg.2 - I80F48::from_num(50) / I80F48::from_num(0.95)).abs() < epsilon);
+        }
+    }
+
+    #[test]
+    fn test_plan_legs_stops_when_health_restored_or_legs_run_out() {
+        let assets = vec![position(1_000, 0.8)];
+        let liabilities = vec![position(1_000, 1.0), position(1_000, 1.0)];
+        assert_eq!(
+            plan_legs(
+                &Pubkey::default(),
+                I80F48::from_num(-15),
+                &assets,
+                &liabilities,
+                &HashSet::new(),
+                I80F48::ONE,
+                4
+            )
+            .len(),
+            1
+        );
+
+        let assets = vec![position(200, 0.8)];
+        let liabilities = vec![position(100, 1.0), position(100, 1.0)];
+        assert_eq!(
+            plan_legs(
+                &Pubkey::default(),
+                I80F48::from_num(-40),
+                &assets,
+                &liabilities,
+                &HashSet::new(),
+                I80F48::ONE,
+                1
+            )
+            .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_plan_legs_skips_incompatible_asset_tags() {
+        // The larger staked collateral can not be seized against the default liability
+        let assets = vec![
+            tagged_position(1_000, 0.8, ASSET_TAG_STAKED),
+            tagged_position(500, 0.8, ASSET_TAG_DEFAULT),
+        ];
+        let liabilities = vec![tagged_position(700, 1.0, ASSET_TAG_DEFAULT)];
+        let legs = plan_legs(
+            &Pubkey::default(),
+            I80F48::from_num(-20),
+            &assets,
+            &liabilities,
+            &HashSet::new(),
+            I80F48::ONE,
+            4,
+        );
+        assert_eq!(legs.len(), 1);
+        assert_eq!((legs[0].0, legs[0].1), (1, 0));
+
+        // Against the SOL liability it is the largest collateral again
+        let liabilities = vec![tagged_position(700, 1.0, ASSET_TAG_SOL)];
+        let legs = plan_legs(
+            &Pubkey::default(),
+            I80F48::from_num(-20),
+            &assets,
+            &liabilities,
+            &HashSet::new(),
+            I80F48::ONE,
+            4,
+        );
+        assert_eq!((legs[0].0, legs[0].1), (0, 0));
+
+        // The liability without a compatible collateral is passed over for the next one
+        let assets = vec![tagged_position(1_000, 0.8, ASSET_TAG_STAKED)];
+        let liabilities = vec![
+            tagged_position(700, 1.0, ASSET_TAG_DEFAULT),
+            tagged_position(300, 1.0, ASSET_TAG_SOL),
+        ];
+        let legs = plan_legs(
+            &Pubkey::default(),
+            I80F48::from_num(-20),
+            &assets,
+            &liabilities,
+            &HashSet::new(),
+            I80F48::ONE,
+            4,
+        );
+        assert_eq!((legs[0].0, legs[0].1), (0, 1));
+
+        let liabilities = vec![tagged_position(700, 1.0, ASSET_TAG_DEFAULT)];
+        assert!(plan_legs(
+            &Pubkey::default(),
+            I80F48::from_num(-20),
+            &assets,
+            &liabilities,
+            &HashSet::new(),
+            I80F48::ONE,
+            4
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_plan_legs_keeps_liquidator_balances_compatible() {
+        let assets = vec![
+            tagged_position(1_000, 0.8, ASSET_TAG_STAKED),
+            tagged_position(500, 0.8, ASSET_TAG_DEFAULT),
+        ];
+        // The liquidator already has the default asset tag balances
+        let liabilities = vec![tagged_position(700, 1.0, ASSET_TAG_SOL)];
+        let legs = plan_legs(
+            &Pubkey::default(),
+            I80F48::from_num(-20),
+            &assets,
+            &liabilities,
+            &HashSet::from([ASSET_TAG_DEFAULT]),
+            I80F48::ONE,
+            4,
+        );
+        assert_eq!((legs[0].0, legs[0].1), (1, 0));
+
+        // The first leg brings the staked collateral, the default one can not follow it
+        let liabilities = vec![
+            tagged_position(100, 1.0, ASSET_TAG_SOL),
+            tagged_position(50, 1.0, ASSET_TAG_DEFAULT),
+        ];
+        let legs = plan_legs(
+            &Pubkey::default(),
+            I80F48::from_num(-200),
+            &assets,
+            &liabilities,
+            &HashSet::new(),
+            I80F48::ONE,
+            4,
+        );
+        assert_eq!(legs.len(), 1);
+        assert_eq!((legs[0].0, legs[0].1), (0, 0));
+    }
+
+    #[test]
+    fn test_plan_legs_skips_paused_banks() {
+        let paused = |value: i64, weight: f64| {
+            let mut position = position(value, weight);
+            let mut bank = *position.bank.bank();
+            bank.config.operational_state = BankOperationalState::Paused;
+            position.bank = CachedBank::from(1, position.bank.address, bank);
+            position
+        };
+
+        // The larger collateral is in a paused bank
+        let assets = vec![paused(1_000, 0.8), position(500, 0.8)];
+        let liabilities = vec![position(700, 1.0)];
+        let legs = plan_legs(
+            &Pubkey::default(),
+            I80F48::from_num(-20),
+            &assets,
+            &liabilities,
+            &HashSet::new(),
+            I80F48::ONE,
+            4,
+        );
+        assert_eq!((legs[0].0, legs[0].1), (1, 0));
+
+        // The larger liability is in a paused bank
+        let assets = vec![position(1_000, 0.8)];
+        let liabilities = vec![paused(700, 1.0), position(300, 1.0)];
+        let legs = plan_legs(
+            &Pubkey::default(),
+            I80F48::from_num(-20),
+            &assets,
+            &liabilities,
+            &HashSet::new(),
+            I80F48::ONE,
+            4,
+        );
+        assert_eq!((legs[0].0, legs[0].1), (0, 1));
+
+        let liabilities = vec![paused(700, 1.0)];
+        assert!(plan_legs(
+            &Pubkey::default(),
+            I80F48::from_num(-20),
+            &assets,
+            &liabilities,
+            &HashSet::new(),
+            I80F48::ONE,
+            4
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_plan_legs_skips_hooked_collateral() {
+        // The larger collateral is of a mint with a transfer hook
+        let mut hooked = position(1_000, 0.8);
+        hooked.transfer_hook = true;
+        let assets = vec![hooked, position(500, 0.8)];
+        let liabilities = vec![position(700, 1.0)];
+        let legs = plan_legs(
+            &Pubkey::default(),
+            I80F48::from_num(-20),
+            &assets,
+            &liabilities,
+            &HashSet::new(),
+            I80F48::ONE,
+            4,
+        );
+        assert_eq!((legs[0].0, legs[0].1), (1, 0));
+
+        // The liability of the hooked mint is still repaid
+        let mut hooked = position(700, 1.0);
+        hooked.transfer_hook = true;
+        let assets = vec![position(1_000, 0.8)];
+        let legs = plan_legs(
+            &Pubkey::default(),
+            I80F48::from_num(-20),
+            &assets,
+            &[hooked],
+            &HashSet::new(),
+            I80F48::ONE,
+            4,
+        );
+        assert_eq!(legs.len(), 1);
+    }
+
+    #[test]
+    fn test_evaluate_positions_reads_transfer_extensions() {
+        let (cache, account) = setup(1);
+        let bank = cache
+            .banks
+            .get_bank(&account._positions()[0].bank_pk)
+            .unwrap();
+        cache
+            .mints
+            .update(
+                *bank.mint(),
+                &create_token_2022_mint(6, Some(Pubkey::new_unique()), Some(100)),
+            )
+            .unwrap();
+        let strategy = BasicLiquidationStrategy::new(cache, Pubkey::new_unique(), 0.0);
+
+        let (assets, liabilities) = strategy.evaluate_positions(&account).unwrap();
+        for position in assets.iter().chain(&liabilities) {
+            assert!(
+                (position.transfer_fee - I80F48::from_num(0.01)).abs() < I80F48::from_num(1e-9)
+            );
+            assert!(position.transfer_hook);
+        }
+    }
+
+    #[test]
+    fn test_plan_legs_prefers_liquid_collateral() {
+        // Only 100 of the larger collateral can be withdrawn from its vault
+        let mut illiquid = position(1_000, 0.8);
+        illiquid.liquidity = I80F48::from_num(100);
+        let assets = vec![illiquid, position(500, 0.8)];
+        let liabilities = vec![position(700, 1.0)];
+
+        let legs = plan_legs(
+            &Pubkey::default(),
+            I80F48::from_num(-20),
+            &assets,
+            &liabilities,
+            &HashSet::new(),
+            I80F48::ONE,
+            4,
+        );
+        assert_eq!((legs[0].0, legs[0].1), (1, 0));
+    }
+
+    #[test]
+    fn test_collateral_score() {
+        assert_eq!(
+            position(1_000, 0.8).collateral_score(),
+            I80F48::from_num(1_000)
+        );
+
+        let mut illiquid = position(1_000, 0.8);
+        illiquid.liquidity = I80F48::from_num(300);
+        assert_eq!(illiquid.collateral_score(), I80F48::from_num(300));
+
+        // A 10% confidence interval
+        let mut uncertain = position(1_000, 0.8);
+        uncertain.confidence_bps = 1_000;
+        assert!(uncertain.collateral_score() < position(950, 0.8).collateral_score());
+        assert!(uncertain.collateral_score() > position(850, 0.8).collateral_score());
+    }
+
+    #[test]
+    fn test_compatible_asset_tags() {
+        assert!(compatible_asset_tags([
+            ASSET_TAG_DEFAULT,
+            ASSET_TAG_DEFAULT
+        ]));
+        assert!(compatible_asset_tags([ASSET_TAG_SOL, ASSET_TAG_DEFAULT]));
+        assert!(compatible_asset_tags([ASSET_TAG_STAKED, ASSET_TAG_SOL]));
+        assert!(!compatible_asset_tags([
+            ASSET_TAG_STAKED,
+            ASSET_TAG_DEFAULT
+        ]));
+        assert!(!compatible_asset_tags([
+            ASSET_TAG_DEFAULT,
+            ASSET_TAG_STAKED
+        ]));
+        // The SOL pair does not fit the liquidator already holding the staked collateral
+        assert!(!compatible_asset_tags([
+            ASSET_TAG_STAKED,
+            ASSET_TAG_SOL,
+            ASSET_TAG_DEFAULT
+        ]));
+    }
+
+    #[test]
+    fn test_max_liquidation_value_restores_health() {
+        let asset = position(1_000, 0.8);
+        let liability = position(1_000, 1.0);
+        // The health gain per seized USD is 1.0 * 0.95 - 0.8 = 0.15
+        let value = max_liquidation_value(I80F48::from_num(-15), &asset, &liability).unwrap();
+        assert!((value - I80F48::from_num(100)).abs() < I80F48::from_num(0.001));
+    }
+
+    #[test]
+    fn test_max_liquidation_value_capped_by_positions() {
+        let asset = position(50, 0.8);
+        let liability = position(1_000, 1.0);
+        let value = max_liquidation_value(I80F48::from_num(-150), &asset, &liability).unwrap();
+        assert_eq!(value, I80F48::from_num(50));
+    }
+
+    #[test]
+    fn test_max_liquidation_value_no_health_gain() {
+        let asset = position(1_000, 1.0);
+        let liability = position(1_000, 1.0);
+        assert!(max_liquidation_value(I80F48::from_num(-15), &asset, &liability).is_none());
+    }
+
+    #[test]
+    fn test_estimate_profit() {
+        let epsilon = I80F48::from_num(0.001);
+        let profit = estimate_profit(I80F48::from_num(1_000), I80F48::ZERO, I80F48::ZERO);
+        assert!((profit - I80F48::from_num(25)).abs() < epsilon);
+
+        // The 1% transfer fees of the withdrawn 1000 and the repaid 975
+        let fee = I80F48::from_num(0.01);
+        let profit = estimate_profit(I80F48::from_num(1_000), fee, fee);
+        assert!((profit - I80F48::from_num(990.0 - 984.75)).abs() < epsilon);
+    }
+
+    #[test]
+    fn test_native_amount() {
+        let amount = native_amount(I80F48::from_num(100), I80F48::from_num(4), 6).unwrap();
+        assert_eq!(amount, 25_000_000);
     }
 }
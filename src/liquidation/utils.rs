@@ -0,0 +1,219 @@
+use anyhow::{anyhow, Result};
+use solana_sdk::{instruction::AccountMeta, pubkey::Pubkey};
+use spl_tlv_account_resolution::state::ExtraAccountMetaList;
+use spl_token_2022::{
+    extension::{transfer_hook::TransferHook, BaseStateWithExtensions, StateWithExtensions},
+    state::Mint,
+};
+use spl_transfer_hook_interface::{
+    get_extra_account_metas_address,
+    instruction::{ExecuteInstruction, TransferHookInstruction},
+};
+
+use crate::comms::CommsClient;
+
+/// The token transfer made by the marginfi program that the transfer hook is executed for.
+pub struct HookedTransfer {
+    pub source: Pubkey,
+    pub destination: Pubkey,
+    pub authority: Pubkey,
+    pub amount: u64,
+}
+
+/// The token-2022 Banks expect their mint ahead of the oracles in the remaining accounts.
+pub fn maybe_add_bank_mint(
+    remaining_accounts: &mut Vec<Pubkey>,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) {
+    if *token_program == spl_token_2022::id() {
+        remaining_accounts.insert(0, *mint);
+    }
+}
+
+/// Resolves the accounts the transfer hook of the token-2022 mint requires, followed by the hook
+/// program and its extra account meta list. Empty for the mints without a transfer hook.
+/// The extra accounts seeded by the account data are not supported.
+pub fn transfer_hook_account_metas<T: CommsClient>(
+    comms_client: &T,
+    mint_address: &Pubkey,
+    mint_data: &[u8],
+    transfer: &HookedTransfer,
+) -> Result<Vec<AccountMeta>> {
+    let mint = StateWithExtensions::<Mint>::unpack(mint_data)
+        .map_err(|e| anyhow!("Invalid token-2022 mint {}: {}", mint_address, e))?;
+    let Some(hook_program_id) = mint
+        .get_extension::<TransferHook>()
+        .ok()
+        .and_then(|transfer_hook| Option::<Pubkey>::from(transfer_hook.program_id))
+    else {
+        return Ok(vec![]);
+    };
+
+    let extra_metas_address = get_extra_account_metas_address(mint_address, &hook_program_id);
+    let extra_metas_account = comms_client.get_account(&extra_metas_address)?;
+    let extra_metas = ExtraAccountMetaList::unpack_with_tlv_state::<ExecuteInstruction>(
+        &extra_metas_account.data,
+    )
+    .map_err(|e| {
+        anyhow!(
+            "Invalid extra account meta list {} of the mint {}: {}",
+            extra_metas_address,
+            mint_address,
+            e
+        )
+    })?;
+
+    let instruction_data = TransferHookInstruction::Execute {
+        amount: transfer.amount,
+    }
+    .pack();
+    // The extra account seeds index into the accounts of the Execute instruction
+    let mut accounts = vec![
+        AccountMeta::new_readonly(transfer.source, false),
+        AccountMeta::new_readonly(*mint_address, false),
+        AccountMeta::new_readonly(transfer.destination, false),
+        AccountMeta::new_readonly(transfer.authority, false),
+        AccountMeta::new_readonly(extra_metas_address, false),
+    ];
+    let execute_accounts_len = accounts.len();
+    for extra_meta in extra_metas.data() {
+        let resolved = extra_meta
+            .resolve(&instruction_data, &hook_program_id, |index| {
+                accounts.get(index).map(|meta| (&meta.pubkey, None))
+            })
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to resolve the transfer hook account of the mint {}: {}",
+                    mint_address,
+                    e
+                )
+            })?;
+        accounts.push(resolved);
+    }
+
+    let mut hook_metas = accounts.split_off(execute_accounts_len);
+    hook_metas.push(AccountMeta::new_readonly(hook_program_id, false));
+    hook_metas.push(AccountMeta::new_readonly(extra_metas_address, false));
+    Ok(hook_metas)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use solana_sdk::account::Account;
+    use spl_tlv_account_resolution::{account::ExtraAccountMeta, seeds::Seed};
+
+    use super::*;
+    use crate::{
+        cache::mints::test_util::create_token_2022_mint, comms::test_util::MockedCommsClient,
+    };
+
+    fn transfer() -> HookedTransfer {
+        HookedTransfer {
+            source: Pubkey::new_unique(),
+            destination: Pubkey::new_unique(),
+            authority: Pubkey::new_unique(),
+            amount: 0,
+        }
+    }
+
+    #[test]
+    fn test_maybe_add_bank_mint() {
+        let mint = Pubkey::new_unique();
+        let oracle = Pubkey::new_unique();
+
+        let mut remaining_accounts = vec![oracle];
+        let token_program = Pubkey::new_unique();
+        maybe_add_bank_mint(&mut remaining_accounts, &mint, &token_program);
+        assert_eq!(remaining_accounts, vec![oracle]);
+
+        maybe_add_bank_mint(&mut remaining_accounts, &mint, &spl_token_2022::id());
+        assert_eq!(remaining_accounts, vec![mint, oracle]);
+    }
+
+    #[test]
+    fn test_transfer_hook_account_metas_without_hook() {
+        let comms_client = MockedCommsClient::with_accounts(HashMap::new());
+        let mint = create_token_2022_mint(6, None, None);
+
+        let metas = transfer_hook_account_metas(
+            &comms_client,
+            &Pubkey::new_unique(),
+            &mint.data,
+            &transfer(),
+        )
+        .unwrap();
+        assert!(metas.is_empty());
+    }
+
+    #[test]
+    fn test_transfer_hook_account_metas_resolves_extra_metas() {
+        let mint_address = Pubkey::new_unique();
+        let hook_program_id = Pubkey::new_unique();
+        let mint = create_token_2022_mint(6, Some(hook_program_id), None);
+
+        let fixed_account = Pubkey::new_unique();
+        let extra_metas = [
+            ExtraAccountMeta::new_with_pubkey(&fixed_account, false, true).unwrap(),
+            // The hook program PDA seeded by the mint
+            ExtraAccountMeta::new_with_seeds(
+                &[
+                    Seed::Literal {
+                        bytes: b"counter".to_vec(),
+                    },
+                    Seed::AccountKey { index: 1 },
+                ],
+                false,
+                true,
+            )
+            .unwrap(),
+        ];
+        let mut extra_metas_data =
+            vec![0u8; ExtraAccountMetaList::size_of(extra_metas.len()).unwrap()];
+        ExtraAccountMetaList::init::<ExecuteInstruction>(&mut extra_metas_data, &extra_metas)
+            .unwrap();
+        let extra_metas_address = get_extra_account_metas_address(&mint_address, &hook_program_id);
+        let comms_client = MockedCommsClient::with_accounts(HashMap::from([(
+            extra_metas_address,
+            Account {
+                lamports: 1,
+                data: extra_metas_data,
+                owner: hook_program_id,
+                executable: false,
+                rent_epoch: 0,
+            },
+        )]));
+
+        let metas =
+            transfer_hook_account_metas(&comms_client, &mint_address, &mint.data, &transfer())
+                .unwrap();
+
+        let (counter, _) =
+            Pubkey::find_program_address(&[b"counter", mint_address.as_ref()], &hook_program_id);
+        assert_eq!(
+            metas,
+            vec![
+                AccountMeta::new(fixed_account, false),
+                AccountMeta::new(counter, false),
+                AccountMeta::new_readonly(hook_program_id, false),
+                AccountMeta::new_readonly(extra_metas_address, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transfer_hook_account_metas_missing_extra_metas() {
+        let comms_client = MockedCommsClient::with_accounts(HashMap::new());
+        let mint = create_token_2022_mint(6, Some(Pubkey::new_unique()), None);
+
+        assert!(transfer_hook_account_metas(
+            &comms_client,
+            &Pubkey::new_unique(),
+            &mint.data,
+            &transfer()
+        )
+        .is_err());
+    }
+}
@@ -2,10 +2,15 @@ mod cache;
 mod common;
 mod comms;
 mod config;
+mod error;
 mod liquidation;
+mod metrics;
+mod notifier;
 mod service;
 
-use crate::comms::RpcCommsClient;
+use crate::comms::{CommsClient, JitoCommsClient, RpcCommsClient};
+use crate::config::{SubmissionMode, SubscriberMode};
+use crate::service::{GeyserSubscriber, Subscriber, WsSubscriber};
 use crate::{config::Config, service::ServiceManager};
 use env_logger::Builder;
 use log::info;
@@ -55,11 +60,30 @@ fn main() -> anyhow::Result<()> {
     // Init Logger
     Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    let config = Config::new()?;
+    let config = Config::from_args(std::env::args_os())?;
     info!("Configuration: {}", config);
 
-    let service_manager: ServiceManager<RpcCommsClient> =
-        ServiceManager::<RpcCommsClient>::new(config, stop.clone())?;
+    match (config.submission_mode, config.subscriber_mode) {
+        (SubmissionMode::Rpc, SubscriberMode::Geyser) => {
+            run::<RpcCommsClient, GeyserSubscriber>(config, stop)
+        }
+        (SubmissionMode::Rpc, SubscriberMode::Ws) => {
+            run::<RpcCommsClient, WsSubscriber>(config, stop)
+        }
+        (SubmissionMode::Jito, SubscriberMode::Geyser) => {
+            run::<JitoCommsClient, GeyserSubscriber>(config, stop)
+        }
+        (SubmissionMode::Jito, SubscriberMode::Ws) => {
+            run::<JitoCommsClient, WsSubscriber>(config, stop)
+        }
+    }
+}
+
+fn run<T: CommsClient + 'static, S: Subscriber + 'static>(
+    config: Config,
+    stop: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    let service_manager: ServiceManager<T, S> = ServiceManager::<T, S>::new(config, stop)?;
     service_manager.start()?;
 
     Ok(())
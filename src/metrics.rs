@@ -0,0 +1,319 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+use solana_sdk::pubkey::Pubkey;
+
+const METRICS_PREFIX: &str = "mary";
+
+#[derive(Debug, Clone, Copy)]
+pub enum Gauge {
+    GeyserQueueDepth,
+    CachedMarginfiAccounts,
+    CachedBanks,
+    CachedOracles,
+    CachedMints,
+    CachedLuts,
+    CacheEstimatedBytes,
+    LatestSlot,
+    MaxOracleAgeSeconds,
+    LiveOracles,
+    Ready,
+}
+
+impl Gauge {
+    const ALL: [Gauge; 11] = [
+        Gauge::GeyserQueueDepth,
+        Gauge::CachedMarginfiAccounts,
+        Gauge::CachedBanks,
+        Gauge::CachedOracles,
+        Gauge::CachedMints,
+        Gauge::CachedLuts,
+        Gauge::CacheEstimatedBytes,
+        Gauge::LatestSlot,
+        Gauge::MaxOracleAgeSeconds,
+        Gauge::LiveOracles,
+        Gauge::Ready,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Gauge::GeyserQueueDepth => "geyser_queue_depth",
+            Gauge::CachedMarginfiAccounts => "cached_marginfi_accounts",
+            Gauge::CachedBanks => "cached_banks",
+            Gauge::CachedOracles => "cached_oracles",
+            Gauge::CachedMints => "cached_mints",
+            Gauge::CachedLuts => "cached_luts",
+            Gauge::CacheEstimatedBytes => "cache_estimated_bytes",
+            Gauge::LatestSlot => "latest_slot",
+            Gauge::MaxOracleAgeSeconds => "max_oracle_age_seconds",
+            Gauge::LiveOracles => "live_oracles",
+            Gauge::Ready => "ready",
+        }
+    }
+
+    fn help(&self) -> &'static str {
+        match self {
+            Gauge::GeyserQueueDepth => "Geyser messages waiting to be processed.",
+            Gauge::CachedMarginfiAccounts => "Marginfi accounts in the cache.",
+            Gauge::CachedBanks => "Banks in the cache.",
+            Gauge::CachedOracles => "Oracles in the cache.",
+            Gauge::CachedMints => "Mints in the cache.",
+            Gauge::CachedLuts => "Address lookup tables in the cache.",
+            Gauge::CacheEstimatedBytes => {
+                "Rough estimate of the memory taken by the cached entries."
+            }
+            Gauge::LatestSlot => "The slot of the cached Solana clock.",
+            Gauge::MaxOracleAgeSeconds => "Seconds since the oldest cached oracle price update.",
+            Gauge::LiveOracles => "Oracles that received a live update since the cache load.",
+            Gauge::Ready => {
+                "1 once the cache caught up with the chain and the liquidations started."
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Counter {
+    LiquidationsAttempted,
+    LiquidationsSucceeded,
+    LiquidationsFailed,
+    DeserializationFailures,
+}
+
+impl Counter {
+    const ALL: [Counter; 4] = [
+        Counter::LiquidationsAttempted,
+        Counter::LiquidationsSucceeded,
+        Counter::LiquidationsFailed,
+        Counter::DeserializationFailures,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Counter::LiquidationsAttempted => "liquidations_attempted_total",
+            Counter::LiquidationsSucceeded => "liquidations_succeeded_total",
+            Counter::LiquidationsFailed => "liquidations_failed_total",
+            Counter::DeserializationFailures => "deserialization_failures_total",
+        }
+    }
+
+    fn help(&self) -> &'static str {
+        match self {
+            Counter::LiquidationsAttempted => "Liquidation transactions submitted.",
+            Counter::LiquidationsSucceeded => "Liquidation transactions confirmed.",
+            Counter::LiquidationsFailed => "Liquidation transactions that failed.",
+            Counter::DeserializationFailures => {
+                "Program accounts skipped as they failed to deserialize."
+            }
+        }
+    }
+}
+
+/// The metrics shared by the services and exposed in the Prometheus text format.
+#[derive(Default)]
+pub struct MetricsRegistry {
+    gauges: [AtomicU64; Gauge::ALL.len()],
+    counters: [AtomicU64; Counter::ALL.len()],
+    oracle_ages: RwLock<Vec<(Pubkey, u64)>>,
+    // The processed and the failed Geyser messages per message type name
+    geyser_messages: RwLock<Vec<(&'static str, u64, u64)>>,
+}
+
+impl MetricsRegistry {
+    pub fn set(&self, gauge: Gauge, value: u64) {
+        self.gauges[gauge as usize].store(value, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self, counter: Counter) {
+        self.counters[counter as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_gauge(&self, gauge: Gauge) -> u64 {
+        self.gauges[gauge as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn get_counter(&self, counter: Counter) -> u64 {
+        self.counters[counter as usize].load(Ordering::Relaxed)
+    }
+
+    /// Replaces the per oracle ages in slots, so that an alert can name the oracle that stopped updating.
+    pub fn set_oracle_ages(&self, oracle_ages: Vec<(Pubkey, u64)>) {
+        // A poisoned lock only means a writer panicked, the ages are replaced as a whole anyway
+        *self
+            .oracle_ages
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = oracle_ages;
+    }
+
+    /// Replaces the per type Geyser message counts, they are counted by the GeyserProcessor.
+    pub fn set_geyser_message_counts(&self, geyser_messages: Vec<(&'static str, u64, u64)>) {
+        *self
+            .geyser_messages
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = geyser_messages;
+    }
+
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        for gauge in Gauge::ALL {
+            write_metric(
+                &mut output,
+                gauge.name(),
+                gauge.help(),
+                "gauge",
+                self.get_gauge(gauge),
+            );
+        }
+        for counter in Counter::ALL {
+            write_metric(
+                &mut output,
+                counter.name(),
+                counter.help(),
+                "counter",
+                self.get_counter(counter),
+            );
+        }
+        self.render_oracle_ages(&mut output);
+        self.render_geyser_messages(&mut output);
+        output
+    }
+
+    fn render_geyser_messages(&self, output: &mut String) {
+        let geyser_messages = self
+            .geyser_messages
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if geyser_messages.is_empty() {
+            return;
+        }
+
+        for (name, help, failed) in [
+            (
+                "geyser_messages_total",
+                "Geyser messages processed by type.",
+                false,
+            ),
+            (
+                "geyser_message_failures_total",
+                "Geyser messages that failed to process by type.",
+                true,
+            ),
+        ] {
+            let _ = writeln!(output, "# HELP {}_{} {}", METRICS_PREFIX, name, help);
+            let _ = writeln!(output, "# TYPE {}_{} counter", METRICS_PREFIX, name);
+            for (message_type, processed, failures) in geyser_messages.iter() {
+                let _ = writeln!(
+                    output,
+                    "{}_{}{{type=\"{}\"}} {}",
+                    METRICS_PREFIX,
+                    name,
+                    message_type,
+                    if failed { failures } else { processed }
+                );
+            }
+        }
+    }
+
+    fn render_oracle_ages(&self, output: &mut String) {
+        let oracle_ages = self
+            .oracle_ages
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if oracle_ages.is_empty() {
+            return;
+        }
+
+        let _ = writeln!(
+            output,
+            "# HELP {}_oracle_age_slots Slots since the oracle price update.",
+            METRICS_PREFIX
+        );
+        let _ = writeln!(output, "# TYPE {}_oracle_age_slots gauge", METRICS_PREFIX);
+        for (oracle, age) in oracle_ages.iter() {
+            let _ = writeln!(
+                output,
+                "{}_oracle_age_slots{{oracle=\"{}\"}} {}",
+                METRICS_PREFIX, oracle, age
+            );
+        }
+    }
+}
+
+fn write_metric(output: &mut String, name: &str, help: &str, kind: &str, value: u64) {
+    // Writing to a String never fails
+    let _ = writeln!(output, "# HELP {}_{} {}", METRICS_PREFIX, name, help);
+    let _ = writeln!(output, "# TYPE {}_{} {}", METRICS_PREFIX, name, kind);
+    let _ = writeln!(output, "{}_{} {}", METRICS_PREFIX, name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_registry_set_and_inc() {
+        let metrics = MetricsRegistry::default();
+        metrics.set(Gauge::LatestSlot, 42);
+        metrics.inc(Counter::LiquidationsAttempted);
+        metrics.inc(Counter::LiquidationsAttempted);
+
+        assert_eq!(metrics.get_gauge(Gauge::LatestSlot), 42);
+        assert_eq!(metrics.get_counter(Counter::LiquidationsAttempted), 2);
+        assert_eq!(metrics.get_counter(Counter::LiquidationsFailed), 0);
+    }
+
+    #[test]
+    fn test_metrics_registry_render() {
+        let metrics = MetricsRegistry::default();
+        metrics.set(Gauge::GeyserQueueDepth, 7);
+        metrics.inc(Counter::LiquidationsSucceeded);
+
+        let output = metrics.render();
+        assert!(
+            output.contains("# TYPE mary_geyser_queue_depth gauge\nmary_geyser_queue_depth 7\n")
+        );
+        assert!(output.contains(
+            "# TYPE mary_liquidations_succeeded_total counter\nmary_liquidations_succeeded_total 1\n"
+        ));
+        assert_eq!(
+            output.lines().count(),
+            3 * (Gauge::ALL.len() + Counter::ALL.len())
+        );
+    }
+
+    #[test]
+    fn test_metrics_registry_render_oracle_ages() {
+        let metrics = MetricsRegistry::default();
+        let oracle = Pubkey::new_unique();
+        metrics.set_oracle_ages(vec![(oracle, 12)]);
+
+        let output = metrics.render();
+        assert!(output.contains("# TYPE mary_oracle_age_slots gauge\n"));
+        assert!(output.contains(&format!(
+            "mary_oracle_age_slots{{oracle=\"{}\"}} 12\n",
+            oracle
+        )));
+
+        metrics.set_oracle_ages(vec![]);
+        assert!(!metrics.render().contains("oracle_age_slots"));
+    }
+
+    #[test]
+    fn test_metrics_registry_render_geyser_messages() {
+        let metrics = MetricsRegistry::default();
+        assert!(!metrics.render().contains("geyser_messages_total"));
+
+        metrics.set_geyser_message_counts(vec![("bank", 5, 1), ("oracle", 7, 0)]);
+        let output = metrics.render();
+        assert!(output.contains("# TYPE mary_geyser_messages_total counter\n"));
+        assert!(output.contains("mary_geyser_messages_total{type=\"bank\"} 5\n"));
+        assert!(output.contains("mary_geyser_messages_total{type=\"oracle\"} 7\n"));
+        assert!(output.contains("mary_geyser_message_failures_total{type=\"bank\"} 1\n"));
+        assert!(output.contains("mary_geyser_message_failures_total{type=\"oracle\"} 0\n"));
+    }
+}
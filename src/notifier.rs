@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use reqwest::blocking::Client;
+use serde_json::{json, Value};
+
+use crate::liquidation::{LiquidationOutcome, LiquidationStatus};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pushes the liquidation outcomes to the operators.
+pub trait Notifier: Send + Sync {
+    fn notify(&self, outcome: &LiquidationOutcome) -> Result<()>;
+}
+
+/// POSTs the outcomes as JSON to a webhook. The "text" field makes the payload a valid Slack
+/// incoming webhook message, the "outcome" field carries the structured event for the other consumers.
+pub struct WebhookNotifier {
+    http_client: Client,
+    url: String,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str) -> Result<Self> {
+        let http_client = Client::builder().timeout(WEBHOOK_TIMEOUT).build()?;
+        Ok(Self {
+            http_client,
+            url: url.to_string(),
+        })
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, outcome: &LiquidationOutcome) -> Result<()> {
+        // The URL is left out of the errors as the webhooks embed their credentials in it
+        self.http_client
+            .post(&self.url)
+            .json(&webhook_payload(outcome)?)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| anyhow!("Failed to post the notification: {}", e.without_url()))?;
+        Ok(())
+    }
+}
+
+fn webhook_payload(outcome: &LiquidationOutcome) -> Result<Value> {
+    let text = match outcome.status {
+        LiquidationStatus::Landed => format!(
+            "Liquidated the Marginfi account {} for ~${:.2}, signature: {}",
+            outcome.liquidatee_account,
+            outcome.profit_usd,
+            outcome
+                .signature
+                .map(|signature| signature.to_string())
+                .unwrap_or_default()
+        ),
+        LiquidationStatus::Partial => format!(
+            "Liquidated {} leg(s) of the Marginfi account {} planned for ~${:.2}, signature: {}",
+            outcome.legs.len(),
+            outcome.liquidatee_account,
+            outcome.profit_usd,
+            outcome
+                .signature
+                .map(|signature| signature.to_string())
+                .unwrap_or_default()
+        ),
+        LiquidationStatus::Reverted => format!(
+            "The liquidation of the Marginfi account {} keeps failing",
+            outcome.liquidatee_account
+        ),
+        LiquidationStatus::Skipped => format!(
+            "Skipped the liquidation of the Marginfi account {}",
+            outcome.liquidatee_account
+        ),
+    };
+    Ok(json!({
+        "text": text,
+        "outcome": serde_json::to_value(outcome)?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use fixed::types::I80F48;
+    use solana_sdk::{pubkey::Pubkey, signature::Signature};
+    use std::{io::Read, thread};
+    use tiny_http::{Response, Server};
+
+    use crate::liquidation::{LiquidationLeg, LiquidationParams};
+
+    #[test]
+    fn test_webhook_notifier_posts_the_outcome() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr());
+
+        let params = LiquidationParams {
+            liquidatee_account: Pubkey::new_unique(),
+            legs: vec![LiquidationLeg {
+                asset_bank: Pubkey::new_unique(),
+                liab_bank: Pubkey::new_unique(),
+                asset_amount: 1_000,
+            }],
+            profit_usd: I80F48::from_num(12.5),
+        };
+        let signature = Signature::new_unique();
+        let outcome = LiquidationOutcome::new(&params, Some(signature), LiquidationStatus::Landed);
+
+        let notifier = WebhookNotifier::new(&url).unwrap();
+        let handle = thread::spawn(move || notifier.notify(&outcome));
+
+        let mut request = server
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .expect("No webhook request received");
+        assert_eq!(request.method(), &tiny_http::Method::Post);
+        assert_eq!(request.url(), "/hook");
+        let mut body = String::new();
+        request.as_reader().read_to_string(&mut body).unwrap();
+        request.respond(Response::empty(200)).unwrap();
+        handle.join().unwrap().unwrap();
+
+        let payload: Value = serde_json::from_str(&body).unwrap();
+        assert!(payload["text"]
+            .as_str()
+            .unwrap()
+            .contains(&params.liquidatee_account.to_string()));
+        let event = &payload["outcome"];
+        assert_eq!(
+            event["liquidatee_account"],
+            params.liquidatee_account.to_string()
+        );
+        let leg = &event["legs"][0];
+        assert_eq!(leg["asset_bank"], params.legs[0].asset_bank.to_string());
+        assert_eq!(leg["liab_bank"], params.legs[0].liab_bank.to_string());
+        assert_eq!(leg["asset_amount"], 1_000);
+        assert_eq!(event["profit_usd"], 12.5);
+        assert_eq!(event["signature"], signature.to_string());
+        assert_eq!(event["status"], "landed");
+    }
+
+    #[test]
+    fn test_webhook_notifier_reports_the_error_status() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr());
+        let outcome = LiquidationOutcome::new(
+            &LiquidationParams {
+                liquidatee_account: Pubkey::new_unique(),
+                legs: vec![],
+                profit_usd: I80F48::ZERO,
+            },
+            None,
+            LiquidationStatus::Reverted,
+        );
+
+        let notifier = WebhookNotifier::new(&url).unwrap();
+        let handle = thread::spawn(move || notifier.notify(&outcome));
+        let request = server
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .expect("No webhook request received");
+        request.respond(Response::empty(500)).unwrap();
+
+        let err = handle.join().unwrap().unwrap_err();
+        assert!(!err.to_string().contains("/hook"));
+    }
+}
@@ -1,70 +1,176 @@
 mod geyser_processor;
 mod geyser_subscriber;
 mod liquidation_service;
+mod metrics_server;
+mod notification_service;
+mod readiness;
+mod reconcile_service;
+mod ws_subscriber;
 
 use std::{
-    sync::{atomic::AtomicBool, Arc},
-    thread,
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
+pub use geyser_subscriber::GeyserSubscriber;
+pub use ws_subscriber::WsSubscriber;
+
+use crate::service::{readiness::Readiness, reconcile_service::ReconcileService};
 use crate::{
-    cache::{Cache, CacheLoader},
-    service::geyser_subscriber::{GeyserMessage, GeyserSubscriber},
+    cache::{marginfi_accounts::INVALID_HEALTH, Cache, CacheLoader},
+    service::geyser_subscriber::{GeyserMessage, GeyserSender},
 };
 use crate::{comms::CommsClient, service::geyser_processor::GeyserProcessor};
-use crate::{config::Config, service::liquidation_service::LiquidationService};
+use crate::{config::Config, error::MaryError, service::liquidation_service::LiquidationService};
+use crate::{metrics::MetricsRegistry, service::metrics_server::MetricsServer};
+use crate::{notifier::WebhookNotifier, service::notification_service::NotificationService};
 use anyhow::Result;
 use bincode::deserialize;
+use crossbeam::channel::Receiver;
 use log::{error, info};
 use solana_sdk::clock::Clock;
+use solana_sdk::pubkey::Pubkey;
 use solana_sdk::sysvar;
 
-pub struct ServiceManager<T: CommsClient + 'static> {
+pub struct ServiceManager<T: CommsClient + 'static, S: Subscriber + 'static> {
     stop: Arc<AtomicBool>,
     stats_interval_sec: u64,
+    marginfi_account_max_age_slots: u64,
+    restart_policy: RestartPolicy,
     cache: Arc<Cache>,
     cache_loader: CacheLoader<T>,
-    geyser_subscriber: Arc<GeyserSubscriber>,
+    readiness: Arc<Readiness>,
+    subscriber: Arc<S>,
     geyser_processor: Arc<GeyserProcessor>,
     liquidation_service: Arc<LiquidationService<T>>,
+    metrics_server: Arc<MetricsServer>,
+    notification_service: Option<Arc<NotificationService>>,
+    reconcile_service: Option<Arc<ReconcileService<T>>>,
 }
 
-impl<T: CommsClient + 'static> ServiceManager<T> {
+impl<T: CommsClient + 'static, S: Subscriber + 'static> ServiceManager<T, S> {
     pub fn new(config: Config, stop: Arc<AtomicBool>) -> Result<Self> {
         // Fetch clock
         info!("Fetching the Solana Clock...");
         let comms_client = T::new(&config)?;
-        let clock = fetch_clock(&comms_client)?;
+        let clock = fetch_clock(&comms_client, &config.rpc_url)?;
 
         // Init cache
         info!("Initializing the Cache...");
-        let cache = Arc::new(Cache::new(clock));
+        let cache = Arc::new(Cache::new(clock, config.max_confidence_bps));
+
+        let metrics = Arc::new(MetricsRegistry::default());
 
         info!("Initializing the CacheLoader...");
-        let cache_loader = CacheLoader::new(&config, cache.clone())?;
+        let cache_loader = CacheLoader::new(&config, cache.clone(), metrics.clone())?;
 
         // Init Geyser services
-        let (geyser_tx, geyser_rx) = crossbeam::channel::unbounded::<GeyserMessage>();
+        let (priority_tx, priority_rx) = crossbeam::channel::unbounded::<GeyserMessage>();
+        let (normal_tx, normal_rx) = crossbeam::channel::unbounded::<GeyserMessage>();
+        let geyser_tx = GeyserSender::new(priority_tx, normal_tx);
+
+        // The new Oracles and Mints seen by the GeyserProcessor are added to the Subscriber subscription
+        let (resubscribe_tx, resubscribe_rx) = crossbeam::channel::unbounded::<Vec<Pubkey>>();
 
-        info!("Initializing the GeyserSubscriber...");
-        let geyser_subscriber =
-            GeyserSubscriber::new(&config, stop.clone(), cache.clone(), geyser_tx)?;
+        info!("Initializing the Subscriber...");
+        let subscriber = Arc::new(S::new(
+            &config,
+            stop.clone(),
+            cache.clone(),
+            geyser_tx,
+            resubscribe_rx,
+        )?);
+
+        // The price moves observed by the GeyserProcessor trigger the liquidation re-evaluation
+        let (trigger_tx, trigger_rx) = crossbeam::channel::unbounded::<Vec<Pubkey>>();
 
         info!("Initializing the GeyserProcessor...");
-        let geyser_processor = GeyserProcessor::new(stop.clone(), cache.clone(), geyser_rx);
+        let geyser_processor = Arc::new(GeyserProcessor::new(
+            stop.clone(),
+            cache.clone(),
+            priority_rx,
+            normal_rx,
+            config.price_move_trigger_bps,
+            trigger_tx,
+            resubscribe_tx,
+        ));
+
+        // The liquidations are held until the Subscriber catches the cache up with the chain
+        let mut readiness = Readiness::new(cache.clone(), config.readiness_live_oracles_ratio);
+        if config.readiness_max_wait_sec > 0 {
+            readiness = readiness.with_max_wait(Duration::from_secs(config.readiness_max_wait_sec));
+        }
+        let readiness = Arc::new(readiness);
 
         info!("Initializing the LiquidationService...");
-        let liquidation_service: LiquidationService<T> =
-            LiquidationService::new(stop.clone(), cache.clone(), comms_client)?;
+        let mut liquidation_service: LiquidationService<T> = LiquidationService::new(
+            &config,
+            stop.clone(),
+            cache.clone(),
+            comms_client,
+            metrics.clone(),
+            trigger_rx,
+        )?
+        .with_readiness(readiness.clone());
+
+        let notification_service = match &config.notify_webhook_url {
+            Some(url) => {
+                info!("Initializing the NotificationService...");
+                let (outcome_tx, outcome_rx) = crossbeam::channel::bounded(NOTIFICATION_QUEUE_SIZE);
+                liquidation_service = liquidation_service.with_outcome_sender(outcome_tx);
+                Some(Arc::new(NotificationService::new(
+                    stop.clone(),
+                    outcome_rx,
+                    Box::new(WebhookNotifier::new(url)?),
+                )))
+            }
+            None => None,
+        };
+
+        let reconcile_service = if config.reconcile_interval_sec > 0 {
+            info!("Initializing the ReconcileService...");
+            Some(Arc::new(ReconcileService::new(
+                &config,
+                stop.clone(),
+                cache.clone(),
+                T::new(&config)?,
+            )))
+        } else {
+            None
+        };
+
+        info!("Initializing the MetricsServer...");
+        let metrics_server = MetricsServer::new(
+            &config,
+            stop.clone(),
+            cache.clone(),
+            geyser_processor.clone(),
+            readiness.clone(),
+            metrics,
+        );
 
         Ok(ServiceManager {
             stop,
             stats_interval_sec: config.stats_interval_sec,
+            marginfi_account_max_age_slots: config.marginfi_account_max_age_slots,
+            restart_policy: RestartPolicy::new(
+                config.service_max_restarts,
+                Duration::from_secs(config.service_restart_window_sec),
+            ),
             cache,
             cache_loader,
-            geyser_subscriber: Arc::new(geyser_subscriber),
-            geyser_processor: Arc::new(geyser_processor),
+            readiness,
+            subscriber,
+            geyser_processor,
             liquidation_service: Arc::new(liquidation_service),
+            metrics_server: Arc::new(metrics_server),
+            notification_service,
+            reconcile_service,
         })
     }
 
@@ -73,88 +179,625 @@ impl<T: CommsClient + 'static> ServiceManager<T> {
 
         info!("Inflating the Cache...");
         self.cache_loader.load_cache()?;
+        self.readiness.set_load_slot(self.cache.get_clock()?.slot);
 
         let geyser_processor = self.geyser_processor.clone();
-        thread::spawn(move || {
-            if let Err(e) = geyser_processor.run() {
-                error!("GeyserProcessor failed! {:?}", e);
-                panic!("Fatal error in GeyserProcessor!");
-            }
-        });
+        let subscriber = self.subscriber.clone();
+        let liquidation_service = self.liquidation_service.clone();
+        let metrics_server = self.metrics_server.clone();
+        let mut handles = vec![
+            self.spawn_service("GeyserProcessor", move || geyser_processor.run()),
+            self.spawn_service("Subscriber", move || subscriber.run()),
+            self.spawn_service("LiquidationService", move || liquidation_service.run()),
+            self.spawn_service("MetricsServer", move || metrics_server.run()),
+        ];
+        if let Some(notification_service) = self.notification_service.clone() {
+            handles.push(
+                self.spawn_service("NotificationService", move || notification_service.run()),
+            );
+        }
+        if let Some(reconcile_service) = self.reconcile_service.clone() {
+            handles.push(self.spawn_service("ReconcileService", move || reconcile_service.run()));
+        }
 
-        let geyser_subscriber = self.geyser_subscriber.clone();
-        thread::spawn(move || {
-            if let Err(e) = geyser_subscriber.run() {
-                error!("GeyserSubscriber failed! {:?}", e);
-                panic!("Fatal error in GeyserSubscriber!");
-            }
-        });
+        self.run_main_loop();
 
-        let liquidation_service = self.liquidation_service.clone();
-        thread::spawn(move || {
-            if let Err(e) = liquidation_service.run() {
-                error!("LiquidationService failed! {:?}", e);
-                panic!("Fatal error in LiquidationService!");
-            }
-        });
+        info!("Waiting for the services to stop...");
+        for name in join_services(handles, SHUTDOWN_TIMEOUT) {
+            error!(
+                "The {} did not stop within {:?}, abandoning it.",
+                name, SHUTDOWN_TIMEOUT
+            );
+        }
+
+        self.cache_loader.save_snapshot();
+
+        Ok(())
+    }
 
+    /// Logs the stats and compacts the cache every stats interval. The interval is slept in short
+    /// steps, so that the stop is honored right away however long the interval is.
+    fn run_main_loop(&self) {
         info!("Entering the Main loop.");
         while !self.stop.load(std::sync::atomic::Ordering::SeqCst) {
             if let Err(err) = self.log_stats() {
                 eprintln!("Error logging stats: {}", err);
             }
-            thread::sleep(std::time::Duration::from_secs(self.stats_interval_sec));
+            if let Err(err) = self.compact_cache() {
+                error!("Failed to compact the Marginfi accounts cache: {}", err);
+            }
+            sleep_unless_stopped(&self.stop, Duration::from_secs(self.stats_interval_sec));
         }
         info!("The Main loop stopped.");
+    }
 
+    /// Runs the service in its own thread and restarts it on failure as allowed by the RestartPolicy.
+    /// Exhausting the restarts is fatal for the whole process.
+    fn spawn_service<F>(&self, name: &'static str, run: F) -> (&'static str, JoinHandle<()>)
+    where
+        F: Fn() -> Result<()> + Send + 'static,
+    {
+        let stop = self.stop.clone();
+        let mut restarts = RestartTracker::new(self.restart_policy);
+        let handle = thread::spawn(move || loop {
+            let Err(e) = run() else {
+                break;
+            };
+            if stop.load(Ordering::Relaxed) {
+                error!("{} failed while stopping: {:?}", name, e);
+                break;
+            }
+            match restarts.on_failure(Instant::now()) {
+                Some(delay) => {
+                    error!("{} failed, restarting in {:?}: {:?}", name, delay, e);
+                    sleep_unless_stopped(&stop, delay);
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                }
+                None => {
+                    error!(
+                        "{} failed more than {} times within {:?}, giving up! {:?}",
+                        name, restarts.policy.max_restarts, restarts.policy.window, e
+                    );
+                    panic!("Fatal error in {}!", name);
+                }
+            }
+        });
+        (name, handle)
+    }
+
+    /// Drops the Marginfi accounts that stopped receiving updates, e.g. the closed ones.
+    fn compact_cache(&self) -> anyhow::Result<()> {
+        if self.marginfi_account_max_age_slots == 0 {
+            return Ok(());
+        }
+
+        let clock = self.cache.get_clock()?;
+        let dropped = self
+            .cache
+            .marginfi_accounts
+            .compact(clock.slot, self.marginfi_account_max_age_slots)?;
+        if dropped > 0 {
+            info!(
+                "Dropped {} stale Marginfi accounts from the cache.",
+                dropped
+            );
+        }
         Ok(())
     }
 
     pub fn log_stats(&self) -> anyhow::Result<()> {
         let clock = self.cache.get_clock()?;
         let queue_depth = self.geyser_processor.queue_depth();
+        let health =
+            HealthHistogram::from(&self.cache.marginfi_accounts.get_accounts_with_health()?);
+        let cache_stats = self.cache.stats()?;
+        let geyser_messages = self
+            .geyser_processor
+            .message_counts()
+            .iter()
+            .filter(|count| count.processed > 0)
+            .map(|count| {
+                format!(
+                    "{} {} ({} failed)",
+                    count.message_type.name(),
+                    count.processed,
+                    count.failed
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
         info!(
-            "Stats: [Latest Slot: {:?}; Geyser Queue Depth: {}]",
-            clock.slot, queue_depth
+            "Stats: [Latest Slot: {:?}; Geyser Queue Depth: {}; Geyser Messages: {}; Accounts Health: {} healthy, {} at risk, {} liquidatable, {} unknown; Cached: {} Marginfi accounts, {} Banks, {} Oracles, {} Mints, {} LUTs, ~{} KiB]",
+            clock.slot,
+            queue_depth,
+            geyser_messages,
+            health.healthy,
+            health.at_risk,
+            health.liquidatable,
+            health.unknown,
+            cache_stats.marginfi_accounts,
+            cache_stats.banks,
+            cache_stats.oracles,
+            cache_stats.mints,
+            cache_stats.luts,
+            cache_stats.estimated_bytes / 1024
         );
         Ok(())
     }
 }
 
-fn fetch_clock(rpc_client: &dyn CommsClient) -> anyhow::Result<Clock> {
+// The accounts with the health below 5% are at risk of the liquidation.
+const AT_RISK_HEALTH_BPS: i64 = 500;
+
+/// The cached accounts counted by their health range.
+#[derive(Debug, Default, PartialEq)]
+struct HealthHistogram {
+    healthy: usize,
+    at_risk: usize,
+    liquidatable: usize,
+    unknown: usize,
+}
+
+impl HealthHistogram {
+    fn from(accounts: &HashMap<Pubkey, i64>) -> Self {
+        let mut histogram = Self::default();
+        for &health in accounts.values() {
+            match health {
+                INVALID_HEALTH => histogram.unknown += 1,
+                health if health < 0 => histogram.liquidatable += 1,
+                health if health < AT_RISK_HEALTH_BPS => histogram.at_risk += 1,
+                _ => histogram.healthy += 1,
+            }
+        }
+        histogram
+    }
+}
+
+/// The source of the account updates fed to the GeyserProcessor.
+pub trait Subscriber: Send + Sync {
+    fn new(
+        config: &Config,
+        stop: Arc<AtomicBool>,
+        cache: Arc<Cache>,
+        geyser_tx: GeyserSender,
+        resubscribe_rx: Receiver<Vec<Pubkey>>,
+    ) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Streams the updates until the stop is requested, reconnecting on the failures.
+    fn run(&self) -> Result<()>;
+}
+
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Time given to the services to finish the in-flight work after the stop is requested.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The liquidation outcomes waiting for the NotificationService, the rest is dropped.
+const NOTIFICATION_QUEUE_SIZE: usize = 100;
+
+const RESTART_BASE_DELAY: Duration = Duration::from_secs(1);
+const RESTART_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// How many times a failed service may be restarted within the window.
+#[derive(Debug, Clone, Copy)]
+struct RestartPolicy {
+    max_restarts: u32,
+    window: Duration,
+}
+
+impl RestartPolicy {
+    fn new(max_restarts: u32, window: Duration) -> Self {
+        Self {
+            max_restarts,
+            window,
+        }
+    }
+}
+
+/// Tracks the recent restarts of a single service.
+struct RestartTracker {
+    policy: RestartPolicy,
+    restarts: VecDeque<Instant>,
+}
+
+impl RestartTracker {
+    fn new(policy: RestartPolicy) -> Self {
+        Self {
+            policy,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// Returns the delay before the restart, or None if the restarts within the window are exhausted.
+    fn on_failure(&mut self, now: Instant) -> Option<Duration> {
+        while let Some(&oldest) = self.restarts.front() {
+            if now.duration_since(oldest) < self.policy.window {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= self.policy.max_restarts as usize {
+            return None;
+        }
+
+        let delay = RESTART_BASE_DELAY
+            .saturating_mul(1 << self.restarts.len().min(16))
+            .min(RESTART_MAX_DELAY);
+        self.restarts.push_back(now);
+        Some(delay)
+    }
+}
+
+/// Joins the service threads, returns the names of those that did not finish within the timeout.
+fn join_services(
+    handles: Vec<(&'static str, JoinHandle<()>)>,
+    timeout: Duration,
+) -> Vec<&'static str> {
+    let deadline = Instant::now() + timeout;
+    let mut pending = handles;
+    while !pending.is_empty() && Instant::now() < deadline {
+        let (finished, unfinished): (Vec<_>, Vec<_>) = pending
+            .into_iter()
+            .partition(|(_, handle)| handle.is_finished());
+        for (name, handle) in finished {
+            if handle.join().is_err() {
+                error!("The {} thread panicked.", name);
+            } else {
+                info!("The {} is stopped.", name);
+            }
+        }
+        pending = unfinished;
+        if !pending.is_empty() {
+            thread::sleep(STOP_CHECK_INTERVAL);
+        }
+    }
+    pending.into_iter().map(|(name, _)| name).collect()
+}
+
+/// Sleeps in short slices so that the stop request is honored promptly.
+fn sleep_unless_stopped(stop: &AtomicBool, duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while !stop.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        thread::sleep((deadline - now).min(STOP_CHECK_INTERVAL));
+    }
+}
+
+/// Fetches the Clock sysvar at the commitment the comms client is configured with. The account is
+/// validated as a wrong endpoint otherwise fails the startup with an opaque deserialization error.
+fn fetch_clock(rpc_client: &dyn CommsClient, rpc_url: &str) -> anyhow::Result<Clock> {
     let clock_account = rpc_client.get_account(&sysvar::clock::id())?;
-    let clock = deserialize(&clock_account.data)?;
+    if clock_account.owner != sysvar::id() || clock_account.data.len() < size_of::<Clock>() {
+        return Err(MaryError::Deserialize(format!(
+            "The Clock sysvar account malformed from {}: owner {}, {} bytes",
+            rpc_url,
+            clock_account.owner,
+            clock_account.data.len()
+        ))
+        .into());
+    }
+    let clock: Clock = deserialize(&clock_account.data).map_err(|e| {
+        MaryError::Deserialize(format!(
+            "The Clock sysvar account malformed from {}: {}",
+            rpc_url, e
+        ))
+    })?;
+    if clock.slot == 0 {
+        return Err(MaryError::Deserialize(format!(
+            "The Clock sysvar account from {} is at the slot 0",
+            rpc_url
+        ))
+        .into());
+    }
     Ok(clock)
 }
 
+#[cfg(test)]
+pub mod test_util {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Sends the scripted messages once run, then idles until the stop like a quiet stream.
+    pub struct MockSubscriber {
+        stop: Arc<AtomicBool>,
+        geyser_tx: GeyserSender,
+        script: Mutex<Vec<GeyserMessage>>,
+    }
+
+    impl MockSubscriber {
+        pub fn script(&self, messages: Vec<GeyserMessage>) {
+            self.script.lock().unwrap().extend(messages);
+        }
+    }
+
+    impl Subscriber for MockSubscriber {
+        fn new(
+            _config: &Config,
+            stop: Arc<AtomicBool>,
+            _cache: Arc<Cache>,
+            geyser_tx: GeyserSender,
+            _resubscribe_rx: Receiver<Vec<Pubkey>>,
+        ) -> Result<Self> {
+            Ok(Self {
+                stop,
+                geyser_tx,
+                script: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn run(&self) -> Result<()> {
+            let messages: Vec<GeyserMessage> = self.script.lock().unwrap().drain(..).collect();
+            for msg in messages {
+                self.geyser_tx.send(msg)?;
+            }
+            while !self.stop.load(Ordering::Relaxed) {
+                thread::sleep(STOP_CHECK_INTERVAL);
+            }
+            Ok(())
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use solana_sdk::account::Account;
 
+    use super::test_util::MockSubscriber;
     use super::*;
     use crate::cache::test_util::generate_test_clock;
+    use crate::common::MessageType;
     use crate::comms::test_util::MockedCommsClient;
+    use crate::config::test_util::create_dummy_config;
 
     use std::collections::HashMap;
 
     #[test]
-    fn test_fetch_clock() {
-        let clock = generate_test_clock(1);
+    fn test_subscriber_updates_flow_into_cache() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let service_manager: ServiceManager<MockedCommsClient, MockSubscriber> =
+            ServiceManager::new(create_dummy_config(), stop.clone()).unwrap();
+
+        let clock = generate_test_clock(10);
+        let mint = Pubkey::new_unique();
+        let token_program = Pubkey::new_unique();
+        service_manager.subscriber.script(vec![
+            GeyserMessage {
+                message_type: MessageType::Clock,
+                slot: 10,
+                address: sysvar::clock::id(),
+                account: Account {
+                    lamports: 1,
+                    data: bincode::serialize(&clock).unwrap(),
+                    owner: sysvar::id(),
+                    executable: false,
+                    rent_epoch: 0,
+                },
+            },
+            GeyserMessage {
+                message_type: MessageType::Mint,
+                slot: 10,
+                address: mint,
+                account: Account::new(1, 82, &token_program),
+            },
+        ]);
 
-        let mut accounts = HashMap::new();
-        accounts.insert(
+        let subscriber = service_manager.subscriber.clone();
+        let geyser_processor = service_manager.geyser_processor.clone();
+        let handles = vec![
+            thread::spawn(move || subscriber.run()),
+            thread::spawn(move || geyser_processor.run()),
+        ];
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while service_manager.cache.mints.get(&mint).unwrap().is_none() && Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        stop.store(true, Ordering::Relaxed);
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+
+        assert_eq!(service_manager.cache.get_clock().unwrap().slot, 10);
+        assert_eq!(
+            service_manager
+                .cache
+                .mints
+                .get(&mint)
+                .unwrap()
+                .unwrap()
+                .owner,
+            token_program
+        );
+    }
+
+    fn clock_client(data: Vec<u8>, owner: Pubkey) -> MockedCommsClient {
+        MockedCommsClient::with_accounts(HashMap::from([(
             sysvar::clock::id(),
             Account {
                 lamports: 0,
-                data: bincode::serialize(&clock).unwrap(),
-                owner: solana_sdk::pubkey::Pubkey::default(),
+                data,
+                owner,
                 executable: false,
                 rent_epoch: 0,
             },
-        );
+        )]))
+    }
+
+    #[test]
+    fn test_fetch_clock() {
+        let clock = generate_test_clock(1);
 
-        let mock_client = MockedCommsClient::with_accounts(accounts);
-        let fetched_clock = fetch_clock(&mock_client).unwrap();
+        let mock_client = clock_client(bincode::serialize(&clock).unwrap(), sysvar::id());
+        let fetched_clock = fetch_clock(&mock_client, "http://rpc").unwrap();
         assert_eq!(fetched_clock, clock);
     }
+
+    #[test]
+    fn test_fetch_clock_rejects_malformed_account() {
+        let data = bincode::serialize(&generate_test_clock(1)).unwrap();
+
+        for client in [
+            clock_client(vec![], sysvar::id()),
+            clock_client(data[..8].to_vec(), sysvar::id()),
+            clock_client(data, Pubkey::new_unique()),
+        ] {
+            let err = fetch_clock(&client, "http://rpc").unwrap_err();
+            assert!(matches!(
+                MaryError::of(&err),
+                Some(MaryError::Deserialize(_))
+            ));
+            assert!(err
+                .to_string()
+                .starts_with("The Clock sysvar account malformed from http://rpc"));
+        }
+    }
+
+    #[test]
+    fn test_fetch_clock_rejects_zero_slot() {
+        let client = clock_client(
+            bincode::serialize(&generate_test_clock(0)).unwrap(),
+            sysvar::id(),
+        );
+        assert!(fetch_clock(&client, "http://rpc").is_err());
+    }
+
+    #[test]
+    fn test_health_histogram() {
+        let accounts = HashMap::from([
+            (Pubkey::new_unique(), 5_000),
+            (Pubkey::new_unique(), AT_RISK_HEALTH_BPS),
+            (Pubkey::new_unique(), AT_RISK_HEALTH_BPS - 1),
+            (Pubkey::new_unique(), 0),
+            (Pubkey::new_unique(), -1),
+            (Pubkey::new_unique(), -5_000),
+            (Pubkey::new_unique(), INVALID_HEALTH),
+        ]);
+
+        assert_eq!(
+            HealthHistogram::from(&accounts),
+            HealthHistogram {
+                healthy: 2,
+                at_risk: 2,
+                liquidatable: 2,
+                unknown: 1,
+            }
+        );
+        assert_eq!(
+            HealthHistogram::from(&HashMap::new()),
+            HealthHistogram::default()
+        );
+    }
+
+    #[test]
+    fn test_main_loop_returns_promptly_on_stop() {
+        let mut config = create_dummy_config();
+        config.stats_interval_sec = 300;
+        let stop = Arc::new(AtomicBool::new(false));
+        let service_manager: ServiceManager<MockedCommsClient, MockSubscriber> =
+            ServiceManager::new(config, stop.clone()).unwrap();
+
+        let started = Instant::now();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            stop.store(true, Ordering::Relaxed);
+        });
+        service_manager.run_main_loop();
+        stopper.join().unwrap();
+
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // Not entered at all with the stop already requested
+        let started = Instant::now();
+        service_manager.run_main_loop();
+        assert!(started.elapsed() < STOP_CHECK_INTERVAL);
+    }
+
+    #[test]
+    fn test_sleep_unless_stopped_exits_on_stop() {
+        let stop = AtomicBool::new(true);
+        let started = Instant::now();
+        sleep_unless_stopped(&stop, Duration::from_secs(30));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_sleep_unless_stopped_sleeps_for_duration() {
+        let stop = AtomicBool::new(false);
+        let started = Instant::now();
+        sleep_unless_stopped(&stop, Duration::from_millis(150));
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn test_restart_tracker_backoff_and_give_up() {
+        let mut tracker = RestartTracker::new(RestartPolicy::new(3, Duration::from_secs(60)));
+        let now = Instant::now();
+
+        assert_eq!(tracker.on_failure(now), Some(RESTART_BASE_DELAY));
+        assert_eq!(tracker.on_failure(now), Some(RESTART_BASE_DELAY * 2));
+        assert_eq!(tracker.on_failure(now), Some(RESTART_BASE_DELAY * 4));
+        assert_eq!(tracker.on_failure(now), None);
+    }
+
+    #[test]
+    fn test_restart_tracker_forgets_restarts_outside_window() {
+        let window = Duration::from_secs(60);
+        let mut tracker = RestartTracker::new(RestartPolicy::new(2, window));
+        let now = Instant::now();
+
+        assert!(tracker.on_failure(now).is_some());
+        assert!(tracker.on_failure(now).is_some());
+        assert_eq!(tracker.on_failure(now + Duration::from_secs(30)), None);
+        assert_eq!(tracker.on_failure(now + window), Some(RESTART_BASE_DELAY));
+    }
+
+    #[test]
+    fn test_restart_tracker_caps_delay() {
+        let mut tracker = RestartTracker::new(RestartPolicy::new(100, Duration::from_secs(3600)));
+        let now = Instant::now();
+        for _ in 0..99 {
+            assert!(tracker.on_failure(now).unwrap() <= RESTART_MAX_DELAY);
+        }
+        assert_eq!(tracker.on_failure(now), Some(RESTART_MAX_DELAY));
+    }
+
+    #[test]
+    fn test_join_services_all_finished() {
+        let handles = vec![
+            ("First", thread::spawn(|| {})),
+            ("Second", thread::spawn(|| {})),
+        ];
+        assert!(join_services(handles, Duration::from_secs(5)).is_empty());
+    }
+
+    #[test]
+    fn test_join_services_reports_hanging() {
+        let release = Arc::new(AtomicBool::new(false));
+        let hanging_release = release.clone();
+        let handles = vec![
+            ("Finished", thread::spawn(|| {})),
+            (
+                "Hanging",
+                thread::spawn(move || {
+                    while !hanging_release.load(Ordering::Relaxed) {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                }),
+            ),
+        ];
+
+        let hanging = join_services(handles, Duration::from_millis(300));
+        release.store(true, Ordering::Relaxed);
+        assert_eq!(hanging, vec!["Hanging"]);
+    }
 }
@@ -1,44 +1,97 @@
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicU64, Ordering},
     Arc,
 };
 
 use anchor_lang::AccountDeserialize;
-use crossbeam::channel::Receiver;
-use log::{error, info, trace};
+use anyhow::anyhow;
+use crossbeam::{
+    channel::{Receiver, RecvError, Sender},
+    select,
+};
+use fixed::types::I80F48;
+use log::{debug, error, info, trace};
 use marginfi::state::{marginfi_account::MarginfiAccount, marginfi_group::Bank};
-use solana_sdk::clock::Clock;
+use solana_sdk::{clock::Clock, pubkey::Pubkey};
+
+use super::STOP_CHECK_INTERVAL;
+use crate::{
+    cache::{banks::CachedBank, Cache},
+    common::MessageType,
+    service::geyser_subscriber::GeyserMessage,
+};
 
-use crate::{cache::Cache, common::MessageType, service::geyser_subscriber::GeyserMessage};
+const PRICE_MOVE_BPS_SCALE: i64 = 10_000;
+
+/// The Geyser messages processed per type, the failed ones are also counted as processed.
+#[derive(Default)]
+struct MessageCounters {
+    processed: [AtomicU64; MessageType::ALL.len()],
+    failed: [AtomicU64; MessageType::ALL.len()],
+}
+
+impl MessageCounters {
+    fn record(&self, message_type: MessageType, failed: bool) {
+        self.processed[message_type as usize].fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.failed[message_type as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The processed and the failed Geyser messages of a type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MessageCount {
+    pub message_type: MessageType,
+    pub processed: u64,
+    pub failed: u64,
+}
 
 pub struct GeyserProcessor {
     stop: Arc<AtomicBool>,
     cache: Arc<Cache>,
-    geyser_rx: Receiver<GeyserMessage>,
+    priority_rx: Receiver<GeyserMessage>,
+    normal_rx: Receiver<GeyserMessage>,
+    // The Oracle price move that triggers the liquidation re-evaluation, 0 disables it
+    price_move_trigger_bps: u64,
+    liquidation_trigger: Sender<Vec<Pubkey>>,
+    // The Oracles and Mints to add to the subscription, e.g. of a new Bank or after an Oracle migration
+    resubscribe_tx: Sender<Vec<Pubkey>>,
+    counters: MessageCounters,
 }
 
 impl GeyserProcessor {
     pub fn new(
         stop: Arc<AtomicBool>,
         cache: Arc<Cache>,
-        geyser_rx: Receiver<GeyserMessage>,
+        priority_rx: Receiver<GeyserMessage>,
+        normal_rx: Receiver<GeyserMessage>,
+        price_move_trigger_bps: u64,
+        liquidation_trigger: Sender<Vec<Pubkey>>,
+        resubscribe_tx: Sender<Vec<Pubkey>>,
     ) -> Self {
         Self {
             stop,
             cache,
-            geyser_rx,
+            priority_rx,
+            normal_rx,
+            price_move_trigger_bps,
+            liquidation_trigger,
+            resubscribe_tx,
+            counters: MessageCounters::default(),
         }
     }
 
     pub fn run(&self) -> anyhow::Result<()> {
         info!("Entering the GeyserProcessor loop.");
         while !self.stop.load(Ordering::Relaxed) {
-            match self.geyser_rx.recv() {
-                Ok(mut msg) => {
+            match self.recv_next() {
+                Ok(Some(mut msg)) => {
                     if let Err(err) = self.process_message(&mut msg) {
                         error!("Failed to process Geyser message {:?}: {}", msg, err);
                     }
                 }
+                Ok(None) => {}
                 Err(error) => {
                     error!("GeyserProcessor error: {}!", error);
                 }
@@ -49,8 +102,27 @@ impl GeyserProcessor {
         Ok(())
     }
 
+    /// Receives the next message, the priority channel is always drained first.
+    /// Waking up periodically so that the stop request is honored on the quiet channels.
+    fn recv_next(&self) -> Result<Option<GeyserMessage>, RecvError> {
+        if let Ok(msg) = self.priority_rx.try_recv() {
+            return Ok(Some(msg));
+        }
+        select! {
+            recv(self.priority_rx) -> msg => msg.map(Some),
+            recv(self.normal_rx) -> msg => msg.map(Some),
+            default(STOP_CHECK_INTERVAL) => Ok(None),
+        }
+    }
+
     fn process_message(&self, msg: &mut GeyserMessage) -> anyhow::Result<()> {
         trace!("Processing Geyser message: {}", msg);
+        let result = self.apply_message(msg);
+        self.counters.record(msg.message_type, result.is_err());
+        result
+    }
+
+    fn apply_message(&self, msg: &mut GeyserMessage) -> anyhow::Result<()> {
         match msg.message_type {
             MessageType::Clock => {
                 let clock: Clock = bincode::deserialize::<Clock>(&msg.account.data)?;
@@ -59,26 +131,186 @@ impl GeyserProcessor {
             MessageType::MarginfiAccount => {
                 let marginfi_account: MarginfiAccount =
                     MarginfiAccount::try_deserialize(&mut msg.account.data.as_slice())?;
-                self.cache
-                    .marginfi_accounts
-                    .update(msg.slot, msg.address, marginfi_account)?;
+                if marginfi_account
+                    .lending_account
+                    .balances
+                    .iter()
+                    .all(|balance| balance.active == 0)
+                {
+                    self.cache.marginfi_accounts.remove(&msg.address)?;
+                } else {
+                    self.cache
+                        .update_marginfi_account(msg.slot, msg.address, &marginfi_account)?;
+                }
             }
             MessageType::Bank => {
                 let bank: Bank = Bank::try_deserialize(&mut msg.account.data.as_slice())?;
+                let previous_bank = self.cache.banks.get_bank(&msg.address).ok();
                 self.cache.banks.update(msg.slot, msg.address, &bank)?;
+                self.resubscribe_on_new_accounts(&msg.address, previous_bank.as_ref())?;
             }
             MessageType::Oracle => {
+                let previous_price = self.oracle_price(&msg.address);
+                // The Geyser account updates carry no time, the latest cached clock is the closest one
+                let timestamp = self.cache.get_clock()?.unix_timestamp;
+                if self.cache.oracles.contains(&msg.address)? {
+                    self.cache.oracles.update(
+                        msg.slot,
+                        timestamp,
+                        &msg.address,
+                        &mut msg.account,
+                    )?;
+                } else {
+                    // The first update of the Oracle subscribed to after the startup
+                    self.cache
+                        .insert_new_oracle(msg.slot, &msg.address, msg.account.clone())?;
+                }
+                let price = self.oracle_price(&msg.address);
+                if price != previous_price {
+                    self.cache.oracles.mark_repriced(&msg.address)?;
+                }
+                self.trigger_on_price_move(&msg.address, previous_price, price)?;
+            }
+            MessageType::Mint => {
+                self.cache.mints.update(msg.address, &msg.account)?;
+            }
+            MessageType::Vault => {
                 self.cache
-                    .oracles
-                    .update(msg.slot, &msg.address, &mut msg.account)?;
+                    .banks
+                    .update_vault(msg.slot, msg.address, &msg.account)?;
+            }
+            MessageType::Closed => {
+                // Only the Marginfi accounts can be closed, the Banks live as long as their group
+                self.cache.marginfi_accounts.remove(&msg.address)?;
+            }
+            MessageType::Watched => {
+                info!(
+                    "The watched account {} is updated at slot {}: {} lamports, {} bytes, owner {}.",
+                    msg.address,
+                    msg.slot,
+                    msg.account.lamports,
+                    msg.account.data.len(),
+                    msg.account.owner
+                );
             }
         }
         Ok(())
     }
 
+    /// Asks the Subscriber to subscribe to the Oracles the Bank started using and the Mint and the
+    /// vaults of a new Bank.
+    fn resubscribe_on_new_accounts(
+        &self,
+        address: &Pubkey,
+        previous_bank: Option<&CachedBank>,
+    ) -> anyhow::Result<()> {
+        let bank = self.cache.banks.get_bank(address)?;
+        let previous_oracles = previous_bank.map_or(&[][..], |bank| bank.oracle_addresses());
+
+        let mut new_accounts = Vec::new();
+        for oracle in bank.oracle_addresses() {
+            if !previous_oracles.contains(oracle) && !self.cache.oracles.contains(oracle)? {
+                new_accounts.push(*oracle);
+            }
+        }
+        if previous_bank.is_none() {
+            if self.cache.mints.get(bank.mint())?.is_none() {
+                new_accounts.push(*bank.mint());
+            }
+            new_accounts.extend(bank.vaults());
+        }
+        if new_accounts.is_empty() {
+            return Ok(());
+        }
+
+        info!(
+            "The Bank {} uses the new accounts {:?}, resubscribing.",
+            address, new_accounts
+        );
+        self.resubscribe_tx
+            .send(new_accounts)
+            .map_err(|e| anyhow!("Failed to request the resubscription: {}", e))
+    }
+
     pub fn queue_depth(&self) -> usize {
-        self.geyser_rx.len()
+        self.priority_rx.len() + self.normal_rx.len()
+    }
+
+    /// The messages processed since the start per type, so that a quiet feed can be told apart from
+    /// a missing subscription.
+    pub fn message_counts(&self) -> Vec<MessageCount> {
+        MessageType::ALL
+            .iter()
+            .map(|&message_type| MessageCount {
+                message_type,
+                processed: self.counters.processed[message_type as usize].load(Ordering::Relaxed),
+                failed: self.counters.failed[message_type as usize].load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    fn oracle_price(&self, address: &Pubkey) -> Option<I80F48> {
+        self.cache
+            .oracles
+            ._get(address)
+            .ok()
+            .flatten()
+            .and_then(|oracle| oracle.price_with_confidence().ok())
+            .map(|(price, _)| price)
+    }
+
+    /// Signals the LiquidationService to re-evaluate the accounts borrowing from the banks priced
+    /// by the oracle right away, if the oracle price moved by the trigger threshold or more.
+    fn trigger_on_price_move(
+        &self,
+        oracle: &Pubkey,
+        previous_price: Option<I80F48>,
+        price: Option<I80F48>,
+    ) -> anyhow::Result<()> {
+        let (Some(previous_price), Some(price)) = (previous_price, price) else {
+            return Ok(());
+        };
+        let Some(move_bps) = price_move_bps(previous_price, price) else {
+            return Ok(());
+        };
+        if self.price_move_trigger_bps == 0 || move_bps < self.price_move_trigger_bps {
+            return Ok(());
+        }
+
+        let mut accounts = Vec::new();
+        for bank in self.cache.banks.get_banks_by_oracle(oracle)? {
+            accounts.extend(
+                self.cache
+                    .marginfi_accounts
+                    .accounts_with_liability_in(&bank)?,
+            );
+        }
+        if accounts.is_empty() {
+            return Ok(());
+        }
+
+        debug!(
+            "The Oracle {} price moved by {} bps, re-evaluating {} accounts.",
+            oracle,
+            move_bps,
+            accounts.len()
+        );
+        self.liquidation_trigger
+            .send(accounts)
+            .map_err(|e| anyhow!("Failed to trigger the liquidation re-evaluation: {}", e))
+    }
+}
+
+/// The relative price move in basis points, None if the previous price is not positive.
+fn price_move_bps(previous_price: I80F48, price: I80F48) -> Option<u64> {
+    if previous_price <= I80F48::ZERO {
+        return None;
     }
+    let move_bps = (price - previous_price)
+        .abs()
+        .checked_div(previous_price)?
+        .saturating_mul(I80F48::from_num(PRICE_MOVE_BPS_SCALE));
+    Some(move_bps.saturating_to_num::<u64>())
 }
 
 #[cfg(test)]
@@ -86,34 +318,62 @@ mod tests {
     use super::*;
 
     use crate::cache::{
-        banks::test_util::create_bank_with_oracles,
-        marginfi_accounts::test_util::create_marginfi_account,
+        banks::test_util::{
+            add_priced_bank, create_bank_account, create_bank_with_oracles, create_token_account,
+        },
+        marginfi_accounts::test_util::{
+            create_balance, create_marginfi_account, create_marginfi_account_account,
+        },
+        oracles::test_util::create_pyth_account,
         test_util::{create_dummy_cache, generate_test_clock},
         Cache,
     };
     use crate::common::MessageType;
-    use crate::service::geyser_subscriber::GeyserMessage;
+    use crate::service::geyser_subscriber::{GeyserMessage, GeyserSender};
     use crossbeam::channel;
     use solana_sdk::{account::Account, clock::Clock, pubkey::Pubkey};
-    use std::sync::{atomic::AtomicBool, Arc};
+    use std::{
+        collections::HashSet,
+        sync::{atomic::AtomicBool, Arc},
+    };
 
+    /// The processor with the sender of its messages, the stop, the cache and the receiver of the
+    /// resubscriptions, which the tests keep alive so that the resubscriptions do not fail.
     fn setup_processor() -> (
         GeyserProcessor,
-        channel::Sender<GeyserMessage>,
+        GeyserSender,
         Arc<AtomicBool>,
         Arc<Cache>,
+        Receiver<Vec<Pubkey>>,
     ) {
         let stop = Arc::new(AtomicBool::new(false));
         let cache = Arc::new(create_dummy_cache());
 
-        let (tx, rx) = channel::unbounded();
-        let processor = GeyserProcessor::new(stop.clone(), cache.clone(), rx);
-        (processor, tx, stop, cache)
+        let (priority_tx, priority_rx) = channel::unbounded();
+        let (normal_tx, normal_rx) = channel::unbounded();
+        let (trigger_tx, _) = channel::unbounded();
+        let (resubscribe_tx, resubscribe_rx) = channel::unbounded();
+        let processor = GeyserProcessor::new(
+            stop.clone(),
+            cache.clone(),
+            priority_rx,
+            normal_rx,
+            0,
+            trigger_tx,
+            resubscribe_tx,
+        );
+        (
+            processor,
+            GeyserSender::new(priority_tx, normal_tx),
+            stop,
+            cache,
+            resubscribe_rx,
+        )
     }
 
     #[test]
     fn test_queue_depth() {
-        let (processor, tx, _, _) = setup_processor();
+        let (processor, tx, _, _, _resubscribe_rx) = setup_processor();
         assert_eq!(processor.queue_depth(), 0);
 
         let msg = GeyserMessage {
@@ -128,7 +388,7 @@ mod tests {
 
     #[test]
     fn test_process_clock_message() {
-        let (processor, tx, stop, cache) = setup_processor();
+        let (processor, tx, stop, cache, _resubscribe_rx) = setup_processor();
         let clock = Clock::default();
         let data = bincode::serialize(&clock).unwrap();
         let msg = GeyserMessage {
@@ -145,19 +405,178 @@ mod tests {
 
     #[test]
     fn test_process_marginfi_account_message() {
-        let _marginfi_account = create_marginfi_account(Pubkey::new_unique(), vec![]);
-        // TODO: implement after figuring out how to serialize MarginfiAccount
+        let (processor, _, _, cache, _resubscribe_rx) = setup_processor();
+        let address = Pubkey::new_unique();
+        let bank = Pubkey::new_unique();
+        let account_msg = |slot: u64, balances| GeyserMessage {
+            message_type: MessageType::MarginfiAccount,
+            slot,
+            address,
+            account: create_marginfi_account_account(&create_marginfi_account(
+                Pubkey::new_unique(),
+                balances,
+            )),
+        };
+
+        processor
+            .process_message(&mut account_msg(1, vec![create_balance(bank, 100, 50)]))
+            .unwrap();
+        let cached = cache.marginfi_accounts.get_account(&address).unwrap();
+        assert_eq!(cached.slot(), 1);
+        assert_eq!(cached._positions()[0].bank_pk, bank);
+
+        // The account with all the positions closed is dropped
+        processor
+            .process_message(&mut account_msg(2, vec![]))
+            .unwrap();
+        assert!(cache.marginfi_accounts.get_account(&address).is_err());
+
+        let mut malformed_msg = account_msg(3, vec![]);
+        malformed_msg.account.data.truncate(16);
+        assert!(processor.process_message(&mut malformed_msg).is_err());
     }
 
     #[test]
     fn test_process_bank_message() {
-        let _bank = create_bank_with_oracles(vec![]);
-        // TODO: implement after figuring out how to serialize Bank
+        let (processor, _, _, cache, _resubscribe_rx) = setup_processor();
+        let address = Pubkey::new_unique();
+        let oracle = Pubkey::new_unique();
+        let bank = create_bank_with_oracles(vec![oracle]);
+
+        processor
+            .process_message(&mut GeyserMessage {
+                message_type: MessageType::Bank,
+                slot: 1,
+                address,
+                account: create_bank_account(&bank),
+            })
+            .unwrap();
+        let cached = cache.banks.get_bank(&address).unwrap();
+        assert_eq!(cached.slot, 1);
+        assert_eq!(cached.mint(), &bank.mint);
+        assert_eq!(cached.oracle_addresses(), &vec![oracle]);
+    }
+
+    #[test]
+    fn test_bank_update_with_new_oracle_enqueues_resubscribe() {
+        let cache = Arc::new(create_dummy_cache());
+        let (_, priority_rx) = channel::unbounded();
+        let (_, normal_rx) = channel::unbounded();
+        let (resubscribe_tx, resubscribe_rx) = channel::unbounded();
+        let processor = GeyserProcessor::new(
+            Arc::new(AtomicBool::new(false)),
+            cache.clone(),
+            priority_rx,
+            normal_rx,
+            0,
+            channel::unbounded().0,
+            resubscribe_tx,
+        );
+
+        let bank_address = Pubkey::new_unique();
+        let old_oracle = Pubkey::new_unique();
+        let new_oracle = Pubkey::new_unique();
+        let bank_msg = |slot: u64, oracle: Pubkey| GeyserMessage {
+            message_type: MessageType::Bank,
+            slot,
+            address: bank_address,
+            account: create_bank_account(&create_bank_with_oracles(vec![oracle])),
+        };
+
+        // The new Bank brings its Oracle, Mint and vaults
+        processor
+            .process_message(&mut bank_msg(1, old_oracle))
+            .unwrap();
+        let bank = cache.banks.get_bank(&bank_address).unwrap();
+        let mut expected = vec![old_oracle, *bank.mint()];
+        expected.extend(bank.vaults());
+        assert_eq!(resubscribe_rx.try_recv().unwrap(), expected);
+        // The further updates of the same config do not
+        processor
+            .process_message(&mut bank_msg(2, old_oracle))
+            .unwrap();
+        assert!(resubscribe_rx.try_recv().is_err());
+
+        // The Oracle migration
+        processor
+            .process_message(&mut bank_msg(3, new_oracle))
+            .unwrap();
+        assert_eq!(resubscribe_rx.try_recv().unwrap(), vec![new_oracle]);
+        assert_eq!(
+            cache
+                .banks
+                .get_bank(&bank_address)
+                .unwrap()
+                .oracle_addresses(),
+            &vec![new_oracle]
+        );
+
+        // The first update of the new Oracle adds it to cache
+        let mut oracle_msg = GeyserMessage {
+            message_type: MessageType::Oracle,
+            slot: 4,
+            address: new_oracle,
+            account: Account::new(1, 100, &Pubkey::new_unique()),
+        };
+        processor.process_message(&mut oracle_msg).unwrap();
+        assert!(cache.oracles.contains(&new_oracle).unwrap());
+    }
+
+    #[test]
+    fn test_message_counts() {
+        let (processor, _, _, _, _resubscribe_rx) = setup_processor();
+        let message = |message_type, account| GeyserMessage {
+            message_type,
+            slot: 2,
+            address: Pubkey::new_unique(),
+            account,
+        };
+        let mut clock_account = Account::new(1, 0, &Pubkey::new_unique());
+        clock_account.data = bincode::serialize(&generate_test_clock(2)).unwrap();
+
+        for mut msg in [
+            message(MessageType::Clock, clock_account),
+            message(
+                MessageType::MarginfiAccount,
+                create_marginfi_account_account(&create_marginfi_account(
+                    Pubkey::new_unique(),
+                    vec![create_balance(Pubkey::new_unique(), 100, 50)],
+                )),
+            ),
+            message(
+                MessageType::Bank,
+                create_bank_account(&create_bank_with_oracles(vec![])),
+            ),
+            message(
+                MessageType::Oracle,
+                Account::new(1, 100, &Pubkey::new_unique()),
+            ),
+        ] {
+            processor.process_message(&mut msg).unwrap();
+        }
+        let mut malformed_bank =
+            message(MessageType::Bank, Account::new(1, 8, &Pubkey::new_unique()));
+        assert!(processor.process_message(&mut malformed_bank).is_err());
+
+        let counts = processor.message_counts();
+        assert_eq!(counts.len(), MessageType::ALL.len());
+        let count = |message_type| {
+            let count = counts
+                .iter()
+                .find(|count| count.message_type == message_type)
+                .unwrap();
+            (count.processed, count.failed)
+        };
+        assert_eq!(count(MessageType::Clock), (1, 0));
+        assert_eq!(count(MessageType::MarginfiAccount), (1, 0));
+        assert_eq!(count(MessageType::Bank), (2, 1));
+        assert_eq!(count(MessageType::Oracle), (1, 0));
+        assert_eq!(count(MessageType::Mint), (0, 0));
     }
 
     #[test]
     fn test_process_oracle_message() {
-        let (processor, tx, stop, _cache) = setup_processor();
+        let (processor, tx, stop, _cache, _resubscribe_rx) = setup_processor();
         let msg = GeyserMessage {
             message_type: MessageType::Oracle,
             slot: 4,
@@ -169,9 +588,72 @@ mod tests {
         processor.run().unwrap();
     }
 
+    #[test]
+    fn test_oracle_price_change_marks_repriced() {
+        let (processor, _, _, cache, _resubscribe_rx) = setup_processor();
+        let bank = add_priced_bank(&cache, create_bank_with_oracles(vec![]), 0.8, 1.2);
+        let oracle = cache.banks.get_bank(&bank).unwrap().oracle_addresses()[0];
+        let oracle_msg = |slot, price| GeyserMessage {
+            message_type: MessageType::Oracle,
+            slot,
+            address: oracle,
+            account: create_pyth_account(price, 0, price, 0, slot),
+        };
+
+        processor
+            .process_message(&mut oracle_msg(2, 1_000))
+            .unwrap();
+        assert!(cache.oracles.take_repriced().unwrap().is_empty());
+
+        processor
+            .process_message(&mut oracle_msg(3, 1_100))
+            .unwrap();
+        assert_eq!(
+            cache.oracles.take_repriced().unwrap(),
+            HashSet::from([oracle])
+        );
+        assert!(cache.oracles.take_repriced().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_process_mint_message() {
+        let (processor, _, _, cache, _resubscribe_rx) = setup_processor();
+        let address = Pubkey::new_unique();
+        let token_program = Pubkey::new_unique();
+        let mut msg = GeyserMessage {
+            message_type: MessageType::Mint,
+            slot: 4,
+            address,
+            account: Account::new(1, 82, &token_program),
+        };
+        processor.process_message(&mut msg).unwrap();
+        assert_eq!(
+            cache.mints.get(&address).unwrap().unwrap().owner,
+            token_program
+        );
+    }
+
+    #[test]
+    fn test_process_vault_message() {
+        let (processor, _, _, cache, _resubscribe_rx) = setup_processor();
+        let mut bank = create_bank_with_oracles(vec![]);
+        bank.liquidity_vault = Pubkey::new_unique();
+        let bank_address = Pubkey::new_unique();
+        cache.banks.update(1, bank_address, &bank).unwrap();
+
+        let mut msg = GeyserMessage {
+            message_type: MessageType::Vault,
+            slot: 4,
+            address: bank.liquidity_vault,
+            account: create_token_account(2_500),
+        };
+        processor.process_message(&mut msg).unwrap();
+        assert_eq!(cache.banks.liquidity(&bank_address).unwrap(), 2_500);
+    }
+
     #[test]
     fn test_run_stops_on_stop_signal() {
-        let (processor, _, stop, _) = setup_processor();
+        let (processor, _, stop, _, _resubscribe_rx) = setup_processor();
         stop.store(true, Ordering::Relaxed);
         assert!(processor.run().is_ok());
     }
@@ -180,10 +662,151 @@ mod tests {
     fn test_run_handles_recv_error() {
         let stop = Arc::new(AtomicBool::new(false));
         let cache = Arc::new(create_dummy_cache());
-        let (tx, rx) = channel::bounded(0);
-        drop(tx); // Close the channel
-        let processor = GeyserProcessor::new(stop.clone(), cache.clone(), rx);
+        let (priority_tx, priority_rx) = channel::bounded(0);
+        let (normal_tx, normal_rx) = channel::bounded(0);
+        // Close the channels
+        drop(priority_tx);
+        drop(normal_tx);
+        let (trigger_tx, _) = channel::unbounded();
+        let processor = GeyserProcessor::new(
+            stop.clone(),
+            cache.clone(),
+            priority_rx,
+            normal_rx,
+            0,
+            trigger_tx,
+            channel::unbounded().0,
+        );
         stop.store(true, Ordering::Relaxed);
         assert!(processor.run().is_ok());
     }
+
+    #[test]
+    fn test_run_stops_on_quiet_channel() {
+        let (processor, _tx, stop, _, _resubscribe_rx) = setup_processor();
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            stop.store(true, Ordering::Relaxed);
+        });
+        assert!(processor.run().is_ok());
+        stopper.join().unwrap();
+    }
+
+    #[test]
+    fn test_clock_message_jumps_ahead_of_account_backlog() {
+        let (processor, tx, _, _, _resubscribe_rx) = setup_processor();
+        for slot in 0..100 {
+            tx.send(GeyserMessage {
+                message_type: MessageType::MarginfiAccount,
+                slot,
+                address: Pubkey::new_unique(),
+                account: Account::default(),
+            })
+            .unwrap();
+        }
+        tx.send(GeyserMessage {
+            message_type: MessageType::Clock,
+            slot: 100,
+            address: Pubkey::default(),
+            account: Account::default(),
+        })
+        .unwrap();
+        assert_eq!(processor.queue_depth(), 101);
+
+        let msg = processor.recv_next().unwrap().unwrap();
+        assert_eq!(msg.message_type, MessageType::Clock);
+        assert_eq!(msg.slot, 100);
+
+        let msg = processor.recv_next().unwrap().unwrap();
+        assert_eq!(msg.message_type, MessageType::MarginfiAccount);
+        assert_eq!(msg.slot, 0);
+    }
+
+    #[test]
+    fn test_price_move_bps() {
+        let price = I80F48::from_num(100);
+        assert_eq!(price_move_bps(price, I80F48::from_num(101)), Some(100));
+        assert_eq!(price_move_bps(price, I80F48::from_num(95)), Some(500));
+        assert_eq!(price_move_bps(price, price), Some(0));
+        assert_eq!(price_move_bps(I80F48::ZERO, price), None);
+    }
+
+    #[test]
+    fn test_large_price_move_triggers_reevaluation() {
+        let cache = Arc::new(create_dummy_cache());
+        let oracle = Pubkey::new_unique();
+        let bank = Pubkey::new_unique();
+        cache
+            .banks
+            .update(1, bank, &create_bank_with_oracles(vec![oracle]))
+            .unwrap();
+        let borrower = Pubkey::new_unique();
+        cache
+            .update_marginfi_account(
+                1,
+                borrower,
+                &create_marginfi_account(Pubkey::new_unique(), vec![create_balance(bank, 0, 50)]),
+            )
+            .unwrap();
+
+        let (_, priority_rx) = channel::unbounded();
+        let (_, normal_rx) = channel::unbounded();
+        let (trigger_tx, trigger_rx) = channel::unbounded();
+        let processor = GeyserProcessor::new(
+            Arc::new(AtomicBool::new(false)),
+            cache,
+            priority_rx,
+            normal_rx,
+            100,
+            trigger_tx,
+            channel::unbounded().0,
+        );
+
+        let previous_price = Some(I80F48::from_num(100));
+        processor
+            .trigger_on_price_move(&oracle, previous_price, Some(I80F48::from_num(100.5)))
+            .unwrap();
+        assert!(trigger_rx.try_recv().is_err());
+
+        processor
+            .trigger_on_price_move(&oracle, previous_price, Some(I80F48::from_num(98)))
+            .unwrap();
+        assert_eq!(trigger_rx.try_recv().unwrap(), vec![borrower]);
+
+        // The oracle of no bank the accounts borrow from
+        processor
+            .trigger_on_price_move(
+                &Pubkey::new_unique(),
+                previous_price,
+                Some(I80F48::from_num(50)),
+            )
+            .unwrap();
+        assert!(trigger_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_closed_marginfi_account_is_removed() {
+        let (processor, _, _, cache, _resubscribe_rx) = setup_processor();
+        let address = Pubkey::new_unique();
+        cache
+            .update_marginfi_account(
+                1,
+                address,
+                &create_marginfi_account(Pubkey::new_unique(), vec![]),
+            )
+            .unwrap();
+
+        let mut msg = GeyserMessage {
+            message_type: MessageType::Closed,
+            slot: 2,
+            address,
+            account: Account::new(0, 0, &Pubkey::new_unique()),
+        };
+        processor.process_message(&mut msg).unwrap();
+        assert!(cache.marginfi_accounts.get_account(&address).is_err());
+
+        // The close of the account that is not cached is a no-op
+        msg.address = Pubkey::new_unique();
+        processor.process_message(&mut msg).unwrap();
+    }
 }
@@ -4,25 +4,62 @@ use std::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::{Duration, Instant, SystemTime},
 };
 use std::{collections::HashSet, fmt};
 
-use crate::common::{get_marginfi_message_type, MessageType};
+use super::{sleep_unless_stopped, Subscriber, STOP_CHECK_INTERVAL};
+use crate::common::{
+    get_marginfi_message_type, MessageType, MARGINFI_ACCOUNT_DISCRIMINATOR,
+    MARGINFI_BANK_DISCRIMINATOR,
+};
 use crate::{cache::Cache, config::Config};
 use anyhow::{anyhow, Result};
-use crossbeam::channel::Sender;
-use futures::stream::StreamExt; // Brings `next` into scope for streams
-use log::{error, info, trace};
-use solana_sdk::{account::Account, pubkey::Pubkey};
-use solana_sdk::{clock::Clock, sysvar};
+use crossbeam::channel::{Receiver, Sender};
+use futures::{stream::StreamExt, SinkExt}; // Brings `next` and `send` into scope for the streams and sinks
+use log::{debug, error, info, trace, warn};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    account::Account,
+    commitment_config::{CommitmentConfig, CommitmentLevel},
+    pubkey::Pubkey,
+};
+use solana_sdk::{system_program, sysvar};
 use tokio::runtime::{Builder, Runtime};
 use yellowstone_grpc_client::{ClientTlsConfig, GeyserGrpcClient};
 use yellowstone_grpc_proto::geyser::{
-    subscribe_update, SubscribeUpdate, SubscribeUpdateAccountInfo,
+    subscribe_request_filter_accounts_filter::Filter,
+    subscribe_request_filter_accounts_filter_lamports::Cmp,
+    subscribe_request_filter_accounts_filter_memcmp::Data, SubscribeRequestFilterAccountsFilter,
+    SubscribeRequestFilterAccountsFilterLamports, SubscribeRequestFilterAccountsFilterMemcmp,
+};
+use yellowstone_grpc_proto::geyser::{
+    subscribe_update, CommitmentLevel as GeyserCommitmentLevel, SubscribeUpdate,
+    SubscribeUpdateAccountInfo,
+};
+use yellowstone_grpc_proto::{
+    geyser::{SubscribeRequestFilterAccounts, SubscribeRequestPing},
+    prelude::SubscribeRequest,
 };
-use yellowstone_grpc_proto::{geyser::SubscribeRequestFilterAccounts, prelude::SubscribeRequest};
 
 const SOLANA_CLOCK_BYTES: [u8; 32] = sysvar::clock::id().to_bytes();
+// The subscription must be yielding messages for at least this long to reset the reconnect backoff.
+pub(super) const MIN_HEALTHY_STREAM_DURATION: Duration = Duration::from_secs(60);
+// The new Oracles and Mints requested within this time are added by a single resubscription.
+pub(super) const RESUBSCRIBE_DEBOUNCE: Duration = Duration::from_secs(2);
+// The stream is pinged this often and reconnected if the pong does not arrive in time, which catches
+// the half-open connections that neither yield nor fail.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+// The network this many slots ahead of the stalled subscription, about 10 seconds, means the
+// subscription fell behind rather than the network slowing down.
+const SUBSCRIPTION_LAG_TOLERANCE_SLOTS: u64 = 25;
+// The network slot is fetched on the stream loop, in a single attempt so that a slow RPC holds the
+// message handling for no longer than this.
+const NETWORK_SLOT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Fetches the current slot of the network.
+pub(super) type NetworkSlot = Box<dyn Fn() -> Result<u64> + Send + Sync>;
 
 #[derive(Debug)]
 pub struct GeyserMessage {
@@ -69,6 +106,37 @@ impl fmt::Display for GeyserMessage {
     }
 }
 
+/// Routes the Clock and Oracle messages to the priority channel so that the price view stays fresh
+/// even when the account updates pile up.
+#[derive(Clone)]
+pub struct GeyserSender {
+    priority_tx: Sender<GeyserMessage>,
+    normal_tx: Sender<GeyserMessage>,
+}
+
+impl GeyserSender {
+    pub fn new(priority_tx: Sender<GeyserMessage>, normal_tx: Sender<GeyserMessage>) -> Self {
+        Self {
+            priority_tx,
+            normal_tx,
+        }
+    }
+
+    pub fn send(&self, msg: GeyserMessage) -> Result<()> {
+        match msg.message_type {
+            MessageType::Clock | MessageType::Oracle => self.priority_tx.send(msg)?,
+            // The closed accounts must stay ordered behind their preceding updates
+            MessageType::MarginfiAccount
+            | MessageType::Bank
+            | MessageType::Mint
+            | MessageType::Vault
+            | MessageType::Closed
+            | MessageType::Watched => self.normal_tx.send(msg)?,
+        }
+        Ok(())
+    }
+}
+
 pub struct GeyserSubscriber {
     endpoint: String,
     x_token: String,
@@ -77,21 +145,185 @@ pub struct GeyserSubscriber {
     tokio_rt: Runtime,
     cache: Arc<Cache>,
     marginfi_program_id: Pubkey,
-    geyser_tx: Sender<GeyserMessage>,
+    geyser_tx: GeyserSender,
+    reconnect_backoff_base: Duration,
+    reconnect_backoff_max: Duration,
+    max_slot_staleness: Duration,
+    commitment: CommitmentLevel,
+    extra_subscribe_accounts: Vec<Pubkey>,
+    resubscribe_rx: Receiver<Vec<Pubkey>>,
+    network_slot: NetworkSlot,
+}
+
+/// The subscribe request with the addresses its updates are routed by.
+struct Subscription {
+    request: SubscribeRequest,
+    oracle_addresses_bytes: HashSet<[u8; 32]>,
+    mint_addresses_bytes: HashSet<[u8; 32]>,
+    vault_addresses_bytes: HashSet<[u8; 32]>,
 }
 
 impl GeyserSubscriber {
-    pub fn new(
+    /// Builds the subscription to the Oracles, the Mints and the vaults of the cached Banks.
+    fn build_subscription(&self) -> Result<Subscription> {
+        let oracle_addresses = self.cache.get_watched_oracle_addresses()?;
+        let mint_addresses = self.cache.get_watched_mint_addresses()?;
+        let vault_addresses = self.cache.banks.get_vaults()?;
+
+        let request = build_geyser_subscribe_request(
+            &self.marginfi_program_id,
+            &oracle_addresses,
+            &mint_addresses,
+            &vault_addresses,
+            &self.extra_subscribe_accounts,
+            self.commitment,
+        )?;
+
+        Ok(Subscription {
+            request,
+            oracle_addresses_bytes: oracle_addresses.iter().map(|pk| pk.to_bytes()).collect(),
+            mint_addresses_bytes: mint_addresses.iter().map(|pk| pk.to_bytes()).collect(),
+            vault_addresses_bytes: vault_addresses.iter().map(|pk| pk.to_bytes()).collect(),
+        })
+    }
+
+    /// Streams the Geyser updates until the stream ends, errors, stalls or the stop is requested.
+    /// The subscription is updated on the same stream when new Oracles or Mints are requested.
+    /// Returns for how long the stream was yielding messages.
+    fn stream_updates(
+        &self,
+        marginfi_program_id_bytes: &[u8; 32],
+        extra_addresses_bytes: &HashSet<[u8; 32]>,
+    ) -> Result<Duration> {
+        // The requests received before the (re)connect are covered by the fresh subscription
+        self.resubscribe_rx.try_iter().for_each(drop);
+        let mut subscription = self.build_subscription()?;
+
+        let mut client = self.tokio_rt.block_on(
+            GeyserGrpcClient::build_from_shared(self.endpoint.clone())?
+                .x_token(Some(self.x_token.clone()))?
+                .tls_config(self.tls_config.clone())?
+                .connect(),
+        )?;
+
+        let (mut subscribe_tx, mut stream) = self
+            .tokio_rt
+            .block_on(client.subscribe_with_request(Some(subscription.request.clone())))?;
+
+        let mut first_message_at: Option<Instant> = None;
+        let mut watchdog = SlotWatchdog::new(
+            self.max_slot_staleness,
+            self.cache.get_clock()?.slot,
+            Instant::now(),
+        );
+        let mut resubscribe = ResubscribeDebounce::new(RESUBSCRIBE_DEBOUNCE);
+        let mut ping_monitor = PingMonitor::new(PING_INTERVAL, PONG_TIMEOUT, Instant::now());
+        loop {
+            // Waiting in short slices so that the stop and the stalled slot are noticed on a silent stream
+            let msg = match self
+                .tokio_rt
+                .block_on(tokio::time::timeout(STOP_CHECK_INTERVAL, stream.next()))
+            {
+                Ok(Some(msg)) => Some(msg),
+                Ok(None) => break,
+                Err(_) => None,
+            };
+
+            match msg {
+                Some(Ok(event)) => {
+                    first_message_at.get_or_insert_with(Instant::now);
+                    if let Some(subscribe_update::UpdateOneof::Pong(pong)) = &event.update_oneof {
+                        ping_monitor.pong(pong.id);
+                    }
+                    if let Err(e) =
+                        handle_watched_event(extra_addresses_bytes, &self.geyser_tx, &event)
+                    {
+                        error!("Error handling watched Geyser update {:?}: {}", event, e);
+                    }
+                    if let Err(e) = handle_event(
+                        marginfi_program_id_bytes,
+                        &subscription.oracle_addresses_bytes,
+                        &subscription.mint_addresses_bytes,
+                        &subscription.vault_addresses_bytes,
+                        &self.cache,
+                        &self.geyser_tx,
+                        &event,
+                    ) {
+                        error!("Error handling Geyser update {:?}: {}", event, e);
+                    }
+                }
+                Some(Err(e)) => {
+                    error!("Received error from Geyser: {}", e);
+                    break;
+                }
+                None => {}
+            }
+
+            // Breaking the loop on stop request
+            if self.stop.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let slot = self.cache.get_clock()?.slot;
+            if watchdog.is_stalled(slot, Instant::now()) {
+                if reconnect_on_stall(&self.network_slot, "Geyser", slot, self.max_slot_staleness) {
+                    break;
+                }
+                watchdog.snooze(Instant::now());
+            }
+
+            if ping_monitor.is_dead(Instant::now()) {
+                error!(
+                    "No Geyser pong received within {:?}, forcing a reconnect.",
+                    PONG_TIMEOUT
+                );
+                break;
+            }
+            if let Some(id) = ping_monitor.next_ping(Instant::now()) {
+                let ping = SubscribeRequest {
+                    ping: Some(SubscribeRequestPing { id }),
+                    ..Default::default()
+                };
+                self.tokio_rt
+                    .block_on(subscribe_tx.send(ping))
+                    .map_err(|e| anyhow!("Failed to ping Geyser: {}", e))?;
+            }
+
+            for new_accounts in self.resubscribe_rx.try_iter() {
+                debug!("Requested the subscription to {:?}.", new_accounts);
+                resubscribe.request(Instant::now());
+            }
+            if resubscribe.is_due(Instant::now()) {
+                subscription = self.build_subscription()?;
+                self.tokio_rt
+                    .block_on(subscribe_tx.send(subscription.request.clone()))
+                    .map_err(|e| anyhow!("Failed to update the Geyser subscription: {}", e))?;
+                info!(
+                    "Updated the Geyser subscription to {} Oracles, {} Mints and {} vaults.",
+                    subscription.oracle_addresses_bytes.len(),
+                    subscription.mint_addresses_bytes.len(),
+                    subscription.vault_addresses_bytes.len()
+                );
+            }
+        }
+
+        Ok(first_message_at.map_or(Duration::ZERO, |started| started.elapsed()))
+    }
+}
+
+impl Subscriber for GeyserSubscriber {
+    fn new(
         config: &Config,
         stop: Arc<AtomicBool>,
         cache: Arc<Cache>,
-        geyser_tx: Sender<GeyserMessage>,
+        geyser_tx: GeyserSender,
+        resubscribe_rx: Receiver<Vec<Pubkey>>,
     ) -> Result<Self> {
         let tls_config = ClientTlsConfig::new().with_native_roots();
 
         let tokio_rt = Builder::new_multi_thread()
             .thread_name("GeyserService")
-            .worker_threads(2)
+            .worker_threads(config.geyser_worker_threads)
             .enable_all()
             .build()?;
 
@@ -104,58 +336,44 @@ impl GeyserSubscriber {
             cache,
             marginfi_program_id: config.marginfi_program_id,
             geyser_tx,
+            reconnect_backoff_base: Duration::from_millis(config.geyser_reconnect_base_ms),
+            reconnect_backoff_max: Duration::from_millis(config.geyser_reconnect_max_ms),
+            max_slot_staleness: Duration::from_secs(config.max_slot_staleness_sec),
+            commitment: config.commitment,
+            extra_subscribe_accounts: config.extra_subscribe_accounts.clone(),
+            resubscribe_rx,
+            network_slot: network_slot(config),
         })
     }
 
-    pub fn run(&self) -> Result<()> {
-        let oracle_addresses = self.cache.oracles.get_oracle_addresses();
-
-        let subscribe_req =
-            build_geyser_subscribe_request(&self.marginfi_program_id, &oracle_addresses)?;
-
+    fn run(&self) -> Result<()> {
         let marginfi_program_id_bytes: [u8; 32] = self.marginfi_program_id.to_bytes();
-        let oracle_addresses_bytes: HashSet<[u8; 32]> =
-            oracle_addresses.iter().map(|pk| pk.to_bytes()).collect();
+        let extra_addresses_bytes: HashSet<[u8; 32]> = self
+            .extra_subscribe_accounts
+            .iter()
+            .map(|pk| pk.to_bytes())
+            .collect();
+
+        let mut backoff =
+            ReconnectBackoff::new(self.reconnect_backoff_base, self.reconnect_backoff_max);
 
         info!("Entering the GeyserService loop.");
         while !self.stop.load(Ordering::Relaxed) {
             info!("Connecting to Geyser...");
 
-            let mut client = self.tokio_rt.block_on(
-                GeyserGrpcClient::build_from_shared(self.endpoint.clone())?
-                    .x_token(Some(self.x_token.clone()))?
-                    .tls_config(self.tls_config.clone())?
-                    .connect(),
-            )?;
-
-            let (_, mut stream) = self
-                .tokio_rt
-                .block_on(client.subscribe_with_request(Some(subscribe_req.clone())))?;
-
-            while let Some(msg) = self.tokio_rt.block_on(stream.next()) {
-                match msg {
-                    Ok(event) => {
-                        if let Err(e) = handle_event(
-                            &marginfi_program_id_bytes,
-                            &oracle_addresses_bytes,
-                            &self.cache.get_clock()?,
-                            &self.geyser_tx,
-                            &event,
-                        ) {
-                            error!("Error handling Geyser update {:?}: {}", event, e);
-                        }
-                    }
-                    Err(e) => {
-                        error!("Received error from Geyser: {}", e);
-                        break;
-                    }
-                }
+            match self.stream_updates(&marginfi_program_id_bytes, &extra_addresses_bytes) {
+                Ok(streamed) if streamed >= MIN_HEALTHY_STREAM_DURATION => backoff.reset(),
+                Ok(_) => {}
+                Err(e) => error!("Geyser subscription failed: {}", e),
+            }
 
-                // Breaking the loop on stop request
-                if self.stop.load(Ordering::Relaxed) {
-                    break;
-                }
+            if self.stop.load(Ordering::Relaxed) {
+                break;
             }
+
+            let delay = backoff.next_delay();
+            info!("Reconnecting to Geyser in {:?}...", delay);
+            sleep_unless_stopped(&self.stop, delay);
         }
         info!("The GeyserService loop is stopped.");
 
@@ -163,9 +381,227 @@ impl GeyserSubscriber {
     }
 }
 
+/// Coalesces the resubscription requests arriving within the delay after the first one, e.g. the
+/// Banks of a new group, into a single resubscription.
+pub(super) struct ResubscribeDebounce {
+    delay: Duration,
+    pending_since: Option<Instant>,
+}
+
+impl ResubscribeDebounce {
+    pub(super) fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            pending_since: None,
+        }
+    }
+
+    pub(super) fn request(&mut self, now: Instant) {
+        self.pending_since.get_or_insert(now);
+    }
+
+    /// True once the delay passed since the first pending request, which is then cleared.
+    pub(super) fn is_due(&mut self, now: Instant) -> bool {
+        match self.pending_since {
+            Some(since) if now.duration_since(since) >= self.delay => {
+                self.pending_since = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Detects the stream that is alive but no longer advances the cached clock.
+pub(super) struct SlotWatchdog {
+    max_staleness: Duration,
+    last_slot: u64,
+    last_advanced_at: Instant,
+}
+
+impl SlotWatchdog {
+    pub(super) fn new(max_staleness: Duration, slot: u64, now: Instant) -> Self {
+        Self {
+            max_staleness,
+            last_slot: slot,
+            last_advanced_at: now,
+        }
+    }
+
+    pub(super) fn is_stalled(&mut self, slot: u64, now: Instant) -> bool {
+        if slot > self.last_slot {
+            self.last_slot = slot;
+            self.last_advanced_at = now;
+            return false;
+        }
+        now.duration_since(self.last_advanced_at) > self.max_staleness
+    }
+
+    /// Restarts the staleness period without the slot advancing, e.g. while the whole network is slow.
+    pub(super) fn snooze(&mut self, now: Instant) {
+        self.last_advanced_at = now;
+    }
+}
+
+/// Why the subscription slot stopped advancing.
+#[derive(Debug, PartialEq)]
+pub(super) enum StallCause {
+    /// The network moved on, the subscription is this many slots behind it.
+    SubscriptionBehind(u64),
+    /// The network is at about the same slot, it is slow as a whole.
+    NetworkSlow,
+}
+
+/// The current slot of the network from a dedicated RPC client, which is only used for the rare
+/// stall checks.
+pub(super) fn network_slot(config: &Config) -> NetworkSlot {
+    let rpc_client = RpcClient::new_with_timeout_and_commitment(
+        config.rpc_url.clone(),
+        NETWORK_SLOT_TIMEOUT,
+        CommitmentConfig {
+            commitment: config.commitment,
+        },
+    );
+    Box::new(move || Ok(rpc_client.get_slot()?))
+}
+
+/// Compares the stalled slot of the subscription against the current slot of the network.
+pub(super) fn diagnose_stall(
+    network_slot: &dyn Fn() -> Result<u64>,
+    slot: u64,
+) -> Result<StallCause> {
+    let behind = network_slot()?.saturating_sub(slot);
+    if behind > SUBSCRIPTION_LAG_TOLERANCE_SLOTS {
+        Ok(StallCause::SubscriptionBehind(behind))
+    } else {
+        Ok(StallCause::NetworkSlow)
+    }
+}
+
+/// Returns whether the stalled subscription is to be reconnected. A reconnect does not help when
+/// the whole network is slow, the subscription is kept then.
+pub(super) fn reconnect_on_stall(
+    network_slot: &dyn Fn() -> Result<u64>,
+    source: &str,
+    slot: u64,
+    max_staleness: Duration,
+) -> bool {
+    match diagnose_stall(network_slot, slot) {
+        Ok(StallCause::SubscriptionBehind(behind)) => {
+            error!(
+                "The {} slot has not advanced for over {:?} and is {} slots behind the network, forcing a reconnect.",
+                source, max_staleness, behind
+            );
+            true
+        }
+        Ok(StallCause::NetworkSlow) => {
+            warn!(
+                "The {} slot has not advanced for over {:?}, neither has the network, keeping the subscription.",
+                source, max_staleness
+            );
+            false
+        }
+        Err(err) => {
+            error!(
+                "The {} slot has not advanced for over {:?}, forcing a reconnect. Failed to get the network slot: {}",
+                source, max_staleness, err
+            );
+            true
+        }
+    }
+}
+
+/// Tracks the pings sent on the subscription stream, the connection is dead once a ping is left
+/// without the pong for longer than the timeout.
+pub(super) struct PingMonitor {
+    interval: Duration,
+    timeout: Duration,
+    last_ping_at: Instant,
+    next_id: i32,
+    pending: Option<(i32, Instant)>,
+}
+
+impl PingMonitor {
+    pub(super) fn new(interval: Duration, timeout: Duration, now: Instant) -> Self {
+        Self {
+            interval,
+            timeout,
+            last_ping_at: now,
+            next_id: 1,
+            pending: None,
+        }
+    }
+
+    /// Returns the id of the ping to send once the interval passed and the previous one is answered.
+    pub(super) fn next_ping(&mut self, now: Instant) -> Option<i32> {
+        if self.pending.is_some() || now.duration_since(self.last_ping_at) < self.interval {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.last_ping_at = now;
+        self.pending = Some((id, now));
+        Some(id)
+    }
+
+    pub(super) fn pong(&mut self, id: i32) {
+        if self.pending.is_some_and(|(pending_id, _)| pending_id == id) {
+            self.pending = None;
+        }
+    }
+
+    pub(super) fn is_dead(&self, now: Instant) -> bool {
+        self.pending
+            .is_some_and(|(_, sent_at)| now.duration_since(sent_at) > self.timeout)
+    }
+}
+
+/// Exponential backoff with jitter for the subscription reconnects.
+pub(super) struct ReconnectBackoff {
+    base: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl ReconnectBackoff {
+    pub(super) fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            current: base,
+        }
+    }
+
+    pub(super) fn reset(&mut self) {
+        self.current = self.base;
+    }
+
+    pub(super) fn next_delay(&mut self) -> Duration {
+        let delay = (self.current + jitter(self.current / 2)).min(self.max);
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+}
+
+fn jitter(max: Duration) -> Duration {
+    let max_nanos = max.as_nanos() as u64;
+    if max_nanos == 0 {
+        return Duration::ZERO;
+    }
+
+    let seed = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |now| now.subsec_nanos() as u64);
+    Duration::from_nanos(seed % max_nanos)
+}
+
 fn build_geyser_subscribe_request(
     marginfi_program_id: &Pubkey,
     oracle_addresses: &[Pubkey],
+    mint_addresses: &[Pubkey],
+    vault_addresses: &[Pubkey],
+    extra_accounts: &[Pubkey],
+    commitment: CommitmentLevel,
 ) -> Result<SubscribeRequest> {
     let mut account_filters: HashMap<String, SubscribeRequestFilterAccounts> = HashMap::new();
 
@@ -175,11 +611,33 @@ fn build_geyser_subscribe_request(
     };
     account_filters.insert("SolanaClock".to_string(), clock_filter);
 
-    let marginfi_program_filter = SubscribeRequestFilterAccounts {
-        owner: vec![marginfi_program_id.to_string()],
+    // The filters of a single entry must all match, so each processed account type gets its own entry
+    for (name, discriminator) in [
+        ("MarginfiAccounts", MARGINFI_ACCOUNT_DISCRIMINATOR),
+        ("MarginfiBanks", MARGINFI_BANK_DISCRIMINATOR),
+    ] {
+        let marginfi_filter = SubscribeRequestFilterAccounts {
+            owner: vec![marginfi_program_id.to_string()],
+            filters: vec![discriminator_filter(&discriminator)],
+            ..Default::default()
+        };
+        account_filters.insert(name.to_string(), marginfi_filter);
+    }
+
+    // The closed account is handed over to the System program with no lamports and no data, so its
+    // close does not match the Marginfi filters above. The closed cached accounts are picked out later.
+    let closed_filter = SubscribeRequestFilterAccounts {
+        owner: vec![system_program::id().to_string()],
+        filters: vec![SubscribeRequestFilterAccountsFilter {
+            filter: Some(Filter::Lamports(
+                SubscribeRequestFilterAccountsFilterLamports {
+                    cmp: Some(Cmp::Eq(0)),
+                },
+            )),
+        }],
         ..Default::default()
     };
-    account_filters.insert("MarginfiProgram".to_string(), marginfi_program_filter);
+    account_filters.insert("ClosedAccounts".to_string(), closed_filter);
 
     let oracles = oracle_addresses
         .iter()
@@ -191,25 +649,124 @@ fn build_geyser_subscribe_request(
     };
     account_filters.insert("Oracles".to_string(), oracle_filter);
 
+    let mint_filter = SubscribeRequestFilterAccounts {
+        account: mint_addresses.iter().map(|pk| pk.to_string()).collect(),
+        ..Default::default()
+    };
+    account_filters.insert("Mints".to_string(), mint_filter);
+
+    let vault_filter = SubscribeRequestFilterAccounts {
+        account: vault_addresses.iter().map(|pk| pk.to_string()).collect(),
+        ..Default::default()
+    };
+    account_filters.insert("Vaults".to_string(), vault_filter);
+
+    if !extra_accounts.is_empty() {
+        let extra_filter = SubscribeRequestFilterAccounts {
+            account: extra_accounts.iter().map(|pk| pk.to_string()).collect(),
+            ..Default::default()
+        };
+        account_filters.insert("ExtraAccounts".to_string(), extra_filter);
+    }
+
+    let commitment = match commitment {
+        CommitmentLevel::Processed => GeyserCommitmentLevel::Processed,
+        CommitmentLevel::Confirmed => GeyserCommitmentLevel::Confirmed,
+        CommitmentLevel::Finalized => GeyserCommitmentLevel::Finalized,
+    };
+
     Ok(SubscribeRequest {
         accounts: account_filters,
+        commitment: Some(commitment as i32),
         ..Default::default()
     })
 }
 
+/// Matches the accounts that start with the discriminator.
+fn discriminator_filter(discriminator: &[u8]) -> SubscribeRequestFilterAccountsFilter {
+    SubscribeRequestFilterAccountsFilter {
+        filter: Some(Filter::Memcmp(SubscribeRequestFilterAccountsFilterMemcmp {
+            offset: 0,
+            data: Some(Data::Bytes(discriminator.to_vec())),
+        })),
+    }
+}
+
+/// Forwards the updates of the extra accounts the operator watches, on top of their regular handling.
+fn handle_watched_event(
+    extra_addresses_bytes: &HashSet<[u8; 32]>,
+    geyser_tx: &GeyserSender,
+    event: &SubscribeUpdate,
+) -> Result<()> {
+    if let Some(subscribe_update::UpdateOneof::Account(subscribe_account)) = &event.update_oneof {
+        if let Some(account) = &subscribe_account.account {
+            if extra_addresses_bytes.contains(account.pubkey.as_slice()) {
+                let msg = GeyserMessage::new(
+                    MessageType::Watched,
+                    subscribe_account.slot,
+                    account.clone(),
+                )?;
+                geyser_tx.send(msg)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The messages of the update of an account subscribed to by its address.
+pub(super) fn watched_message_types(
+    is_oracle: bool,
+    is_mint: bool,
+    is_vault: bool,
+) -> Vec<MessageType> {
+    let mut message_types = Vec::new();
+    if is_oracle {
+        message_types.push(MessageType::Oracle);
+    }
+    // The LST mint of the staked collateral Bank is an oracle account as well
+    if is_mint {
+        message_types.push(MessageType::Mint);
+    }
+    if is_vault {
+        message_types.push(MessageType::Vault);
+    }
+    message_types
+}
+
+/// The closed account keeps neither lamports nor data, whichever program owns it afterwards.
+fn is_closed_cached_account(cache: &Cache, account: &SubscribeUpdateAccountInfo) -> Result<bool> {
+    if account.lamports != 0 && !account.data.is_empty() {
+        return Ok(false);
+    }
+    match Pubkey::try_from(account.pubkey.as_slice()) {
+        Ok(address) => cache.marginfi_accounts.contains(&address),
+        Err(_) => Ok(false),
+    }
+}
+
 fn handle_event(
     marginfi_program_id_bytes: &[u8; 32],
     oracle_addresses_bytes: &HashSet<[u8; 32]>,
-    clock: &Clock,
-    geyser_tx: &Sender<GeyserMessage>,
+    mint_addresses_bytes: &HashSet<[u8; 32]>,
+    vault_addresses_bytes: &HashSet<[u8; 32]>,
+    cache: &Cache,
+    geyser_tx: &GeyserSender,
     event: &SubscribeUpdate,
 ) -> Result<()> {
     match &event.update_oneof {
         Some(subscribe_update::UpdateOneof::Account(subscribe_account))
-            if subscribe_account.slot >= clock.slot =>
+            if subscribe_account.slot >= cache.get_clock()?.slot =>
         {
             if let Some(account) = &subscribe_account.account {
-                if account.owner == marginfi_program_id_bytes {
+                if is_closed_cached_account(cache, account)? {
+                    trace!("Handling closed Marginfi account update: {:?}", event);
+                    let msg = GeyserMessage::new(
+                        MessageType::Closed,
+                        subscribe_account.slot,
+                        account.clone(),
+                    )?;
+                    geyser_tx.send(msg)?;
+                } else if account.owner == marginfi_program_id_bytes {
                     trace!("Handling Marginfi update: {:?}", event);
                     if let Some(message_type) = get_marginfi_message_type(&account.data) {
                         let msg = GeyserMessage::new(
@@ -227,16 +784,24 @@ fn handle_event(
                         account.clone(),
                     )?;
                     geyser_tx.send(msg)?;
-                } else if oracle_addresses_bytes.contains(account.pubkey.as_slice()) {
-                    trace!("Handling Oracle update: {:?}", event);
-                    let msg = GeyserMessage::new(
-                        MessageType::Oracle,
-                        subscribe_account.slot,
-                        account.clone(),
-                    )?;
-                    geyser_tx.send(msg)?;
                 } else {
-                    trace!("Ignoring update for unrecognized account: {:?}", event);
+                    let message_types = watched_message_types(
+                        oracle_addresses_bytes.contains(account.pubkey.as_slice()),
+                        mint_addresses_bytes.contains(account.pubkey.as_slice()),
+                        vault_addresses_bytes.contains(account.pubkey.as_slice()),
+                    );
+                    if message_types.is_empty() {
+                        trace!("Ignoring update for unrecognized account: {:?}", event);
+                    }
+                    for message_type in message_types {
+                        trace!("Handling {:?} update: {:?}", message_type, event);
+                        let msg = GeyserMessage::new(
+                            message_type,
+                            subscribe_account.slot,
+                            account.clone(),
+                        )?;
+                        geyser_tx.send(msg)?;
+                    }
                 }
             }
         }
@@ -254,8 +819,10 @@ mod tests {
     use yellowstone_grpc_proto::geyser::SubscribeUpdateAccount;
 
     use crate::{
-        cache::test_util::generate_test_clock,
+        cache::marginfi_accounts::test_util::create_marginfi_account,
+        cache::test_util::{create_dummy_cache, generate_test_clock},
         common::{MARGINFI_ACCOUNT_DISCRIMINATOR, MARGINFI_ACCOUNT_DISCRIMINATOR_LEN},
+        config::test_util::create_dummy_config,
     };
 
     use super::*;
@@ -278,7 +845,8 @@ mod tests {
     #[test]
     fn test_handle_event_clock_update() {
         let (tx, rx) = channel::unbounded();
-        let clock = generate_test_clock(1);
+        let tx = GeyserSender::new(tx.clone(), tx);
+        let cache = create_dummy_cache();
 
         let account_info = make_account_info(sysvar::clock::id(), vec![]);
 
@@ -296,7 +864,9 @@ mod tests {
         let result = handle_event(
             &MARGINFI_PROGRAM_ID_BYTES,
             &HashSet::new(),
-            &clock,
+            &HashSet::new(),
+            &HashSet::new(),
+            &cache,
             &tx,
             &event,
         );
@@ -313,7 +883,8 @@ mod tests {
     #[test]
     fn test_handle_event_non_clock_account() {
         let (tx, rx) = channel::unbounded();
-        let clock = generate_test_clock(1);
+        let tx = GeyserSender::new(tx.clone(), tx);
+        let cache = create_dummy_cache();
 
         let random_pubkey = Pubkey::new_unique();
         let account_info = make_account_info(random_pubkey, vec![]);
@@ -331,7 +902,9 @@ mod tests {
         let result = handle_event(
             &MARGINFI_PROGRAM_ID_BYTES,
             &HashSet::new(),
-            &clock,
+            &HashSet::new(),
+            &HashSet::new(),
+            &cache,
             &tx,
             &event,
         );
@@ -344,7 +917,9 @@ mod tests {
     #[test]
     fn test_handle_event_slot_too_low() {
         let (tx, rx) = channel::unbounded();
-        let clock = generate_test_clock(2);
+        let tx = GeyserSender::new(tx.clone(), tx);
+        let cache = create_dummy_cache();
+        cache.update_clock(generate_test_clock(2)).unwrap();
 
         let account_info = make_account_info(sysvar::clock::id(), vec![]);
 
@@ -362,7 +937,9 @@ mod tests {
         let result = handle_event(
             &MARGINFI_PROGRAM_ID_BYTES,
             &HashSet::new(),
-            &clock,
+            &HashSet::new(),
+            &HashSet::new(),
+            &cache,
             &tx,
             &event,
         );
@@ -374,8 +951,9 @@ mod tests {
 
     #[test]
     fn test_handle_event_no_account() {
-        let clock = generate_test_clock(1);
+        let cache = create_dummy_cache();
         let (tx, rx) = channel::unbounded();
+        let tx = GeyserSender::new(tx.clone(), tx);
         let subscribe_account = SubscribeUpdateAccount {
             slot: 10,
             account: None,
@@ -390,7 +968,9 @@ mod tests {
         let result = handle_event(
             &MARGINFI_PROGRAM_ID_BYTES,
             &HashSet::new(),
-            &clock,
+            &HashSet::new(),
+            &HashSet::new(),
+            &cache,
             &tx,
             &event,
         );
@@ -403,7 +983,8 @@ mod tests {
     #[test]
     fn test_handle_event_other_update_type() {
         let (tx, rx) = channel::unbounded();
-        let clock = generate_test_clock(1);
+        let tx = GeyserSender::new(tx.clone(), tx);
+        let cache = create_dummy_cache();
         let event = SubscribeUpdate {
             update_oneof: None,
             ..Default::default()
@@ -412,7 +993,9 @@ mod tests {
         let result = handle_event(
             &MARGINFI_PROGRAM_ID_BYTES,
             &HashSet::new(),
-            &clock,
+            &HashSet::new(),
+            &HashSet::new(),
+            &cache,
             &tx,
             &event,
         );
@@ -425,7 +1008,8 @@ mod tests {
     #[test]
     fn test_handle_event_marginfi_account_update() {
         let (tx, rx) = channel::unbounded();
-        let clock = generate_test_clock(1);
+        let tx = GeyserSender::new(tx.clone(), tx);
+        let cache = create_dummy_cache();
 
         // Use a pubkey that matches the marginfi_program_id
         let marginfi_pubkey = Pubkey::new_from_array(MARGINFI_PROGRAM_ID_BYTES);
@@ -452,7 +1036,9 @@ mod tests {
         let result = handle_event(
             &MARGINFI_PROGRAM_ID_BYTES,
             &HashSet::new(),
-            &clock,
+            &HashSet::new(),
+            &HashSet::new(),
+            &cache,
             &tx,
             &event,
         );
@@ -466,10 +1052,71 @@ mod tests {
         assert_eq!(msg.account.lamports, 42);
     }
 
+    #[test]
+    fn test_handle_event_closed_marginfi_account() {
+        let (tx, rx) = channel::unbounded();
+        let tx = GeyserSender::new(tx.clone(), tx);
+        let cache = create_dummy_cache();
+
+        let address = Pubkey::new_unique();
+        cache
+            .update_marginfi_account(
+                1,
+                address,
+                &create_marginfi_account(Pubkey::new_unique(), vec![]),
+            )
+            .unwrap();
+        // The closed account is handed over to the System program with no lamports and no data
+        let closed_event = |address: Pubkey| {
+            let mut account_info = make_account_info(address, vec![]);
+            account_info.owner = system_program::id().to_bytes().to_vec();
+            account_info.lamports = 0;
+            SubscribeUpdate {
+                update_oneof: Some(subscribe_update::UpdateOneof::Account(
+                    SubscribeUpdateAccount {
+                        slot: 10,
+                        account: Some(account_info),
+                        is_startup: false,
+                    },
+                )),
+                ..Default::default()
+            }
+        };
+        handle_event(
+            &MARGINFI_PROGRAM_ID_BYTES,
+            &HashSet::new(),
+            &HashSet::new(),
+            &HashSet::new(),
+            &cache,
+            &tx,
+            &closed_event(address),
+        )
+        .unwrap();
+
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(msg.message_type, MessageType::Closed);
+        assert_eq!(msg.address, address);
+        assert_eq!(msg.slot, 10);
+
+        // The closes of the accounts that are not cached are not forwarded
+        handle_event(
+            &MARGINFI_PROGRAM_ID_BYTES,
+            &HashSet::new(),
+            &HashSet::new(),
+            &HashSet::new(),
+            &cache,
+            &tx,
+            &closed_event(Pubkey::new_unique()),
+        )
+        .unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
     #[test]
     fn test_handle_event_oracle_account_update() {
         let (tx, rx) = channel::unbounded();
-        let clock = generate_test_clock(1);
+        let tx = GeyserSender::new(tx.clone(), tx);
+        let cache = create_dummy_cache();
 
         let oracle_pubkey = Pubkey::new_unique();
         let mut account_info = make_account_info(oracle_pubkey, vec![]);
@@ -490,7 +1137,15 @@ mod tests {
             ..Default::default()
         };
 
-        let result = handle_event(&MARGINFI_PROGRAM_ID_BYTES, &oracle_set, &clock, &tx, &event);
+        let result = handle_event(
+            &MARGINFI_PROGRAM_ID_BYTES,
+            &oracle_set,
+            &HashSet::new(),
+            &HashSet::new(),
+            &cache,
+            &tx,
+            &event,
+        );
         assert!(result.is_ok());
 
         // Should have sent a message
@@ -500,10 +1155,97 @@ mod tests {
         assert_eq!(msg.address, oracle_pubkey);
     }
 
+    #[test]
+    fn test_handle_event_mint_account_update() {
+        let (tx, rx) = channel::unbounded();
+        let tx = GeyserSender::new(tx.clone(), tx);
+        let cache = create_dummy_cache();
+
+        let mint_pubkey = Pubkey::new_unique();
+        let mut account_info = make_account_info(mint_pubkey, vec![0; 82]);
+        account_info.owner = Pubkey::new_unique().to_bytes().to_vec();
+        let event = SubscribeUpdate {
+            update_oneof: Some(subscribe_update::UpdateOneof::Account(
+                SubscribeUpdateAccount {
+                    slot: 10,
+                    account: Some(account_info),
+                    is_startup: false,
+                },
+            )),
+            ..Default::default()
+        };
+
+        let mint_set = HashSet::from([mint_pubkey.to_bytes()]);
+        handle_event(
+            &MARGINFI_PROGRAM_ID_BYTES,
+            &HashSet::new(),
+            &mint_set,
+            &HashSet::new(),
+            &cache,
+            &tx,
+            &event,
+        )
+        .unwrap();
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(msg.message_type, MessageType::Mint);
+        assert_eq!(msg.address, mint_pubkey);
+        assert!(rx.try_recv().is_err());
+
+        // The LST mint is both an oracle account and a Bank mint
+        handle_event(
+            &MARGINFI_PROGRAM_ID_BYTES,
+            &mint_set,
+            &mint_set,
+            &HashSet::new(),
+            &cache,
+            &tx,
+            &event,
+        )
+        .unwrap();
+        assert_eq!(rx.try_recv().unwrap().message_type, MessageType::Oracle);
+        assert_eq!(rx.try_recv().unwrap().message_type, MessageType::Mint);
+    }
+
+    #[test]
+    fn test_handle_event_vault_account_update() {
+        let (tx, rx) = channel::unbounded();
+        let tx = GeyserSender::new(tx.clone(), tx);
+        let cache = create_dummy_cache();
+
+        let vault_pubkey = Pubkey::new_unique();
+        let event = SubscribeUpdate {
+            update_oneof: Some(subscribe_update::UpdateOneof::Account(
+                SubscribeUpdateAccount {
+                    slot: 10,
+                    account: Some(make_account_info(vault_pubkey, vec![0; 165])),
+                    is_startup: false,
+                },
+            )),
+            ..Default::default()
+        };
+
+        handle_event(
+            &MARGINFI_PROGRAM_ID_BYTES,
+            &HashSet::new(),
+            &HashSet::new(),
+            &HashSet::from([vault_pubkey.to_bytes()]),
+            &cache,
+            &tx,
+            &event,
+        )
+        .unwrap();
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(msg.message_type, MessageType::Vault);
+        assert_eq!(msg.address, vault_pubkey);
+        assert_eq!(msg.slot, 10);
+        assert!(rx.try_recv().is_err());
+    }
+
     #[test]
     fn test_handle_event_ignores_unrecognized_account() {
         let (tx, rx) = channel::unbounded();
-        let clock = generate_test_clock(1);
+        let tx = GeyserSender::new(tx.clone(), tx);
+        let cache = create_dummy_cache();
 
         let random_pubkey = Pubkey::new_unique();
         let mut account_info = make_account_info(random_pubkey, vec![]);
@@ -525,7 +1267,9 @@ mod tests {
         let result = handle_event(
             &MARGINFI_PROGRAM_ID_BYTES,
             &HashSet::new(),
-            &clock,
+            &HashSet::new(),
+            &HashSet::new(),
+            &cache,
             &tx,
             &event,
         );
@@ -534,4 +1278,330 @@ mod tests {
         // Should NOT have sent a message
         assert!(rx.try_recv().is_err());
     }
+
+    #[test]
+    fn test_build_geyser_subscribe_request_commitment() {
+        let marginfi_program_id = Pubkey::new_unique();
+        let oracle = Pubkey::new_unique();
+        for (commitment, expected) in [
+            (CommitmentLevel::Processed, GeyserCommitmentLevel::Processed),
+            (CommitmentLevel::Confirmed, GeyserCommitmentLevel::Confirmed),
+            (CommitmentLevel::Finalized, GeyserCommitmentLevel::Finalized),
+        ] {
+            let request = build_geyser_subscribe_request(
+                &marginfi_program_id,
+                &[oracle],
+                &[],
+                &[],
+                &[],
+                commitment,
+            )
+            .unwrap();
+            assert_eq!(request.commitment, Some(expected as i32));
+            assert!(request.accounts["Mints"].account.is_empty());
+            assert_eq!(
+                request.accounts["Oracles"].account,
+                vec![oracle.to_string()]
+            );
+        }
+    }
+
+    #[test]
+    fn test_build_geyser_subscribe_request_discriminator_filters() {
+        let marginfi_program_id = Pubkey::new_unique();
+        let request = build_geyser_subscribe_request(
+            &marginfi_program_id,
+            &[],
+            &[],
+            &[],
+            &[],
+            CommitmentLevel::Confirmed,
+        )
+        .unwrap();
+
+        for (name, discriminator) in [
+            ("MarginfiAccounts", MARGINFI_ACCOUNT_DISCRIMINATOR),
+            ("MarginfiBanks", MARGINFI_BANK_DISCRIMINATOR),
+        ] {
+            let filter = &request.accounts[name];
+            assert_eq!(filter.owner, vec![marginfi_program_id.to_string()]);
+            assert_eq!(filter.filters, vec![discriminator_filter(&discriminator)]);
+            assert_eq!(
+                filter.filters[0].filter,
+                Some(Filter::Memcmp(SubscribeRequestFilterAccountsFilterMemcmp {
+                    offset: 0,
+                    data: Some(Data::Bytes(discriminator.to_vec())),
+                }))
+            );
+        }
+        assert!(!request.accounts.contains_key("MarginfiProgram"));
+        assert!(!request.accounts.contains_key("ExtraAccounts"));
+
+        let closed_filter = &request.accounts["ClosedAccounts"];
+        assert_eq!(closed_filter.owner, vec![system_program::id().to_string()]);
+        assert_eq!(
+            closed_filter.filters[0].filter,
+            Some(Filter::Lamports(
+                SubscribeRequestFilterAccountsFilterLamports {
+                    cmp: Some(Cmp::Eq(0)),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_build_geyser_subscribe_request_vaults() {
+        let vault = Pubkey::new_unique();
+        let request = build_geyser_subscribe_request(
+            &Pubkey::new_unique(),
+            &[],
+            &[],
+            &[vault],
+            &[],
+            CommitmentLevel::Confirmed,
+        )
+        .unwrap();
+
+        assert_eq!(request.accounts["Vaults"].account, vec![vault.to_string()]);
+    }
+
+    #[test]
+    fn test_build_geyser_subscribe_request_extra_accounts() {
+        let group = Pubkey::new_unique();
+        let insurance_vault = Pubkey::new_unique();
+        let request = build_geyser_subscribe_request(
+            &Pubkey::new_unique(),
+            &[],
+            &[],
+            &[],
+            &[group, insurance_vault],
+            CommitmentLevel::Confirmed,
+        )
+        .unwrap();
+
+        assert_eq!(
+            request.accounts["ExtraAccounts"].account,
+            vec![group.to_string(), insurance_vault.to_string()]
+        );
+    }
+
+    #[test]
+    fn test_handle_watched_event() {
+        let (tx, rx) = channel::unbounded();
+        let tx = GeyserSender::new(tx.clone(), tx);
+        let watched = Pubkey::new_unique();
+        let extra_addresses_bytes = HashSet::from([watched.to_bytes()]);
+
+        for (address, expected) in [(watched, true), (Pubkey::new_unique(), false)] {
+            let event = SubscribeUpdate {
+                update_oneof: Some(subscribe_update::UpdateOneof::Account(
+                    SubscribeUpdateAccount {
+                        slot: 10,
+                        account: Some(make_account_info(address, vec![1, 2])),
+                        is_startup: false,
+                    },
+                )),
+                ..Default::default()
+            };
+            handle_watched_event(&extra_addresses_bytes, &tx, &event).unwrap();
+            match rx.try_recv() {
+                Ok(msg) => {
+                    assert!(expected);
+                    assert_eq!(msg.message_type, MessageType::Watched);
+                    assert_eq!(msg.address, watched);
+                }
+                Err(_) => assert!(!expected),
+            }
+        }
+    }
+
+    #[test]
+    fn test_reconnect_backoff_grows_and_caps() {
+        let base = Duration::from_millis(500);
+        let max = Duration::from_secs(30);
+        let mut backoff = ReconnectBackoff::new(base, max);
+
+        let first = backoff.next_delay();
+        assert!(first >= base && first < base + base / 2);
+
+        let second = backoff.next_delay();
+        assert!(second >= base * 2 && second < base * 3);
+
+        for _ in 0..20 {
+            assert!(backoff.next_delay() <= max);
+        }
+        assert_eq!(backoff.next_delay(), max);
+    }
+
+    #[test]
+    fn test_resubscribe_debounce_coalesces_requests() {
+        let mut debounce = ResubscribeDebounce::new(Duration::from_secs(2));
+        let start = Instant::now();
+        assert!(!debounce.is_due(start));
+
+        debounce.request(start);
+        debounce.request(start + Duration::from_secs(1));
+        assert!(!debounce.is_due(start + Duration::from_secs(1)));
+        assert!(debounce.is_due(start + Duration::from_secs(2)));
+        // Cleared once due
+        assert!(!debounce.is_due(start + Duration::from_secs(3)));
+
+        debounce.request(start + Duration::from_secs(3));
+        assert!(!debounce.is_due(start + Duration::from_secs(4)));
+        assert!(debounce.is_due(start + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_reconnect_backoff_reset() {
+        let base = Duration::from_millis(500);
+        let mut backoff = ReconnectBackoff::new(base, Duration::from_secs(30));
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+
+        backoff.reset();
+        let delay = backoff.next_delay();
+        assert!(delay >= base && delay < base + base / 2);
+    }
+
+    #[test]
+    fn test_slot_watchdog_fires_on_frozen_clock() {
+        let max_staleness = Duration::from_secs(30);
+        let start = Instant::now();
+        let mut watchdog = SlotWatchdog::new(max_staleness, 100, start);
+
+        assert!(!watchdog.is_stalled(100, start + Duration::from_secs(10)));
+        assert!(!watchdog.is_stalled(100, start + max_staleness));
+        assert!(watchdog.is_stalled(100, start + Duration::from_secs(31)));
+    }
+
+    #[test]
+    fn test_ping_monitor_times_out_without_pong() {
+        let start = Instant::now();
+        let mut monitor = PingMonitor::new(Duration::from_secs(15), Duration::from_secs(10), start);
+
+        assert_eq!(monitor.next_ping(start + Duration::from_secs(5)), None);
+        let id = monitor.next_ping(start + Duration::from_secs(15)).unwrap();
+        // A single ping is in flight at a time
+        assert_eq!(monitor.next_ping(start + Duration::from_secs(30)), None);
+        assert!(!monitor.is_dead(start + Duration::from_secs(25)));
+        assert!(monitor.is_dead(start + Duration::from_secs(26)));
+
+        // A stale pong does not count
+        monitor.pong(id + 1);
+        assert!(monitor.is_dead(start + Duration::from_secs(26)));
+        monitor.pong(id);
+        assert!(!monitor.is_dead(start + Duration::from_secs(26)));
+        assert_eq!(
+            monitor.next_ping(start + Duration::from_secs(30)),
+            Some(id + 1)
+        );
+    }
+
+    #[test]
+    fn test_slot_watchdog_resets_on_advanced_slot() {
+        let max_staleness = Duration::from_secs(30);
+        let start = Instant::now();
+        let mut watchdog = SlotWatchdog::new(max_staleness, 100, start);
+
+        assert!(!watchdog.is_stalled(101, start + Duration::from_secs(25)));
+        assert!(!watchdog.is_stalled(101, start + Duration::from_secs(50)));
+        assert!(watchdog.is_stalled(101, start + Duration::from_secs(56)));
+
+        watchdog.snooze(start + Duration::from_secs(56));
+        assert!(!watchdog.is_stalled(101, start + Duration::from_secs(80)));
+    }
+
+    #[test]
+    fn test_diagnose_stall_compares_network_slot() {
+        let network_slot = || -> Result<u64> { Ok(1_000) };
+
+        assert_eq!(
+            diagnose_stall(&network_slot, 900).unwrap(),
+            StallCause::SubscriptionBehind(100)
+        );
+        assert_eq!(
+            diagnose_stall(&network_slot, 1_000 - SUBSCRIPTION_LAG_TOLERANCE_SLOTS).unwrap(),
+            StallCause::NetworkSlow
+        );
+        // The RPC lagging behind the subscription
+        assert_eq!(
+            diagnose_stall(&network_slot, 1_010).unwrap(),
+            StallCause::NetworkSlow
+        );
+
+        assert!(reconnect_on_stall(
+            &network_slot,
+            "Geyser",
+            900,
+            Duration::from_secs(30)
+        ));
+        assert!(!reconnect_on_stall(
+            &network_slot,
+            "Geyser",
+            1_000,
+            Duration::from_secs(30)
+        ));
+    }
+
+    #[test]
+    fn test_new_uses_configured_worker_threads() {
+        let mut config = create_dummy_config();
+        config.geyser_worker_threads = 3;
+        let (priority_tx, _priority_rx) = channel::unbounded();
+        let (normal_tx, _normal_rx) = channel::unbounded();
+        let (_resubscribe_tx, resubscribe_rx) = channel::unbounded();
+
+        let subscriber = GeyserSubscriber::new(
+            &config,
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(create_dummy_cache()),
+            GeyserSender::new(priority_tx, normal_tx),
+            resubscribe_rx,
+        )
+        .unwrap();
+        assert_eq!(subscriber.tokio_rt.metrics().num_workers(), 3);
+    }
+
+    #[test]
+    fn test_geyser_sender_routes_by_priority() {
+        let (priority_tx, priority_rx) = channel::unbounded();
+        let (normal_tx, normal_rx) = channel::unbounded();
+        let sender = GeyserSender::new(priority_tx, normal_tx);
+
+        for message_type in [
+            MessageType::Clock,
+            MessageType::Oracle,
+            MessageType::MarginfiAccount,
+            MessageType::Bank,
+        ] {
+            sender
+                .send(GeyserMessage {
+                    message_type,
+                    slot: 1,
+                    address: Pubkey::new_unique(),
+                    account: Account::default(),
+                })
+                .unwrap();
+        }
+
+        assert_eq!(
+            priority_rx.try_recv().unwrap().message_type,
+            MessageType::Clock
+        );
+        assert_eq!(
+            priority_rx.try_recv().unwrap().message_type,
+            MessageType::Oracle
+        );
+        assert!(priority_rx.try_recv().is_err());
+        assert_eq!(
+            normal_rx.try_recv().unwrap().message_type,
+            MessageType::MarginfiAccount
+        );
+        assert_eq!(
+            normal_rx.try_recv().unwrap().message_type,
+            MessageType::Bank
+        );
+        assert!(normal_rx.try_recv().is_err());
+    }
 }
@@ -1,22 +1,47 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::Result;
+use anchor_lang::AccountDeserialize;
+use anyhow::{anyhow, Result};
 
-use log::{error, info};
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender, TrySendError};
+use fixed::types::I80F48;
+use log::{debug, error, info, warn};
+use marginfi::state::marginfi_account::MarginfiAccount;
 use solana_sdk::pubkey::Pubkey;
 
+use super::{readiness::Readiness, sleep_unless_stopped, STOP_CHECK_INTERVAL};
 use crate::{
-    cache::Cache,
+    cache::{
+        marginfi_accounts::{health_to_bps, CachedMarginfiAccount, HEALTH_BPS_SCALE},
+        Cache,
+    },
     comms::CommsClient,
-    liquidation::{choose_liquidation_strategy, LiquidationStrategy},
+    config::Config,
+    error::MaryError,
+    liquidation::{
+        choose_liquidation_strategy, ExecutionMode, LiquidationOutcome, LiquidationParams,
+        LiquidationStatus, LiquidationStrategy,
+    },
+    metrics::{Counter, MetricsRegistry},
 };
 
+// The pause between the candidate batches, leaves the CPU to the GeyserProcessor during the long scans
+const SCAN_BATCH_PAUSE: Duration = Duration::from_millis(1);
+// The USD profit per USD of the shortfall the pre-filter assumes: the 2.5% liquidator fee of the
+// collateral seized to restore it, at the health gain of 5% per USD seized, which is on the low side
+const PREFILTER_PROFIT_PER_SHORTFALL: f64 = 0.5;
+// The pause before re-fetching the account after a failed liquidation, lets the competing
+// transactions land on the RPC node
+const REVERT_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
 pub struct LiquidationService<T>
 where
     T: CommsClient + 'static,
@@ -24,59 +49,1457 @@ where
     stop: Arc<AtomicBool>,
     cache: Arc<Cache>,
     comms_client: T,
+    execution_mode: ExecutionMode,
+    marginfi_program_id: Pubkey,
+    min_profit_usd: f64,
+    max_repay_fraction: f64,
+    liquidation_prefilter: bool,
+    simulate_before_send: bool,
+    liquidation_interval: Duration,
+    continuous_liquidation: bool,
+    // Accounts with the health in basis points at or below this value are considered for liquidation
+    health_threshold_bps: i64,
+    // The most unhealthy candidates processed per cycle, the rest wait for the next one
+    max_candidates: usize,
+    // The liquidations attempted per cycle at most, the rest of the candidates wait for the next one
+    max_liquidations_per_cycle: usize,
+    // The candidates processed between the stop checks of a cycle
+    scan_batch_size: usize,
+    // The liquidator account itself and the configured accounts that are never liquidated
+    excluded_accounts: HashSet<Pubkey>,
+    // Only the accounts of these groups are liquidated, all of them if empty
+    allowed_groups: HashSet<Pubkey>,
+    // The accounts of these authorities are never liquidated
+    excluded_authorities: HashSet<Pubkey>,
+    lazy_bank_loading: bool,
+    // The reverted liquidation is re-prepared and resent at most this many times within the deadline
+    liquidation_max_retries: u32,
+    liquidation_retry_deadline: Duration,
+    metrics: Arc<MetricsRegistry>,
+    cooldowns: LiquidationCooldowns,
+    // The accounts to re-evaluate ahead of the next cycle, sent by the GeyserProcessor on the price moves
+    liquidation_trigger: Receiver<Vec<Pubkey>>,
+    // Receives a copy of every liquidation outcome, e.g. for the metrics or the HTTP layer
+    outcome_sender: Option<Sender<LiquidationOutcome>>,
+    // The liquidations start once the cache is ready, right away without it
+    readiness: Option<Arc<Readiness>>,
 }
 
 impl<T: CommsClient> LiquidationService<T> {
-    pub fn new(stop: Arc<AtomicBool>, cache: Arc<Cache>, comms_client: T) -> Result<Self> {
+    pub fn new(
+        config: &Config,
+        stop: Arc<AtomicBool>,
+        cache: Arc<Cache>,
+        comms_client: T,
+        metrics: Arc<MetricsRegistry>,
+        liquidation_trigger: Receiver<Vec<Pubkey>>,
+    ) -> Result<Self> {
         Ok(Self {
             stop,
             cache,
             comms_client,
+            execution_mode: ExecutionMode::from_dry_run(config.dry_run),
+            marginfi_program_id: config.marginfi_program_id,
+            min_profit_usd: config.min_profit_usd,
+            max_repay_fraction: config.max_repay_fraction,
+            liquidation_prefilter: config.liquidation_prefilter,
+            simulate_before_send: config.simulate_before_send,
+            liquidation_interval: Duration::from_millis(config.liquidation_interval_ms),
+            continuous_liquidation: config.continuous_liquidation,
+            health_threshold_bps: (config.liquidation_health_threshold * HEALTH_BPS_SCALE as f64)
+                .round() as i64,
+            max_candidates: match config.liquidation_max_candidates {
+                0 => usize::MAX,
+                max_candidates => max_candidates,
+            },
+            max_liquidations_per_cycle: match config.max_liquidations_per_cycle {
+                0 => usize::MAX,
+                max_liquidations => max_liquidations,
+            },
+            scan_batch_size: config.liquidation_scan_batch_size,
+            excluded_accounts: config
+                .excluded_accounts
+                .iter()
+                .copied()
+                .chain([config.liquidator_account])
+                .collect(),
+            allowed_groups: config.allowed_groups.iter().copied().collect(),
+            excluded_authorities: config.excluded_authorities.iter().copied().collect(),
+            lazy_bank_loading: config.lazy_bank_loading,
+            liquidation_max_retries: config.liquidation_max_retries,
+            liquidation_retry_deadline: Duration::from_millis(config.liquidation_retry_deadline_ms),
+            metrics,
+            cooldowns: LiquidationCooldowns::new(Duration::from_secs(
+                config.liquidation_cooldown_sec,
+            )),
+            liquidation_trigger,
+            outcome_sender: None,
+            readiness: None,
         })
     }
 
+    /// Forwards the liquidation outcomes to the channel on top of logging them. The outcomes are
+    /// dropped rather than blocking the liquidations when the bounded channel is full.
+    pub fn with_outcome_sender(mut self, outcome_sender: Sender<LiquidationOutcome>) -> Self {
+        self.outcome_sender = Some(outcome_sender);
+        self
+    }
+
+    /// Holds the liquidations until the cache catches up with the chain after the startup.
+    pub fn with_readiness(mut self, readiness: Arc<Readiness>) -> Self {
+        self.readiness = Some(readiness);
+        self
+    }
+
     pub fn run(&self) -> anyhow::Result<()> {
-        info!("Entering the LiquidationService loop.");
+        self.comms_client
+            .set_luts(self.cache.luts.get_all().to_vec())?;
+
+        if let Some(readiness) = &self.readiness {
+            info!("Waiting for the Cache to be ready before liquidating...");
+            if !readiness.wait(&self.stop)? {
+                info!("The LiquidationService is stopped before the Cache is ready.");
+                return Ok(());
+            }
+        }
+
+        info!(
+            "Entering the LiquidationService loop in the {:?} mode.",
+            self.execution_mode
+        );
         while !self.stop.load(Ordering::Relaxed) {
-            info!("Starting the Liquidation cycle...");
-            match self.cache.marginfi_accounts.get_accounts_with_health() {
-                Ok(accounts_by_health) => {
-                    let sorted_accounts = sort_accounts_by_health(&accounts_by_health);
-                    for account_address in sorted_accounts {
-                        if let Err(err) = self.process_account(account_address) {
-                            error!(
-                                "Failed to process the Marginfi account {}: {}",
-                                account_address, err
-                            );
-                        }
-                    }
-                }
+            let candidates = match self.run_cycle() {
+                Ok(candidates) => candidates,
                 Err(e) => {
-                    error!("Failed to get the Marginfiaccounts with health map: {}", e);
-                    continue;
+                    error!("The Liquidation cycle failed: {}", e);
+                    0
                 }
             };
-            info!("Liquidation cycle is completed.");
-            // Temporary hack to avoid busy spin
-            std::thread::sleep(std::time::Duration::from_secs(5));
+
+            if !self.continuous_liquidation || candidates == 0 {
+                self.wait_for_next_cycle();
+            }
         }
 
         info!("The LiquidationService loop is stopped.");
         Ok(())
     }
 
-    fn process_account(&self, address: Pubkey) -> Result<()> {
+    /// Waits for the liquidation interval, re-evaluating the triggered accounts as they arrive.
+    fn wait_for_next_cycle(&self) {
+        let deadline = Instant::now() + self.liquidation_interval;
+        while !self.stop.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match self
+                .liquidation_trigger
+                .recv_timeout((deadline - now).min(STOP_CHECK_INTERVAL))
+            {
+                Ok(accounts) => {
+                    if let Err(err) = self.reevaluate_accounts(accounts) {
+                        error!("Failed to re-evaluate the triggered accounts: {}", err);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    sleep_unless_stopped(&self.stop, deadline - now);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Recomputes the health of the triggered accounts with the latest cached prices, since their
+    /// on-chain health cache lags the price moves, and processes the ones at or below the threshold.
+    /// Returns the number of the processed accounts.
+    fn reevaluate_accounts(&self, mut accounts: Vec<Pubkey>) -> Result<usize> {
+        // Coalesce the triggers queued behind this one
+        while let Ok(more_accounts) = self.liquidation_trigger.try_recv() {
+            accounts.extend(more_accounts);
+        }
+        accounts.sort();
+        accounts.dedup();
+        accounts.retain(|address| !self.excluded_accounts.contains(address));
+
+        let mut processed = 0;
+        for address in accounts {
+            if self.stop.load(Ordering::Relaxed) {
+                break;
+            }
+            let Ok(account) = self.cache.marginfi_accounts.get_account(&address) else {
+                continue;
+            };
+            if !self.is_allowed_group(account.group())
+                || self.excluded_authorities.contains(account.authority())
+            {
+                continue;
+            }
+            let health = match account.compute_health(&self.cache) {
+                Ok(health) => health_to_bps(health),
+                Err(err) => {
+                    debug!(
+                        "Failed to compute the health of the Marginfi account {}: {}",
+                        address, err
+                    );
+                    continue;
+                }
+            };
+            if health > self.health_threshold_bps {
+                continue;
+            }
+
+            processed += 1;
+            if let Err(err) = self.process_account(address, health) {
+                error!(
+                    "Failed to process the Marginfi account {}: {}",
+                    address, err
+                );
+            }
+        }
+        Ok(processed)
+    }
+
+    /// Returns the number of the liquidation candidates of the cycle. They are processed in
+    /// batches, the cycle stops between them once the stop is requested. The most unhealthy
+    /// candidates go first, the ones past the attempts cap wait for the next cycle.
+    fn run_cycle(&self) -> Result<usize> {
+        info!("Starting the Liquidation cycle...");
+        // The accounts also become liquidatable as the prices move without them being updated
+        let refreshed = self.cache.marginfi_accounts.refresh_health(&self.cache)?;
+        debug!("Refreshed the health of {} Marginfi accounts.", refreshed);
+        let candidates = self.liquidation_candidates()?;
+        let mut attempts = 0;
+        'batches: for (index, batch) in candidates.chunks(self.scan_batch_size).enumerate() {
+            if index > 0 {
+                thread::sleep(SCAN_BATCH_PAUSE);
+            }
+            if self.stop.load(Ordering::Relaxed) {
+                info!("The Liquidation cycle is interrupted by the stop.");
+                break;
+            }
+            for &(account_address, health) in batch {
+                if attempts >= self.max_liquidations_per_cycle {
+                    info!(
+                        "Attempted {} liquidations, leaving the rest of the candidates to the next cycle.",
+                        self.max_liquidations_per_cycle
+                    );
+                    break 'batches;
+                }
+                match self.process_account(account_address, health) {
+                    Ok(attempted) => attempts += usize::from(attempted),
+                    Err(err) => {
+                        // The failed sends count too, or they would run past the cap
+                        attempts += 1;
+                        error!(
+                            "Failed to process the Marginfi account {}: {}",
+                            account_address, err
+                        );
+                    }
+                }
+            }
+        }
+        info!("Liquidation cycle is completed.");
+        Ok(candidates.len())
+    }
+
+    /// The most unhealthy accounts at or below the threshold without the excluded ones, the ones of
+    /// the groups not allowed and the ones of the excluded authorities, which do not take up the
+    /// slots of the candidates.
+    fn liquidation_candidates(&self) -> Result<Vec<(Pubkey, i64)>> {
+        let filtered = !self.allowed_groups.is_empty() || !self.excluded_authorities.is_empty();
+        // How many of the candidates are in the other groups or of the excluded authorities is only
+        // known after the lookup
+        let limit = if filtered {
+            usize::MAX
+        } else {
+            self.max_candidates
+                .saturating_add(self.excluded_accounts.len())
+        };
+        let mut candidates = self
+            .cache
+            .marginfi_accounts
+            .liquidation_candidates(self.health_threshold_bps, limit)?;
+        candidates.retain(|(address, _)| !self.excluded_accounts.contains(address));
+        if filtered {
+            let mut allowed = Vec::with_capacity(candidates.len());
+            for (address, health) in candidates {
+                if self.is_allowed_account(&address)? {
+                    allowed.push((address, health));
+                }
+            }
+            candidates = allowed;
+        }
+        candidates.truncate(self.max_candidates);
+        Ok(candidates)
+    }
+
+    fn is_allowed_group(&self, group: &Pubkey) -> bool {
+        self.allowed_groups.is_empty() || self.allowed_groups.contains(group)
+    }
+
+    /// Whether the cached account is of an allowed group and not of an excluded authority.
+    fn is_allowed_account(&self, address: &Pubkey) -> Result<bool> {
+        let marginfi_accounts = &self.cache.marginfi_accounts;
+        if !self.allowed_groups.is_empty() {
+            match marginfi_accounts.get_group(address)? {
+                Some(group) if self.is_allowed_group(&group) => {}
+                _ => return Ok(false),
+            }
+        }
+        if !self.excluded_authorities.is_empty() {
+            match marginfi_accounts.get_authority(address)? {
+                Some(authority) if !self.excluded_authorities.contains(&authority) => {}
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns whether the liquidation of the account was prepared and attempted, the dry runs
+    /// included.
+    fn process_account(&self, address: Pubkey, health: i64) -> Result<bool> {
         let account = self.cache.marginfi_accounts.get_account(&address)?;
-        let liquidation_strategy = choose_liquidation_strategy(&account, &self.cache)?;
-        if let Some(lq_params) = liquidation_strategy.prepare(&account)? {
-            liquidation_strategy.liquidate(lq_params, &self.comms_client)?;
+        if self
+            .cooldowns
+            .is_cooling_down(&address, account.slot(), health, Instant::now())?
+        {
+            debug!(
+                "The Marginfi account {} was recently liquidated, skipping it.",
+                address
+            );
+            return Ok(false);
+        }
+        if !self.should_attempt(&account) {
+            debug!(
+                "The shortfall of the Marginfi account {} is too small to be profitable, skipping it.",
+                address
+            );
+            return Ok(false);
         }
+
+        if self.lazy_bank_loading {
+            for position in account._positions() {
+                self.cache
+                    .ensure_bank(&position.bank_pk, &self.comms_client)?;
+            }
+        }
+
+        let liquidation_strategy = choose_liquidation_strategy(
+            &account,
+            &self.cache,
+            &self.marginfi_program_id,
+            self.min_profit_usd,
+            self.max_repay_fraction,
+            self.simulate_before_send,
+            &self.comms_client,
+        )?;
+        let Some(mut lq_params) = liquidation_strategy.prepare(&account)? else {
+            return Ok(false);
+        };
+        if self.execution_mode == ExecutionMode::DryRun {
+            info!(
+                "Dry run, skipping the liquidation of the Marginfi account {}: {:?}",
+                address, lq_params
+            );
+            self.emit_outcome(LiquidationOutcome::new(
+                &lq_params,
+                None,
+                LiquidationStatus::Skipped,
+            ));
+            return Ok(true);
+        }
+
+        self.metrics.inc(Counter::LiquidationsAttempted);
+        self.cooldowns
+            .start(address, account.slot(), health, Instant::now())?;
+        let deadline = Instant::now() + self.liquidation_retry_deadline;
+        let mut retries = 0;
+        loop {
+            let outcome = LiquidationOutcome::new(&lq_params, None, LiquidationStatus::Skipped);
+            let legs = lq_params.legs.len();
+            let err = match liquidation_strategy.liquidate(lq_params, &self.comms_client) {
+                Ok(Some(landed)) => {
+                    self.metrics.inc(Counter::LiquidationsSucceeded);
+                    info!(
+                        "Liquidated {} of the {} legs of the Marginfi account {}, signature: {}",
+                        landed.count, legs, address, landed.signature
+                    );
+                    let mut outcome = LiquidationOutcome {
+                        signature: Some(landed.signature),
+                        status: if landed.count < legs {
+                            LiquidationStatus::Partial
+                        } else {
+                            LiquidationStatus::Landed
+                        },
+                        ..outcome
+                    };
+                    // Only the landed legs seized the collateral
+                    outcome.legs.truncate(landed.count);
+                    self.emit_outcome(outcome);
+                    return Ok(true);
+                }
+                Ok(None) => {
+                    self.emit_outcome(outcome);
+                    return Ok(true);
+                }
+                Err(err) => err,
+            };
+
+            let reverted = LiquidationOutcome {
+                status: LiquidationStatus::Reverted,
+                ..outcome.clone()
+            };
+            if !is_retryable(&err)
+                || retries >= self.liquidation_max_retries
+                || Instant::now() >= deadline
+            {
+                self.metrics.inc(Counter::LiquidationsFailed);
+                self.emit_outcome(reverted);
+                return Err(err);
+            }
+
+            sleep_unless_stopped(&self.stop, REVERT_RETRY_BACKOFF);
+            match self.classify_revert(address, &liquidation_strategy) {
+                Ok(RevertReason::Healthy) => {
+                    info!(
+                        "The liquidation of the Marginfi account {} failed, but the account is healthy now: {}",
+                        address, err
+                    );
+                    self.emit_outcome(outcome);
+                    return Ok(true);
+                }
+                Ok(RevertReason::LostRace) => {
+                    warn!(
+                        "Lost the liquidation of the Marginfi account {}, what is left is not worth liquidating: {}",
+                        address, err
+                    );
+                    self.metrics.inc(Counter::LiquidationsFailed);
+                    self.emit_outcome(reverted);
+                    return Ok(true);
+                }
+                Ok(RevertReason::Transient(params)) => {
+                    retries += 1;
+                    warn!(
+                        "The liquidation of the Marginfi account {} failed, retrying {}/{}: {}",
+                        address, retries, self.liquidation_max_retries, err
+                    );
+                    lq_params = params;
+                }
+                Err(classify_err) => {
+                    error!(
+                        "Failed to re-evaluate the Marginfi account {} after the failed liquidation: {}",
+                        address, classify_err
+                    );
+                    self.metrics.inc(Counter::LiquidationsFailed);
+                    self.emit_outcome(reverted);
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// The cheap pre-filter ahead of the prepare: whether the shortfall valued at the cached prices,
+    /// as the cached health is, could plausibly yield the min profit. The accounts that can not be
+    /// valued or have no shortfall are left for the prepare to judge.
+    fn should_attempt(&self, account: &CachedMarginfiAccount) -> bool {
+        if !self.liquidation_prefilter {
+            return true;
+        }
+        let shortfall = match account.position_values(&self.cache) {
+            Ok(values) => values
+                .iter()
+                .map(|value| value.liability_value - value.asset_value)
+                .sum::<I80F48>(),
+            Err(err) => {
+                debug!(
+                    "Failed to value the Marginfi account {} for the pre-filter: {}",
+                    account.address(),
+                    err
+                );
+                return true;
+            }
+        };
+        if shortfall <= I80F48::ZERO {
+            return true;
+        }
+        shortfall.to_num::<f64>() * PREFILTER_PROFIT_PER_SHORTFALL >= self.min_profit_usd
+    }
+
+    /// Refreshes the account from the RPC after its liquidation failed and prepares it again.
+    /// The fetched state is stamped with the context slot of the RPC response, so a fresher Geyser
+    /// update is not overwritten.
+    fn classify_revert(
+        &self,
+        address: Pubkey,
+        liquidation_strategy: &impl LiquidationStrategy,
+    ) -> Result<RevertReason> {
+        let (slot, accounts) = self.comms_client.get_accounts_with_slot(&[address])?;
+        // Only the accounts without the liabilities can be closed
+        let Some((_, account)) = accounts.into_iter().next() else {
+            return Ok(RevertReason::Healthy);
+        };
+        let marginfi_account = MarginfiAccount::try_deserialize(&mut account.data.as_slice())
+            .map_err(|e| {
+                MaryError::Deserialize(format!(
+                    "Failed to parse the Marginfi account {}: {}",
+                    address, e
+                ))
+            })?;
+        self.cache
+            .update_marginfi_account(slot, address, &marginfi_account)?;
+
+        let account = self.cache.marginfi_accounts.get_account(&address)?;
+        if account.compute_health(&self.cache)? >= I80F48::ZERO {
+            return Ok(RevertReason::Healthy);
+        }
+        Ok(match liquidation_strategy.prepare(&account)? {
+            Some(params) => RevertReason::Transient(params),
+            None => RevertReason::LostRace,
+        })
+    }
+
+    /// Logs the outcome as a JSON line under the "liquidation_outcome" target and forwards it.
+    fn emit_outcome(&self, outcome: LiquidationOutcome) {
+        match serde_json::to_string(&outcome) {
+            Ok(json) => info!(target: "liquidation_outcome", "{}", json),
+            Err(err) => error!("Failed to serialize the liquidation outcome: {}", err),
+        }
+        if let Some(sender) = &self.outcome_sender {
+            match sender.try_send(outcome) {
+                Ok(()) => {}
+                Err(TrySendError::Full(outcome)) => warn!(
+                    "The liquidation outcome queue is full, dropping the outcome of {}.",
+                    outcome.liquidatee_account
+                ),
+                Err(TrySendError::Disconnected(_)) => {
+                    debug!("The liquidation outcome receiver is disconnected.")
+                }
+            }
+        }
+    }
+}
+
+/// Only the failures to send or land the transaction, the reverts included, are worth preparing the
+/// liquidation again for. The cache failures, e.g. the poisoned lock, would only repeat.
+fn is_retryable(err: &anyhow::Error) -> bool {
+    matches!(MaryError::of(err), Some(MaryError::Rpc(_)))
+}
+
+/// What the refreshed account state tells about the failed liquidation.
+enum RevertReason {
+    /// The account was liquidated by someone else or topped up, nothing is left to do.
+    Healthy,
+    /// The account is still unhealthy, but what is left is not worth liquidating.
+    LostRace,
+    /// The liquidation is still profitable, possibly at a reduced size, and is retried.
+    Transient(LiquidationParams),
+}
+
+struct Cooldown {
+    started_at: Instant,
+    slot: u64,
+    health: i64,
+}
+
+/// Keeps the recently liquidated accounts from being resubmitted before their cached health catches up.
+struct LiquidationCooldowns {
+    duration: Duration,
+    cooldowns: Mutex<HashMap<Pubkey, Cooldown>>,
+}
+
+impl LiquidationCooldowns {
+    fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            cooldowns: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn start(&self, address: Pubkey, slot: u64, health: i64, now: Instant) -> Result<()> {
+        self.cooldowns
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the liquidation cooldowns for update: {}", e))?
+            .insert(
+                address,
+                Cooldown {
+                    started_at: now,
+                    slot,
+                    health,
+                },
+            );
         Ok(())
     }
+
+    /// The cooldown ends when it expires or when a fresher account update shows the improved health.
+    fn is_cooling_down(
+        &self,
+        address: &Pubkey,
+        slot: u64,
+        health: i64,
+        now: Instant,
+    ) -> Result<bool> {
+        let mut cooldowns = self
+            .cooldowns
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock the liquidation cooldowns for check: {}", e))?;
+        let Some(cooldown) = cooldowns.get(address) else {
+            return Ok(false);
+        };
+
+        let expired = now.duration_since(cooldown.started_at) >= self.duration;
+        let improved = slot > cooldown.slot && health > cooldown.health;
+        if expired || improved {
+            cooldowns.remove(address);
+            return Ok(false);
+        }
+        Ok(true)
+    }
 }
 
-fn sort_accounts_by_health(accounts: &HashMap<Pubkey, i64>) -> Vec<Pubkey> {
-    let mut sorted: Vec<(Pubkey, i64)> = accounts.iter().map(|(&k, &v)| (k, v)).collect();
-    sorted.sort_by(|a, b| b.1.cmp(&a.1));
-    sorted.into_iter().map(|(k, _)| k).collect()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{
+        banks::test_util::{add_priced_bank, create_bank_account, create_bank_with_oracles},
+        marginfi_accounts::test_util::{
+            create_balance, create_marginfi_account, create_marginfi_account_account,
+        },
+        oracles::test_util::{create_dummy_oracle_account, create_pyth_account},
+        test_util::{create_dummy_cache, generate_test_clock},
+    };
+    use crate::comms::test_util::MockedCommsClient;
+    use crate::config::test_util::create_dummy_config;
+    use crossbeam::channel;
+    use marginfi::state::price::OracleSetup;
+    use solana_sdk::account::Account;
+    use std::thread;
+
+    const TOKEN: i64 = 1_000_000;
+
+    fn create_cache_with_account() -> (Arc<Cache>, Pubkey) {
+        let cache = Arc::new(create_dummy_cache());
+        let address = Pubkey::new_unique();
+        let bank_address = Pubkey::new_unique();
+        let oracle_address = Pubkey::new_unique();
+        cache
+            .banks
+            .update(
+                1,
+                bank_address,
+                &create_bank_with_oracles(vec![oracle_address]),
+            )
+            .unwrap();
+        cache
+            .oracles
+            .insert(
+                1,
+                0,
+                &oracle_address,
+                OracleSetup::SwitchboardPull,
+                create_dummy_oracle_account(OracleSetup::SwitchboardPull),
+            )
+            .unwrap();
+        let marginfi_account = create_marginfi_account(
+            Pubkey::new_unique(),
+            vec![create_balance(bank_address, 100, 50)],
+        );
+        cache
+            .update_marginfi_account(1, address, &marginfi_account)
+            .unwrap();
+        (cache, address)
+    }
+
+    fn create_borrower(
+        group: Pubkey,
+        collateral_bank: Pubkey,
+        liability_bank: Pubkey,
+        liability_usd: i64,
+    ) -> MarginfiAccount {
+        create_marginfi_account(
+            group,
+            vec![
+                create_balance(collateral_bank, 1_000 * TOKEN, 0),
+                create_balance(liability_bank, 0, liability_usd * TOKEN),
+            ],
+        )
+    }
+
+    /// The cache with the account borrowing 900 USD against 1000 USD of the collateral weighted at
+    /// 0.8, next to the liquidator's empty account.
+    fn create_cache_with_borrower(config: &Config) -> (Arc<Cache>, Pubkey) {
+        let cache = Arc::new(create_dummy_cache());
+        let collateral_bank = add_priced_bank(&cache, create_bank_with_oracles(vec![]), 0.8, 1.0);
+        let liability_bank = add_priced_bank(&cache, create_bank_with_oracles(vec![]), 0.8, 1.0);
+        let group = Pubkey::new_unique();
+        let address = Pubkey::new_unique();
+        cache
+            .update_marginfi_account(
+                1,
+                address,
+                &create_borrower(group, collateral_bank, liability_bank, 900),
+            )
+            .unwrap();
+        cache
+            .update_marginfi_account(
+                1,
+                config.liquidator_account,
+                &create_marginfi_account(group, vec![]),
+            )
+            .unwrap();
+        (cache, address)
+    }
+
+    /// The service reporting its outcomes to the returned receiver.
+    fn create_service(
+        config: &Config,
+        cache: Arc<Cache>,
+        comms_client: MockedCommsClient,
+    ) -> (
+        LiquidationService<MockedCommsClient>,
+        Receiver<LiquidationOutcome>,
+    ) {
+        let (outcome_tx, outcome_rx) = channel::unbounded();
+        let service = LiquidationService::new(
+            config,
+            Arc::new(AtomicBool::new(false)),
+            cache,
+            comms_client,
+            Arc::new(MetricsRegistry::default()),
+            channel::never(),
+        )
+        .unwrap()
+        .with_outcome_sender(outcome_tx);
+        (service, outcome_rx)
+    }
+
+    /// The service liquidating the borrower. The sends follow the script, the account state
+    /// fetched after a failed send has the given liability.
+    fn create_retry_service(
+        config: &Config,
+        fetched_liability_usd: i64,
+        send_results: Vec<Result<(), &str>>,
+    ) -> (
+        LiquidationService<MockedCommsClient>,
+        Pubkey,
+        Receiver<LiquidationOutcome>,
+    ) {
+        create_simulating_service(config, fetched_liability_usd, send_results, vec![])
+    }
+
+    /// The retry service whose simulations follow the script.
+    fn create_simulating_service(
+        config: &Config,
+        fetched_liability_usd: i64,
+        send_results: Vec<Result<(), &str>>,
+        simulate_results: Vec<Result<(), &str>>,
+    ) -> (
+        LiquidationService<MockedCommsClient>,
+        Pubkey,
+        Receiver<LiquidationOutcome>,
+    ) {
+        let (cache, address) = create_cache_with_borrower(config);
+
+        let borrower = cache.marginfi_accounts.get_account(&address).unwrap();
+        let banks: Vec<Pubkey> = borrower
+            ._positions()
+            .iter()
+            .map(|position| position.bank_pk)
+            .collect();
+        let accounts = HashMap::from([(
+            address,
+            create_marginfi_account_account(&create_borrower(
+                *borrower.group(),
+                banks[0],
+                banks[1],
+                fetched_liability_usd,
+            )),
+        )]);
+        // The fetched account is stamped past the initial state
+        let comms_client = MockedCommsClient::with_accounts(accounts)
+            .with_slot(2)
+            .with_liquidator_account(config.liquidator_account)
+            .with_send_results(send_results)
+            .with_simulate_results(simulate_results);
+
+        let (service, outcomes) = create_service(config, cache, comms_client);
+        (service, address, outcomes)
+    }
+
+    #[test]
+    fn test_process_account_stops_retrying_when_account_is_healthy() {
+        // Someone else repaid the whole liability
+        let (service, address, outcomes) = create_retry_service(
+            &create_dummy_config(),
+            0,
+            vec![Err("custom program error: 0x1771")],
+        );
+
+        assert!(service.process_account(address, -1).is_ok());
+        assert_eq!(service.comms_client.sent_ixs().len(), 1);
+        assert_eq!(
+            outcomes.try_recv().unwrap().status,
+            LiquidationStatus::Skipped
+        );
+    }
+
+    #[test]
+    fn test_process_account_stops_retrying_when_race_is_lost() {
+        // Someone else liquidated most of the account, the rest yields less than the minimum profit
+        let mut config = create_dummy_config();
+        config.min_profit_usd = 5.0;
+        let (service, address, outcomes) =
+            create_retry_service(&config, 810, vec![Err("custom program error: 0x1771")]);
+
+        assert!(service.process_account(address, -1).is_ok());
+        assert_eq!(service.comms_client.sent_ixs().len(), 1);
+        assert_eq!(
+            outcomes.try_recv().unwrap().status,
+            LiquidationStatus::Reverted
+        );
+    }
+
+    #[test]
+    fn test_process_account_stops_retrying_when_account_is_closed() {
+        let config = create_dummy_config();
+        let (cache, address) = create_cache_with_borrower(&config);
+        let comms_client = MockedCommsClient::with_accounts(HashMap::new())
+            .with_slot(2)
+            .with_liquidator_account(config.liquidator_account)
+            .with_send_results(vec![Err("custom program error: 0x1771")]);
+        let (service, outcomes) = create_service(&config, cache, comms_client);
+
+        assert!(service.process_account(address, -1).is_ok());
+        assert_eq!(service.comms_client.sent_ixs().len(), 1);
+        assert_eq!(
+            outcomes.try_recv().unwrap().status,
+            LiquidationStatus::Skipped
+        );
+    }
+
+    #[test]
+    fn test_process_account_skips_failed_simulation() {
+        let mut config = create_dummy_config();
+        config.simulate_before_send = true;
+        let (service, address, outcomes) = create_simulating_service(
+            &config,
+            900,
+            vec![],
+            vec![Err("custom program error: 0x1771")],
+        );
+
+        assert!(service.process_account(address, -1).is_ok());
+        assert!(service.comms_client.sent_ixs().is_empty());
+        assert_eq!(
+            outcomes.try_recv().unwrap().status,
+            LiquidationStatus::Skipped
+        );
+
+        // The simulation succeeds
+        let (service, address, outcomes) = create_simulating_service(&config, 900, vec![], vec![]);
+        assert!(service.process_account(address, -1).is_ok());
+        assert_eq!(service.comms_client.sent_ixs().len(), 1);
+        assert_eq!(
+            outcomes.try_recv().unwrap().status,
+            LiquidationStatus::Landed
+        );
+    }
+
+    #[test]
+    fn test_process_account_retries_transient_failure() {
+        let (service, address, outcomes) = create_retry_service(
+            &create_dummy_config(),
+            900,
+            vec![Err("Blockhash not found")],
+        );
+
+        assert!(service.process_account(address, -1).is_ok());
+        assert_eq!(service.comms_client.sent_ixs().len(), 2);
+        assert_eq!(
+            outcomes.try_recv().unwrap().status,
+            LiquidationStatus::Landed
+        );
+
+        // The retries run out
+        let mut config = create_dummy_config();
+        config.liquidation_max_retries = 1;
+        let (service, address, outcomes) = create_retry_service(
+            &config,
+            900,
+            vec![Err("Blockhash not found"), Err("Blockhash not found")],
+        );
+
+        assert!(service.process_account(address, -1).is_err());
+        assert_eq!(service.comms_client.sent_ixs().len(), 2);
+        assert_eq!(
+            outcomes.try_recv().unwrap().status,
+            LiquidationStatus::Reverted
+        );
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(is_retryable(
+            &MaryError::Rpc("Blockhash not found".to_string()).into()
+        ));
+        assert!(!is_retryable(
+            &MaryError::LockPoisoned("Failed to lock the Banks cache".to_string()).into()
+        ));
+        assert!(!is_retryable(&anyhow!(
+            "Math overflow in the native amount"
+        )));
+    }
+
+    #[test]
+    fn test_run_holds_liquidations_until_ready() {
+        let mut config = create_dummy_config();
+        config.dry_run = true;
+        let (cache, _) = create_cache_with_borrower(&config);
+        let (service, outcomes) = create_service(
+            &config,
+            cache.clone(),
+            MockedCommsClient::with_accounts(HashMap::new()),
+        );
+        let readiness = Arc::new(Readiness::new(cache.clone(), 1.0));
+        readiness.set_load_slot(2);
+        let service = service.with_readiness(readiness);
+
+        thread::scope(|scope| {
+            scope.spawn(|| service.run().unwrap());
+            assert!(outcomes.recv_timeout(Duration::from_millis(300)).is_err());
+
+            // The clock moves past the load slot, but the Oracles are not live yet
+            cache.update_clock(generate_test_clock(3)).unwrap();
+            assert!(outcomes.recv_timeout(Duration::from_millis(300)).is_err());
+
+            for oracle in cache.oracles.get_oracle_addresses() {
+                cache
+                    .oracles
+                    .update(
+                        3,
+                        0,
+                        &oracle,
+                        &mut create_pyth_account(1_000, 0, 1_000, 0, 3),
+                    )
+                    .unwrap();
+            }
+            let outcome = outcomes.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(outcome.status, LiquidationStatus::Skipped);
+            service.stop.store(true, Ordering::Relaxed);
+        });
+    }
+
+    #[test]
+    fn test_run_cycle_stops_between_batches() {
+        let mut config = create_dummy_config();
+        config.dry_run = true;
+        config.liquidation_scan_batch_size = 1;
+        let (cache, address) = create_cache_with_borrower(&config);
+        let (service, outcomes) = create_service(
+            &config,
+            cache,
+            MockedCommsClient::with_accounts(HashMap::new()),
+        );
+        let account = service
+            .cache
+            .marginfi_accounts
+            .get_account(&address)
+            .unwrap();
+        let banks: Vec<Pubkey> = account
+            ._positions()
+            .iter()
+            .map(|position| position.bank_pk)
+            .collect();
+        let candidates = 50;
+        for _ in 1..candidates {
+            service
+                .cache
+                .update_marginfi_account(
+                    1,
+                    Pubkey::new_unique(),
+                    &create_borrower(*account.group(), banks[0], banks[1], 900),
+                )
+                .unwrap();
+        }
+
+        thread::scope(|scope| {
+            let cycle = scope.spawn(|| service.run_cycle().unwrap());
+            outcomes.recv_timeout(Duration::from_secs(5)).unwrap();
+            service.stop.store(true, Ordering::Relaxed);
+            assert_eq!(cycle.join().unwrap(), candidates);
+        });
+
+        // The first one and at most the batch in flight when the stop was requested
+        let processed = 1 + outcomes.try_iter().count();
+        assert!(processed < candidates);
+    }
+
+    #[test]
+    fn test_should_attempt_prefilters_barely_unhealthy_accounts() {
+        let mut config = create_dummy_config();
+        config.min_profit_usd = 5.0;
+        config.liquidation_prefilter = true;
+        let cache = Arc::new(create_dummy_cache());
+        let collateral_bank = add_priced_bank(&cache, create_bank_with_oracles(vec![]), 0.8, 1.0);
+        let liability_bank = add_priced_bank(&cache, create_bank_with_oracles(vec![]), 0.8, 1.0);
+        // The 800 USD of the weighted collateral
+        let account_with_liability = |liability_usd: i64| {
+            let marginfi_account = create_borrower(
+                Pubkey::new_unique(),
+                collateral_bank,
+                liability_bank,
+                liability_usd,
+            );
+            CachedMarginfiAccount::from(1, Pubkey::new_unique(), &marginfi_account)
+        };
+        let (service, _) = create_service(
+            &config,
+            cache.clone(),
+            MockedCommsClient::with_accounts(HashMap::new()),
+        );
+
+        assert!(!service.should_attempt(&account_with_liability(801)));
+        assert!(service.should_attempt(&account_with_liability(900)));
+        // Not judged without the shortfall
+        assert!(service.should_attempt(&account_with_liability(700)));
+        // Nor without the prices of its banks
+        assert!(service.should_attempt(&CachedMarginfiAccount::from(
+            1,
+            Pubkey::new_unique(),
+            &create_marginfi_account(
+                Pubkey::new_unique(),
+                vec![create_balance(Pubkey::new_unique(), 0, 1_000 * TOKEN)],
+            ),
+        )));
+
+        config.liquidation_prefilter = false;
+        let (service, _) = create_service(
+            &config,
+            cache,
+            MockedCommsClient::with_accounts(HashMap::new()),
+        );
+        assert!(service.should_attempt(&account_with_liability(801)));
+    }
+
+    #[test]
+    fn test_process_account_skips_prefiltered_account() {
+        let mut config = create_dummy_config();
+        config.dry_run = true;
+        config.min_profit_usd = 5.0;
+        config.liquidation_prefilter = true;
+        let (cache, address) = create_cache_with_borrower(&config);
+        let (service, outcomes) = create_service(
+            &config,
+            cache,
+            MockedCommsClient::with_accounts(HashMap::new()),
+        );
+        let account = service
+            .cache
+            .marginfi_accounts
+            .get_account(&address)
+            .unwrap();
+        let banks: Vec<Pubkey> = account
+            ._positions()
+            .iter()
+            .map(|position| position.bank_pk)
+            .collect();
+        // The shortfall of 1 USD at the cached prices
+        service
+            .cache
+            .update_marginfi_account(
+                2,
+                address,
+                &create_borrower(*account.group(), banks[0], banks[1], 801),
+            )
+            .unwrap();
+
+        assert!(!service.process_account(address, -1).unwrap());
+        assert!(outcomes.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_run_cycle_caps_liquidation_attempts() {
+        let mut config = create_dummy_config();
+        config.dry_run = true;
+        config.max_liquidations_per_cycle = 2;
+        let (cache, address) = create_cache_with_borrower(&config);
+        let (service, outcomes) = create_service(
+            &config,
+            cache,
+            MockedCommsClient::with_accounts(HashMap::new()),
+        );
+        let account = service
+            .cache
+            .marginfi_accounts
+            .get_account(&address)
+            .unwrap();
+        let banks: Vec<Pubkey> = account
+            ._positions()
+            .iter()
+            .map(|position| position.bank_pk)
+            .collect();
+        for _ in 0..4 {
+            service
+                .cache
+                .update_marginfi_account(
+                    1,
+                    Pubkey::new_unique(),
+                    &create_borrower(*account.group(), banks[0], banks[1], 900),
+                )
+                .unwrap();
+        }
+
+        assert_eq!(service.run_cycle().unwrap(), 5);
+        assert_eq!(outcomes.try_iter().count(), 2);
+
+        // The count starts over with the next cycle
+        assert_eq!(service.run_cycle().unwrap(), 5);
+        assert_eq!(outcomes.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn test_process_account_through_mocked_comms_client() {
+        let (cache, address) = create_cache_with_account();
+        let service = LiquidationService::new(
+            &create_dummy_config(),
+            Arc::new(AtomicBool::new(false)),
+            cache,
+            MockedCommsClient::with_accounts(HashMap::new()),
+            Arc::new(MetricsRegistry::default()),
+            channel::never(),
+        )
+        .unwrap();
+
+        assert!(service.process_account(address, -1).is_ok());
+        assert!(service.process_account(Pubkey::new_unique(), -1).is_err());
+    }
+
+    #[test]
+    fn test_process_account_dry_run_sends_nothing() {
+        let (cache, address) = create_cache_with_account();
+        let mut config = create_dummy_config();
+        config.dry_run = true;
+        let service = LiquidationService::new(
+            &config,
+            Arc::new(AtomicBool::new(false)),
+            cache,
+            MockedCommsClient::with_accounts(HashMap::new()),
+            Arc::new(MetricsRegistry::default()),
+            channel::never(),
+        )
+        .unwrap();
+
+        assert!(service.process_account(address, -1).is_ok());
+        assert!(service.comms_client.sent_ixs().is_empty());
+    }
+
+    #[test]
+    fn test_process_account_lazily_loads_missing_bank() {
+        let cache = Arc::new(create_dummy_cache());
+        let address = Pubkey::new_unique();
+        let bank_address = Pubkey::new_unique();
+        let bank = create_bank_with_oracles(vec![]);
+        cache
+            .update_marginfi_account(
+                1,
+                address,
+                &create_marginfi_account(
+                    Pubkey::new_unique(),
+                    vec![create_balance(bank_address, 100, 50)],
+                ),
+            )
+            .unwrap();
+        let accounts = HashMap::from([
+            (bank_address, create_bank_account(&bank)),
+            (bank.mint, Account::new(1, 82, &Pubkey::new_unique())),
+        ]);
+
+        let mut config = create_dummy_config();
+        config.dry_run = true;
+        let service = LiquidationService::new(
+            &config,
+            Arc::new(AtomicBool::new(false)),
+            cache.clone(),
+            MockedCommsClient::with_accounts(accounts.clone()),
+            Arc::new(MetricsRegistry::default()),
+            channel::never(),
+        )
+        .unwrap();
+        let _ = service.process_account(address, -1);
+        assert!(cache.banks.get_bank(&bank_address).is_err());
+
+        config.lazy_bank_loading = true;
+        let service = LiquidationService::new(
+            &config,
+            Arc::new(AtomicBool::new(false)),
+            cache.clone(),
+            MockedCommsClient::with_accounts(accounts),
+            Arc::new(MetricsRegistry::default()),
+            channel::never(),
+        )
+        .unwrap();
+        let _ = service.process_account(address, -1);
+        assert!(cache.banks.get_bank(&bank_address).is_ok());
+        assert!(cache.mints.get(&bank.mint).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_run_cycle_skips_accounts_above_health_threshold() {
+        let cache = Arc::new(create_dummy_cache());
+        let mut marginfi_account = create_marginfi_account(
+            Pubkey::new_unique(),
+            vec![create_balance(Pubkey::new_unique(), 100, 50)],
+        );
+        // The health ratio of 0.1
+        marginfi_account.health_cache.asset_value_maint = I80F48::from_num(1000).into();
+        marginfi_account.health_cache.liability_value_maint = I80F48::from_num(900).into();
+        cache
+            .update_marginfi_account(1, Pubkey::new_unique(), &marginfi_account)
+            .unwrap();
+
+        let mut config = create_dummy_config();
+        for (threshold, candidates) in [(0.0, 0), (0.05, 0), (0.1, 1), (0.5, 1)] {
+            config.liquidation_health_threshold = threshold;
+            let service = LiquidationService::new(
+                &config,
+                Arc::new(AtomicBool::new(false)),
+                cache.clone(),
+                MockedCommsClient::with_accounts(HashMap::new()),
+                Arc::new(MetricsRegistry::default()),
+                channel::never(),
+            )
+            .unwrap();
+            assert_eq!(service.run_cycle().unwrap(), candidates);
+        }
+    }
+
+    #[test]
+    fn test_excluded_accounts_are_never_candidates() {
+        let cache = Arc::new(create_dummy_cache());
+        let mut config = create_dummy_config();
+        let excluded = Pubkey::new_unique();
+        let unhealthy = Pubkey::new_unique();
+        config.excluded_accounts = vec![excluded];
+        config.liquidation_max_candidates = 1;
+
+        // The liquidator account and the excluded account are the most unhealthy ones
+        for (address, liability_value_maint) in [
+            (config.liquidator_account, 2000),
+            (excluded, 1500),
+            (unhealthy, 1100),
+        ] {
+            let mut marginfi_account = create_marginfi_account(
+                Pubkey::new_unique(),
+                vec![create_balance(Pubkey::new_unique(), 100, 50)],
+            );
+            marginfi_account.health_cache.asset_value_maint = I80F48::from_num(1000).into();
+            marginfi_account.health_cache.liability_value_maint =
+                I80F48::from_num(liability_value_maint).into();
+            cache
+                .update_marginfi_account(1, address, &marginfi_account)
+                .unwrap();
+        }
+
+        let service = LiquidationService::new(
+            &config,
+            Arc::new(AtomicBool::new(false)),
+            cache,
+            MockedCommsClient::with_accounts(HashMap::new()),
+            Arc::new(MetricsRegistry::default()),
+            channel::never(),
+        )
+        .unwrap();
+
+        let candidates = service.liquidation_candidates().unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].0, unhealthy);
+        assert_eq!(service.run_cycle().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_accounts_of_disallowed_groups_are_skipped() {
+        let cache = Arc::new(create_dummy_cache());
+        let mut config = create_dummy_config();
+        let allowed_group = Pubkey::new_unique();
+        let isolated_group = Pubkey::new_unique();
+        let allowed = Pubkey::new_unique();
+        let isolated = Pubkey::new_unique();
+        config.allowed_groups = vec![allowed_group];
+        config.liquidation_max_candidates = 1;
+
+        // The account of the isolated pool is the most unhealthy one
+        for (address, group, liability_value_maint) in [
+            (isolated, isolated_group, 2000),
+            (allowed, allowed_group, 1100),
+        ] {
+            let mut marginfi_account =
+                create_marginfi_account(group, vec![create_balance(Pubkey::new_unique(), 100, 50)]);
+            marginfi_account.health_cache.asset_value_maint = I80F48::from_num(1000).into();
+            marginfi_account.health_cache.liability_value_maint =
+                I80F48::from_num(liability_value_maint).into();
+            cache
+                .update_marginfi_account(1, address, &marginfi_account)
+                .unwrap();
+        }
+
+        let service = LiquidationService::new(
+            &config,
+            Arc::new(AtomicBool::new(false)),
+            cache,
+            MockedCommsClient::with_accounts(HashMap::new()),
+            Arc::new(MetricsRegistry::default()),
+            channel::never(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            service.liquidation_candidates().unwrap(),
+            vec![(allowed, -1000)]
+        );
+        assert_eq!(service.run_cycle().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_accounts_of_excluded_authorities_are_skipped() {
+        let cache = Arc::new(create_dummy_cache());
+        let mut config = create_dummy_config();
+        config.dry_run = true;
+        let partner = Pubkey::new_unique();
+        config.excluded_authorities = vec![partner];
+
+        // The partner controls the two most unhealthy accounts
+        let collateral_bank = add_priced_bank(&cache, create_bank_with_oracles(vec![]), 0.8, 1.0);
+        let liability_bank = add_priced_bank(&cache, create_bank_with_oracles(vec![]), 0.8, 1.0);
+        let partner_accounts = [Pubkey::new_unique(), Pubkey::new_unique()];
+        let other = Pubkey::new_unique();
+        for (address, authority, liability_usd) in [
+            (partner_accounts[0], partner, 1_500),
+            (partner_accounts[1], partner, 1_200),
+            (other, Pubkey::new_unique(), 900),
+        ] {
+            let mut marginfi_account = create_borrower(
+                Pubkey::new_unique(),
+                collateral_bank,
+                liability_bank,
+                liability_usd,
+            );
+            marginfi_account.authority = authority;
+            cache
+                .update_marginfi_account(1, address, &marginfi_account)
+                .unwrap();
+        }
+
+        let (service, outcomes) = create_service(
+            &config,
+            cache,
+            MockedCommsClient::with_accounts(HashMap::new()),
+        );
+
+        let candidates: Vec<Pubkey> = service
+            .liquidation_candidates()
+            .unwrap()
+            .into_iter()
+            .map(|(address, _)| address)
+            .collect();
+        assert_eq!(candidates, vec![other]);
+        assert_eq!(
+            service
+                .reevaluate_accounts(vec![partner_accounts[0], partner_accounts[1], other])
+                .unwrap(),
+            1
+        );
+        // Only the other account is prepared for the liquidation
+        let liquidated: Vec<Pubkey> = outcomes
+            .try_iter()
+            .map(|outcome| outcome.liquidatee_account)
+            .collect();
+        assert_eq!(liquidated, vec![other]);
+    }
+
+    #[test]
+    fn test_reevaluate_accounts_coalesces_triggers() {
+        let (cache, address) = create_cache_with_account();
+        let mut config = create_dummy_config();
+        config.dry_run = true;
+        let (trigger_tx, trigger_rx) = channel::unbounded();
+        let service = LiquidationService::new(
+            &config,
+            Arc::new(AtomicBool::new(false)),
+            cache,
+            MockedCommsClient::with_accounts(HashMap::new()),
+            Arc::new(MetricsRegistry::default()),
+            trigger_rx,
+        )
+        .unwrap();
+
+        // The queued triggers are coalesced, the unknown accounts and the accounts whose health
+        // cannot be computed from the unpriced dummy oracle are skipped
+        trigger_tx.send(vec![address]).unwrap();
+        assert_eq!(
+            service
+                .reevaluate_accounts(vec![address, Pubkey::new_unique()])
+                .unwrap(),
+            0
+        );
+        assert!(trigger_tx.is_empty());
+    }
+
+    #[test]
+    fn test_run_cycle_skips_accounts_with_invalid_health() {
+        let (cache, _) = create_cache_with_account();
+        let service = LiquidationService::new(
+            &create_dummy_config(),
+            Arc::new(AtomicBool::new(false)),
+            cache,
+            MockedCommsClient::with_accounts(HashMap::new()),
+            Arc::new(MetricsRegistry::default()),
+            channel::never(),
+        )
+        .unwrap();
+
+        assert_eq!(service.run_cycle().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_cooldown_skips_recently_liquidated_account() {
+        let cooldowns = LiquidationCooldowns::new(Duration::from_secs(30));
+        let address = Pubkey::new_unique();
+        let now = Instant::now();
+        cooldowns.start(address, 10, -100, now).unwrap();
+
+        assert!(cooldowns
+            .is_cooling_down(&address, 10, -100, now + Duration::from_secs(5))
+            .unwrap());
+        // A fresher update without the health improvement keeps the cooldown
+        assert!(cooldowns
+            .is_cooling_down(&address, 11, -100, now + Duration::from_secs(5))
+            .unwrap());
+        assert!(!cooldowns
+            .is_cooling_down(&Pubkey::new_unique(), 10, -100, now)
+            .unwrap());
+        assert!(!cooldowns
+            .is_cooling_down(&address, 10, -100, now + Duration::from_secs(30))
+            .unwrap());
+    }
+
+    #[test]
+    fn test_cooldown_clears_on_improved_update() {
+        let cooldowns = LiquidationCooldowns::new(Duration::from_secs(30));
+        let address = Pubkey::new_unique();
+        let now = Instant::now();
+        cooldowns.start(address, 10, -100, now).unwrap();
+
+        // The improved health from the same slot is not a fresh update
+        assert!(cooldowns.is_cooling_down(&address, 10, -50, now).unwrap());
+        assert!(!cooldowns.is_cooling_down(&address, 11, -50, now).unwrap());
+        // The entry is cleared, so the worse health later is not skipped either
+        assert!(!cooldowns.is_cooling_down(&address, 11, -200, now).unwrap());
+    }
+
+    #[test]
+    fn test_execution_mode_from_dry_run() {
+        assert_eq!(ExecutionMode::from_dry_run(true), ExecutionMode::DryRun);
+        assert_eq!(ExecutionMode::from_dry_run(false), ExecutionMode::Live);
+    }
 }
@@ -0,0 +1,294 @@
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use serde_json::{json, Value};
+use solana_sdk::pubkey::Pubkey;
+use tiny_http::{Header, Request, Response, Server};
+
+use super::STOP_CHECK_INTERVAL;
+use crate::{
+    cache::{marginfi_accounts::INVALID_HEALTH, Cache},
+    config::Config,
+    metrics::{Gauge, MetricsRegistry},
+    service::{geyser_processor::GeyserProcessor, readiness::Readiness},
+};
+
+const METRICS_PATH: &str = "/metrics";
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const ACCOUNT_PATH_PREFIX: &str = "/account/";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+pub struct MetricsServer {
+    stop: Arc<AtomicBool>,
+    port: u16,
+    cache: Arc<Cache>,
+    geyser_processor: Arc<GeyserProcessor>,
+    readiness: Arc<Readiness>,
+    metrics: Arc<MetricsRegistry>,
+}
+
+impl MetricsServer {
+    pub fn new(
+        config: &Config,
+        stop: Arc<AtomicBool>,
+        cache: Arc<Cache>,
+        geyser_processor: Arc<GeyserProcessor>,
+        readiness: Arc<Readiness>,
+        metrics: Arc<MetricsRegistry>,
+    ) -> Self {
+        Self {
+            stop,
+            port: config.metrics_port,
+            cache,
+            geyser_processor,
+            readiness,
+            metrics,
+        }
+    }
+
+    pub fn run(&self) -> Result<()> {
+        let server = Server::http(("0.0.0.0", self.port)).map_err(|e| {
+            anyhow!(
+                "Failed to start the metrics server on port {}: {}",
+                self.port,
+                e
+            )
+        })?;
+
+        info!("Serving the metrics on port {}.", self.port);
+        while !self.stop.load(Ordering::Relaxed) {
+            match server.recv_timeout(STOP_CHECK_INTERVAL) {
+                Ok(Some(request)) => {
+                    if let Err(err) = self.handle_request(request) {
+                        error!("Failed to respond to the metrics request: {}", err);
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => error!("Failed to receive the metrics request: {}", err),
+            }
+        }
+
+        info!("The MetricsServer loop is stopped.");
+        Ok(())
+    }
+
+    fn handle_request(&self, request: Request) -> Result<()> {
+        if let Some(address) = request.url().strip_prefix(ACCOUNT_PATH_PREFIX) {
+            let Ok(address) = Pubkey::from_str(address) else {
+                request.respond(Response::from_string("Bad Request").with_status_code(400))?;
+                return Ok(());
+            };
+            let Some(report) = self.account_report(&address)? else {
+                request.respond(Response::from_string("Not Found").with_status_code(404))?;
+                return Ok(());
+            };
+            let content_type = Header::from_bytes("Content-Type", JSON_CONTENT_TYPE)
+                .map_err(|_| anyhow!("Invalid account Content-Type header"))?;
+            request.respond(Response::from_string(report.to_string()).with_header(content_type))?;
+            return Ok(());
+        }
+
+        if request.url() != METRICS_PATH {
+            request.respond(Response::from_string("Not Found").with_status_code(404))?;
+            return Ok(());
+        }
+
+        self.refresh_gauges()?;
+        let content_type = Header::from_bytes("Content-Type", METRICS_CONTENT_TYPE)
+            .map_err(|_| anyhow!("Invalid metrics Content-Type header"))?;
+        request.respond(Response::from_string(self.metrics.render()).with_header(content_type))?;
+        Ok(())
+    }
+
+    /// What the bot thinks of the cached account: the on-chain health cache, the health it is ranked
+    /// by and the local recomputation with the per-position values and prices. None if not cached.
+    fn account_report(&self, address: &Pubkey) -> Result<Option<Value>> {
+        let Some(health) = self.cache.marginfi_accounts.get_health(address)? else {
+            return Ok(None);
+        };
+        let account = self.cache.marginfi_accounts.get_account(address)?;
+
+        let positions = match account.position_values(&self.cache) {
+            Ok(positions) => json!(positions
+                .iter()
+                .map(|position| json!({
+                    "bank": position.bank.to_string(),
+                    "asset_shares": position.asset_shares.to_string(),
+                    "liability_shares": position.liability_shares.to_string(),
+                    "asset_price": position.asset_price.map(|price| price.to_string()),
+                    "liability_price": position.liability_price.map(|price| price.to_string()),
+                    "asset_value": position.asset_value.to_string(),
+                    "liability_value": position.liability_value.to_string(),
+                }))
+                .collect::<Vec<_>>()),
+            Err(err) => json!({ "error": err.to_string() }),
+        };
+        let computed_health = match account.compute_health(&self.cache) {
+            Ok(health) => json!(health.to_string()),
+            Err(err) => json!({ "error": err.to_string() }),
+        };
+
+        Ok(Some(json!({
+            "address": address.to_string(),
+            "group": account.group().to_string(),
+            "slot": account.slot(),
+            "health_bps": (health != INVALID_HEALTH).then_some(health),
+            "health_cache": {
+                "asset_value_maint": account.asset_value_maint().to_string(),
+                "liability_value_maint": account.liability_value_maint().to_string(),
+            },
+            "computed_health": computed_health,
+            "positions": positions,
+        })))
+    }
+
+    /// The gauges are sampled on every scrape so that they are never older than the scrape itself.
+    fn refresh_gauges(&self) -> Result<()> {
+        self.metrics.set(
+            Gauge::GeyserQueueDepth,
+            self.geyser_processor.queue_depth() as u64,
+        );
+        self.metrics.set_geyser_message_counts(
+            self.geyser_processor
+                .message_counts()
+                .iter()
+                .map(|count| (count.message_type.name(), count.processed, count.failed))
+                .collect(),
+        );
+        let stats = self.cache.stats()?;
+        self.metrics.set(
+            Gauge::CachedMarginfiAccounts,
+            stats.marginfi_accounts as u64,
+        );
+        self.metrics.set(Gauge::CachedBanks, stats.banks as u64);
+        self.metrics.set(Gauge::CachedOracles, stats.oracles as u64);
+        self.metrics.set(Gauge::CachedMints, stats.mints as u64);
+        self.metrics.set(Gauge::CachedLuts, stats.luts as u64);
+        self.metrics
+            .set(Gauge::CacheEstimatedBytes, stats.estimated_bytes as u64);
+        let clock = self.cache.get_clock()?;
+        self.metrics.set(Gauge::LatestSlot, clock.slot);
+        self.metrics.set(
+            Gauge::MaxOracleAgeSeconds,
+            self.cache.oracles.max_age_secs(&clock)?,
+        );
+        self.metrics
+            .set_oracle_ages(self.cache.oracles.staleness_report(&clock)?);
+        self.metrics.set(
+            Gauge::LiveOracles,
+            self.cache.oracles.live_count()?.0 as u64,
+        );
+        self.metrics
+            .set(Gauge::Ready, self.readiness.is_ready()? as u64);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam::channel;
+    use fixed::types::I80F48;
+
+    use super::*;
+    use crate::{
+        cache::{
+            banks::test_util::create_bank_with_oracles,
+            marginfi_accounts::test_util::{create_balance, create_marginfi_account},
+            test_util::create_dummy_cache,
+        },
+        config::test_util::create_dummy_config,
+    };
+
+    fn create_server(cache: Arc<Cache>, metrics: Arc<MetricsRegistry>) -> MetricsServer {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (_priority_tx, priority_rx) = channel::unbounded();
+        let (_normal_tx, normal_rx) = channel::unbounded();
+        let (trigger_tx, _) = channel::unbounded();
+        let (resubscribe_tx, _) = channel::unbounded();
+        let geyser_processor = Arc::new(GeyserProcessor::new(
+            stop.clone(),
+            cache.clone(),
+            priority_rx,
+            normal_rx,
+            0,
+            trigger_tx,
+            resubscribe_tx,
+        ));
+        let readiness = Arc::new(Readiness::new(cache.clone(), 0.0));
+        MetricsServer::new(
+            &create_dummy_config(),
+            stop,
+            cache,
+            geyser_processor,
+            readiness,
+            metrics,
+        )
+    }
+
+    #[test]
+    fn test_refresh_gauges() {
+        let cache = Arc::new(create_dummy_cache());
+        cache
+            .banks
+            .update(1, Pubkey::new_unique(), &create_bank_with_oracles(vec![]))
+            .unwrap();
+        let metrics = Arc::new(MetricsRegistry::default());
+
+        let server = create_server(cache, metrics.clone());
+        server.refresh_gauges().unwrap();
+
+        assert_eq!(metrics.get_gauge(Gauge::CachedBanks), 1);
+        assert_eq!(metrics.get_gauge(Gauge::CachedMarginfiAccounts), 0);
+        assert_eq!(metrics.get_gauge(Gauge::CachedMints), 0);
+        assert_eq!(metrics.get_gauge(Gauge::CachedLuts), 0);
+        assert!(metrics.get_gauge(Gauge::CacheEstimatedBytes) > 0);
+        assert_eq!(metrics.get_gauge(Gauge::GeyserQueueDepth), 0);
+        assert!(metrics
+            .render()
+            .contains("mary_geyser_messages_total{type=\"bank\"} 0\n"));
+        assert_eq!(metrics.get_gauge(Gauge::LatestSlot), 1);
+        assert_eq!(metrics.get_gauge(Gauge::MaxOracleAgeSeconds), 0);
+        assert_eq!(metrics.get_gauge(Gauge::LiveOracles), 0);
+        // The cache load slot is not recorded
+        assert_eq!(metrics.get_gauge(Gauge::Ready), 0);
+    }
+
+    #[test]
+    fn test_account_report() {
+        let cache = Arc::new(create_dummy_cache());
+        let address = Pubkey::new_unique();
+        let bank = Pubkey::new_unique();
+        let mut marginfi_account =
+            create_marginfi_account(Pubkey::new_unique(), vec![create_balance(bank, 100, 50)]);
+        marginfi_account.health_cache.asset_value_maint = I80F48::from_num(1000).into();
+        marginfi_account.health_cache.liability_value_maint = I80F48::from_num(500).into();
+        cache
+            .update_marginfi_account(7, address, &marginfi_account)
+            .unwrap();
+        let server = create_server(cache, Arc::new(MetricsRegistry::default()));
+
+        assert!(server
+            .account_report(&Pubkey::new_unique())
+            .unwrap()
+            .is_none());
+
+        let report = server.account_report(&address).unwrap().unwrap();
+        assert_eq!(report["address"], address.to_string());
+        assert_eq!(report["slot"], 7);
+        assert_eq!(report["health_bps"], 5_000);
+        assert_eq!(report["health_cache"]["asset_value_maint"], "1000");
+        // The bank is not cached, so the local recomputation reports why it failed
+        assert!(report["positions"]["error"]
+            .as_str()
+            .unwrap()
+            .contains("not found in cache"));
+        assert!(report["computed_health"]["error"].is_string());
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use log::{error, info, warn};
+use solana_sdk::pubkey::Pubkey;
+
+use super::STOP_CHECK_INTERVAL;
+use crate::{
+    liquidation::{LiquidationOutcome, LiquidationStatus},
+    notifier::Notifier,
+};
+
+// The consecutive failed liquidations of an account before they are notified
+const REPEATED_FAILURE_THRESHOLD: u32 = 3;
+// At most this many notifications are sent per window, the rest is dropped during the cascades
+const MAX_NOTIFICATIONS_PER_WINDOW: usize = 10;
+const NOTIFICATION_WINDOW: Duration = Duration::from_secs(60);
+
+/// Sends the notable liquidation outcomes through the Notifier off the liquidation path.
+pub struct NotificationService {
+    stop: Arc<AtomicBool>,
+    outcomes: Receiver<LiquidationOutcome>,
+    notifier: Box<dyn Notifier>,
+}
+
+impl NotificationService {
+    pub fn new(
+        stop: Arc<AtomicBool>,
+        outcomes: Receiver<LiquidationOutcome>,
+        notifier: Box<dyn Notifier>,
+    ) -> Self {
+        Self {
+            stop,
+            outcomes,
+            notifier,
+        }
+    }
+
+    pub fn run(&self) -> Result<()> {
+        let mut failures = FailureTracker::default();
+        let mut rate_limiter = RateLimiter::new(MAX_NOTIFICATIONS_PER_WINDOW, NOTIFICATION_WINDOW);
+
+        info!("Entering the NotificationService loop.");
+        while !self.stop.load(Ordering::Relaxed) {
+            let outcome = match self.outcomes.recv_timeout(STOP_CHECK_INTERVAL) {
+                Ok(outcome) => outcome,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if !failures.is_notable(&outcome) {
+                continue;
+            }
+            if !rate_limiter.allow(Instant::now()) {
+                warn!(
+                    "Dropping the notification of the Marginfi account {}, the rate limit is exceeded.",
+                    outcome.liquidatee_account
+                );
+                continue;
+            }
+            if let Err(err) = self.notifier.notify(&outcome) {
+                error!("Failed to notify the liquidation outcome: {}", err);
+            }
+        }
+
+        info!("The NotificationService loop is stopped.");
+        Ok(())
+    }
+}
+
+/// Tracks the consecutive failed liquidations per account, so that only the landed liquidations and
+/// the accounts that keep failing are notified.
+#[derive(Default)]
+struct FailureTracker {
+    failures: HashMap<Pubkey, u32>,
+}
+
+impl FailureTracker {
+    fn is_notable(&mut self, outcome: &LiquidationOutcome) -> bool {
+        match outcome.status {
+            LiquidationStatus::Landed | LiquidationStatus::Partial => {
+                self.failures.remove(&outcome.liquidatee_account);
+                true
+            }
+            LiquidationStatus::Reverted => {
+                let failures = self.failures.entry(outcome.liquidatee_account).or_default();
+                *failures += 1;
+                *failures == REPEATED_FAILURE_THRESHOLD
+            }
+            LiquidationStatus::Skipped => false,
+        }
+    }
+}
+
+/// Allows at most `max` events within any sliding window.
+struct RateLimiter {
+    max: usize,
+    window: Duration,
+    sent: VecDeque<Instant>,
+}
+
+impl RateLimiter {
+    fn new(max: usize, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            sent: VecDeque::new(),
+        }
+    }
+
+    fn allow(&mut self, now: Instant) -> bool {
+        while self
+            .sent
+            .front()
+            .is_some_and(|sent| now.duration_since(*sent) >= self.window)
+        {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= self.max {
+            return false;
+        }
+        self.sent.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use fixed::types::I80F48;
+    use std::sync::Mutex;
+
+    use crate::liquidation::LiquidationParams;
+
+    fn create_outcome(account: Pubkey, status: LiquidationStatus) -> LiquidationOutcome {
+        let params = LiquidationParams {
+            liquidatee_account: account,
+            legs: vec![],
+            profit_usd: I80F48::ONE,
+        };
+        LiquidationOutcome::new(&params, None, status)
+    }
+
+    #[derive(Default)]
+    struct RecordingNotifier {
+        notified: Arc<Mutex<Vec<Pubkey>>>,
+    }
+
+    impl Notifier for RecordingNotifier {
+        fn notify(&self, outcome: &LiquidationOutcome) -> Result<()> {
+            self.notified
+                .lock()
+                .unwrap()
+                .push(outcome.liquidatee_account);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failure_tracker_notifies_landed_and_repeated_failures() {
+        let mut tracker = FailureTracker::default();
+        let account = Pubkey::new_unique();
+
+        assert!(!tracker.is_notable(&create_outcome(account, LiquidationStatus::Skipped)));
+        assert!(!tracker.is_notable(&create_outcome(account, LiquidationStatus::Reverted)));
+        assert!(!tracker.is_notable(&create_outcome(account, LiquidationStatus::Reverted)));
+        assert!(tracker.is_notable(&create_outcome(account, LiquidationStatus::Reverted)));
+        // Notified once per streak
+        assert!(!tracker.is_notable(&create_outcome(account, LiquidationStatus::Reverted)));
+
+        assert!(tracker.is_notable(&create_outcome(account, LiquidationStatus::Landed)));
+        assert!(!tracker.is_notable(&create_outcome(account, LiquidationStatus::Reverted)));
+        assert!(tracker.is_notable(&create_outcome(account, LiquidationStatus::Partial)));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert!(limiter.allow(start));
+        assert!(limiter.allow(start + Duration::from_secs(1)));
+        assert!(!limiter.allow(start + Duration::from_secs(2)));
+        assert!(limiter.allow(start + Duration::from_secs(60)));
+        assert!(!limiter.allow(start + Duration::from_secs(60)));
+        assert!(limiter.allow(start + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_run_notifies_until_disconnected() {
+        let notifier = RecordingNotifier::default();
+        let notified = notifier.notified.clone();
+        let (tx, rx) = crossbeam::channel::unbounded();
+        let service =
+            NotificationService::new(Arc::new(AtomicBool::new(false)), rx, Box::new(notifier));
+
+        let landed = Pubkey::new_unique();
+        tx.send(create_outcome(landed, LiquidationStatus::Landed))
+            .unwrap();
+        tx.send(create_outcome(
+            Pubkey::new_unique(),
+            LiquidationStatus::Skipped,
+        ))
+        .unwrap();
+        for _ in 0..MAX_NOTIFICATIONS_PER_WINDOW {
+            tx.send(create_outcome(
+                Pubkey::new_unique(),
+                LiquidationStatus::Landed,
+            ))
+            .unwrap();
+        }
+        drop(tx);
+
+        service.run().unwrap();
+        let notified = notified.lock().unwrap();
+        assert_eq!(notified.len(), MAX_NOTIFICATIONS_PER_WINDOW);
+        assert_eq!(notified[0], landed);
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::{info, warn};
+
+use super::STOP_CHECK_INTERVAL;
+use crate::cache::Cache;
+
+/// Holds the liquidations after the startup until the cache catches up with the chain: the clock
+/// advanced past the slot the cache was loaded at and enough of the Oracles received a live update.
+pub struct Readiness {
+    cache: Arc<Cache>,
+    // The share of the Oracles that must have received a live update
+    live_oracles_ratio: f64,
+    // The liquidations start after this long since the startup even if the cache is not caught up,
+    // e.g. with the dead Oracle feeds
+    max_wait: Option<Duration>,
+    started_at: Instant,
+    load_slot: OnceLock<u64>,
+    ready: AtomicBool,
+}
+
+impl Readiness {
+    pub fn new(cache: Arc<Cache>, live_oracles_ratio: f64) -> Self {
+        Self {
+            cache,
+            live_oracles_ratio,
+            max_wait: None,
+            started_at: Instant::now(),
+            load_slot: OnceLock::new(),
+            ready: AtomicBool::new(false),
+        }
+    }
+
+    pub fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = Some(max_wait);
+        self
+    }
+
+    /// Records the slot of the clock the cache was loaded at, the first one wins.
+    pub fn set_load_slot(&self, slot: u64) {
+        let _ = self.load_slot.set(slot);
+    }
+
+    /// Once reached, the readiness is kept, the later Oracle outages are caught by the staleness checks.
+    pub fn is_ready(&self) -> Result<bool> {
+        if self.ready.load(Ordering::Relaxed) {
+            return Ok(true);
+        }
+        let Some(&load_slot) = self.load_slot.get() else {
+            return Ok(false);
+        };
+
+        let slot = self.cache.get_clock()?.slot;
+        let (live, total) = self.cache.oracles.live_count()?;
+        if slot <= load_slot || (live as f64) < total as f64 * self.live_oracles_ratio {
+            let Some(max_wait) = self
+                .max_wait
+                .filter(|max_wait| self.started_at.elapsed() >= *max_wait)
+            else {
+                return Ok(false);
+            };
+            warn!(
+                "The Cache is not ready after {:?}, at the slot {} {} of {} Oracles are live, starting the liquidations anyway.",
+                max_wait, slot, live, total
+            );
+            self.ready.store(true, Ordering::Relaxed);
+            return Ok(true);
+        }
+
+        info!(
+            "The Cache is ready at the slot {}, {} of {} Oracles are live.",
+            slot, live, total
+        );
+        self.ready.store(true, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Blocks until the cache is ready, returns false if the stop is requested first.
+    pub fn wait(&self, stop: &AtomicBool) -> Result<bool> {
+        while !stop.load(Ordering::Relaxed) {
+            if self.is_ready()? {
+                return Ok(true);
+            }
+            thread::sleep(STOP_CHECK_INTERVAL);
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{
+        oracles::test_util::create_pyth_account,
+        test_util::{create_dummy_cache, generate_test_clock},
+    };
+    use marginfi::state::price::OracleSetup;
+    use solana_sdk::pubkey::Pubkey;
+
+    #[test]
+    fn test_is_ready_waits_for_clock_and_live_oracles() {
+        let cache = Arc::new(create_dummy_cache());
+        let oracles = [Pubkey::new_unique(), Pubkey::new_unique()];
+        for oracle in &oracles {
+            cache
+                .oracles
+                .insert(
+                    1,
+                    0,
+                    oracle,
+                    OracleSetup::PythPushOracle,
+                    create_pyth_account(100_000, 0, 100_000, 0, 1),
+                )
+                .unwrap();
+        }
+        let readiness = Readiness::new(cache.clone(), 0.5);
+
+        // Not loaded yet
+        cache.update_clock(generate_test_clock(2)).unwrap();
+        assert!(!readiness.is_ready().unwrap());
+
+        readiness.set_load_slot(2);
+        cache
+            .oracles
+            .update(
+                2,
+                0,
+                &oracles[0],
+                &mut create_pyth_account(100_000, 0, 100_000, 0, 2),
+            )
+            .unwrap();
+        assert!(!readiness.is_ready().unwrap());
+
+        cache.update_clock(generate_test_clock(3)).unwrap();
+        assert!(readiness.is_ready().unwrap());
+    }
+
+    #[test]
+    fn test_is_ready_after_max_wait() {
+        let cache = Arc::new(create_dummy_cache());
+        cache
+            .oracles
+            .insert(
+                1,
+                0,
+                &Pubkey::new_unique(),
+                OracleSetup::PythPushOracle,
+                create_pyth_account(100_000, 0, 100_000, 0, 1),
+            )
+            .unwrap();
+        cache.update_clock(generate_test_clock(3)).unwrap();
+
+        // The Oracle feed is dead
+        let readiness = Readiness::new(cache.clone(), 1.0).with_max_wait(Duration::from_secs(60));
+        readiness.set_load_slot(2);
+        assert!(!readiness.is_ready().unwrap());
+
+        let readiness = Readiness::new(cache, 1.0).with_max_wait(Duration::ZERO);
+        readiness.set_load_slot(2);
+        assert!(readiness.is_ready().unwrap());
+    }
+
+    #[test]
+    fn test_wait_returns_on_stop() {
+        let readiness = Readiness::new(Arc::new(create_dummy_cache()), 0.0);
+        assert!(!readiness.wait(&AtomicBool::new(true)).unwrap());
+    }
+}
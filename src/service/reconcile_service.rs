@@ -0,0 +1,150 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use log::{error, info, warn};
+
+use super::sleep_unless_stopped;
+use crate::{cache::Cache, comms::CommsClient, config::Config};
+
+/// Re-fetches the cached Banks periodically and corrects the ones that diverged from the chain,
+/// a safety net for the Geyser updates dropped during the reconnects.
+pub struct ReconcileService<T: CommsClient> {
+    stop: Arc<AtomicBool>,
+    cache: Arc<Cache>,
+    comms_client: T,
+    interval: Duration,
+}
+
+impl<T: CommsClient> ReconcileService<T> {
+    pub fn new(config: &Config, stop: Arc<AtomicBool>, cache: Arc<Cache>, comms_client: T) -> Self {
+        Self {
+            stop,
+            cache,
+            comms_client,
+            interval: Duration::from_secs(config.reconcile_interval_sec),
+        }
+    }
+
+    pub fn run(&self) -> Result<()> {
+        info!("Entering the ReconcileService loop.");
+        // The cache is freshly loaded when the service starts
+        sleep_unless_stopped(&self.stop, self.interval);
+        while !self.stop.load(Ordering::Relaxed) {
+            // The RPC failures are left to the next pass rather than restarting the service
+            match self.reconcile_banks() {
+                Ok(0) => {}
+                Ok(corrected) => info!("Reconciled {} diverged Banks.", corrected),
+                Err(err) => error!("Failed to reconcile the Banks: {}", err),
+            }
+            sleep_unless_stopped(&self.stop, self.interval);
+        }
+        info!("The ReconcileService loop is stopped.");
+        Ok(())
+    }
+
+    /// Returns the number of the corrected Banks. The fetched Banks are stamped with the context slot
+    /// of the RPC response, the Banks Geyser updated at or past it are kept.
+    fn reconcile_banks(&self) -> Result<usize> {
+        let addresses: Vec<_> = self.cache.banks.get_banks_map()?.into_keys().collect();
+        let (slot, accounts) = self.comms_client.get_accounts_with_slot(&addresses)?;
+        let corrected = self.cache.banks.reconcile(slot, accounts)?;
+        for address in &corrected {
+            warn!(
+                "The cached Bank {} diverged from the chain, updated it.",
+                address
+            );
+        }
+        Ok(corrected.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use solana_sdk::pubkey::Pubkey;
+
+    use super::*;
+    use crate::{
+        cache::{
+            banks::test_util::{create_bank_account, create_bank_with_oracles},
+            test_util::create_dummy_cache,
+        },
+        comms::test_util::MockedCommsClient,
+        config::test_util::create_dummy_config,
+    };
+
+    #[test]
+    fn test_reconcile_banks_updates_diverged_bank() {
+        let cache = Arc::new(create_dummy_cache());
+        let diverged = Pubkey::new_unique();
+        let unchanged = Pubkey::new_unique();
+        let bank = create_bank_with_oracles(vec![]);
+        cache.banks.update(1, diverged, &bank).unwrap();
+        cache.banks.update(1, unchanged, &bank).unwrap();
+
+        let mut chain_bank = bank;
+        chain_bank.mint_decimals = bank.mint_decimals + 1;
+        let comms_client = MockedCommsClient::with_accounts(HashMap::from([
+            (diverged, create_bank_account(&chain_bank)),
+            (unchanged, create_bank_account(&bank)),
+        ]))
+        .with_slot(5);
+        let service = ReconcileService::new(
+            &create_dummy_config(),
+            Arc::new(AtomicBool::new(false)),
+            cache.clone(),
+            comms_client,
+        );
+
+        assert_eq!(service.reconcile_banks().unwrap(), 1);
+        let reconciled = cache.banks.get_bank(&diverged).unwrap();
+        assert_eq!(reconciled.slot, 5);
+        assert_eq!(reconciled.mint_decimals(), chain_bank.mint_decimals);
+        assert_eq!(cache.banks.get_bank(&unchanged).unwrap().slot, 1);
+    }
+
+    #[test]
+    fn test_reconcile_banks_keeps_newer_cached_bank() {
+        let cache = Arc::new(create_dummy_cache());
+        let address = Pubkey::new_unique();
+        let bank = create_bank_with_oracles(vec![]);
+        // Updated by Geyser at the current slot
+        cache.banks.update(1, address, &bank).unwrap();
+
+        let mut chain_bank = bank;
+        chain_bank.mint_decimals = bank.mint_decimals + 1;
+        let service = ReconcileService::new(
+            &create_dummy_config(),
+            Arc::new(AtomicBool::new(false)),
+            cache.clone(),
+            MockedCommsClient::with_accounts(HashMap::from([(
+                address,
+                create_bank_account(&chain_bank),
+            )])),
+        );
+
+        assert_eq!(service.reconcile_banks().unwrap(), 0);
+        assert_eq!(
+            cache.banks.get_bank(&address).unwrap().mint_decimals(),
+            bank.mint_decimals
+        );
+    }
+
+    #[test]
+    fn test_run_returns_on_stop() {
+        let service = ReconcileService::new(
+            &create_dummy_config(),
+            Arc::new(AtomicBool::new(true)),
+            Arc::new(create_dummy_cache()),
+            MockedCommsClient::with_accounts(HashMap::new()),
+        );
+        service.run().unwrap();
+    }
+}
@@ -0,0 +1,554 @@
+use std::{
+    collections::HashSet,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use crossbeam::channel::Receiver;
+use futures::{
+    future::try_join_all,
+    stream::{BoxStream, SelectAll, StreamExt},
+};
+use log::{debug, error, info, trace, warn};
+use solana_account_decoder::{UiAccount, UiAccountEncoding};
+use solana_client::{
+    nonblocking::pubsub_client::PubsubClient,
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+};
+use solana_sdk::{
+    account::Account,
+    clock::Clock,
+    commitment_config::{CommitmentConfig, CommitmentLevel},
+    pubkey::Pubkey,
+    sysvar,
+};
+use tokio::runtime::{Builder, Runtime};
+
+use super::{
+    geyser_subscriber::{
+        network_slot, reconnect_on_stall, watched_message_types, GeyserMessage, GeyserSender,
+        NetworkSlot, ReconnectBackoff, ResubscribeDebounce, SlotWatchdog,
+        MIN_HEALTHY_STREAM_DURATION, RESUBSCRIBE_DEBOUNCE,
+    },
+    sleep_unless_stopped, Subscriber, STOP_CHECK_INTERVAL,
+};
+use crate::{
+    cache::Cache,
+    common::{
+        discriminator_filter, get_marginfi_message_type, MessageType,
+        MARGINFI_ACCOUNT_DISCRIMINATOR, MARGINFI_BANK_DISCRIMINATOR,
+    },
+    config::Config,
+};
+
+/// The slot, the address and the account of a WebSocket notification.
+type AccountUpdate = (u64, Pubkey, UiAccount);
+
+/// Subscribes to the account updates through the standard RPC WebSocket API, for the deployments
+/// without a Geyser endpoint. The Marginfi program is followed with a programSubscribe per account
+/// type and every Oracle, Mint and the Clock with its own accountSubscribe, all multiplexed over a
+/// single connection.
+pub struct WsSubscriber {
+    ws_url: String,
+    stop: Arc<AtomicBool>,
+    tokio_rt: Runtime,
+    cache: Arc<Cache>,
+    marginfi_program_id: Pubkey,
+    geyser_tx: GeyserSender,
+    reconnect_backoff_base: Duration,
+    reconnect_backoff_max: Duration,
+    max_slot_staleness: Duration,
+    commitment: CommitmentLevel,
+    extra_subscribe_accounts: Vec<Pubkey>,
+    resubscribe_rx: Receiver<Vec<Pubkey>>,
+    network_slot: NetworkSlot,
+}
+
+/// The addresses the updates are routed by.
+struct WatchedAddresses {
+    marginfi_program_id: Pubkey,
+    oracles: HashSet<Pubkey>,
+    mints: HashSet<Pubkey>,
+    vaults: HashSet<Pubkey>,
+    extra: HashSet<Pubkey>,
+}
+
+impl WsSubscriber {
+    /// Subscribes to the watched accounts and the Marginfi program over the client connection and
+    /// merges the notifications into a single stream.
+    async fn subscribe<'a>(
+        &self,
+        client: &'a PubsubClient,
+        watched: &WatchedAddresses,
+    ) -> Result<SelectAll<BoxStream<'a, Result<AccountUpdate>>>> {
+        let account_config = RpcAccountInfoConfig {
+            encoding: Some(UiAccountEncoding::Base64),
+            commitment: Some(CommitmentConfig {
+                commitment: self.commitment,
+            }),
+            ..Default::default()
+        };
+
+        let addresses = std::iter::once(sysvar::clock::id())
+            .chain(watched.oracles.iter().cloned())
+            .chain(watched.mints.iter().cloned())
+            .chain(watched.vaults.iter().cloned())
+            .chain(watched.extra.iter().cloned())
+            .collect::<HashSet<_>>();
+        let account_config_ref = &account_config;
+        let account_subscriptions = try_join_all(addresses.into_iter().map(|address| async move {
+            client
+                .account_subscribe(&address, Some(account_config_ref.clone()))
+                .await
+                .map(|(stream, _)| (address, stream))
+                .map_err(|e| anyhow!("Failed to subscribe to the account {}: {}", address, e))
+        }))
+        .await?;
+
+        // The subscriptions end with the connection, so they are not unsubscribed one by one
+        let mut updates = SelectAll::new();
+        for (address, stream) in account_subscriptions {
+            updates.push(
+                stream
+                    .map(move |response| Ok((response.context.slot, address, response.value)))
+                    .boxed(),
+            );
+        }
+
+        // The filters of a single subscription must all match, so each account type gets its own
+        for discriminator in [MARGINFI_ACCOUNT_DISCRIMINATOR, MARGINFI_BANK_DISCRIMINATOR] {
+            let program_config = RpcProgramAccountsConfig {
+                filters: Some(vec![discriminator_filter(&discriminator)]),
+                account_config: account_config.clone(),
+                ..Default::default()
+            };
+            let (stream, _) = client
+                .program_subscribe(&watched.marginfi_program_id, Some(program_config))
+                .await
+                .map_err(|e| anyhow!("Failed to subscribe to the Marginfi program: {}", e))?;
+            updates.push(
+                stream
+                    .map(|response| {
+                        let address = Pubkey::from_str(&response.value.pubkey).map_err(|e| {
+                            anyhow!("Invalid address {}: {}", response.value.pubkey, e)
+                        })?;
+                        Ok((response.context.slot, address, response.value.account))
+                    })
+                    .boxed(),
+            );
+        }
+
+        Ok(updates)
+    }
+
+    /// Forwards the WebSocket updates until the connection drops, the slot stalls, the stop is
+    /// requested or new Oracles or Mints are to be subscribed to, which takes the new subscriptions.
+    fn stream_updates(&self, watched: &WatchedAddresses) -> Result<StreamEnd> {
+        let client = self
+            .tokio_rt
+            .block_on(PubsubClient::new(&self.ws_url))
+            .map_err(|e| anyhow!("Failed to connect to {}: {}", self.ws_url, e))?;
+        let mut updates = self.tokio_rt.block_on(self.subscribe(&client, watched))?;
+        info!(
+            "Subscribed to {} accounts and the Marginfi program over WebSocket.",
+            updates.len() - 2
+        );
+
+        let mut first_message_at: Option<Instant> = None;
+        let mut resubscribe = ResubscribeDebounce::new(RESUBSCRIBE_DEBOUNCE);
+        let mut resubscribing = false;
+        let mut watchdog = SlotWatchdog::new(
+            self.max_slot_staleness,
+            self.cache.get_clock()?.slot,
+            Instant::now(),
+        );
+        loop {
+            // Waiting in short slices so that the stop and the stalled slot are noticed on silent subscriptions
+            let update = match self
+                .tokio_rt
+                .block_on(tokio::time::timeout(STOP_CHECK_INTERVAL, updates.next()))
+            {
+                Ok(Some(update)) => Some(update),
+                Ok(None) => {
+                    error!("The WebSocket subscriptions are disconnected.");
+                    break;
+                }
+                Err(_) => None,
+            };
+
+            match update {
+                Some(Ok((slot, address, ui_account))) => {
+                    first_message_at.get_or_insert_with(Instant::now);
+                    let result = ui_account
+                        .decode::<Account>()
+                        .ok_or_else(|| anyhow!("Failed to decode the account {}", address))
+                        .and_then(|account| {
+                            handle_update(
+                                watched,
+                                &self.cache.get_clock()?,
+                                &self.geyser_tx,
+                                slot,
+                                address,
+                                account,
+                            )
+                        });
+                    if let Err(e) = result {
+                        error!("Error handling the WebSocket update of {}: {}", address, e);
+                    }
+                }
+                Some(Err(e)) => error!("Error handling the WebSocket update: {}", e),
+                None => {}
+            }
+
+            // Breaking the loop on stop request
+            if self.stop.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let slot = self.cache.get_clock()?.slot;
+            if watchdog.is_stalled(slot, Instant::now()) {
+                if reconnect_on_stall(
+                    &self.network_slot,
+                    "WebSocket",
+                    slot,
+                    self.max_slot_staleness,
+                ) {
+                    break;
+                }
+                watchdog.snooze(Instant::now());
+            }
+
+            for new_accounts in self.resubscribe_rx.try_iter() {
+                debug!("Requested the subscription to {:?}.", new_accounts);
+                resubscribe.request(Instant::now());
+            }
+            if resubscribe.is_due(Instant::now()) {
+                info!("Resubscribing over WebSocket to the new Oracles and Mints.");
+                resubscribing = true;
+                break;
+            }
+        }
+
+        // The streams borrow the client
+        drop(updates);
+        if let Err(e) = self.tokio_rt.block_on(client.shutdown()) {
+            warn!("Failed to shut down the WebSocket connection: {}", e);
+        }
+        if resubscribing {
+            return Ok(StreamEnd::Resubscribe);
+        }
+        Ok(StreamEnd::Disconnected(
+            first_message_at.map_or(Duration::ZERO, |started| started.elapsed()),
+        ))
+    }
+}
+
+impl Subscriber for WsSubscriber {
+    fn new(
+        config: &Config,
+        stop: Arc<AtomicBool>,
+        cache: Arc<Cache>,
+        geyser_tx: GeyserSender,
+        resubscribe_rx: Receiver<Vec<Pubkey>>,
+    ) -> Result<Self> {
+        let tokio_rt = Builder::new_multi_thread()
+            .thread_name("WsSubscriber")
+            .worker_threads(config.geyser_worker_threads)
+            .enable_all()
+            .build()?;
+
+        Ok(Self {
+            ws_url: config.ws_url.clone(),
+            stop,
+            tokio_rt,
+            cache,
+            marginfi_program_id: config.marginfi_program_id,
+            geyser_tx,
+            reconnect_backoff_base: Duration::from_millis(config.geyser_reconnect_base_ms),
+            reconnect_backoff_max: Duration::from_millis(config.geyser_reconnect_max_ms),
+            max_slot_staleness: Duration::from_secs(config.max_slot_staleness_sec),
+            commitment: config.commitment,
+            extra_subscribe_accounts: config.extra_subscribe_accounts.clone(),
+            resubscribe_rx,
+            network_slot: network_slot(config),
+        })
+    }
+
+    fn run(&self) -> Result<()> {
+        let mut backoff =
+            ReconnectBackoff::new(self.reconnect_backoff_base, self.reconnect_backoff_max);
+
+        info!("Entering the WsSubscriber loop.");
+        while !self.stop.load(Ordering::Relaxed) {
+            // Rebuilt on every subscription to pick up the Oracles and the Mints added since the last one,
+            // covering the pending resubscription requests
+            self.resubscribe_rx.try_iter().for_each(drop);
+            let watched = WatchedAddresses {
+                marginfi_program_id: self.marginfi_program_id,
+                oracles: self
+                    .cache
+                    .get_watched_oracle_addresses()?
+                    .into_iter()
+                    .collect(),
+                mints: self
+                    .cache
+                    .get_watched_mint_addresses()?
+                    .into_iter()
+                    .collect(),
+                vaults: self.cache.banks.get_vaults()?.into_iter().collect(),
+                extra: self.extra_subscribe_accounts.iter().cloned().collect(),
+            };
+
+            info!("Subscribing over WebSocket...");
+
+            match self.stream_updates(&watched) {
+                Ok(StreamEnd::Resubscribe) => continue,
+                Ok(StreamEnd::Disconnected(streamed))
+                    if streamed >= MIN_HEALTHY_STREAM_DURATION =>
+                {
+                    backoff.reset()
+                }
+                Ok(StreamEnd::Disconnected(_)) => {}
+                Err(e) => error!("WebSocket subscription failed: {}", e),
+            }
+
+            if self.stop.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let delay = backoff.next_delay();
+            info!("Resubscribing over WebSocket in {:?}...", delay);
+            sleep_unless_stopped(&self.stop, delay);
+        }
+        info!("The WsSubscriber loop is stopped.");
+
+        Ok(())
+    }
+}
+
+/// Why the subscriptions stopped streaming.
+enum StreamEnd {
+    /// A subscription failed or the slot stalled after yielding messages for this long.
+    Disconnected(Duration),
+    /// New Oracles or Mints are to be subscribed to right away.
+    Resubscribe,
+}
+
+/// Maps the WebSocket account update to the same messages the Geyser updates produce.
+/// The programSubscribe does not notify the closes of the Marginfi accounts, as the closed account
+/// is owned by the System program, so they stay cached until the compaction evicts them.
+fn handle_update(
+    watched: &WatchedAddresses,
+    clock: &Clock,
+    geyser_tx: &GeyserSender,
+    slot: u64,
+    address: Pubkey,
+    account: Account,
+) -> Result<()> {
+    if slot < clock.slot {
+        trace!(
+            "Ignoring the outdated update of {} at slot {}.",
+            address,
+            slot
+        );
+        return Ok(());
+    }
+
+    let mut message_types = Vec::new();
+    if watched.extra.contains(&address) {
+        message_types.push(MessageType::Watched);
+    }
+    if account.owner == watched.marginfi_program_id {
+        message_types.extend(get_marginfi_message_type(&account.data));
+    } else if address == sysvar::clock::id() {
+        message_types.push(MessageType::Clock);
+    } else {
+        message_types.extend(watched_message_types(
+            watched.oracles.contains(&address),
+            watched.mints.contains(&address),
+            watched.vaults.contains(&address),
+        ));
+    }
+
+    if message_types.is_empty() {
+        trace!("Ignoring update for unrecognized account: {}", address);
+    }
+    for message_type in message_types {
+        geyser_tx.send(GeyserMessage {
+            message_type,
+            slot,
+            address,
+            account: account.clone(),
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam::channel;
+
+    use super::*;
+    use crate::cache::test_util::generate_test_clock;
+
+    fn watched(oracles: Vec<Pubkey>, mints: Vec<Pubkey>) -> WatchedAddresses {
+        WatchedAddresses {
+            marginfi_program_id: Pubkey::new_unique(),
+            oracles: oracles.into_iter().collect(),
+            mints: mints.into_iter().collect(),
+            vaults: HashSet::new(),
+            extra: HashSet::new(),
+        }
+    }
+
+    fn account(owner: Pubkey, lamports: u64, data: Vec<u8>) -> Account {
+        Account {
+            lamports,
+            data,
+            owner,
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+
+    #[test]
+    fn test_handle_update_maps_message_types() {
+        let (tx, rx) = channel::unbounded();
+        let tx = GeyserSender::new(tx.clone(), tx);
+        let clock = generate_test_clock(5);
+        let oracle = Pubkey::new_unique();
+        let lst_mint = Pubkey::new_unique();
+        let watched = watched(vec![oracle, lst_mint], vec![lst_mint]);
+        let program_id = watched.marginfi_program_id;
+
+        let mut bank_data = MARGINFI_BANK_DISCRIMINATOR.to_vec();
+        bank_data.extend_from_slice(&[1, 2, 3]);
+        let bank = Pubkey::new_unique();
+        handle_update(
+            &watched,
+            &clock,
+            &tx,
+            5,
+            bank,
+            account(program_id, 1, bank_data),
+        )
+        .unwrap();
+        let msg = rx.try_recv().unwrap();
+        assert_eq!(msg.message_type, MessageType::Bank);
+        assert_eq!(msg.address, bank);
+        assert_eq!(msg.slot, 5);
+
+        handle_update(
+            &watched,
+            &clock,
+            &tx,
+            6,
+            sysvar::clock::id(),
+            account(sysvar::id(), 1, vec![]),
+        )
+        .unwrap();
+        assert_eq!(rx.try_recv().unwrap().message_type, MessageType::Clock);
+
+        handle_update(
+            &watched,
+            &clock,
+            &tx,
+            6,
+            oracle,
+            account(Pubkey::new_unique(), 1, vec![]),
+        )
+        .unwrap();
+        assert_eq!(rx.try_recv().unwrap().message_type, MessageType::Oracle);
+
+        handle_update(
+            &watched,
+            &clock,
+            &tx,
+            6,
+            lst_mint,
+            account(Pubkey::new_unique(), 1, vec![]),
+        )
+        .unwrap();
+        assert_eq!(rx.try_recv().unwrap().message_type, MessageType::Oracle);
+        assert_eq!(rx.try_recv().unwrap().message_type, MessageType::Mint);
+
+        handle_update(
+            &watched,
+            &clock,
+            &tx,
+            6,
+            Pubkey::new_unique(),
+            account(Pubkey::new_unique(), 1, vec![]),
+        )
+        .unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_handle_update_forwards_watched_account() {
+        let (tx, rx) = channel::unbounded();
+        let tx = GeyserSender::new(tx.clone(), tx);
+        let clock = generate_test_clock(5);
+        let mut watched = watched(vec![], vec![]);
+        let group = Pubkey::new_unique();
+        watched.extra.insert(group);
+
+        handle_update(
+            &watched,
+            &clock,
+            &tx,
+            5,
+            group,
+            account(watched.marginfi_program_id, 1, vec![1, 2, 3]),
+        )
+        .unwrap();
+        assert_eq!(rx.try_recv().unwrap().message_type, MessageType::Watched);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_handle_update_forwards_vault() {
+        let (tx, rx) = channel::unbounded();
+        let tx = GeyserSender::new(tx.clone(), tx);
+        let clock = generate_test_clock(5);
+        let mut watched = watched(vec![], vec![]);
+        let vault = Pubkey::new_unique();
+        watched.vaults.insert(vault);
+
+        handle_update(
+            &watched,
+            &clock,
+            &tx,
+            5,
+            vault,
+            account(Pubkey::new_unique(), 1, vec![0; 165]),
+        )
+        .unwrap();
+        assert_eq!(rx.try_recv().unwrap().message_type, MessageType::Vault);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_handle_update_skips_outdated_slot() {
+        let (tx, rx) = channel::unbounded();
+        let tx = GeyserSender::new(tx.clone(), tx);
+        let clock = generate_test_clock(5);
+        let oracle = Pubkey::new_unique();
+
+        handle_update(
+            &watched(vec![oracle], vec![]),
+            &clock,
+            &tx,
+            4,
+            oracle,
+            account(Pubkey::new_unique(), 1, vec![]),
+        )
+        .unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+}